@@ -0,0 +1 @@
+ALTER TABLE principal ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
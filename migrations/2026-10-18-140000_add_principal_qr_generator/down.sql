@@ -0,0 +1 @@
+ALTER TABLE principal DROP COLUMN qr_generator;
@@ -0,0 +1 @@
+ALTER TABLE principal ADD COLUMN qr_generator VARCHAR(255);
@@ -0,0 +1,5 @@
+DROP INDEX refresh_token_family_idx;
+
+ALTER TABLE refresh_token DROP COLUMN rotated_at;
+ALTER TABLE refresh_token DROP COLUMN session_expiry;
+ALTER TABLE refresh_token DROP COLUMN family;
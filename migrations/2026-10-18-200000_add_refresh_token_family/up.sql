@@ -0,0 +1,10 @@
+ALTER TABLE refresh_token ADD COLUMN family UUID;
+ALTER TABLE refresh_token ADD COLUMN session_expiry TIMESTAMP WITH TIME ZONE;
+ALTER TABLE refresh_token ADD COLUMN rotated_at TIMESTAMP WITH TIME ZONE;
+
+UPDATE refresh_token SET family = uuid, session_expiry = expiry;
+
+ALTER TABLE refresh_token ALTER COLUMN family SET NOT NULL;
+ALTER TABLE refresh_token ALTER COLUMN session_expiry SET NOT NULL;
+
+CREATE INDEX refresh_token_family_idx ON refresh_token(family);
@@ -0,0 +1,4 @@
+ALTER TABLE refresh_token DROP COLUMN ip_address;
+ALTER TABLE refresh_token DROP COLUMN user_agent;
+ALTER TABLE refresh_token DROP COLUMN last_used_at;
+ALTER TABLE refresh_token DROP COLUMN created_at;
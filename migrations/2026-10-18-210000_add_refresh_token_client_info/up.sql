@@ -0,0 +1,7 @@
+ALTER TABLE refresh_token ADD COLUMN created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+ALTER TABLE refresh_token ADD COLUMN last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+ALTER TABLE refresh_token ADD COLUMN user_agent TEXT;
+ALTER TABLE refresh_token ADD COLUMN ip_address TEXT;
+
+ALTER TABLE refresh_token ALTER COLUMN created_at DROP DEFAULT;
+ALTER TABLE refresh_token ALTER COLUMN last_used_at DROP DEFAULT;
@@ -0,0 +1,3 @@
+DROP TABLE password_reset_token;
+
+ALTER TABLE principal DROP COLUMN email;
@@ -0,0 +1,9 @@
+ALTER TABLE principal ADD COLUMN email VARCHAR(255);
+
+CREATE TABLE password_reset_token(
+    pk SERIAL PRIMARY KEY,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    expiry TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    fk_principal INTEGER REFERENCES principal(pk) NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE login_throttle(
+    pk SERIAL PRIMARY KEY,
+    scope VARCHAR(16) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    failed_attempts INTEGER NOT NULL,
+    last_failure TIMESTAMP WITH TIME ZONE NOT NULL,
+    blocked_until TIMESTAMP WITH TIME ZONE,
+    UNIQUE (scope, key)
+);
@@ -0,0 +1,6 @@
+DROP TABLE totp_challenge;
+DROP TABLE totp_recovery_code;
+
+ALTER TABLE principal DROP COLUMN totp_last_used_step;
+ALTER TABLE principal DROP COLUMN totp_enabled;
+ALTER TABLE principal DROP COLUMN totp_secret;
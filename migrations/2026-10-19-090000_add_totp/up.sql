@@ -0,0 +1,18 @@
+ALTER TABLE principal ADD COLUMN totp_secret BYTEA;
+ALTER TABLE principal ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE principal ADD COLUMN totp_last_used_step BIGINT;
+
+CREATE TABLE totp_recovery_code(
+    pk SERIAL PRIMARY KEY,
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    fk_principal INTEGER REFERENCES principal(pk) NOT NULL
+);
+
+CREATE TABLE totp_challenge(
+    pk SERIAL PRIMARY KEY,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    expiry TIMESTAMP WITH TIME ZONE NOT NULL,
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    fk_principal INTEGER REFERENCES principal(pk) NOT NULL
+);
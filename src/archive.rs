@@ -0,0 +1,578 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io::{self, Seek, SeekFrom, Write},
+    rc::Rc,
+};
+
+#[cfg(feature = "server")]
+use tokio::runtime::Handle;
+#[cfg(feature = "server")]
+use warp::hyper::{
+    body::{Bytes, Sender},
+    Body,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{error::Error, templating::QrData};
+
+const MANIFEST_FILE_NAME: &str = "manifest.csv";
+
+/// Creates a ZIP archive containing one PDF file for each provided [`QrData`] object, where `pdfs`
+/// contains the PDF file for each object in `qr_data_vec` in the same position, and writes it to
+/// `output`.
+///
+/// Each file is named after the reference number of the slip, or the debtor name if the slip has
+/// no reference number. Additionally, the archive contains a manifest.csv file that maps each file
+/// name to the reference and amount of the slip.
+///
+/// The archive is passed on to `output` while it is written, only the bytes written since the
+/// current file was started are kept in memory, see [`StreamingOutput`].
+pub fn create_slip_archive<W: Write>(
+    qr_data_vec: &[QrData],
+    pdfs: Vec<Vec<u8>>,
+    output: W,
+) -> Result<W, Error> {
+    let output = StreamingOutput::new(output);
+    let mut zip_writer = ZipWriter::new(output.clone());
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut manifest_writer = csv::Writer::from_writer(Vec::new());
+    let mut used_file_names = HashSet::new();
+
+    manifest_writer.write_record(
+        [
+            "file_name",
+            "debtor_name",
+            "reference_type",
+            "reference_number",
+            "amount",
+            "currency",
+        ]
+        .iter(),
+    )?;
+
+    for (qr_data, pdf) in qr_data_vec.iter().zip(pdfs) {
+        let file_name = unique_file_name(qr_data, "pdf", &mut used_file_names);
+
+        // starting the next file completes the previous one, nothing before it is modified anymore
+        let file_start = output.position();
+        zip_writer.start_file(file_name.as_str(), options)?;
+        output.stream_until(file_start)?;
+        zip_writer.write_all(&pdf)?;
+
+        manifest_writer.write_record(
+            [
+                file_name.as_str(),
+                qr_data.debtor_name(),
+                qr_data.reference_type(),
+                qr_data.reference_number().unwrap_or(""),
+                qr_data.amount().to_string().as_str(),
+                qr_data.currency().to_string().as_str(),
+            ]
+            .iter()
+            .map(|cell| escape_formula(cell)),
+        )?;
+    }
+
+    let manifest = manifest_writer.into_inner().map_err(|e| e.into_error())?;
+    zip_writer.start_file(MANIFEST_FILE_NAME, options)?;
+    zip_writer.write_all(&manifest)?;
+
+    zip_writer.finish()?;
+    drop(zip_writer);
+
+    Ok(output.into_inner()?)
+}
+
+/// Streams the ZIP archive created by [`create_slip_archive`] for the given slips into the returned
+/// response body.
+///
+/// The archive is written by a blocking task that waits until the client received each chunk. If
+/// writing the archive fails, the body is aborted so that the client does not receive a truncated
+/// archive as complete response.
+#[cfg(feature = "server")]
+pub fn stream_slip_archive(qr_data_vec: Vec<QrData>, pdfs: Vec<Vec<u8>>) -> Body {
+    let (sender, body) = Body::channel();
+    let writer = BodyWriter {
+        sender: Some(sender),
+        runtime: Handle::current(),
+    };
+
+    tokio::task::spawn_blocking(
+        move || match create_slip_archive(&qr_data_vec, pdfs, writer) {
+            Ok(writer) => writer.finish(),
+            Err(e) => log::warn!("Failed to stream slip archive: {}", e),
+        },
+    );
+
+    body
+}
+
+/// Prefixes cells that start with a character that spreadsheet applications interpret as the start
+/// of a formula with a single quote, so that the cell is displayed as text.
+fn escape_formula(cell: &str) -> String {
+    if cell.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        format!("'{}", cell)
+    } else {
+        cell.to_owned()
+    }
+}
+
+/// Output of the [`ZipWriter`] that passes the written archive on to the underlying writer.
+///
+/// The ZIP writer seeks back to the header of the file that is currently being written to fill in
+/// its size and checksum once the file is complete, so all bytes after the position passed to
+/// [`StreamingOutput::stream_until`] are kept in memory, where they can still be modified. Seeking
+/// to a position that has already been streamed fails.
+///
+/// The output is shared between the ZIP writer and [`create_slip_archive`], which decides when the
+/// bytes can be streamed.
+struct StreamingOutput<W: Write>(Rc<RefCell<StreamingState<W>>>);
+
+struct StreamingState<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    position: u64,
+}
+
+impl<W: Write> StreamingOutput<W> {
+    fn new(inner: W) -> Self {
+        StreamingOutput(Rc::new(RefCell::new(StreamingState {
+            inner,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            position: 0,
+        })))
+    }
+
+    fn position(&self) -> u64 {
+        self.0.borrow().position
+    }
+
+    /// Writes all buffered bytes before the given position to the underlying writer.
+    fn stream_until(&self, position: u64) -> io::Result<()> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        let len = position.saturating_sub(state.buffer_start) as usize;
+        let len = len.min(state.buffer.len());
+
+        state.inner.write_all(&state.buffer[..len])?;
+        state.buffer.drain(..len);
+        state.buffer_start += len as u64;
+        Ok(())
+    }
+
+    /// Writes the remaining bytes to the underlying writer and returns it. Must only be called after
+    /// all other references to the output have been dropped.
+    fn into_inner(self) -> io::Result<W> {
+        self.stream_until(u64::MAX)?;
+        let state = Rc::try_unwrap(self.0)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "the output is still in use"))?
+            .into_inner();
+        let mut inner = state.inner;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Clone for StreamingOutput<W> {
+    fn clone(&self) -> Self {
+        StreamingOutput(Rc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Write for StreamingOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        let offset = (state.position - state.buffer_start) as usize;
+        let overwritten = buf.len().min(state.buffer.len() - offset);
+
+        state.buffer[offset..offset + overwritten].copy_from_slice(&buf[..overwritten]);
+        state.buffer.extend_from_slice(&buf[overwritten..]);
+        state.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Seek for StreamingOutput<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.0.borrow_mut();
+        let buffer_end = state.buffer_start as i128 + state.buffer.len() as i128;
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::Current(offset) => state.position as i128 + offset as i128,
+            SeekFrom::End(offset) => buffer_end + offset as i128,
+        };
+
+        if target < state.buffer_start as i128 || target > buffer_end {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot seek outside of the part of the archive that has not been streamed yet",
+            ));
+        }
+
+        state.position = target as u64;
+        Ok(state.position)
+    }
+}
+
+/// Writer sending the written bytes to a response body, used from a blocking task.
+///
+/// The body is aborted if the writer is dropped without calling [`BodyWriter::finish`].
+#[cfg(feature = "server")]
+struct BodyWriter {
+    sender: Option<Sender>,
+    runtime: Handle,
+}
+
+#[cfg(feature = "server")]
+impl BodyWriter {
+    /// Completes the response body.
+    fn finish(mut self) {
+        self.sender.take();
+    }
+}
+
+#[cfg(feature = "server")]
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "the response body is closed");
+        let sender = self.sender.as_mut().ok_or_else(closed)?;
+
+        self.runtime
+            .block_on(sender.send_data(Bytes::copy_from_slice(buf)))
+            .map_err(|_| closed())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for BodyWriter {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            sender.abort();
+        }
+    }
+}
+
+/// Creates a file name with the given extension for the slip of the given [`QrData`] object based
+/// on its reference number, or the debtor name if it has no reference number, that is not contained
+/// in `used_file_names` yet.
+pub fn unique_file_name(
+    qr_data: &QrData,
+    extension: &str,
+    used_file_names: &mut HashSet<String>,
+) -> String {
+    let name = match qr_data.reference_number() {
+        Some(reference_number) if !reference_number.is_empty() => reference_number,
+        _ => qr_data.debtor_name(),
+    };
+    let base_name = sanitise_file_name(name);
+
+    let mut file_name = format!("{}.{}", base_name, extension);
+    let mut suffix = 2;
+    while used_file_names.contains(&file_name) {
+        file_name = format!("{}_{}.{}", base_name, suffix, extension);
+        suffix += 1;
+    }
+
+    used_file_names.insert(file_name.clone());
+    file_name
+}
+
+/// Replaces whitespace with underscores and removes all characters that are not safe to use in a file
+/// name on all platforms.
+fn sanitise_file_name(name: &str) -> String {
+    let sanitised = name
+        .trim()
+        .chars()
+        .filter_map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                Some(c)
+            } else if c.is_whitespace() {
+                Some('_')
+            } else {
+                None
+            }
+        })
+        .collect::<String>();
+    let sanitised = sanitised.trim_matches('.');
+
+    if sanitised.is_empty() {
+        String::from("slip")
+    } else {
+        sanitised.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::payment::{Amount, Currency, Reference};
+
+    fn qr_data(debtor_name: &str, reference: Reference) -> QrData {
+        QrData::builder()
+            .creditor_iban("CH9300762011623852957".parse().unwrap())
+            .creditor("Pia Rutschmann", "Marktgasse 28", "9400", "Rorschach", "CH")
+            .debtor(debtor_name, "Musterstrasse 1", "8000", "Zürich", "CH")
+            .amount(Amount::from_cents(19995).unwrap())
+            .currency(Currency::Chf)
+            .reference(reference)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sanitise_file_name() {
+        assert_eq!(sanitise_file_name(" Hans Muster "), "Hans_Muster");
+        assert_eq!(sanitise_file_name("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitise_file_name("a/../b\\c"), "a..bc");
+        assert_eq!(sanitise_file_name(".."), "slip");
+        assert_eq!(sanitise_file_name("..hidden."), "hidden");
+        assert_eq!(sanitise_file_name("Ödön\u{0}\u{7}\u{1b}"), "Ödön");
+        assert_eq!(sanitise_file_name("a\tb\nc"), "a_b_c");
+        assert_eq!(sanitise_file_name("C:*?\"<>|"), "C");
+        assert_eq!(sanitise_file_name(""), "slip");
+        assert_eq!(sanitise_file_name("\u{0}/\\:"), "slip");
+    }
+
+    #[test]
+    fn test_unique_file_name() {
+        let mut used_file_names = HashSet::new();
+        let scor = || Reference::scor("RF18539007547034").unwrap();
+
+        assert_eq!(
+            unique_file_name(&qr_data("Hans Muster", scor()), "pdf", &mut used_file_names),
+            "RF18539007547034.pdf"
+        );
+        assert_eq!(
+            unique_file_name(
+                &qr_data("Simon Muster", scor()),
+                "pdf",
+                &mut used_file_names
+            ),
+            "RF18539007547034_2.pdf"
+        );
+        assert_eq!(
+            unique_file_name(
+                &qr_data("Simon Muster", scor()),
+                "pdf",
+                &mut used_file_names
+            ),
+            "RF18539007547034_3.pdf"
+        );
+        assert_eq!(
+            unique_file_name(
+                &qr_data("Simon Muster", scor()),
+                "png",
+                &mut used_file_names
+            ),
+            "RF18539007547034.png"
+        );
+
+        // slips without reference number are named after the debtor
+        assert_eq!(
+            unique_file_name(
+                &qr_data("Hans Muster", Reference::Non),
+                "pdf",
+                &mut used_file_names
+            ),
+            "Hans_Muster.pdf"
+        );
+        // a name taken by a previous suffix gets the next free suffix
+        used_file_names.insert(String::from("Hans_Muster_2.pdf"));
+        assert_eq!(
+            unique_file_name(
+                &qr_data("Hans Muster", Reference::Non),
+                "pdf",
+                &mut used_file_names
+            ),
+            "Hans_Muster_3.pdf"
+        );
+        assert_eq!(
+            unique_file_name(&qr_data("...", Reference::Non), "pdf", &mut used_file_names),
+            "slip.pdf"
+        );
+        assert_eq!(
+            unique_file_name(&qr_data("...", Reference::Non), "pdf", &mut used_file_names),
+            "slip_2.pdf"
+        );
+    }
+
+    #[test]
+    fn test_create_slip_archive() {
+        let qr_data_vec = vec![
+            qr_data("Hans Muster", Reference::scor("RF18539007547034").unwrap()),
+            qr_data("Pia Rutschmann", Reference::Non),
+            qr_data("Pia Rutschmann", Reference::Non),
+        ];
+        let pdfs = vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()];
+
+        let archive = create_slip_archive(&qr_data_vec, pdfs, Vec::new()).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 4);
+
+        let mut read_file = |name: &str| {
+            let mut content = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        assert_eq!(read_file("RF18539007547034.pdf"), "first");
+        assert_eq!(read_file("Pia_Rutschmann.pdf"), "second");
+        assert_eq!(read_file("Pia_Rutschmann_2.pdf"), "third");
+        assert_eq!(
+            read_file(MANIFEST_FILE_NAME),
+            "file_name,debtor_name,reference_type,reference_number,amount,currency\n\
+             RF18539007547034.pdf,Hans Muster,SCOR,RF18539007547034,199.95,CHF\n\
+             Pia_Rutschmann.pdf,Pia Rutschmann,NON,,199.95,CHF\n\
+             Pia_Rutschmann_2.pdf,Pia Rutschmann,NON,,199.95,CHF\n"
+        );
+    }
+
+    /// Writer recording the chunks it receives.
+    #[derive(Default)]
+    struct ChunkWriter(Vec<Vec<u8>>);
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_create_slip_archive_streamed() {
+        let qr_data_vec = vec![
+            qr_data("Hans Muster", Reference::Non),
+            qr_data("Pia Rutschmann", Reference::Non),
+            qr_data("Simon Muster", Reference::Non),
+        ];
+        // incompressible content, so the compressed files are written while they are added
+        let mut seed = 1u32;
+        let mut random_pdf = || {
+            (0..100_000)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (seed >> 24) as u8
+                })
+                .collect::<Vec<u8>>()
+        };
+        let pdfs = vec![random_pdf(), random_pdf(), random_pdf()];
+
+        let chunks = create_slip_archive(&qr_data_vec, pdfs.clone(), ChunkWriter::default())
+            .unwrap()
+            .0;
+        // the completed files are streamed whenever the next file is started, so at most the current
+        // file and the output still held back by the compression are kept in memory
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() < 2 * 100_000));
+
+        let mut archive = ZipArchive::new(Cursor::new(chunks.concat())).unwrap();
+        assert_eq!(archive.len(), 4);
+        let mut content = Vec::new();
+        archive
+            .by_name("Pia_Rutschmann.pdf")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, pdfs[1]);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_stream_slip_archive() {
+        let qr_data_vec = vec![
+            qr_data("Hans Muster", Reference::Non),
+            qr_data("Pia Rutschmann", Reference::Non),
+        ];
+        let pdfs = vec![b"first".to_vec(), b"second".to_vec()];
+
+        let body = stream_slip_archive(qr_data_vec, pdfs);
+        let archive = warp::hyper::body::to_bytes(body).await.unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 3);
+
+        let mut content = String::new();
+        archive
+            .by_name("Pia_Rutschmann.pdf")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "second");
+    }
+
+    #[test]
+    fn test_streaming_output() {
+        let output = StreamingOutput::new(Vec::new());
+        let mut writer = output.clone();
+
+        writer.write_all(b"header data").unwrap();
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"HEADER").unwrap();
+        assert_eq!(writer.seek(SeekFrom::End(0)).unwrap(), 11);
+        output.stream_until(7).unwrap();
+
+        // streamed bytes cannot be modified anymore
+        assert!(writer.seek(SeekFrom::Start(6)).is_err());
+        assert!(writer.seek(SeekFrom::Current(12)).is_err());
+        assert_eq!(writer.seek(SeekFrom::Start(7)).unwrap(), 7);
+        writer.write_all(b"DATA and more").unwrap();
+        assert_eq!(output.position(), 20);
+
+        drop(writer);
+        assert_eq!(output.into_inner().unwrap(), b"HEADER DATA and more");
+    }
+
+    #[test]
+    fn test_escape_formula() {
+        assert_eq!(escape_formula("=1+2"), "'=1+2");
+        assert_eq!(escape_formula("+41 79"), "'+41 79");
+        assert_eq!(escape_formula("-x.pdf"), "'-x.pdf");
+        assert_eq!(escape_formula("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(escape_formula("\tx"), "'\tx");
+        assert_eq!(escape_formula("\rx"), "'\rx");
+        assert_eq!(escape_formula("Hans Muster"), "Hans Muster");
+        assert_eq!(escape_formula("199.95"), "199.95");
+        assert_eq!(escape_formula(""), "");
+    }
+
+    #[test]
+    fn test_manifest_escapes_formulas() {
+        let qr_data_vec = vec![qr_data("-2+3", Reference::Non)];
+        let archive = create_slip_archive(&qr_data_vec, vec![b"pdf".to_vec()], Vec::new()).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+
+        let mut manifest = String::new();
+        archive
+            .by_name(MANIFEST_FILE_NAME)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        assert_eq!(
+            manifest,
+            "file_name,debtor_name,reference_type,reference_number,amount,currency\n\
+             '-23.pdf,'-2+3,NON,,199.95,CHF\n"
+        );
+    }
+}
@@ -1,11 +1,24 @@
+use std::{
+    borrow::Cow,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
 use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{offset::Utc, Duration};
-use diesel::{dsl::count, expression::dsl::any, expression_methods::BoolExpressionMethods};
+use chrono::{offset::Utc, DateTime, Duration};
+use diesel::{
+    dsl::{self, count},
+    expression::dsl::any,
+    expression_methods::BoolExpressionMethods,
+    pg::Pg,
+    sql_types::Bool,
+    BoxableExpression, Connection,
+};
 use exec_rs::sync::MutexSync;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use validator::{Validate, ValidationError, ValidationErrors};
 use warp::{
     filters::header::headers_cloned,
     http::{
@@ -17,10 +30,21 @@ use warp::{
 
 use crate::{
     acquire_db_connection,
+    cache::hash_bytes,
     diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl},
     error::Error,
-    model::{NewPrincipal, NewRefreshToken, NewUser, Principal, RefreshToken, User},
-    schema::{principal, qr_user, refresh_token},
+    jwt::JWT_KEYS,
+    model::{
+        NewPasswordResetToken, NewPrincipal, NewRefreshToken, NewUser, PasswordResetToken,
+        Principal, RefreshToken, User,
+    },
+    notifier::{Notification, NOTIFIER, PASSWORD_RESET_TOKEN_EXPIRY_MINUTES},
+    password::{validate_password, PASSWORD_POLICY},
+    payment::Iban,
+    schema::{password_reset_token, principal, qr_user, refresh_token},
+    templating::validate_character_set,
+    throttle, totp,
+    validation::ordered_field_errors,
     DbConnection,
 };
 
@@ -38,17 +62,118 @@ pub struct LoginResponse {
     pub expiration_secs: i64,
 }
 
-/// Struct received by the /register endpoint used to create a principal and one qr_user.
+/// Struct returned by the /login endpoint instead of a [`LoginResponse`] if the principal has enabled
+/// two-factor authentication, the login is completed by /login-totp.
+#[derive(Serialize)]
+pub struct TotpChallengeResponse {
+    pub totp_token: String,
+    pub expiration_secs: i64,
+}
+
+/// Struct received by the /login-totp endpoint, either a code generated by the authenticator or a
+/// recovery code must be provided.
 #[derive(Deserialize)]
+pub struct TotpLoginRequest {
+    pub totp_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+/// Struct received by the /register endpoint used to create a principal and one qr_user. The
+/// creditor fields are validated like the creditor of a [`QrData`](crate::templating::QrData)
+/// object and the password has to satisfy the [`PASSWORD_POLICY`].
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "validate_registration", skip_on_field_errors = true))]
 pub struct UserRegistration {
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     pub name: String,
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     pub address: String,
+    #[validate(length(min = 1), custom = "validate_character_set")]
     pub zip_code: String,
+    #[validate(length(min = 1), custom = "validate_character_set")]
     pub city: String,
+    #[validate(custom = "validate_iban")]
     pub iban: String,
+    #[validate(length(min = 2, max = 2))]
     pub country: String,
+    #[validate(length(min = 3, max = 64), custom = "validate_user_name")]
     pub user_name: String,
+    #[validate(custom = "validate_password")]
     pub password: String,
+    /// The address password reset tokens are sent to, optional.
+    #[validate(email(code = "invalid_email", message = "Invalid email address"))]
+    pub email: Option<String>,
+}
+
+/// The fields of [`UserRegistration`] in the order their errors are reported.
+const USER_REGISTRATION_FIELDS: [&str; 9] = [
+    "user_name",
+    "password",
+    "email",
+    "name",
+    "address",
+    "zip_code",
+    "city",
+    "iban",
+    "country",
+];
+
+/// Struct received by the /change-password endpoint.
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+/// Struct received by the /request-password-reset endpoint.
+#[derive(Deserialize)]
+pub struct PasswordResetRequest {
+    pub user_name: String,
+}
+
+/// Struct received by the /reset-password endpoint.
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// An active login session of the principal, i.e. a family of refresh tokens, returned by the
+/// /sessions endpoint.
+#[derive(Serialize)]
+pub struct Session {
+    pub id: String,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub expires_at: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Whether the session belongs to the refresh_token cookie sent with the request.
+    pub current: bool,
+}
+
+impl Session {
+    /// Creates the session of the latest refresh token of a family, which is the current session if
+    /// the token matches the provided refresh token of the request.
+    fn from_token(token: RefreshToken, current_token_uuid: Option<Uuid>) -> Session {
+        Session {
+            id: token.family.to_string(),
+            created_at: token.created_at.to_rfc3339(),
+            last_used_at: token.last_used_at.to_rfc3339(),
+            expires_at: token.expiry.to_rfc3339(),
+            current: current_token_uuid == Some(token.uuid),
+            user_agent: token.user_agent,
+            ip_address: token.ip_address,
+        }
+    }
+}
+
+/// Information about the client recorded for each refresh token to identify sessions.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
 }
 
 /// Struct encoded in the JWT that contains its expiry and subject principal.
@@ -69,6 +194,102 @@ pub fn with_principal() -> impl warp::Filter<Extract = (Principal,), Error = Rej
     headers_cloned().and_then(require_principal_from_auth_header)
 }
 
+/// Warp filter for requests that require a logged in principal with admin permissions. Admins are
+/// assigned by setting the is_admin column of the principal table in the database.
+pub fn with_admin() -> impl warp::Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    with_principal().and_then(require_admin)
+}
+
+/// Warp filter that extracts the [`ClientInfo`] from the User-Agent header and the remote address
+/// of the request, or the X-Real-IP header set by the reverse proxy if TRUST_X_REAL_IP is enabled.
+/// User agents longer than MAX_USER_AGENT_LENGTH characters are truncated.
+pub fn with_client_info() -> impl warp::Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("user-agent")
+        .and(warp::header::optional::<IpAddr>("x-real-ip"))
+        .and(warp::addr::remote())
+        .map(
+            |user_agent: Option<String>,
+             real_ip: Option<IpAddr>,
+             remote_addr: Option<SocketAddr>| {
+                let ip_address = match real_ip {
+                    Some(real_ip) if *TRUST_X_REAL_IP => Some(real_ip),
+                    _ => remote_addr.map(|addr| addr.ip()),
+                };
+
+                ClientInfo {
+                    user_agent: user_agent
+                        .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+                    ip_address: ip_address.map(|ip_address| ip_address.to_string()),
+                }
+            },
+        )
+}
+
+fn validate_registration(registration: &UserRegistration) -> Result<(), ValidationError> {
+    validate_zip_code_city_length(&registration.zip_code, &registration.city)
+}
+
+fn validate_create_user(create_user: &CreateUser) -> Result<(), ValidationError> {
+    validate_zip_code_city_length(&create_user.zip_code, &create_user.city)
+}
+
+fn validate_zip_code_city_length(zip_code: &str, city: &str) -> Result<(), ValidationError> {
+    if zip_code.len() + city.len() > 69 {
+        let mut error = ValidationError::new("zip_code_city_length");
+        error.message = Some(Cow::from(
+            "Combined length of zip code and city may not exceed 69",
+        ));
+        error.add_param(Cow::from("field"), &"city");
+        error.add_param(Cow::from("max"), &69);
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn validate_iban(iban: &str) -> Result<(), ValidationError> {
+    if Iban::from_str(iban).is_err() {
+        let mut error = ValidationError::new("invalid_iban");
+        error.message = Some(Cow::from(
+            "Invalid IBAN, expected a valid IBAN from Switzerland or Liechtenstein",
+        ));
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn validate_user_name(user_name: &str) -> Result<(), ValidationError> {
+    let is_permitted = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+    if !user_name.chars().all(is_permitted) {
+        let mut error = ValidationError::new("invalid_user_name");
+        error.message = Some(Cow::from(
+            "User names may only contain letters, digits, '.', '_' and '-'",
+        ));
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+/// Validates a new password against the [`PASSWORD_POLICY`], returning the error for the
+/// `new_password` field.
+fn check_new_password(password: &str) -> Result<(), Error> {
+    PASSWORD_POLICY.check(password).map_err(|error| {
+        let mut errors = ValidationErrors::new();
+        errors.add("new_password", error);
+        Error::InvalidFieldsError(ordered_field_errors(&errors, &["new_password"]))
+    })
+}
+
+async fn require_admin(principal: Principal) -> Result<Principal, Rejection> {
+    if principal.is_admin {
+        Ok(principal)
+    } else {
+        Err(warp::reject::custom(Error::MissingPermissionError))
+    }
+}
+
 async fn require_principal_from_auth_header(header_map: HeaderMap) -> Result<Principal, Rejection> {
     match get_principal_from_auth_header(header_map).await {
         Ok(Some(principal)) => Ok(principal),
@@ -98,13 +319,7 @@ async fn get_principal_from_auth_header(
     }
 
     let jwt_token = auth_header.trim_start_matches(JWT_BEARER_PREFIX);
-    let token_data = decode::<Claims>(
-        jwt_token,
-        &DecodingKey::from_secret(&crate::JWT_SECRET.to_be_bytes()),
-        &Validation::new(Algorithm::HS512),
-    )
-    .map_err(|_| warp::reject::custom(Error::InvalidJwtError))?;
-    let claims = &token_data.claims;
+    let claims = JWT_KEYS.decode::<Claims>(jwt_token)?;
 
     let connection = acquire_db_connection()?;
     match principal::table
@@ -119,67 +334,174 @@ async fn get_principal_from_auth_header(
 /// Handler for the /login endpoint that receives a json deserialized to the [`LoginRequest`] struct
 /// and returns a [`LoginResponse`] if the credentials are correct or a InvalidCredentialsError, which
 /// results in a 403, if the credentials are not correct.
-pub async fn login_handler(request: LoginRequest) -> Result<impl Reply, Rejection> {
+///
+/// Failed attempts are throttled per client IP address and user name, see [`crate::throttle`], while
+/// attempts are blocked a TooManyLoginAttemptsError is returned, which results in a 429, without
+/// checking the password. Each attempt is counted as failed before checking the password, so that
+/// concurrent attempts cannot bypass the throttle, and only undone if the password is correct.
+///
+/// If the principal has enabled two-factor authentication, a [`TotpChallengeResponse`] is returned
+/// instead and the login has to be completed by [`login_totp_handler`].
+pub async fn login_handler(
+    request: LoginRequest,
+    client: ClientInfo,
+) -> Result<warp::reply::Response, Rejection> {
+    // longer user names cannot exist and do not fit the throttle table
+    if request.user_name.chars().count() > 255 {
+        return Err(warp::reject::custom(Error::InvalidCredentialsError));
+    }
+
     let connection = acquire_db_connection()?;
+    let attempt = throttle::reserve_login_attempt(
+        client.ip_address.as_deref(),
+        &request.user_name,
+        &connection,
+    )?;
 
     let found_principal = principal::table
         .filter(principal::user_name.eq(&request.user_name))
-        .first::<Principal>(&connection);
+        .first::<Principal>(&connection)
+        .optional()
+        .map_err(|_| warp::reject::custom(Error::QueryError))?;
+    // verify unknown user names against a dummy hash so that the response time does not reveal
+    // which user names exist
+    let hashed_password = match found_principal {
+        Some(ref principal) => principal.password.clone(),
+        None => DUMMY_PASSWORD_HASH.clone(),
+    };
+    let password_matches = verify_password(request.password, hashed_password).await?;
     let principal = match found_principal {
-        Ok(principal) => {
-            let hashed_password = &principal.password;
-            match verify(&request.password, hashed_password) {
-                Ok(valid) => {
-                    if valid {
-                        principal
-                    } else {
-                        return Err(warp::reject::custom(Error::InvalidCredentialsError));
-                    }
-                }
-                Err(_) => return Err(warp::reject::custom(Error::EncryptionError)),
-            }
+        Some(principal) if password_matches => principal,
+        _ => {
+            attempt.failed();
+            return Err(warp::reject::custom(Error::InvalidCredentialsError));
         }
-        Err(diesel::NotFound) => return Err(warp::reject::custom(Error::InvalidCredentialsError)),
-        Err(_) => return Err(warp::reject::custom(Error::QueryError)),
     };
 
-    let refresh_token_cookie = create_refresh_token_cookie(&principal, &connection)?;
+    // failed attempts of the user name are only reset once the second factor has been checked
+    if principal.totp_enabled {
+        attempt.release(&connection)?;
+        let totp_token = totp::create_totp_challenge(principal.pk, &connection)?;
+        return Ok(warp::reply::json(&TotpChallengeResponse {
+            totp_token,
+            expiration_secs: Duration::minutes(totp::TOTP_CHALLENGE_EXPIRY_MINUTES).num_seconds(),
+        })
+        .into_response());
+    }
+    attempt.succeeded(&connection)?;
+
+    let refresh_token_cookie = create_refresh_token_cookie(&principal, client, &connection)?;
+    Ok(create_login_response(&principal, refresh_token_cookie)?.into_response())
+}
+
+/// Handler for the /login-totp endpoint that completes the login of a principal with two-factor
+/// authentication enabled using the token returned by /login and a code generated by the
+/// authenticator or one of the recovery codes, see [`totp::complete_totp_challenge`]. Returns the
+/// same response as a /login without two-factor authentication if the code is valid.
+pub async fn login_totp_handler(
+    request: TotpLoginRequest,
+    client: ClientInfo,
+) -> Result<impl Reply, Rejection> {
+    let connection = acquire_db_connection()?;
+    let principal = totp::complete_totp_challenge(
+        &request.totp_token,
+        request.code.as_deref(),
+        request.recovery_code.as_deref(),
+        client.ip_address.as_deref(),
+        &connection,
+    )?;
+
+    let refresh_token_cookie = create_refresh_token_cookie(&principal, client, &connection)?;
     create_login_response(&principal, refresh_token_cookie)
 }
 
+/// Verifies the password against the bcrypt hash on a blocking thread, as bcrypt is deliberately
+/// slow and would otherwise block the async executor.
+pub(crate) async fn verify_password(
+    password: String,
+    hashed_password: String,
+) -> Result<bool, Error> {
+    tokio::task::spawn_blocking(move || verify(&password, &hashed_password))
+        .await
+        .map_err(|_| Error::EncryptionError)?
+        .map_err(|_| Error::EncryptionError)
+}
+
+/// Hashes the password using bcrypt on a blocking thread, see [`verify_password`]. Passwords are
+/// hashed before starting a transaction so that no locks are held while hashing.
+async fn hash_password(password: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || hash(&password, DEFAULT_COST))
+        .await
+        .map_err(|_| Error::EncryptionError)?
+        .map_err(|_| Error::EncryptionError)
+}
+
 /// Create a HttpOnly Cookie that may be used to refresh logins by generating a UUID which is persisted
 /// to the database as a RefreshToken entity which links the UUID to the Principal.
+///
+/// Each login starts a new token family, whose session expires after REFRESH_SESSION_LIFETIME_HOURS
+/// regardless of how often the token is refreshed.
 fn create_refresh_token_cookie(
     principal: &Principal,
+    client: ClientInfo,
     connection: &DbConnection,
 ) -> Result<String, Rejection> {
-    let uuid = Uuid::new_v4();
     let current_utc = Utc::now();
-    let expiry = current_utc + Duration::hours(24);
+    let refresh_token = insert_refresh_token(
+        principal.pk,
+        Uuid::new_v4(),
+        current_utc,
+        current_utc + Duration::hours(*REFRESH_SESSION_LIFETIME_HOURS),
+        client,
+        connection,
+    )?;
+
+    Ok(refresh_token_cookie(&refresh_token))
+}
+
+/// Inserts a new refresh token of the given family, issued to the provided client, that expires in
+/// 24 hours, or at the end of the session if that is earlier.
+fn insert_refresh_token(
+    fk_principal: i32,
+    family: Uuid,
+    created_at: DateTime<Utc>,
+    session_expiry: DateTime<Utc>,
+    client: ClientInfo,
+    connection: &DbConnection,
+) -> Result<RefreshToken, Error> {
+    let current_utc = Utc::now();
 
     let new_refresh_token = NewRefreshToken {
-        uuid,
-        expiry,
+        uuid: Uuid::new_v4(),
+        expiry: refresh_token_expiry(current_utc, session_expiry),
         invalidated: false,
-        fk_principal: principal.pk,
+        fk_principal,
+        family,
+        session_expiry,
+        created_at,
+        last_used_at: current_utc,
+        user_agent: client.user_agent,
+        ip_address: client.ip_address,
     };
 
-    let refresh_token = match diesel::insert_into(refresh_token::table)
+    diesel::insert_into(refresh_token::table)
         .values(&new_refresh_token)
         .get_result::<RefreshToken>(connection)
-    {
-        Ok(refresh_token) => refresh_token,
-        Err(_) => return Err(warp::reject::custom(Error::QueryError)),
-    };
+        .map_err(|_| Error::QueryError)
+}
+
+/// The expiry of a refresh token issued at the provided time, 24 hours later but never after the end
+/// of its session.
+fn refresh_token_expiry(issued_at: DateTime<Utc>, session_expiry: DateTime<Utc>) -> DateTime<Utc> {
+    std::cmp::min(issued_at + Duration::hours(24), session_expiry)
+}
 
+fn refresh_token_cookie(refresh_token: &RefreshToken) -> String {
     let uuid = refresh_token.uuid.to_string();
     let expiry = refresh_token.expiry.to_rfc2822();
 
     // TODO set Secure once moving to production
-    Ok(format!(
-        "refresh_token={}; Expires={}; HttpOnly",
-        uuid, expiry
-    ))
+    format!("refresh_token={}; Expires={}; HttpOnly", uuid, expiry)
 }
 
 /// Create a [`LoginResponse`] for the provided Principal and add the provided refresh token cookie.
@@ -200,15 +522,7 @@ fn create_login_response(
         sub: principal.user_name.clone(),
     };
 
-    let header = Header::new(Algorithm::HS512);
-    let token = match encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(&crate::JWT_SECRET.to_be_bytes()),
-    ) {
-        Ok(token) => token,
-        Err(_) => return Err(warp::reject::custom(Error::JwtCreationError)),
-    };
+    let token = JWT_KEYS.encode(&claims)?;
 
     let json_response = serde_json::to_vec(&LoginResponse {
         token,
@@ -226,56 +540,608 @@ fn create_login_response(
 }
 
 /// Refreshes a login for the provided refresh token by creating a fresh JWT for the Principal linked
-/// to the refresh token and refreshes the refresh token with a new UUID and resets its expiration.
+/// to the refresh token and rotating the refresh token, i.e. replacing it with a new token of the
+/// same family with a new UUID and reset expiration.
+///
+/// Returns a [`LoginResponse`] with the new JWT if the refresh token is valid (the UUID exists, has
+/// not been invalidated or rotated and neither the refresh token nor its session are expired) or
+/// else returns a InvalidRefreshTokenError which results in a 401.
 ///
-/// Returns a [`LoginResponse`] with the new JWT if the refresh token is valid (the UUID exists and
-/// the refresh token is not expired) or else returns a InvalidRefreshTokenError which results in a 401.
-pub async fn refresh_login_handler(refresh_token: String) -> Result<impl Reply, Rejection> {
+/// Using a refresh token that has already been rotated means that either the client or an attacker
+/// holds a stolen copy of the token, so the entire token family is invalidated, logging out both,
+/// and a security event is logged.
+pub async fn refresh_login_handler(
+    refresh_token: String,
+    client: ClientInfo,
+) -> Result<impl Reply, Rejection> {
     let connection = acquire_db_connection()?;
     let curr_token_uuid = Uuid::parse_str(&refresh_token)
         .map_err(|_| warp::reject::custom(Error::BadRequestError))?;
-    let current_utc = Utc::now();
 
-    let refresh_token = refresh_token::table
+    // lock the token so that concurrent requests cannot both rotate it
+    let rotation = connection.transaction::<_, Error, _>(|| {
+        let refresh_token = refresh_token::table
+            .filter(refresh_token::uuid.eq(&curr_token_uuid))
+            .for_update()
+            .first::<RefreshToken>(&connection)
+            .optional()
+            .map_err(|_| Error::QueryError)?
+            .ok_or(Error::InvalidRefreshTokenError)?;
+
+        let current_utc = Utc::now();
+        match refresh_token_use(&refresh_token, current_utc) {
+            RefreshTokenUse::Rotate => {}
+            RefreshTokenUse::InvalidateFamily => {
+                invalidate_token_family(refresh_token.family, &connection)?;
+                return Ok(TokenRotation::Reused(refresh_token));
+            }
+            RefreshTokenUse::Reject => return Err(Error::InvalidRefreshTokenError),
+        }
+
+        diesel::update(refresh_token::table)
+            .filter(refresh_token::pk.eq(refresh_token.pk))
+            .set(refresh_token::rotated_at.eq(current_utc))
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        insert_refresh_token(
+            refresh_token.fk_principal,
+            refresh_token.family,
+            refresh_token.created_at,
+            refresh_token.session_expiry,
+            client.clone(),
+            &connection,
+        )
+        .map(TokenRotation::Rotated)
+    })?;
+
+    let new_token = match rotation {
+        TokenRotation::Rotated(new_token) => new_token,
+        TokenRotation::Reused(reused_token) => {
+            log::warn!(
+                target: "qr_slip::security",
+                "Reuse of refresh token rotated at {} detected for principal {} from {}, invalidated token family {}",
+                reused_token
+                    .rotated_at
+                    .map_or_else(String::new, |rotated_at| rotated_at.to_rfc3339()),
+                reused_token.fk_principal,
+                client.ip_address.as_deref().unwrap_or("unknown address"),
+                reused_token.family
+            );
+            return Err(warp::reject::custom(Error::InvalidRefreshTokenError));
+        }
+    };
+
+    let principal = principal::table
+        .filter(principal::pk.eq(new_token.fk_principal))
+        .first::<Principal>(&connection)
+        .map_err(|_| warp::reject::custom(Error::QueryError))?;
+
+    create_login_response(&principal, refresh_token_cookie(&new_token))
+}
+
+/// The outcome of using a refresh token that has not been invalidated.
+enum TokenRotation {
+    /// The token was valid and has been replaced by the contained token.
+    Rotated(RefreshToken),
+    /// The token had already been rotated and its family has been invalidated.
+    Reused(RefreshToken),
+}
+
+/// How a refresh token used to refresh a login has to be handled.
+#[derive(Debug, PartialEq)]
+enum RefreshTokenUse {
+    /// The token is valid and is replaced by a new token of its family.
+    Rotate,
+    /// The token has already been rotated and is being reused, so its family is invalidated.
+    InvalidateFamily,
+    /// The token has been invalidated, or the token or its session has expired.
+    Reject,
+}
+
+/// Decides how the refresh token used at the provided time is handled. Reuse of a rotated token is
+/// detected even after the token expired, as long as its family has not been invalidated.
+fn refresh_token_use(refresh_token: &RefreshToken, current_utc: DateTime<Utc>) -> RefreshTokenUse {
+    if refresh_token.invalidated {
+        RefreshTokenUse::Reject
+    } else if refresh_token.rotated_at.is_some() {
+        RefreshTokenUse::InvalidateFamily
+    } else if refresh_token.expiry < current_utc || refresh_token.session_expiry < current_utc {
+        RefreshTokenUse::Reject
+    } else {
+        RefreshTokenUse::Rotate
+    }
+}
+
+fn invalidate_token_family(family: Uuid, connection: &DbConnection) -> Result<usize, Error> {
+    diesel::update(refresh_token::table)
         .filter(
-            refresh_token::uuid
-                .eq(&curr_token_uuid)
-                .and(refresh_token::expiry.ge(&current_utc))
+            refresh_token::family
+                .eq(family)
                 .and(refresh_token::invalidated.eq(false)),
         )
-        .first::<RefreshToken>(&connection)
-        .optional()
-        .map_err(|_| warp::reject::custom(Error::QueryError))?
-        .ok_or_else(|| warp::reject::custom(Error::InvalidRefreshTokenError))?;
+        .set(refresh_token::invalidated.eq(true))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)
+}
+
+/// Cookie replacing the refresh_token cookie when logging out so that the browser discards it.
+const CLEARED_REFRESH_TOKEN_COOKIE: &str =
+    "refresh_token=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0; HttpOnly";
+
+/// Invalidates the family of the refresh token provided by the `refresh_token` cookie, if any, and
+/// clears the cookie. Does not require a valid JWT so that a client can log out after the JWT expired.
+///
+/// Unknown or already invalidated refresh tokens are ignored, but a refresh token that is not a valid
+/// UUID results in a BadRequestError.
+pub async fn logout_handler(refresh_token: Option<String>) -> Result<impl Reply, Rejection> {
+    if let Some(refresh_token) = refresh_token {
+        let token_uuid = Uuid::parse_str(&refresh_token)
+            .map_err(|_| warp::reject::custom(Error::BadRequestError))?;
+        let connection = acquire_db_connection()?;
+
+        let family = refresh_token::table
+            .select(refresh_token::family)
+            .filter(refresh_token::uuid.eq(token_uuid))
+            .first::<Uuid>(&connection)
+            .optional()
+            .map_err(|_| warp::reject::custom(Error::QueryError))?;
+        if let Some(family) = family {
+            invalidate_token_family(family, &connection)?;
+        }
+    }
+
+    Ok(warp::reply::with_header(
+        warp::reply(),
+        header::SET_COOKIE,
+        CLEARED_REFRESH_TOKEN_COOKIE,
+    ))
+}
+
+/// Invalidates all refresh tokens of the logged in principal, logging out all of its sessions once
+/// their JWT expires, and clears the `refresh_token` cookie.
+pub async fn logout_all_handler(principal: Principal) -> Result<impl Reply, Rejection> {
+    let connection = acquire_db_connection()?;
+    invalidate_principal_refresh_tokens(principal.pk, &connection)?;
+
+    Ok(warp::reply::with_header(
+        warp::reply(),
+        header::SET_COOKIE,
+        CLEARED_REFRESH_TOKEN_COOKIE,
+    ))
+}
+
+fn invalidate_principal_refresh_tokens(
+    fk_principal: i32,
+    connection: &DbConnection,
+) -> Result<usize, Error> {
+    principal_refresh_tokens_invalidation(fk_principal)
+        .execute(connection)
+        .map_err(|_| Error::QueryError)
+}
+
+type RefreshTokensInvalidation = dsl::Update<
+    dsl::Filter<
+        refresh_token::table,
+        dsl::And<
+            dsl::Eq<refresh_token::fk_principal, i32>,
+            dsl::Eq<refresh_token::invalidated, bool>,
+        >,
+    >,
+    dsl::Eq<refresh_token::invalidated, bool>,
+>;
+
+/// The statement invalidating all refresh tokens of the principal.
+fn principal_refresh_tokens_invalidation(fk_principal: i32) -> RefreshTokensInvalidation {
+    diesel::update(
+        refresh_token::table.filter(
+            refresh_token::fk_principal
+                .eq(fk_principal)
+                .and(refresh_token::invalidated.eq(false)),
+        ),
+    )
+    .set(refresh_token::invalidated.eq(true))
+}
+
+/// Changes the password of the logged in principal if the provided old password matches the current
+/// password, or else returns a InvalidCredentialsError which results in a 403. Changing the password
+/// logs out all sessions, including the current one, by invalidating all refresh tokens of the
+/// principal and clearing the `refresh_token` cookie.
+///
+/// The new password is checked against the [`PASSWORD_POLICY`] first. Wrong old passwords count as
+/// failed login attempts of the principal, see [`crate::throttle`], so that a stolen JWT cannot be
+/// used to guess the password.
+pub async fn change_password_handler(
+    principal: Principal,
+    request: ChangePasswordRequest,
+    client: ClientInfo,
+) -> Result<impl Reply, Rejection> {
+    check_new_password(&request.new_password)?;
+
+    let connection = acquire_db_connection()?;
+    let attempt = throttle::reserve_login_attempt(
+        client.ip_address.as_deref(),
+        &principal.user_name,
+        &connection,
+    )?;
+    if !verify_password(request.old_password, principal.password.clone()).await? {
+        attempt.failed();
+        return Err(warp::reject::custom(Error::InvalidCredentialsError));
+    }
+    attempt.succeeded(&connection)?;
+
+    let hashed_password = hash_password(request.new_password).await?;
+    set_password(principal.pk, hashed_password, &connection)?;
+
+    Ok(warp::reply::with_header(
+        warp::reply(),
+        header::SET_COOKIE,
+        CLEARED_REFRESH_TOKEN_COOKIE,
+    ))
+}
+
+/// Starts the password reset for the principal with the provided user name by creating a reset token
+/// that expires after PASSWORD_RESET_TOKEN_EXPIRY_MINUTES and sending it to the email address of the
+/// principal using the configured [`Notifier`](crate::notifier::Notifier). Previously requested
+/// tokens that have not been used are deleted.
+///
+/// Always succeeds, even if the principal does not exist or has no email address, so that the
+/// endpoint cannot be used to find out which user names exist. For the same reason, the notification
+/// is sent in the background and failures are only logged.
+///
+/// Requests are throttled per client IP address and user name like failed logins, see
+/// [`throttle::record_password_reset_request`], throttled requests are ignored.
+pub async fn request_password_reset_handler(
+    request: PasswordResetRequest,
+    client: ClientInfo,
+) -> Result<impl Reply, Rejection> {
+    // longer user names cannot exist and do not fit the throttle table
+    if request.user_name.chars().count() > 255 {
+        return Ok(warp::reply());
+    }
+
+    let connection = acquire_db_connection()?;
+    let ip_address = client.ip_address.as_deref();
+    if !throttle::record_password_reset_request(ip_address, &request.user_name, &connection)? {
+        log::info!(
+            "Ignoring throttled password reset request from {}",
+            ip_address.unwrap_or("unknown address")
+        );
+        return Ok(warp::reply());
+    }
 
     let principal = principal::table
-        .filter(principal::pk.eq(refresh_token.fk_principal))
+        .filter(principal::user_name.eq(&request.user_name))
         .first::<Principal>(&connection)
+        .optional()
+        .map_err(|_| warp::reject::custom(Error::QueryError))?;
+    let principal = match principal {
+        Some(principal) => principal,
+        None => return Ok(warp::reply()),
+    };
+    let email = match principal.email {
+        Some(ref email) => email.clone(),
+        None => {
+            log::info!(
+                "Ignoring password reset request for principal {} without email address",
+                principal.pk
+            );
+            return Ok(warp::reply());
+        }
+    };
+
+    let token = Uuid::new_v4().to_simple().to_string();
+    connection.transaction::<_, Error, _>(|| {
+        diesel::delete(
+            password_reset_token::table.filter(
+                password_reset_token::fk_principal
+                    .eq(principal.pk)
+                    .and(password_reset_token::used_at.is_null()),
+            ),
+        )
+        .execute(&connection)
+        .map_err(|_| Error::QueryError)?;
+
+        let new_reset_token = NewPasswordResetToken {
+            token_hash: hash_bytes(token.as_bytes()),
+            expiry: Utc::now() + Duration::minutes(*PASSWORD_RESET_TOKEN_EXPIRY_MINUTES),
+            fk_principal: principal.pk,
+        };
+        diesel::insert_into(password_reset_token::table)
+            .values(&new_reset_token)
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)
+    })?;
+
+    let notification = Notification::password_reset(email, &principal.user_name, &token);
+    let fk_principal = principal.pk;
+    tokio::spawn(async move {
+        let result = tokio::task::spawn_blocking(move || NOTIFIER.notify(&notification)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!(
+                "Failed to send password reset token to principal {}: {}",
+                fk_principal,
+                e
+            ),
+            Err(e) => log::error!(
+                "Failed to send password reset token to principal {}: {}",
+                fk_principal,
+                e
+            ),
+        }
+    });
+
+    Ok(warp::reply())
+}
+
+/// Sets a new password for the principal of the provided password reset token if the token exists,
+/// has not been used and is not expired, or else returns a InvalidResetTokenError which results in a
+/// 400. The new password is checked against the [`PASSWORD_POLICY`] first, so that the token is not
+/// used up by a rejected password. Like changing the password, this logs out all sessions of the
+/// principal.
+pub async fn reset_password_handler(
+    request: ResetPasswordRequest,
+) -> Result<impl Reply, Rejection> {
+    check_new_password(&request.new_password)?;
+    let hashed_password = hash_password(request.new_password).await?;
+    let token_hash = hash_bytes(request.token.as_bytes());
+    let connection = acquire_db_connection()?;
+
+    // lock the token so that it cannot be used by concurrent requests
+    connection.transaction::<_, Error, _>(|| {
+        let reset_token = password_reset_token::table
+            .filter(password_reset_token::token_hash.eq(&token_hash))
+            .for_update()
+            .first::<PasswordResetToken>(&connection)
+            .optional()
+            .map_err(|_| Error::QueryError)?
+            .ok_or(Error::InvalidResetTokenError)?;
+
+        let current_utc = Utc::now();
+        check_reset_token(&reset_token, current_utc)?;
+
+        diesel::update(password_reset_token::table)
+            .filter(password_reset_token::pk.eq(reset_token.pk))
+            .set(password_reset_token::used_at.eq(current_utc))
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        set_password(reset_token.fk_principal, hashed_password, &connection)
+    })?;
+
+    Ok(warp::reply::with_header(
+        warp::reply(),
+        header::SET_COOKIE,
+        CLEARED_REFRESH_TOKEN_COOKIE,
+    ))
+}
+
+/// Returns a InvalidResetTokenError if the password reset token has already been used or is expired
+/// at the provided time.
+fn check_reset_token(
+    reset_token: &PasswordResetToken,
+    current_utc: DateTime<Utc>,
+) -> Result<(), Error> {
+    if reset_token.used_at.is_some() || reset_token.expiry < current_utc {
+        Err(Error::InvalidResetTokenError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Stores the new password hash of the principal and invalidates all of its refresh tokens, see
+/// [`set_password_statements`].
+fn set_password(
+    fk_principal: i32,
+    hashed_password: String,
+    connection: &DbConnection,
+) -> Result<(), Error> {
+    let (password_update, refresh_tokens_invalidation) =
+        set_password_statements(fk_principal, hashed_password);
+
+    connection.transaction(|| {
+        password_update
+            .execute(connection)
+            .map_err(|_| Error::QueryError)?;
+        refresh_tokens_invalidation
+            .execute(connection)
+            .map_err(|_| Error::QueryError)?;
+        Ok(())
+    })
+}
+
+type PasswordUpdate = dsl::Update<
+    dsl::Filter<principal::table, dsl::Eq<principal::pk, i32>>,
+    dsl::Eq<principal::password, String>,
+>;
+
+/// The statements run by [`set_password`], updating the password of the principal and invalidating
+/// all of its refresh tokens, which logs out all of its sessions.
+fn set_password_statements(
+    fk_principal: i32,
+    hashed_password: String,
+) -> (PasswordUpdate, RefreshTokensInvalidation) {
+    let password_update = diesel::update(principal::table.filter(principal::pk.eq(fk_principal)))
+        .set(principal::password.eq(hashed_password));
+
+    (
+        password_update,
+        principal_refresh_tokens_invalidation(fk_principal),
+    )
+}
+
+/// Returns the active sessions of the logged in principal, most recently used first. The session of
+/// the provided `refresh_token` cookie, if any, is marked as the current session.
+pub async fn get_sessions_handler(
+    principal: Principal,
+    refresh_token: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let connection = acquire_db_connection()?;
+    let current_token_uuid = refresh_token.and_then(|token| Uuid::parse_str(&token).ok());
+
+    let active_tokens = refresh_token::table
+        .filter(
+            refresh_token::fk_principal
+                .eq(principal.pk)
+                .and(refresh_token::invalidated.eq(false))
+                .and(refresh_token::rotated_at.is_null())
+                .and(refresh_token::expiry.gt(Utc::now())),
+        )
+        .order(refresh_token::last_used_at.desc())
+        .load::<RefreshToken>(&connection)
         .map_err(|_| warp::reject::custom(Error::QueryError))?;
 
-    let expiry = current_utc + Duration::hours(24);
-    let new_token = Uuid::new_v4();
+    let sessions = active_tokens
+        .into_iter()
+        .map(|token| Session::from_token(token, current_token_uuid))
+        .collect::<Vec<_>>();
 
-    let updated_token = diesel::update(refresh_token::table)
-        .filter(refresh_token::pk.eq(refresh_token.pk))
-        .set((
-            refresh_token::uuid.eq(new_token),
-            refresh_token::expiry.eq(expiry),
-        ))
-        .get_result::<RefreshToken>(&connection)
+    Ok(warp::reply::json(&sessions))
+}
+
+/// Revokes the session identified by the `session_id` path parameter by invalidating all refresh
+/// tokens of its family. Returns a 404 if the principal has no session with the provided id that has
+/// not already been revoked.
+pub async fn revoke_session_handler(
+    principal: Principal,
+    session_id: String,
+) -> Result<impl Reply, Rejection> {
+    let family =
+        Uuid::parse_str(&session_id).map_err(|_| warp::reject::custom(Error::BadRequestError))?;
+    let connection = acquire_db_connection()?;
+
+    let revoked_count = diesel::update(refresh_token::table)
+        .filter(
+            refresh_token::family
+                .eq(family)
+                .and(refresh_token::fk_principal.eq(principal.pk))
+                .and(refresh_token::invalidated.eq(false)),
+        )
+        .set(refresh_token::invalidated.eq(true))
+        .execute(&connection)
         .map_err(|_| warp::reject::custom(Error::QueryError))?;
 
-    let uuid = updated_token.uuid.to_string();
-    let expiry = updated_token.expiry.to_rfc2822();
+    if revoked_count == 0 {
+        return Err(warp::reject::not_found());
+    }
 
-    // TODO set Secure once moving to production
-    let refresh_token_cookie = format!("refresh_token={}; Expires={}; HttpOnly", uuid, expiry);
+    Ok(warp::reply())
+}
 
-    create_login_response(&principal, refresh_token_cookie)
+/// Deletes all refresh tokens that are expired or have been invalidated, returning the number of
+/// deleted tokens. Rotated tokens are kept until the end of their session to detect their reuse.
+pub fn purge_refresh_tokens(connection: &DbConnection) -> Result<usize, Error> {
+    diesel::delete(refresh_token::table.filter(purgeable_refresh_tokens(Utc::now())))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)
 }
 
+/// The condition of the refresh tokens deleted by [`purge_refresh_tokens`] at the provided time.
+fn purgeable_refresh_tokens(
+    current_utc: DateTime<Utc>,
+) -> Box<dyn BoxableExpression<refresh_token::table, Pg, SqlType = Bool>> {
+    Box::new(
+        refresh_token::invalidated
+            .eq(true)
+            .or(refresh_token::session_expiry.lt(current_utc))
+            .or(refresh_token::expiry
+                .lt(current_utc)
+                .and(refresh_token::rotated_at.is_null())),
+    )
+}
+
+/// Deletes all password reset tokens that are expired or have been used, returning the number of
+/// deleted tokens.
+pub fn purge_password_reset_tokens(connection: &DbConnection) -> Result<usize, Error> {
+    diesel::delete(
+        password_reset_token::table.filter(
+            password_reset_token::expiry
+                .lt(Utc::now())
+                .or(password_reset_token::used_at.is_not_null()),
+        ),
+    )
+    .execute(connection)
+    .map_err(|_| Error::QueryError)
+}
+
+/// Runs [`purge_refresh_tokens`], [`purge_password_reset_tokens`],
+/// [`purge_login_throttles`](throttle::purge_login_throttles) and
+/// [`purge_totp_challenges`](totp::purge_totp_challenges) every
+/// REFRESH_TOKEN_PURGE_INTERVAL_SECS seconds, starting immediately. Failures are logged and retried
+/// at the next interval.
+pub async fn run_refresh_token_purge() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        *REFRESH_TOKEN_PURGE_INTERVAL_SECS,
+    ));
+
+    loop {
+        interval.tick().await;
+
+        let result = tokio::task::spawn_blocking(|| {
+            let connection = crate::CONNECTION_POOL
+                .get()
+                .map_err(|_| Error::DatabaseConnectionError)?;
+            Ok::<_, Error>((
+                purge_refresh_tokens(&connection)?,
+                purge_password_reset_tokens(&connection)?,
+                throttle::purge_login_throttles(&connection)?,
+                totp::purge_totp_challenges(&connection)?,
+            ))
+        })
+        .await;
+
+        match result {
+            Ok(Ok((refresh_tokens, reset_tokens, login_throttles, totp_challenges))) => log::debug!(
+                "Purged {} refresh tokens, {} password reset tokens, {} login throttles and {} TOTP challenges",
+                refresh_tokens,
+                reset_tokens,
+                login_throttles,
+                totp_challenges
+            ),
+            Ok(Err(e)) => log::error!("Failed to purge refresh tokens: {}", e),
+            Err(e) => log::error!("Failed to purge refresh tokens: {}", e),
+        }
+    }
+}
+
+/// The maximum number of characters of the User-Agent header stored for a session.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
 lazy_static! {
     static ref USER_NAME_SYNC: MutexSync<String> = MutexSync::new();
+    /// The hash the password of logins with an unknown user name is verified against, so that
+    /// verifying takes as long as for existing principals.
+    pub static ref DUMMY_PASSWORD_HASH: String =
+        hash(Uuid::new_v4().to_string(), DEFAULT_COST).expect("Failed to hash dummy password");
+    /// Whether the client IP address is taken from the X-Real-IP header, which must only be enabled
+    /// when running behind a reverse proxy that sets the header, defaults to false.
+    pub static ref TRUST_X_REAL_IP: bool = {
+        std::env::var("TRUST_X_REAL_IP").map_or(false, |val| {
+            bool::from_str(&val).expect("TRUST_X_REAL_IP is not a valid bool value")
+        })
+    };
+    /// Absolute lifetime of a login session, after which the refresh token cannot be refreshed
+    /// anymore and the principal has to log in again, defaults to 30 days.
+    pub static ref REFRESH_SESSION_LIFETIME_HOURS: i64 = {
+        std::env::var("REFRESH_SESSION_LIFETIME_HOURS").map_or(720, |val| {
+            let hours = val
+                .parse::<i64>()
+                .expect("REFRESH_SESSION_LIFETIME_HOURS is not a valid i64");
+            assert!(hours > 0, "REFRESH_SESSION_LIFETIME_HOURS must be positive");
+            hours
+        })
+    };
+    /// Interval in which expired and invalidated refresh tokens are deleted, defaults to one hour.
+    pub static ref REFRESH_TOKEN_PURGE_INTERVAL_SECS: u64 = {
+        std::env::var("REFRESH_TOKEN_PURGE_INTERVAL_SECS").map_or(3600, |val| {
+            let secs = val
+                .parse::<u64>()
+                .expect("REFRESH_TOKEN_PURGE_INTERVAL_SECS is not a valid u64");
+            assert!(secs > 0, "REFRESH_TOKEN_PURGE_INTERVAL_SECS must not be 0");
+            secs
+        })
+    };
 }
 
 /// Registers a user by creating a new Principal and one QrUser. This request receives a json that
@@ -292,6 +1158,11 @@ lazy_static! {
 pub async fn register_handler(
     user_registration: UserRegistration,
 ) -> Result<impl Reply, Rejection> {
+    user_registration.validate().map_err(|e| {
+        Error::InvalidFieldsError(ordered_field_errors(&e, &USER_REGISTRATION_FIELDS))
+    })?;
+    let hashed_password = hash_password(user_registration.password.clone()).await?;
+
     // synchronise principal creation based on user_name
     USER_NAME_SYNC.evaluate(user_registration.user_name.clone(), || {
         let connection = acquire_db_connection()?;
@@ -312,14 +1183,10 @@ pub async fn register_handler(
             Err(_) => return Err(warp::reject::custom(Error::QueryError)),
         };
 
-        let hashed_password = match hash(&user_registration.password, DEFAULT_COST) {
-            Ok(hashed_password) => hashed_password,
-            Err(_) => return Err(warp::reject::custom(Error::EncryptionError)),
-        };
-
         let new_principal = NewPrincipal {
             user_name: user_registration.user_name,
             password: hashed_password,
+            email: user_registration.email,
         };
 
         let principal = match diesel::insert_into(principal::table)
@@ -352,13 +1219,22 @@ pub async fn register_handler(
     })
 }
 
-#[derive(Deserialize)]
+/// Struct received by the /create-user endpoint, validated like the creditor fields of the
+/// [`UserRegistration`].
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "validate_create_user", skip_on_field_errors = true))]
 pub struct CreateUser {
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     pub name: String,
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     pub address: String,
+    #[validate(length(min = 1), custom = "validate_character_set")]
     pub zip_code: String,
+    #[validate(length(min = 1), custom = "validate_character_set")]
     pub city: String,
+    #[validate(custom = "validate_iban")]
     pub iban: String,
+    #[validate(length(min = 2, max = 2))]
     pub country: String,
 }
 
@@ -368,6 +1244,9 @@ pub async fn create_user_handler(
     principal: Principal,
     create_user: CreateUser,
 ) -> Result<impl Reply, Rejection> {
+    create_user.validate().map_err(|e| {
+        Error::InvalidFieldsError(ordered_field_errors(&e, &USER_REGISTRATION_FIELDS[3..]))
+    })?;
     let connection = acquire_db_connection()?;
 
     let new_user = NewUser {
@@ -434,3 +1313,298 @@ pub async fn delete_users_handler(
         Err(_) => Err(warp::reject::custom(Error::QueryError)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use diesel::debug_query;
+    use warp::hyper::body;
+
+    use super::*;
+
+    fn principal() -> Principal {
+        Principal {
+            pk: 1,
+            user_name: String::from("pia"),
+            password: String::new(),
+            is_admin: false,
+            qr_generator: None,
+            email: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_used_step: None,
+        }
+    }
+
+    fn refresh_token(issued_at: DateTime<Utc>, session_expiry: DateTime<Utc>) -> RefreshToken {
+        RefreshToken {
+            pk: 1,
+            uuid: Uuid::new_v4(),
+            expiry: refresh_token_expiry(issued_at, session_expiry),
+            invalidated: false,
+            fk_principal: 1,
+            family: Uuid::new_v4(),
+            session_expiry,
+            rotated_at: None,
+            created_at: issued_at,
+            last_used_at: issued_at,
+            user_agent: None,
+            ip_address: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logout() {
+        let response = logout_handler(None).await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::SET_COOKIE],
+            CLEARED_REFRESH_TOKEN_COOKIE
+        );
+        assert!(body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // the token is parsed before acquiring a database connection
+        let rejection = logout_handler(Some(String::from("not-a-uuid")))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::BadRequestError)
+        ));
+    }
+
+    #[test]
+    fn test_purgeable_refresh_tokens() {
+        let current_utc = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let query =
+            diesel::delete(refresh_token::table.filter(purgeable_refresh_tokens(current_utc)));
+
+        // invalidated tokens and tokens of expired sessions are deleted, while expired tokens that
+        // have been rotated are kept until the end of the session to detect their reuse
+        assert_eq!(
+            debug_query::<Pg, _>(&query).to_string(),
+            "DELETE FROM \"refresh_token\" WHERE ((\"refresh_token\".\"invalidated\" = $1 \
+             OR \"refresh_token\".\"session_expiry\" < $2) \
+             OR \"refresh_token\".\"expiry\" < $3 AND \"refresh_token\".\"rotated_at\" IS NULL) \
+             -- binds: [true, 2021-05-01T12:00:00Z, 2021-05-01T12:00:00Z]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_client_info() {
+        let remote_addr = SocketAddr::from(([192, 0, 2, 1], 4711));
+        let client = warp::test::request()
+            .header("user-agent", "a".repeat(MAX_USER_AGENT_LENGTH + 1))
+            .header("x-real-ip", "198.51.100.7")
+            .remote_addr(remote_addr)
+            .filter(&with_client_info())
+            .await
+            .unwrap();
+
+        assert_eq!(client.user_agent, Some("a".repeat(MAX_USER_AGENT_LENGTH)));
+        // X-Real-IP is ignored unless TRUST_X_REAL_IP is enabled
+        assert_eq!(client.ip_address.as_deref(), Some("192.0.2.1"));
+
+        let client = warp::test::request()
+            .filter(&with_client_info())
+            .await
+            .unwrap();
+        assert_eq!(client.user_agent, None);
+        assert_eq!(client.ip_address, None);
+    }
+
+    #[test]
+    fn test_session_from_token() {
+        let issued_at = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let token = RefreshToken {
+            created_at: issued_at - Duration::hours(48),
+            user_agent: Some(String::from("Mozilla/5.0")),
+            ip_address: Some(String::from("192.0.2.1")),
+            ..refresh_token(issued_at, issued_at + Duration::hours(720))
+        };
+        let (uuid, family) = (token.uuid, token.family);
+
+        let session = Session::from_token(token, Some(uuid));
+        assert_eq!(session.id, family.to_string());
+        assert_eq!(session.created_at, "2021-04-29T12:00:00+00:00");
+        assert_eq!(session.last_used_at, "2021-05-01T12:00:00+00:00");
+        assert_eq!(session.expires_at, "2021-05-02T12:00:00+00:00");
+        assert_eq!(session.user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(session.ip_address.as_deref(), Some("192.0.2.1"));
+        assert!(session.current);
+
+        let token = refresh_token(issued_at, issued_at + Duration::hours(720));
+        assert!(!Session::from_token(token, Some(uuid)).current);
+        let token = refresh_token(issued_at, issued_at + Duration::hours(720));
+        assert!(!Session::from_token(token, None).current);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_session_invalid_id() {
+        // the session id is parsed before acquiring a database connection
+        let rejection = revoke_session_handler(principal(), String::from("current"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::BadRequestError)
+        ));
+    }
+
+    #[test]
+    fn test_refresh_token_expiry() {
+        let issued_at = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+
+        let session_expiry = issued_at + Duration::hours(720);
+        assert_eq!(
+            refresh_token_expiry(issued_at, session_expiry),
+            issued_at + Duration::hours(24)
+        );
+        // rotating a token near the end of its session does not extend the session
+        let session_expiry = issued_at + Duration::hours(2);
+        assert_eq!(
+            refresh_token_expiry(issued_at, session_expiry),
+            session_expiry
+        );
+    }
+
+    #[test]
+    fn test_refresh_token_use() {
+        let issued_at = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let session_expiry = issued_at + Duration::hours(30);
+        let token = refresh_token(issued_at, session_expiry);
+
+        assert_eq!(
+            refresh_token_use(&token, issued_at + Duration::hours(1)),
+            RefreshTokenUse::Rotate
+        );
+        assert_eq!(
+            refresh_token_use(&token, token.expiry),
+            RefreshTokenUse::Rotate
+        );
+        assert_eq!(
+            refresh_token_use(&token, token.expiry + Duration::seconds(1)),
+            RefreshTokenUse::Reject
+        );
+
+        // the session expiry applies even if the token itself has not expired yet
+        let late_token = refresh_token(issued_at + Duration::hours(20), session_expiry);
+        assert_eq!(
+            refresh_token_use(&late_token, session_expiry + Duration::seconds(1)),
+            RefreshTokenUse::Reject
+        );
+
+        // reusing a rotated token invalidates its family, even after the token expired
+        let rotated_token = RefreshToken {
+            rotated_at: Some(issued_at + Duration::hours(1)),
+            ..refresh_token(issued_at, session_expiry)
+        };
+        assert_eq!(
+            refresh_token_use(&rotated_token, issued_at + Duration::hours(2)),
+            RefreshTokenUse::InvalidateFamily
+        );
+        assert_eq!(
+            refresh_token_use(&rotated_token, issued_at + Duration::hours(25)),
+            RefreshTokenUse::InvalidateFamily
+        );
+
+        // tokens of an invalidated family are rejected without invalidating it again
+        let invalidated_token = RefreshToken {
+            invalidated: true,
+            ..rotated_token
+        };
+        assert_eq!(
+            refresh_token_use(&invalidated_token, issued_at + Duration::hours(2)),
+            RefreshTokenUse::Reject
+        );
+    }
+
+    #[test]
+    fn test_dummy_password_hash() {
+        // the cost determines the time taken to verify a password
+        assert!(DUMMY_PASSWORD_HASH.starts_with(&format!("$2b${}$", DEFAULT_COST)));
+    }
+
+    #[test]
+    fn test_check_reset_token() {
+        let current_utc = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let reset_token = |expiry, used_at| PasswordResetToken {
+            pk: 1,
+            token_hash: hash_bytes(b"8f14e45fceea467fa0e6c1b2f3d4e5a6"),
+            expiry,
+            used_at,
+            fk_principal: 1,
+        };
+
+        let expiry = current_utc + Duration::minutes(60);
+        assert!(check_reset_token(&reset_token(expiry, None), current_utc).is_ok());
+        assert!(check_reset_token(&reset_token(current_utc, None), current_utc).is_ok());
+        // tokens can only be used once
+        assert!(matches!(
+            check_reset_token(&reset_token(expiry, Some(current_utc)), current_utc),
+            Err(Error::InvalidResetTokenError)
+        ));
+        assert!(matches!(
+            check_reset_token(
+                &reset_token(current_utc - Duration::seconds(1), None),
+                current_utc
+            ),
+            Err(Error::InvalidResetTokenError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_new_password() {
+        let assert_rejected = |rejection: Rejection| match rejection.find::<Error>() {
+            Some(Error::InvalidFieldsError(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].field.as_deref(), Some("new_password"));
+                assert_eq!(errors[0].code, "length");
+            }
+            _ => panic!("expected the new password to be rejected"),
+        };
+
+        // the password policy is checked before acquiring a database connection, so neither the
+        // reset token is used up nor is a failed login attempt recorded
+        let request = ResetPasswordRequest {
+            token: String::from("8f14e45fceea467fa0e6c1b2f3d4e5a6"),
+            new_password: String::from("short"),
+        };
+        assert_rejected(reset_password_handler(request).await.err().unwrap());
+
+        let request = ChangePasswordRequest {
+            old_password: String::from("wrong password"),
+            new_password: String::from("short"),
+        };
+        assert_rejected(
+            change_password_handler(principal(), request, ClientInfo::default())
+                .await
+                .err()
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_set_password_statements() {
+        let (password_update, refresh_tokens_invalidation) =
+            set_password_statements(7, String::from("$2b$12$hash"));
+
+        assert_eq!(
+            debug_query::<Pg, _>(&password_update).to_string(),
+            "UPDATE \"principal\" SET \"password\" = $1 WHERE \"principal\".\"pk\" = $2 \
+             -- binds: [\"$2b$12$hash\", 7]"
+        );
+        // setting the password logs out all sessions of the principal
+        assert_eq!(
+            debug_query::<Pg, _>(&refresh_tokens_invalidation).to_string(),
+            "UPDATE \"refresh_token\" SET \"invalidated\" = $1 \
+             WHERE \"refresh_token\".\"fk_principal\" = $2 AND \"refresh_token\".\"invalidated\" = $3 \
+             -- binds: [true, 7, false]"
+        );
+    }
+}
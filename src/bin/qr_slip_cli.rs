@@ -0,0 +1,344 @@
+//! Generates slips from files containing [`QrData`] objects without running the web service, so
+//! neither a database nor JWT_SECRET is required.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use structopt::StructOpt;
+
+use qr_slip::{
+    archive,
+    error::{Error, Error::InvalidRequestInputError, Error::IoError},
+    input::{self, InputFormat},
+    pdf,
+    plugin::QrGenerator,
+    templating::{self, QrData},
+};
+
+/// The format of the generated output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    /// One PDF file containing the slips for all objects.
+    Pdf,
+    /// One HTML file containing the slips for all objects.
+    Html,
+    /// One svg file containing the qr code for each object.
+    Svg,
+    /// One png file containing the qr code for each object.
+    Png,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pdf" => Ok(OutputFormat::Pdf),
+            "html" => Ok(OutputFormat::Html),
+            "svg" => Ok(OutputFormat::Svg),
+            "png" => Ok(OutputFormat::Png),
+            _ => Err(InvalidRequestInputError(format!(
+                "Unsupported output format '{}', expected pdf, html, svg or png",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "qr_slip_cli",
+    about = "Generates swiss QR-bill payment slips from JSON, JSON Lines or CSV files"
+)]
+struct Opt {
+    /// Files containing the QrData objects, reads from stdin if no file is provided or the file is -
+    #[structopt(parse(from_os_str))]
+    input: Vec<PathBuf>,
+    /// Format of the input files: json, jsonl or csv. Determined by the file extension if not set,
+    /// stdin is read as json
+    #[structopt(short, long)]
+    input_format: Option<InputFormat>,
+    /// Format of the output: pdf, html, svg or png
+    #[structopt(short, long, default_value = "pdf")]
+    format: OutputFormat,
+    /// Output file for pdf and html, written to stdout if not set. Output directory for svg and png,
+    /// which contains one file per slip named after its reference number or debtor name
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Transliterate characters that are not permitted by the specification instead of failing
+    #[structopt(short, long)]
+    transliterate: bool,
+    /// Name of the QR generator plugin used to generate the qr code svgs
+    #[cfg(feature = "python")]
+    #[structopt(short, long)]
+    generator: Option<String>,
+}
+
+fn main() {
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    procspawn::init();
+
+    let opt = Opt::from_args();
+
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn run(opt: Opt) -> Result<(), Error> {
+    let stdin = io::stdin();
+    let mut qr_data_vec = read_input(&opt.input, opt.input_format, stdin.lock())?;
+    if opt.transliterate {
+        qr_data_vec.iter_mut().for_each(QrData::transliterate);
+    }
+
+    #[cfg(feature = "python")]
+    let generator = QrGenerator::select(opt.generator.as_deref(), None)?;
+    #[cfg(not(feature = "python"))]
+    let generator = QrGenerator::default();
+    let qr_svg_vec = templating::generate_qr_svg_for_all(&mut qr_data_vec, &generator)?;
+
+    match opt.format {
+        OutputFormat::Pdf => {
+            let pdf = pdf::generate_pdf_for_all(&qr_data_vec, &qr_svg_vec).await?;
+            write_output(opt.output.as_deref(), &pdf)
+        }
+        OutputFormat::Html => {
+            let html = templating::generate_html_slip(&qr_data_vec, &qr_svg_vec)?;
+            write_output(opt.output.as_deref(), html.as_bytes())
+        }
+        OutputFormat::Svg => {
+            let files = qr_svg_vec.into_iter().map(String::into_bytes).collect();
+            write_files(opt.output.as_deref(), &qr_data_vec, files, "svg")
+        }
+        OutputFormat::Png => {
+            let files = qr_data_vec
+                .iter()
+                .map(templating::render_native_qr_png)
+                .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+            write_files(opt.output.as_deref(), &qr_data_vec, files, "png")
+        }
+    }
+}
+
+/// Reads the [`QrData`] objects of all input files in order, or from stdin if there are none.
+fn read_input<R: Read>(
+    paths: &[PathBuf],
+    format: Option<InputFormat>,
+    mut stdin: R,
+) -> Result<Vec<QrData>, Error> {
+    if paths.is_empty() {
+        return input::read_qr_data(stdin, format.unwrap_or(InputFormat::Json));
+    }
+
+    let mut qr_data_vec = Vec::new();
+    for path in paths {
+        let mut qr_data = if path.as_os_str() == "-" {
+            input::read_qr_data(&mut stdin, format.unwrap_or(InputFormat::Json))
+        } else {
+            let format = format
+                .or_else(|| InputFormat::from_path(path))
+                .ok_or_else(|| {
+                    InvalidRequestInputError(format!(
+                        "Cannot determine the format of '{}', set --input-format",
+                        path.display()
+                    ))
+                })?;
+            let file =
+                fs::File::open(path).map_err(|e| IoError(format!("{}: {}", path.display(), e)))?;
+            input::read_qr_data(io::BufReader::new(file), format)
+        }
+        .map_err(|e| match e {
+            InvalidRequestInputError(msg) => {
+                InvalidRequestInputError(format!("{}: {}", path.display(), msg))
+            }
+            e => e,
+        })?;
+
+        qr_data_vec.append(&mut qr_data);
+    }
+
+    Ok(qr_data_vec)
+}
+
+fn write_output(path: Option<&Path>, bytes: &[u8]) -> Result<(), Error> {
+    match path {
+        Some(path) => fs::write(path, bytes),
+        None => io::stdout().lock().write_all(bytes),
+    }
+    .map_err(|e| IoError(e.to_string()))
+}
+
+/// Writes one file per slip to the output directory, named like the files in the ZIP archive
+/// returned by the /generate-slip endpoint.
+fn write_files(
+    dir: Option<&Path>,
+    qr_data_vec: &[QrData],
+    files: Vec<Vec<u8>>,
+    extension: &str,
+) -> Result<(), Error> {
+    let dir = dir.ok_or_else(|| {
+        InvalidRequestInputError(format!(
+            "--output must be set to a directory for {} output",
+            extension
+        ))
+    })?;
+    fs::create_dir_all(dir).map_err(|e| IoError(e.to_string()))?;
+
+    let mut used_file_names = HashSet::new();
+    for (qr_data, file) in qr_data_vec.iter().zip(files) {
+        let file_name = archive::unique_file_name(qr_data, extension, &mut used_file_names);
+        fs::write(dir.join(file_name), file).map_err(|e| IoError(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_object(debtor_name: &str) -> String {
+        format!(
+            r#"{{"creditor_iban":"CH9300762011623852957","creditor_name":"Robert Schneider AG","creditor_address":"Rue du Lac 1268","creditor_zip_code":"2501","creditor_city":"Biel","creditor_country":"CH","debtor_name":"{}","debtor_address":"Grosse Marktgasse 28","debtor_zip_code":"9400","debtor_city":"Rorschach","debtor_country":"CH","amount":"199.95","currency":"CHF","reference_type":"NON"}}"#,
+            debtor_name
+        )
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qr_slip_cli_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn debtor_names(qr_data_vec: &[QrData]) -> Vec<&str> {
+        qr_data_vec.iter().map(QrData::debtor_name).collect()
+    }
+
+    #[test]
+    fn test_read_input() {
+        let dir = temp_dir();
+        let json = dir.join("slips.json");
+        fs::write(&json, format!("[{}]", json_object("Pia Rutschmann"))).unwrap();
+        let json_lines = dir.join("slips.JSONL");
+        fs::write(
+            &json_lines,
+            format!(
+                "{}\n{}\n",
+                json_object("Max Muster"),
+                json_object("Hans Muster")
+            ),
+        )
+        .unwrap();
+        let stdin = json_object("Simon Muster");
+
+        // stdin is read as json if no file is provided
+        let qr_data_vec = read_input(&[], None, stdin.as_bytes()).unwrap();
+        assert_eq!(debtor_names(&qr_data_vec), vec!["Simon Muster"]);
+
+        // the format is determined by the extension of each file and - reads stdin in order
+        let paths = vec![json.clone(), PathBuf::from("-"), json_lines.clone()];
+        let qr_data_vec = read_input(&paths, None, stdin.as_bytes()).unwrap();
+        assert_eq!(
+            debtor_names(&qr_data_vec),
+            vec![
+                "Pia Rutschmann",
+                "Simon Muster",
+                "Max Muster",
+                "Hans Muster"
+            ]
+        );
+
+        // an explicit format overrides the extension
+        let renamed = dir.join("slips.txt");
+        fs::copy(&json_lines, &renamed).unwrap();
+        let paths = vec![renamed.clone()];
+        assert_eq!(
+            read_input(&paths, Some(InputFormat::JsonLines), io::empty())
+                .unwrap()
+                .len(),
+            2
+        );
+        match read_input(&paths, None, io::empty()) {
+            Err(InvalidRequestInputError(msg)) => assert_eq!(
+                msg,
+                format!(
+                    "Cannot determine the format of '{}', set --input-format",
+                    renamed.display()
+                )
+            ),
+            _ => panic!("expected an unknown format to fail"),
+        }
+
+        // errors are prefixed with the path of the file
+        let invalid = dir.join("invalid.json");
+        fs::write(&invalid, "[{").unwrap();
+        match read_input(&[json.clone(), invalid.clone()], None, io::empty()) {
+            Err(InvalidRequestInputError(msg)) => {
+                assert!(
+                    msg.starts_with(&format!("{}: Invalid JSON", invalid.display())),
+                    "{}",
+                    msg
+                )
+            }
+            _ => panic!("expected invalid json to fail"),
+        }
+        let missing = dir.join("missing.json");
+        match read_input(std::slice::from_ref(&missing), None, io::empty()) {
+            Err(IoError(msg)) => assert!(msg.starts_with(&format!("{}: ", missing.display()))),
+            _ => panic!("expected a missing file to fail"),
+        }
+        match read_input(&[PathBuf::from("-")], None, "{}".as_bytes()) {
+            Err(InvalidRequestInputError(msg)) => assert!(msg.starts_with("-: "), "{}", msg),
+            _ => panic!("expected invalid stdin to fail"),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_files() {
+        let paths = vec![PathBuf::from("-")];
+        let stdin = format!(
+            "[{}, {}, {}]",
+            json_object("Pia Rutschmann"),
+            json_object("Pia Rutschmann"),
+            json_object("Max Muster")
+        );
+        let qr_data_vec = read_input(&paths, None, stdin.as_bytes()).unwrap();
+        let files = || vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()];
+
+        match write_files(None, &qr_data_vec, files(), "svg") {
+            Err(InvalidRequestInputError(msg)) => {
+                assert_eq!(msg, "--output must be set to a directory for svg output")
+            }
+            _ => panic!("expected write_files to require --output"),
+        }
+
+        let dir = temp_dir().join("nested");
+        write_files(Some(&dir), &qr_data_vec, files(), "svg").unwrap();
+        let mut file_names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        file_names.sort();
+        assert_eq!(
+            file_names,
+            vec![
+                "Max_Muster.svg",
+                "Pia_Rutschmann.svg",
+                "Pia_Rutschmann_2.svg"
+            ]
+        );
+        assert_eq!(fs::read(dir.join("Pia_Rutschmann_2.svg")).unwrap(), b"2");
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use lazy_static::lazy_static;
+use lru::LruCache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+lazy_static! {
+    pub static ref CONTENT_CACHE: ContentCache = {
+        let memory_limit_mb = std::env::var("CONTENT_CACHE_MEMORY_MB").map_or(64, |val| {
+            val.parse::<usize>()
+                .expect("CONTENT_CACHE_MEMORY_MB is not a valid usize")
+        });
+        let disk_limit_mb = std::env::var("CONTENT_CACHE_DISK_MB").map_or(1024, |val| {
+            val.parse::<u64>()
+                .expect("CONTENT_CACHE_DISK_MB is not a valid u64")
+        });
+        let disk_dir = std::env::var("CONTENT_CACHE_DIR").ok().map(PathBuf::from);
+        ContentCache::new(
+            memory_limit_mb * 1024 * 1024,
+            disk_dir.map(|disk_dir| (disk_dir, disk_limit_mb * 1024 * 1024)),
+        )
+    };
+    /// Hash of the source of the code that generates the cached content from its input, including
+    /// the built-in python script, included in every cache key so that content cached on disk by a
+    /// build that generates different output for the same input is not reused. The templates,
+    /// wkhtmltopdf options and plugins are part of the input of the respective keys.
+    static ref SOURCE_VERSION: String = hash_bytes(
+        &[
+            include_bytes!("templating.rs").as_ref(),
+            include_bytes!("payment.rs"),
+            include_bytes!("pdf.rs"),
+            include_bytes!("plugin.rs"),
+            include_bytes!("resources/py/qr_generator.py"),
+        ]
+        .concat(),
+    );
+}
+
+/// Key identifying content in the [`ContentCache`], consisting of the hex encoded SHA-256 hash of all
+/// input that determines the content and the kind of content, which is used as file extension when
+/// stored on disk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheKey {
+    hash: String,
+    kind: &'static str,
+}
+
+impl CacheKey {
+    /// Creates a key for content of the given kind by hashing the json representation of the provided
+    /// input, which must contain everything that may change the generated content.
+    pub fn new<T: Serialize>(kind: &'static str, input: &T) -> Self {
+        let json = serde_json::to_vec(&(&*SOURCE_VERSION, kind, input))
+            .expect("Failed to serialise cache key input");
+
+        Self {
+            hash: hash_bytes(&json),
+            kind,
+        }
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}.{}", self.hash, self.kind)
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of the given bytes.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Content-addressed cache for generated QR codes and PDF files. Content is kept in memory up to
+/// the configured size limit, evicting the least recently used content first, and is additionally
+/// written to the configured directory if set, so that content evicted from memory or generated
+/// before a restart can be loaded from disk. The directory is limited in size as well, evicting the
+/// oldest files first.
+pub struct ContentCache {
+    memory: Option<Mutex<MemoryTier>>,
+    disk: Option<DiskTier>,
+}
+
+struct MemoryTier {
+    entries: LruCache<String, Vec<u8>>,
+    size: usize,
+    limit: usize,
+}
+
+struct DiskTier {
+    dir: PathBuf,
+    /// The approximate size of all files in the directory, files replaced by content of a different
+    /// size are only accounted for correctly once the directory is scanned again on eviction.
+    size: Mutex<u64>,
+    limit: u64,
+}
+
+impl ContentCache {
+    /// Creates a new cache holding at most `memory_limit` bytes in memory, or nothing if 0, and storing
+    /// content in the provided directory if set, holding at most the provided number of bytes.
+    pub fn new(memory_limit: usize, disk: Option<(PathBuf, u64)>) -> Self {
+        let disk = disk.map(|(dir, limit)| {
+            if let Err(e) = fs::create_dir_all(&dir) {
+                panic!(
+                    "Failed to create content cache directory {}: {}",
+                    dir.display(),
+                    e
+                );
+            }
+
+            let size = match cached_files(&dir) {
+                Ok(files) => files.iter().map(|file| file.size).sum(),
+                Err(e) => panic!(
+                    "Failed to read content cache directory {}: {}",
+                    dir.display(),
+                    e
+                ),
+            };
+            let disk = DiskTier {
+                dir,
+                size: Mutex::new(size),
+                limit,
+            };
+            // the limit may have been lowered since the content was stored
+            disk.evict(None);
+            disk
+        });
+
+        let memory = if memory_limit > 0 {
+            Some(Mutex::new(MemoryTier {
+                entries: LruCache::unbounded(),
+                size: 0,
+                limit: memory_limit,
+            }))
+        } else {
+            None
+        };
+
+        Self { memory, disk }
+    }
+
+    /// Returns the cached content for the given key, checking the memory first and the disk second.
+    /// Content loaded from disk is added to the memory.
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        if let Some(ref memory) = self.memory {
+            let mut memory = memory.lock().expect("Content cache mutex poisoned");
+            if let Some(content) = memory.entries.get(&key.hash) {
+                log::debug!("Found {} {} in memory cache", key.kind, key.hash);
+                return Some(content.clone());
+            }
+        }
+
+        if let Some(ref disk) = self.disk {
+            if let Ok(content) = fs::read(disk.dir.join(key.file_name())) {
+                log::debug!("Found {} {} in disk cache", key.kind, key.hash);
+                self.insert_into_memory(key, &content);
+                return Some(content);
+            }
+        }
+
+        None
+    }
+
+    /// Adds the content for the given key to the memory and disk. Failing to write the content to
+    /// disk is logged but does not fail the operation as the content can simply be generated again.
+    pub fn insert(&self, key: &CacheKey, content: &[u8]) {
+        self.insert_into_memory(key, content);
+
+        if let Some(ref disk) = self.disk {
+            if content.len() as u64 > disk.limit {
+                return;
+            }
+
+            let file_name = key.file_name();
+            if let Err(e) = write_file_atomically(&disk.dir.join(&file_name), content) {
+                log::warn!(
+                    "Failed to write {} {} to disk cache: {}",
+                    key.kind,
+                    key.hash,
+                    e
+                );
+                return;
+            }
+
+            let mut size = disk.size.lock().expect("Content cache mutex poisoned");
+            *size += content.len() as u64;
+            if *size > disk.limit {
+                drop(size);
+                disk.evict(Some(&file_name));
+            }
+        }
+    }
+
+    fn insert_into_memory(&self, key: &CacheKey, content: &[u8]) {
+        if let Some(ref memory) = self.memory {
+            let mut memory = memory.lock().expect("Content cache mutex poisoned");
+            if content.len() > memory.limit {
+                return;
+            }
+
+            if let Some(replaced) = memory.entries.put(key.hash.clone(), content.to_vec()) {
+                memory.size -= replaced.len();
+            }
+            memory.size += content.len();
+
+            while memory.size > memory.limit {
+                match memory.entries.pop_lru() {
+                    Some((_, evicted)) => memory.size -= evicted.len(),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+impl DiskTier {
+    /// Deletes the oldest files until the size of the directory is below the limit, except for the
+    /// file with the provided name that has just been written.
+    fn evict(&self, keep: Option<&str>) {
+        let mut size = self.size.lock().expect("Content cache mutex poisoned");
+        let mut files = match cached_files(&self.dir) {
+            Ok(files) => files,
+            Err(e) => {
+                log::warn!("Failed to read disk cache for eviction: {}", e);
+                return;
+            }
+        };
+
+        *size = files.iter().map(|file| file.size).sum();
+        if *size <= self.limit {
+            return;
+        }
+
+        files.sort_by_key(|file| file.modified);
+        let mut evicted = 0;
+        for file in files {
+            if *size <= self.limit {
+                break;
+            }
+            if keep.map_or(false, |keep| file.path.file_name() == Some(keep.as_ref())) {
+                continue;
+            }
+
+            match fs::remove_file(&file.path) {
+                Ok(()) => {
+                    *size -= file.size;
+                    evicted += 1;
+                }
+                // the file may have been replaced concurrently
+                Err(e) => log::warn!("Failed to evict {}: {}", file.path.display(), e),
+            }
+        }
+
+        log::debug!("Evicted {} files from the disk cache", evicted);
+    }
+}
+
+/// A file in the disk cache directory.
+struct CachedFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Lists all files in the disk cache directory, including temporary files left behind by an
+/// interrupted write.
+fn cached_files(dir: &Path) -> std::io::Result<Vec<CachedFile>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push(CachedFile {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Writes the content to a temporary file first and then renames it so that concurrent readers never
+/// see a partially written file.
+fn write_file_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+    fs::write(&tmp_path, content)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(input: &str) -> CacheKey {
+        CacheKey::new("svg", &input)
+    }
+
+    fn memory_size(cache: &ContentCache) -> usize {
+        cache.memory.as_ref().unwrap().lock().unwrap().size
+    }
+
+    #[test]
+    fn test_cache_key() {
+        assert_eq!(key("a"), key("a"));
+        assert_ne!(key("a"), key("b"));
+        assert_ne!(key("a").hash, CacheKey::new("pdf", &"a").hash);
+        assert_eq!(key("a").file_name(), format!("{}.svg", key("a").hash));
+    }
+
+    #[test]
+    fn test_memory_tier() {
+        let cache = ContentCache::new(100, None);
+
+        cache.insert(&key("a"), &[1; 40]);
+        cache.insert(&key("b"), &[2; 40]);
+        assert_eq!(memory_size(&cache), 80);
+
+        // replacing content only accounts for the new size
+        cache.insert(&key("a"), &[1; 10]);
+        assert_eq!(memory_size(&cache), 50);
+        assert_eq!(cache.get(&key("a")), Some(vec![1; 10]));
+
+        // content larger than the limit is not cached and does not evict anything
+        cache.insert(&key("c"), &[3; 101]);
+        assert_eq!(memory_size(&cache), 50);
+        assert_eq!(cache.get(&key("c")), None);
+
+        // b is the least recently used content
+        cache.insert(&key("d"), &[4; 60]);
+        assert_eq!(memory_size(&cache), 70);
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")), Some(vec![1; 10]));
+        assert_eq!(cache.get(&key("d")), Some(vec![4; 60]));
+
+        cache.insert(&key("e"), &[5; 100]);
+        assert_eq!(memory_size(&cache), 100);
+        assert_eq!(cache.get(&key("a")), None);
+        assert_eq!(cache.get(&key("d")), None);
+
+        let disabled = ContentCache::new(0, None);
+        disabled.insert(&key("a"), &[1; 10]);
+        assert_eq!(disabled.get(&key("a")), None);
+    }
+
+    #[test]
+    fn test_disk_tier() {
+        let dir = std::env::temp_dir().join(format!("qr_slip_cache_{}", Uuid::new_v4()));
+        let disk_size = |cache: &ContentCache| *cache.disk.as_ref().unwrap().size.lock().unwrap();
+        let file_count = || fs::read_dir(&dir).unwrap().count();
+
+        let cache = ContentCache::new(0, Some((dir.clone(), 100)));
+        cache.insert(&key("a"), &[1; 40]);
+        cache.insert(&key("b"), &[2; 40]);
+        assert_eq!(disk_size(&cache), 80);
+        assert_eq!(cache.get(&key("a")), Some(vec![1; 40]));
+
+        // exceeding the limit evicts older files but keeps the file just written
+        cache.insert(&key("c"), &[3; 40]);
+        assert_eq!(disk_size(&cache), 80);
+        assert_eq!(file_count(), 2);
+        assert_eq!(cache.get(&key("c")), Some(vec![3; 40]));
+
+        cache.insert(&key("d"), &[4; 101]);
+        assert_eq!(cache.get(&key("d")), None);
+        assert_eq!(disk_size(&cache), 80);
+
+        // content stored by a previous instance is loaded and evicted if exceeding a lower limit
+        let cache = ContentCache::new(0, Some((dir.clone(), 100)));
+        assert_eq!(disk_size(&cache), 80);
+        assert_eq!(cache.get(&key("c")), Some(vec![3; 40]));
+        let cache = ContentCache::new(0, Some((dir.clone(), 50)));
+        assert_eq!(disk_size(&cache), 40);
+        assert_eq!(file_count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,35 @@
+#[cfg(feature = "server")]
+use std::error::Error as StdError;
+
+#[cfg(feature = "server")]
 use serde::Serialize;
 use thiserror::Error;
-use warp::{hyper::StatusCode, reject::Reject, Rejection, Reply};
+#[cfg(feature = "server")]
+use uuid::Uuid;
+#[cfg(feature = "server")]
+use warp::{
+    body::BodyDeserializeError,
+    http::header::{self, HeaderValue},
+    hyper::StatusCode,
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, Reject, UnsupportedMediaType,
+    },
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::i18n::{self, Language};
+use crate::validation::FieldError;
 
+/// The error type returned by all fallible functions of this crate. When running the server, errors
+/// are converted to a [`Rejection`] and mapped to a response by [`rejection_response`].
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("invalid credentials")]
     InvalidCredentialsError,
+    #[error("The principal is not permitted to perform this action")]
+    MissingPermissionError,
     #[error("Could not establish database connection")]
     DatabaseConnectionError,
     #[error("There has been an error executing a query")]
@@ -42,53 +66,462 @@ pub enum Error {
     IoError(String),
     #[error("The request input could not be validated: '{0}'")]
     InvalidRequestInputError(String),
+    #[error("The request input could not be validated: '{}'", join_field_errors(.0))]
+    InvalidFieldsError(Vec<FieldError>),
+    #[error("The PDF worker queue is full, try again later")]
+    PdfQueueFullError,
+    #[error("The provided password reset token is invalid")]
+    InvalidResetTokenError,
+    #[error("Failed to send a notification: '{0}'")]
+    NotificationError(String),
+    #[error("Too many failed login attempts, try again in {0} seconds")]
+    TooManyLoginAttemptsError(i64),
+    #[error("The provided two-factor authentication code is invalid")]
+    InvalidTotpCodeError,
+    #[error("The provided two-factor login token is invalid or expired")]
+    InvalidTotpTokenError,
+    #[error("Two-factor authentication is already enabled")]
+    TotpAlreadyEnabledError,
+    #[error("Two-factor authentication has not been enrolled")]
+    TotpNotEnrolledError,
 }
 
-impl Reject for Error {}
+impl Error {
+    /// Returns the stable, machine-readable code identifying the kind of error, returned as `code`
+    /// in error responses so that clients do not have to match on messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidCredentialsError => "invalid_credentials",
+            Error::MissingPermissionError => "missing_permission",
+            Error::DatabaseConnectionError => "database_connection",
+            Error::QueryError => "query",
+            Error::JwtCreationError => "jwt_creation",
+            Error::EncryptionError => "encryption",
+            Error::PrincipalExistsError(_) => "principal_exists",
+            Error::UtfEncodingError => "utf_encoding",
+            Error::InvalidAuthHeaderError => "invalid_auth_header",
+            Error::MissingAuthHeaderError => "missing_auth_header",
+            Error::BadRequestError => "bad_request",
+            Error::InvalidJwtError => "invalid_jwt",
+            Error::SerialisationError => "serialisation",
+            Error::InvalidRefreshTokenError => "invalid_refresh_token",
+            Error::PythonError(_) => "python",
+            Error::QrCodeError(_) => "qr_code",
+            Error::TeraError(_) => "template",
+            Error::PdfError(_) => "pdf",
+            Error::IoError(_) => "io",
+            Error::InvalidRequestInputError(_) => "invalid_input",
+            Error::InvalidFieldsError(_) => "invalid_fields",
+            Error::PdfQueueFullError => "pdf_queue_full",
+            Error::InvalidResetTokenError => "invalid_reset_token",
+            Error::NotificationError(_) => "notification",
+            Error::TooManyLoginAttemptsError(_) => "too_many_login_attempts",
+            Error::InvalidTotpCodeError => "invalid_totp_code",
+            Error::InvalidTotpTokenError => "invalid_totp_token",
+            Error::TotpAlreadyEnabledError => "totp_already_enabled",
+            Error::TotpNotEnrolledError => "totp_not_enrolled",
+        }
+    }
 
-#[derive(Serialize, Debug)]
-struct ErrorResponse {
-    message: String,
-    status: String,
-}
+    /// Returns the field-level details of the error, if any.
+    pub fn details(&self) -> &[FieldError] {
+        match self {
+            Error::InvalidFieldsError(errors) => errors,
+            _ => &[],
+        }
+    }
 
-/// Creates a Rejection response for the given error and logs internal server errors.
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(e) = err.find::<Error>() {
-        let (code, message) = match e {
-            Error::InvalidCredentialsError => (StatusCode::FORBIDDEN, e.to_string()),
+    /// Returns the field-level details of the error with their messages in the given language.
+    pub fn localized_details(&self, language: Language) -> Vec<FieldError> {
+        let mut details = self.details().to_vec();
+        details
+            .iter_mut()
+            .for_each(|detail| detail.localize(language));
+        details
+    }
+
+    /// Returns the message of the error in the given language, falling back to the English message
+    /// if there is no translation. Details provided by the caller, e.g. the message of
+    /// [`InvalidRequestInputError`](Error::InvalidRequestInputError), are not translated.
+    pub fn localized_message(&self, language: Language) -> String {
+        let detail = match self {
+            Error::InvalidFieldsError(_) => {
+                Some(join_field_errors(&self.localized_details(language)))
+            }
+            Error::PrincipalExistsError(detail)
+            | Error::QrCodeError(detail)
+            | Error::InvalidRequestInputError(detail) => Some(detail.clone()),
+            Error::TooManyLoginAttemptsError(retry_after_secs) => {
+                Some(retry_after_secs.to_string())
+            }
+            _ => None,
+        };
+
+        i18n::error_message(self.code(), detail.as_deref(), language)
+            .unwrap_or_else(|| self.to_string())
+    }
+
+    /// Returns the status code of the response for the error.
+    #[cfg(feature = "server")]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidCredentialsError
+            | Error::MissingPermissionError
+            | Error::InvalidTotpCodeError => StatusCode::FORBIDDEN,
             Error::MissingAuthHeaderError
             | Error::InvalidJwtError
-            | Error::InvalidRefreshTokenError => (StatusCode::UNAUTHORIZED, e.to_string()),
+            | Error::InvalidRefreshTokenError
+            | Error::InvalidTotpTokenError => StatusCode::UNAUTHORIZED,
             Error::PrincipalExistsError(_)
             | Error::UtfEncodingError
             | Error::InvalidAuthHeaderError
             | Error::BadRequestError
-            | Error::InvalidRequestInputError(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            | Error::InvalidResetTokenError
+            | Error::TotpAlreadyEnabledError
+            | Error::TotpNotEnrolledError
+            | Error::QrCodeError(_)
+            | Error::InvalidRequestInputError(_)
+            | Error::InvalidFieldsError(_) => StatusCode::BAD_REQUEST,
+            Error::TooManyLoginAttemptsError(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::PdfQueueFullError => StatusCode::SERVICE_UNAVAILABLE,
             Error::DatabaseConnectionError
             | Error::QueryError
             | Error::JwtCreationError
             | Error::EncryptionError
             | Error::SerialisationError
             | Error::PythonError(_)
-            | Error::QrCodeError(_)
             | Error::TeraError(_)
             | Error::PdfError(_)
-            | Error::IoError(_) => {
-                log::error!("Encountered internal server error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-            }
-        };
+            | Error::IoError(_)
+            | Error::NotificationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
-        let err_response = ErrorResponse {
-            message,
-            status: code.to_string(),
-        };
+fn join_field_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(feature = "server")]
+impl Reject for Error {}
+
+/// Required to run diesel transactions returning an [`Error`], failed queries are not exposed.
+#[cfg(feature = "server")]
+impl From<diesel::result::Error> for Error {
+    fn from(_: diesel::result::Error) -> Self {
+        Error::QueryError
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::IoError(e.to_string())
+    }
+}
+
+/// The body of all error responses, see the README for the codes.
+#[cfg(feature = "server")]
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    status: String,
+    /// Identifies the response in the logs, also returned in the X-Request-Id header.
+    request_id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    details: Vec<FieldError>,
+}
+
+/// Recovers the rejections of the given routes by mapping them to an error response with
+/// [`rejection_response`], in the language selected by the Accept-Language header of the request.
+#[cfg(feature = "server")]
+pub fn recover_localized<F, R>(
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let routes = routes
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|err| async move { Ok::<_, Rejection>((Err(err),)) });
+
+    i18n::with_language()
+        .and(routes)
+        .map(
+            |language, result: Result<Response, Rejection>| match result {
+                Ok(response) => response,
+                Err(err) => rejection_response(&err, language),
+            },
+        )
+}
 
-        let json = warp::reply::json(&err_response);
+/// Creates the error response for the given rejection with the message in the given language and
+/// logs internal server errors. Responses for a TooManyLoginAttemptsError include the Retry-After
+/// header.
+///
+/// Besides the [`Error`]s of this crate, the rejections of warp's built-in filters are mapped to a
+/// response, e.g. if the path does not exist or the body cannot be deserialized.
+#[cfg(feature = "server")]
+pub fn rejection_response(err: &Rejection, language: Language) -> Response {
+    let request_id = Uuid::new_v4().to_string();
+
+    let (code, status, message, details) = match err.find::<Error>() {
+        Some(e) => (
+            e.code(),
+            e.status(),
+            e.localized_message(language),
+            e.localized_details(language),
+        ),
+        None => {
+            let (code, status, message, detail) = warp_rejection(err);
+            let message = i18n::error_message(code, detail.as_deref(), language).unwrap_or(message);
+            (code, status, message, Vec::new())
+        }
+    };
+
+    if status.is_server_error() {
+        log::error!(
+            "Encountered internal server error (request id {}): {:?}",
+            request_id,
+            err
+        );
+    } else {
+        log::debug!("Rejected request (request id {}): {:?}", request_id, err);
+    }
+
+    let err_response = ErrorResponse {
+        code,
+        message,
+        status: status.to_string(),
+        request_id: request_id.clone(),
+        details,
+    };
+
+    let json = warp::reply::json(&err_response);
+    let reply = warp::reply::with_status(json, status);
+
+    let mut response = warp::reply::with_header(reply, "x-request-id", request_id).into_response();
+    if let Some(Error::TooManyLoginAttemptsError(retry_after_secs)) = err.find::<Error>() {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after_secs));
+    }
+
+    response
+}
 
-        Ok(warp::reply::with_status(json, code))
+/// Returns the code, status, English message and detail of a rejection of warp's built-in filters.
+#[cfg(feature = "server")]
+fn warp_rejection(err: &Rejection) -> (&'static str, StatusCode, String, Option<String>) {
+    if let Some(e) = err.find::<BodyDeserializeError>() {
+        let detail = e.source().map(ToString::to_string);
+        (
+            "invalid_body",
+            StatusCode::BAD_REQUEST,
+            e.to_string(),
+            detail,
+        )
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        (
+            "invalid_query",
+            StatusCode::BAD_REQUEST,
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        let detail = Some(e.name().to_string());
+        (
+            "missing_header",
+            StatusCode::BAD_REQUEST,
+            e.to_string(),
+            detail,
+        )
+    } else if let Some(e) = err.find::<InvalidHeader>() {
+        let detail = Some(e.name().to_string());
+        (
+            "invalid_header",
+            StatusCode::BAD_REQUEST,
+            e.to_string(),
+            detail,
+        )
+    } else if let Some(e) = err.find::<LengthRequired>() {
+        (
+            "length_required",
+            StatusCode::LENGTH_REQUIRED,
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<PayloadTooLarge>() {
+        (
+            "payload_too_large",
+            StatusCode::PAYLOAD_TOO_LARGE,
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<UnsupportedMediaType>() {
+        let code = "unsupported_media_type";
+        (
+            code,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            e.to_string(),
+            None,
+        )
+    } else if let Some(e) = err.find::<MethodNotAllowed>() {
+        (
+            "method_not_allowed",
+            StatusCode::METHOD_NOT_ALLOWED,
+            e.to_string(),
+            None,
+        )
+    } else if err.is_not_found() {
+        let message = String::from("The requested resource does not exist");
+        ("not_found", StatusCode::NOT_FOUND, message, None)
     } else {
-        return Err(err);
+        let message = String::from("An unexpected error occurred");
+        ("internal", StatusCode::INTERNAL_SERVER_ERROR, message, None)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use serde_json::Value;
+    use warp::{hyper::body, Filter};
+
+    use super::*;
+
+    async fn error_response(
+        err: Rejection,
+        language: Language,
+    ) -> (StatusCode, Option<String>, Value) {
+        let response = rejection_response(&err, language);
+        let status = response.status();
+        let request_id = response
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, request_id, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_handle_error() {
+        let error = || {
+            Error::InvalidFieldsError(vec![FieldError {
+                field: Some(String::from("debtor_name")),
+                code: String::from("length"),
+                message: String::from("Length must be between 1 and 70 characters"),
+                params: serde_json::json!({"min": 1, "max": 70})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            }])
+        };
+        let (status, request_id, json) =
+            error_response(warp::reject::custom(error()), Language::En).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "invalid_fields");
+        assert_eq!(json["status"], "400 Bad Request");
+        assert_eq!(json["details"][0]["field"], "debtor_name");
+        assert_eq!(json["details"][0]["code"], "length");
+        assert_eq!(request_id.as_deref(), json["request_id"].as_str());
+
+        let (_, _, json) = error_response(warp::reject::custom(error()), Language::De).await;
+        assert_eq!(
+            json["message"],
+            "Die Eingabe ist ungültig: 'debtor_name: Die Länge muss zwischen 1 und 70 Zeichen liegen'"
+        );
+        assert_eq!(
+            json["details"][0]["message"],
+            "Die Länge muss zwischen 1 und 70 Zeichen liegen"
+        );
+        assert_eq!(json["details"][0]["params"]["max"], 70);
+
+        let (status, _, json) =
+            error_response(warp::reject::custom(Error::PdfQueueFullError), Language::En).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["code"], "pdf_queue_full");
+        assert!(json.get("details").is_none());
+
+        let response = rejection_response(
+            &warp::reject::custom(Error::TooManyLoginAttemptsError(16)),
+            Language::Fr,
+        );
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "16");
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        let json = serde_json::from_slice::<Value>(&bytes).unwrap();
+        assert_eq!(json["code"], "too_many_login_attempts");
+        assert_eq!(
+            json["message"],
+            "Trop de tentatives de connexion échouées, réessayez dans 16 secondes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_warp_rejection() {
+        let route = warp::path("slips")
+            .and(warp::post())
+            .and(warp::body::json::<Vec<Value>>())
+            .map(|_| warp::reply());
+
+        let err = warp::test::request()
+            .method("POST")
+            .path("/slips")
+            .body("{")
+            .filter(&route)
+            .await
+            .err()
+            .unwrap();
+        let (status, _, json) = error_response(err, Language::En).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "invalid_body");
+
+        let err = warp::test::request()
+            .method("GET")
+            .path("/slips")
+            .filter(&route)
+            .await
+            .err()
+            .unwrap();
+        let (status, _, json) = error_response(err, Language::En).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(json["code"], "method_not_allowed");
+
+        let (status, _, json) = error_response(warp::reject::not_found(), Language::En).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json["code"], "not_found");
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/slips")
+            .header("accept-language", "fr-CH, de;q=0.5")
+            .reply(&recover_localized(route))
+            .await;
+        let json = serde_json::from_slice::<Value>(response.body()).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            json["message"],
+            "La méthode HTTP n'est pas autorisée pour ce chemin"
+        );
     }
 }
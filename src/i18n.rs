@@ -0,0 +1,426 @@
+//! Translations of error and validation messages into the languages of the slip. Messages are keyed
+//! by the stable codes of [`Error::code`](crate::error::Error::code) and
+//! [`FieldError`](crate::validation::FieldError), English messages are the messages of the errors
+//! themselves and only used as fallback here.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use serde_json::{Map, Value};
+#[cfg(feature = "server")]
+use warp::{
+    http::{header::ACCEPT_LANGUAGE, HeaderMap},
+    Filter,
+};
+
+use crate::error::{Error, Error::InvalidRequestInputError};
+
+/// Codes of internal server errors, whose details are not translated.
+const INTERNAL_ERROR_CODES: [&str; 11] = [
+    "database_connection",
+    "query",
+    "jwt_creation",
+    "encryption",
+    "serialisation",
+    "python",
+    "template",
+    "pdf",
+    "io",
+    "notification",
+    "internal",
+];
+
+/// The languages error messages are available in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    De,
+    Fr,
+    It,
+    En,
+}
+
+impl Language {
+    /// Selects the supported language with the highest quality value of an Accept-Language header,
+    /// e.g. `fr-CH, fr;q=0.9, en;q=0.8`, defaulting to [`Language::En`] if none is supported.
+    pub fn from_accept_language(header: &str) -> Language {
+        let mut selected = None;
+        for range in header.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().unwrap_or("");
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            let primary = tag.split('-').next().unwrap_or("");
+
+            if let Ok(language) = primary.parse::<Language>() {
+                if quality > 0.0 && selected.map_or(true, |(_, q)| quality > q) {
+                    selected = Some((language, quality));
+                }
+            }
+        }
+
+        selected.map_or(Language::En, |(language, _)| language)
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::De => "de",
+            Language::Fr => "fr",
+            Language::It => "it",
+            Language::En => "en",
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "de" => Ok(Language::De),
+            "fr" => Ok(Language::Fr),
+            "it" => Ok(Language::It),
+            "en" => Ok(Language::En),
+            _ => Err(InvalidRequestInputError(format!(
+                "Unsupported language '{}', expected de, fr, it or en",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Extracts the [`Language`] selected by the Accept-Language header of the request, see
+/// [`Language::from_accept_language`].
+#[cfg(feature = "server")]
+pub fn with_language(
+) -> impl Filter<Extract = (Language,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Language::En, Language::from_accept_language)
+    })
+}
+
+/// Returns the message for the code of an [`Error`] in the given language, `{detail}` is replaced
+/// by the detail of the error, if any. Returns `None` for English and unknown codes.
+pub fn error_message(code: &str, detail: Option<&str>, language: Language) -> Option<String> {
+    let template = match (code, language) {
+        (_, Language::En) => return None,
+        ("invalid_credentials", Language::De) => "Ungültige Anmeldedaten",
+        ("invalid_credentials", Language::Fr) => "Identifiants invalides",
+        ("invalid_credentials", Language::It) => "Credenziali non valide",
+        ("missing_permission", Language::De) => "Keine Berechtigung für diese Aktion",
+        ("missing_permission", Language::Fr) => "Pas d'autorisation pour cette action",
+        ("missing_permission", Language::It) => "Nessuna autorizzazione per questa azione",
+        ("principal_exists", Language::De) => {
+            "Es existiert bereits ein Benutzer mit dem Namen '{detail}'"
+        }
+        ("principal_exists", Language::Fr) => "Un utilisateur avec le nom '{detail}' existe déjà",
+        ("principal_exists", Language::It) => "Esiste già un utente con il nome '{detail}'",
+        ("utf_encoding", Language::De) => "Der Header der Anfrage ist kein gültiges UTF-8",
+        ("utf_encoding", Language::Fr) => "L'en-tête de la requête n'est pas un UTF-8 valide",
+        ("utf_encoding", Language::It) => "L'intestazione della richiesta non è UTF-8 valido",
+        ("invalid_auth_header", Language::De) => {
+            "Der Authorization-Header ist ungültig (erwartet wird 'Bearer ' mit einem JWT)"
+        }
+        ("invalid_auth_header", Language::Fr) => {
+            "L'en-tête Authorization est invalide ('Bearer ' avec un JWT est attendu)"
+        }
+        ("invalid_auth_header", Language::It) => {
+            "L'intestazione Authorization non è valida (è previsto 'Bearer ' con un JWT)"
+        }
+        ("missing_auth_header", Language::De) => "Der Authorization-Header fehlt",
+        ("missing_auth_header", Language::Fr) => "L'en-tête Authorization est manquant",
+        ("missing_auth_header", Language::It) => "Manca l'intestazione Authorization",
+        ("bad_request", Language::De) => "Die Anfrage ist nicht korrekt formatiert",
+        ("bad_request", Language::Fr) => "La requête n'est pas formatée correctement",
+        ("bad_request", Language::It) => "La richiesta non è formattata correttamente",
+        ("invalid_jwt", Language::De) => "Das JWT ist ungültig oder abgelaufen",
+        ("invalid_jwt", Language::Fr) => "Le JWT est invalide ou a expiré",
+        ("invalid_jwt", Language::It) => "Il JWT non è valido o è scaduto",
+        ("invalid_refresh_token", Language::De) => "Das Refresh-Token ist ungültig",
+        ("invalid_refresh_token", Language::Fr) => "Le jeton de rafraîchissement est invalide",
+        ("invalid_refresh_token", Language::It) => "Il token di aggiornamento non è valido",
+        ("too_many_login_attempts", Language::De) => {
+            "Zu viele fehlgeschlagene Anmeldeversuche, versuchen Sie es in {detail} Sekunden erneut"
+        }
+        ("too_many_login_attempts", Language::Fr) => {
+            "Trop de tentatives de connexion échouées, réessayez dans {detail} secondes"
+        }
+        ("too_many_login_attempts", Language::It) => {
+            "Troppi tentativi di accesso falliti, riprova tra {detail} secondi"
+        }
+        ("invalid_totp_code", Language::De) => "Der Bestätigungscode ist ungültig",
+        ("invalid_totp_code", Language::Fr) => "Le code de vérification est invalide",
+        ("invalid_totp_code", Language::It) => "Il codice di verifica non è valido",
+        ("invalid_totp_token", Language::De) => {
+            "Das Token für die Zwei-Faktor-Anmeldung ist ungültig oder abgelaufen"
+        }
+        ("invalid_totp_token", Language::Fr) => {
+            "Le jeton de connexion à deux facteurs est invalide ou expiré"
+        }
+        ("invalid_totp_token", Language::It) => {
+            "Il token di accesso a due fattori non è valido o è scaduto"
+        }
+        ("totp_already_enabled", Language::De) => {
+            "Die Zwei-Faktor-Authentifizierung ist bereits aktiviert"
+        }
+        ("totp_already_enabled", Language::Fr) => {
+            "L'authentification à deux facteurs est déjà activée"
+        }
+        ("totp_already_enabled", Language::It) => "L'autenticazione a due fattori è già attivata",
+        ("totp_not_enrolled", Language::De) => {
+            "Die Zwei-Faktor-Authentifizierung wurde nicht eingerichtet"
+        }
+        ("totp_not_enrolled", Language::Fr) => {
+            "L'authentification à deux facteurs n'a pas été configurée"
+        }
+        ("totp_not_enrolled", Language::It) => {
+            "L'autenticazione a due fattori non è stata configurata"
+        }
+        ("invalid_reset_token", Language::De) => {
+            "Das Token zum Zurücksetzen des Passworts ist ungültig"
+        }
+        ("invalid_reset_token", Language::Fr) => {
+            "Le jeton de réinitialisation du mot de passe est invalide"
+        }
+        ("invalid_reset_token", Language::It) => {
+            "Il token per reimpostare la password non è valido"
+        }
+        ("qr_code", Language::De) => "Der QR-Code konnte nicht erstellt werden: '{detail}'",
+        ("qr_code", Language::Fr) => "Le code QR n'a pas pu être généré: '{detail}'",
+        ("qr_code", Language::It) => "Non è stato possibile generare il codice QR: '{detail}'",
+        ("invalid_input", Language::De) | ("invalid_fields", Language::De) => {
+            "Die Eingabe ist ungültig: '{detail}'"
+        }
+        ("invalid_input", Language::Fr) | ("invalid_fields", Language::Fr) => {
+            "La saisie est invalide: '{detail}'"
+        }
+        ("invalid_input", Language::It) | ("invalid_fields", Language::It) => {
+            "L'input non è valido: '{detail}'"
+        }
+        ("pdf_queue_full", Language::De) => {
+            "Die PDF-Warteschlange ist voll, bitte später erneut versuchen"
+        }
+        ("pdf_queue_full", Language::Fr) => {
+            "La file d'attente PDF est pleine, veuillez réessayer plus tard"
+        }
+        ("pdf_queue_full", Language::It) => "La coda PDF è piena, riprovare più tardi",
+        ("invalid_body", Language::De) => "Der Inhalt der Anfrage ist ungültig: '{detail}'",
+        ("invalid_body", Language::Fr) => "Le contenu de la requête est invalide: '{detail}'",
+        ("invalid_body", Language::It) => "Il contenuto della richiesta non è valido: '{detail}'",
+        ("invalid_query", Language::De) => "Die Query-Parameter sind ungültig",
+        ("invalid_query", Language::Fr) => "Les paramètres de la requête sont invalides",
+        ("invalid_query", Language::It) => "I parametri della richiesta non sono validi",
+        ("missing_header", Language::De) => "Ein erforderlicher Header fehlt: '{detail}'",
+        ("missing_header", Language::Fr) => "Un en-tête requis est manquant: '{detail}'",
+        ("missing_header", Language::It) => "Manca un'intestazione obbligatoria: '{detail}'",
+        ("invalid_header", Language::De) => "Ein Header ist ungültig: '{detail}'",
+        ("invalid_header", Language::Fr) => "Un en-tête est invalide: '{detail}'",
+        ("invalid_header", Language::It) => "Un'intestazione non è valida: '{detail}'",
+        ("length_required", Language::De) => "Der Content-Length-Header ist erforderlich",
+        ("length_required", Language::Fr) => "L'en-tête Content-Length est requis",
+        ("length_required", Language::It) => "L'intestazione Content-Length è obbligatoria",
+        ("payload_too_large", Language::De) => "Der Inhalt der Anfrage ist zu gross",
+        ("payload_too_large", Language::Fr) => "Le contenu de la requête est trop volumineux",
+        ("payload_too_large", Language::It) => "Il contenuto della richiesta è troppo grande",
+        ("unsupported_media_type", Language::De) => {
+            "Der Content-Type der Anfrage wird nicht unterstützt"
+        }
+        ("unsupported_media_type", Language::Fr) => {
+            "Le Content-Type de la requête n'est pas pris en charge"
+        }
+        ("unsupported_media_type", Language::It) => {
+            "Il Content-Type della richiesta non è supportato"
+        }
+        ("method_not_allowed", Language::De) => {
+            "Die HTTP-Methode ist für diesen Pfad nicht erlaubt"
+        }
+        ("method_not_allowed", Language::Fr) => {
+            "La méthode HTTP n'est pas autorisée pour ce chemin"
+        }
+        ("method_not_allowed", Language::It) => {
+            "Il metodo HTTP non è consentito per questo percorso"
+        }
+        ("not_found", Language::De) => "Die angeforderte Ressource existiert nicht",
+        ("not_found", Language::Fr) => "La ressource demandée n'existe pas",
+        ("not_found", Language::It) => "La risorsa richiesta non esiste",
+        (code, Language::De) if INTERNAL_ERROR_CODES.contains(&code) => {
+            "Ein interner Fehler ist aufgetreten"
+        }
+        (code, Language::Fr) if INTERNAL_ERROR_CODES.contains(&code) => {
+            "Une erreur interne s'est produite"
+        }
+        (code, Language::It) if INTERNAL_ERROR_CODES.contains(&code) => {
+            "Si è verificato un errore interno"
+        }
+        _ => return None,
+    };
+
+    Some(template.replace("{detail}", detail.unwrap_or("")))
+}
+
+/// Returns the message for the code of a [`FieldError`](crate::validation::FieldError) in the given
+/// language, with `{name}` replaced by the parameter of that name. Returns `None` for unknown codes
+/// and for English, except for the `length` code whose message depends on the parameters.
+pub fn field_message(
+    code: &str,
+    params: &Map<String, Value>,
+    language: Language,
+) -> Option<String> {
+    let template = match (code, language) {
+        ("length", _) => length_template(params, language)?,
+        (_, Language::En) => return None,
+        ("missing", Language::De) => "Pflichtfeld fehlt",
+        ("missing", Language::Fr) => "Champ obligatoire manquant",
+        ("missing", Language::It) => "Campo obbligatorio mancante",
+        ("invalid_type", Language::De) => "Ungültiger Datentyp, erwartet wird ein Text bzw. ein Objekt",
+        ("invalid_type", Language::Fr) => "Type de données invalide, un texte ou un objet est attendu",
+        ("invalid_type", Language::It) => "Tipo di dati non valido, è previsto un testo o un oggetto",
+        ("invalid_iban", Language::De) => "Ungültige IBAN, erwartet wird eine gültige IBAN aus der Schweiz oder Liechtenstein",
+        ("invalid_iban", Language::Fr) => "IBAN invalide, une IBAN valide de Suisse ou du Liechtenstein est attendue",
+        ("invalid_iban", Language::It) => "IBAN non valido, è previsto un IBAN valido della Svizzera o del Liechtenstein",
+        ("invalid_amount", Language::De) => "Ungültiger Betrag, erwartet wird ein Betrag zwischen 0.01 und 999999999.99 mit zwei Nachkommastellen, z.B. 1250.00",
+        ("invalid_amount", Language::Fr) => "Montant invalide, un montant entre 0.01 et 999999999.99 avec deux décimales est attendu, p. ex. 1250.00",
+        ("invalid_amount", Language::It) => "Importo non valido, è previsto un importo tra 0.01 e 999999999.99 con due decimali, p. es. 1250.00",
+        ("invalid_currency", Language::De) => "Die Währung muss CHF oder EUR sein",
+        ("invalid_currency", Language::Fr) => "La monnaie doit être CHF ou EUR",
+        ("invalid_currency", Language::It) => "La valuta deve essere CHF o EUR",
+        ("invalid_reference", Language::De) => "Ungültige Referenz, der Referenztyp muss QRR, SCOR oder NON sein und die Referenznummer muss zum Typ passen",
+        ("invalid_reference", Language::Fr) => "Référence invalide, le type de référence doit être QRR, SCOR ou NON et le numéro de référence doit correspondre au type",
+        ("invalid_reference", Language::It) => "Riferimento non valido, il tipo di riferimento deve essere QRR, SCOR o NON e il numero di riferimento deve corrispondere al tipo",
+        ("reference_type_mismatch", Language::De) => "Der Referenztyp passt nicht zur IBAN, QRR muss mit QR-IBANs und nur mit QR-IBANs verwendet werden",
+        ("reference_type_mismatch", Language::Fr) => "Le type de référence ne correspond pas à l'IBAN, QRR doit être utilisé avec les QR-IBAN et uniquement avec celles-ci",
+        ("reference_type_mismatch", Language::It) => "Il tipo di riferimento non corrisponde all'IBAN, QRR deve essere utilizzato con i QR-IBAN e solo con essi",
+        ("character_set", Language::De) => "Enthält Zeichen ausserhalb des von der Spezifikation erlaubten lateinischen Zeichensatzes",
+        ("character_set", Language::Fr) => "Contient des caractères en dehors du jeu de caractères latins autorisé par la spécification",
+        ("character_set", Language::It) => "Contiene caratteri al di fuori del set di caratteri latini consentito dalla specifica",
+        ("zip_code_city_length", Language::De) => "Postleitzahl und Ort dürfen zusammen höchstens {max} Zeichen lang sein",
+        ("zip_code_city_length", Language::Fr) => "Le code postal et la localité ne doivent pas dépasser {max} caractères au total",
+        ("zip_code_city_length", Language::It) => "Il codice postale e la località non devono superare complessivamente {max} caratteri",
+        ("qr_code", Language::De) => "Die Daten überschreiten die maximale Grösse des QR-Codes (Version 25)",
+        ("qr_code", Language::Fr) => "Les données dépassent la taille maximale du code QR (version 25)",
+        ("qr_code", Language::It) => "I dati superano la dimensione massima del codice QR (versione 25)",
+        ("generator", Language::De) => "Vom QR-Generator-Plugin abgelehnt: '{detail}'",
+        ("generator", Language::Fr) => "Refusé par le plugin de génération QR: '{detail}'",
+        ("generator", Language::It) => "Rifiutato dal plugin del generatore QR: '{detail}'",
+        ("invalid_user_name", Language::De) => "Der Benutzername darf nur Buchstaben, Ziffern, '.', '_' und '-' enthalten",
+        ("invalid_user_name", Language::Fr) => "Le nom d'utilisateur ne peut contenir que des lettres, des chiffres, '.', '_' et '-'",
+        ("invalid_user_name", Language::It) => "Il nome utente può contenere solo lettere, cifre, '.', '_' e '-'",
+        ("invalid_email", Language::De) => "Ungültige E-Mail-Adresse",
+        ("invalid_email", Language::Fr) => "Adresse e-mail invalide",
+        ("invalid_email", Language::It) => "Indirizzo e-mail non valido",
+        ("blocklisted_password", Language::De) => "Das Passwort ist zu verbreitet oder wurde bei einem Datenleck veröffentlicht",
+        ("blocklisted_password", Language::Fr) => "Le mot de passe est trop courant ou a été exposé lors d'une fuite de données",
+        ("blocklisted_password", Language::It) => "La password è troppo comune o è stata esposta in una violazione di dati",
+        _ => return None,
+    };
+
+    Some(
+        params
+            .iter()
+            .fold(template.to_string(), |message, (name, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                message.replace(&format!("{{{}}}", name), &value)
+            }),
+    )
+}
+
+fn length_template(params: &Map<String, Value>, language: Language) -> Option<&'static str> {
+    let template = match (
+        params.contains_key("min"),
+        params.contains_key("max"),
+        params.get("min") == params.get("max"),
+        language,
+    ) {
+        (true, true, true, Language::De) => "Die Länge muss genau {min} Zeichen betragen",
+        (true, true, true, Language::Fr) => "La longueur doit être exactement de {min} caractères",
+        (true, true, true, Language::It) => {
+            "La lunghezza deve essere esattamente di {min} caratteri"
+        }
+        (true, true, true, Language::En) => "Length must be exactly {min} characters",
+        (true, true, false, Language::De) => {
+            "Die Länge muss zwischen {min} und {max} Zeichen liegen"
+        }
+        (true, true, false, Language::Fr) => {
+            "La longueur doit être comprise entre {min} et {max} caractères"
+        }
+        (true, true, false, Language::It) => {
+            "La lunghezza deve essere compresa tra {min} e {max} caratteri"
+        }
+        (true, true, false, Language::En) => "Length must be between {min} and {max} characters",
+        (false, true, _, Language::De) => "Die Länge darf {max} Zeichen nicht überschreiten",
+        (false, true, _, Language::Fr) => "La longueur ne doit pas dépasser {max} caractères",
+        (false, true, _, Language::It) => "La lunghezza non deve superare {max} caratteri",
+        (false, true, _, Language::En) => "Length may not exceed {max} characters",
+        (true, false, _, Language::De) => "Die Länge muss mindestens {min} Zeichen betragen",
+        (true, false, _, Language::Fr) => "La longueur doit être d'au moins {min} caractères",
+        (true, false, _, Language::It) => "La lunghezza deve essere di almeno {min} caratteri",
+        (true, false, _, Language::En) => "Length must be at least {min} characters",
+        (false, false, ..) => return None,
+    };
+
+    Some(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_from_accept_language() {
+        assert_eq!(Language::from_accept_language("de-CH"), Language::De);
+        assert_eq!(
+            Language::from_accept_language("es, it;q=0.8, fr;q=0.9"),
+            Language::Fr
+        );
+        assert_eq!(
+            Language::from_accept_language("fr;q=0, IT-ch;q=0.5"),
+            Language::It
+        );
+        assert_eq!(Language::from_accept_language("*"), Language::En);
+        assert_eq!(Language::from_accept_language(""), Language::En);
+    }
+
+    #[test]
+    fn test_field_message() {
+        let params = json!({"min": 1, "max": 70});
+        let params = params.as_object().unwrap();
+        assert_eq!(
+            field_message("length", params, Language::De).unwrap(),
+            "Die Länge muss zwischen 1 und 70 Zeichen liegen"
+        );
+        assert_eq!(
+            field_message("length", params, Language::En).unwrap(),
+            "Length must be between 1 and 70 characters"
+        );
+        assert_eq!(field_message("missing", params, Language::En), None);
+        assert_eq!(field_message("unknown", params, Language::Fr), None);
+
+        let params = json!({"max": 69});
+        assert_eq!(
+            field_message(
+                "zip_code_city_length",
+                params.as_object().unwrap(),
+                Language::It
+            )
+            .unwrap(),
+            "Il codice postale e la località non devono superare complessivamente 69 caratteri"
+        );
+    }
+}
@@ -0,0 +1,550 @@
+//! Reads [`QrData`] objects from files in the formats supported for batch processing outside of
+//! the web service.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
+
+#[cfg(feature = "xlsx")]
+use calamine::{Data, Reader, Xlsx};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Error::InvalidRequestInputError, Error::IoError};
+use crate::templating::QrData;
+
+/// Fields of [`QrData`] in the JSON format, which are also the default column names of spreadsheets.
+pub const QR_DATA_FIELDS: [&str; 16] = [
+    "creditor_iban",
+    "creditor_name",
+    "creditor_address",
+    "creditor_zip_code",
+    "creditor_city",
+    "creditor_country",
+    "debtor_name",
+    "debtor_address",
+    "debtor_zip_code",
+    "debtor_city",
+    "debtor_country",
+    "amount",
+    "currency",
+    "reference_type",
+    "reference_number",
+    "additional_information",
+];
+/// Fields of [`QrData`] that may be missing from spreadsheets.
+pub(crate) const OPTIONAL_QR_DATA_FIELDS: [&str; 2] =
+    ["reference_number", "additional_information"];
+
+/// The format of a file containing [`QrData`] objects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    /// A JSON array of objects in the format accepted by the /generate-slip endpoint, or a single
+    /// object.
+    Json,
+    /// One JSON object per line, blank lines are skipped.
+    JsonLines,
+    /// A CSV file with a header row naming the fields of the JSON format, the optional fields may be
+    /// empty or omitted.
+    Csv,
+}
+
+impl InputFormat {
+    /// Determines the format by the extension of the file: .json, .jsonl / .ndjson or .csv.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.to_ascii_lowercase().parse().ok())
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(InputFormat::Json),
+            "jsonl" | "ndjson" => Ok(InputFormat::JsonLines),
+            "csv" => Ok(InputFormat::Csv),
+            _ => Err(InvalidRequestInputError(format!(
+                "Unsupported input format '{}', expected json, jsonl or csv",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputFormat::Json => "json",
+            InputFormat::JsonLines => "jsonl",
+            InputFormat::Csv => "csv",
+        })
+    }
+}
+
+/// Reads all [`QrData`] objects from the reader in the given format. Fails with an
+/// [`InvalidRequestInputError`] naming the line or record if any object cannot be deserialized,
+/// the remaining fields are verified when generating the slips.
+pub fn read_qr_data<R: Read>(reader: R, format: InputFormat) -> Result<Vec<QrData>, Error> {
+    match format {
+        InputFormat::Json => {
+            let value = serde_json::from_reader::<_, Value>(reader)
+                .map_err(|e| InvalidRequestInputError(format!("Invalid JSON: {}", e)))?;
+            match value {
+                Value::Array(_) => serde_json::from_value::<Vec<QrData>>(value),
+                _ => serde_json::from_value::<QrData>(value).map(|qr_data| vec![qr_data]),
+            }
+            .map_err(|e| InvalidRequestInputError(e.to_string()))
+        }
+        InputFormat::JsonLines => {
+            let mut qr_data_vec = Vec::new();
+            for (idx, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line.map_err(|e| IoError(e.to_string()))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let qr_data = serde_json::from_str::<QrData>(&line)
+                    .map_err(|e| InvalidRequestInputError(format!("Line {}: {}", idx + 1, e)))?;
+                qr_data_vec.push(qr_data);
+            }
+
+            Ok(qr_data_vec)
+        }
+        InputFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::Headers)
+            .from_reader(reader)
+            .deserialize::<QrData>()
+            .collect::<Result<Vec<QrData>, csv::Error>>()
+            .map_err(|e| InvalidRequestInputError(e.to_string())),
+    }
+}
+
+/// The format of a spreadsheet containing one [`QrData`] object per row, see [`read_sheet`].
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SheetFormat {
+    /// A CSV file separated by commas or, as exported by Excel in some locales, by semicolons.
+    Csv,
+    /// The first worksheet of an Excel workbook.
+    #[cfg(feature = "xlsx")]
+    Xlsx,
+}
+
+/// Maps the fields of [`QrData`] to the columns of a spreadsheet. Fields that are not mapped are read
+/// from the column named like the field, see [`QR_DATA_FIELDS`].
+#[derive(Clone, Debug, Default)]
+pub struct ColumnMapping(HashMap<String, String>);
+
+impl ColumnMapping {
+    /// Returns the name of the column containing the given field.
+    pub fn column<'a>(&'a self, field: &'a str) -> &'a str {
+        self.0.get(field).map(String::as_str).unwrap_or(field)
+    }
+}
+
+/// Parses a comma separated list of `field:column` pairs, e.g. `debtor_name:Name,amount:Betrag`.
+impl FromStr for ColumnMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mapping = HashMap::new();
+        for pair in s.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (field, column) = match pair.splitn(2, ':').collect::<Vec<&str>>().as_slice() {
+                [field, column] if !column.trim().is_empty() => (field.trim(), column.trim()),
+                _ => {
+                    return Err(InvalidRequestInputError(format!(
+                        "Column mapping '{}' is not formatted correctly, expected 'field:column'",
+                        pair
+                    )))
+                }
+            };
+
+            if !QR_DATA_FIELDS.contains(&field) {
+                return Err(InvalidRequestInputError(format!(
+                    "Cannot map column '{}' to unknown field '{}'",
+                    column, field
+                )));
+            }
+
+            mapping.insert(field.to_string(), column.to_string());
+        }
+
+        Ok(ColumnMapping(mapping))
+    }
+}
+
+/// A data row of a spreadsheet along with the JSON representation of the [`QrData`] object read
+/// from it.
+#[derive(Debug)]
+pub struct SheetRow {
+    /// The 1-based number of the row as displayed by spreadsheet applications, i.e. including the
+    /// header row.
+    pub row: usize,
+    /// The JSON object containing the cell of each mapped column, see
+    /// [`validate_item`](crate::validation::validate_item).
+    pub object: Value,
+}
+
+/// Reads a spreadsheet with a header row, returning the JSON object of each data row, skipping empty
+/// rows. The objects are not converted to [`QrData`], so that invalid rows do not fail the entire file
+/// and all their invalid fields can be reported, the caller is responsible for validating them.
+///
+/// Fails if the file cannot be read or if a column for a required field is missing.
+pub fn read_sheet(
+    bytes: &[u8],
+    format: SheetFormat,
+    mapping: &ColumnMapping,
+) -> Result<Vec<SheetRow>, Error> {
+    let rows = match format {
+        SheetFormat::Csv => read_csv_cells(bytes)?,
+        #[cfg(feature = "xlsx")]
+        SheetFormat::Xlsx => read_xlsx_cells(bytes, mapping)?,
+    };
+    let mut rows = rows.into_iter();
+    let header = rows.next().ok_or_else(|| {
+        InvalidRequestInputError(String::from("The file does not contain a header row"))
+    })?;
+
+    let columns = QR_DATA_FIELDS
+        .iter()
+        .filter_map(|field| {
+            let column = mapping.column(field);
+            match header.iter().position(|name| name.trim() == column) {
+                Some(idx) => Some(Ok((*field, idx))),
+                None if OPTIONAL_QR_DATA_FIELDS.contains(field) => None,
+                None => Some(Err(InvalidRequestInputError(format!(
+                    "Missing column '{}' for field {}",
+                    column, field
+                )))),
+            }
+        })
+        .collect::<Result<Vec<(&str, usize)>, Error>>()?;
+
+    Ok(rows
+        .enumerate()
+        .filter(|(_, cells)| cells.iter().any(|cell| !cell.trim().is_empty()))
+        .map(|(idx, cells)| SheetRow {
+            row: idx + 2,
+            object: read_row(&cells, &columns),
+        })
+        .collect())
+}
+
+/// Reads the cells of a row by building the JSON object for the given field columns, so that the
+/// typed fields fail with the same error as when deserializing the JSON format.
+fn read_row(cells: &[String], columns: &[(&str, usize)]) -> Value {
+    let object = columns
+        .iter()
+        .filter_map(|(field, idx)| {
+            let value = cells.get(*idx).map_or("", |cell| cell.trim());
+            if value.is_empty() && OPTIONAL_QR_DATA_FIELDS.contains(field) {
+                None
+            } else {
+                Some((field.to_string(), Value::String(value.to_string())))
+            }
+        })
+        .collect::<Map<String, Value>>();
+
+    Value::Object(object)
+}
+
+fn read_csv_cells(bytes: &[u8]) -> Result<Vec<Vec<String>>, Error> {
+    // strip the byte order mark written by Excel
+    let bytes = bytes.strip_prefix("\u{feff}".as_bytes()).unwrap_or(bytes);
+    let header_line = bytes.split(|b| *b == b'\n').next().unwrap_or(&[]);
+    let count = |delimiter| header_line.iter().filter(|b| **b == delimiter).count();
+    let delimiter = if count(b';') > count(b',') {
+        b';'
+    } else {
+        b','
+    };
+
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(bytes)
+        .records()
+        .map(|record| record.map(|record| record.iter().map(String::from).collect()))
+        .collect::<Result<Vec<Vec<String>>, csv::Error>>()
+        .map_err(|e| InvalidRequestInputError(format!("Invalid CSV: {}", e)))
+}
+
+/// Reads the cells of the first worksheet. Numbers in the amount column are formatted with two
+/// fractional digits, as the cell is usually formatted as currency rather than containing text.
+#[cfg(feature = "xlsx")]
+fn read_xlsx_cells(bytes: &[u8], mapping: &ColumnMapping) -> Result<Vec<Vec<String>>, Error> {
+    let invalid = |e: calamine::XlsxError| InvalidRequestInputError(format!("Invalid XLSX: {}", e));
+    let mut workbook = Xlsx::new(std::io::Cursor::new(bytes)).map_err(invalid)?;
+    let range = match workbook.worksheet_range_at(0) {
+        Some(range) => range.map_err(invalid)?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut amount_idx = None;
+    Ok(range
+        .rows()
+        .enumerate()
+        .map(|(row_idx, row)| {
+            if row_idx == 0 {
+                amount_idx = row
+                    .iter()
+                    .position(|cell| cell.to_string().trim() == mapping.column("amount"));
+            }
+
+            row.iter()
+                .enumerate()
+                .map(|(idx, cell)| match cell {
+                    Data::Float(amount) if Some(idx) == amount_idx => format!("{:.2}", amount),
+                    Data::Int(amount) if Some(idx) == amount_idx => format!("{}.00", amount),
+                    cell => cell.to_string(),
+                })
+                .collect()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    #[cfg(feature = "xlsx")]
+    use crate::templating::build_qr_payload;
+    use crate::templating::QrDataJson;
+
+    const CSV_HEADER: &str = "creditor_iban,creditor_name,creditor_address,creditor_zip_code,creditor_city,creditor_country,debtor_name,debtor_address,debtor_zip_code,debtor_city,debtor_country,amount,currency,reference_type,reference_number,additional_information";
+
+    fn json_line(debtor_name: &str) -> String {
+        format!(
+            r#"{{"creditor_iban":"CH9300762011623852957","creditor_name":"Robert Schneider AG","creditor_address":"Rue du Lac 1268","creditor_zip_code":"2501","creditor_city":"Biel","creditor_country":"CH","debtor_name":"{}","debtor_address":"Grosse Marktgasse 28","debtor_zip_code":"9400","debtor_city":"Rorschach","debtor_country":"CH","amount":"199.95","currency":"CHF","reference_type":"NON"}}"#,
+            debtor_name
+        )
+    }
+
+    #[test]
+    fn test_input_format() {
+        assert_eq!(
+            InputFormat::from_path(Path::new("slips/2021-07.JSONL")),
+            Some(InputFormat::JsonLines)
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("slips.csv")),
+            Some(InputFormat::Csv)
+        );
+        assert_eq!(InputFormat::from_path(Path::new("slips.txt")), None);
+        assert_eq!(InputFormat::from_path(Path::new("slips")), None);
+    }
+
+    #[test]
+    fn test_read_json() {
+        let array = format!(
+            "[{}, {}]",
+            json_line("Pia Rutschmann"),
+            json_line("Max Muster")
+        );
+        let qr_data_vec = read_qr_data(array.as_bytes(), InputFormat::Json).unwrap();
+        assert_eq!(qr_data_vec.len(), 2);
+        assert_eq!(qr_data_vec[1].debtor_name(), "Max Muster");
+
+        let object = json_line("Pia Rutschmann");
+        assert_eq!(
+            read_qr_data(object.as_bytes(), InputFormat::Json)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_read_json_lines() {
+        let lines = format!(
+            "{}\n\n{}\n",
+            json_line("Pia Rutschmann"),
+            json_line("Max Muster")
+        );
+        let qr_data_vec = read_qr_data(lines.as_bytes(), InputFormat::JsonLines).unwrap();
+        assert_eq!(qr_data_vec.len(), 2);
+
+        let invalid = format!("{}\n{}\n", json_line("Pia Rutschmann"), "{}");
+        match read_qr_data(invalid.as_bytes(), InputFormat::JsonLines) {
+            Err(InvalidRequestInputError(msg)) => assert!(msg.starts_with("Line 2:"), "{}", msg),
+            other => panic!("expected invalid input error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let csv = format!(
+            "{}\n{}\n{}\n",
+            CSV_HEADER,
+            "CH4431999123000889012,Robert Schneider AG,Rue du Lac 1268,2501,Biel,CH,Pia Rutschmann,Grosse Marktgasse 28,9400,Rorschach,CH,1949.75,CHF,QRR,210000000003139471430009017,Auftrag vom 15.06.2020",
+            "CH9300762011623852957,Robert Schneider AG,Rue du Lac 1268,2501,Biel,CH,Max Muster,Musterstrasse 1,8000,Zürich,CH,0.50,EUR,NON,,"
+        );
+        let qr_data_vec = read_qr_data(csv.as_bytes(), InputFormat::Csv).unwrap();
+        assert_eq!(qr_data_vec.len(), 2);
+        assert_eq!(qr_data_vec[0].reference_type(), "QRR");
+        assert_eq!(qr_data_vec[1].reference_number(), None);
+        assert_eq!(qr_data_vec[1].amount().cents(), 50);
+
+        let invalid = format!(
+            "{}\n{}\n",
+            CSV_HEADER,
+            "CH9300762011623852957,Robert Schneider AG,Rue du Lac 1268,2501,Biel,CH,Max Muster,Musterstrasse 1,8000,Zürich,CH,5,CHF,NON,,"
+        );
+        assert!(read_qr_data(invalid.as_bytes(), InputFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn test_column_mapping() {
+        let mapping = "debtor_name:Name, amount : Betrag,"
+            .parse::<ColumnMapping>()
+            .unwrap();
+        assert_eq!(mapping.column("debtor_name"), "Name");
+        assert_eq!(mapping.column("amount"), "Betrag");
+        assert_eq!(mapping.column("currency"), "currency");
+
+        assert!("debtor_name".parse::<ColumnMapping>().is_err());
+        assert!("debtor_name:".parse::<ColumnMapping>().is_err());
+        assert!("debtor:Name".parse::<ColumnMapping>().is_err());
+    }
+
+    #[test]
+    fn test_read_sheet_csv() {
+        let header = CSV_HEADER
+            .replace(',', ";")
+            .replace("debtor_name", "Name")
+            .replace(";additional_information", "");
+        let csv = format!(
+            "\u{feff}{}\n{}\n;;;\n{}\n",
+            header,
+            "CH4431999123000889012;Robert Schneider AG;Rue du Lac 1268;2501;Biel;CH;Pia Rutschmann;Grosse Marktgasse 28;9400;Rorschach;CH;1949.75;CHF;QRR;210000000003139471430009017",
+            "CH9300762011623852957;Robert Schneider AG;Rue du Lac 1268;2501;Biel;CH;Max Muster;Musterstrasse 1;8000;Zürich;CH;5;CHF;NON;"
+        );
+        let mapping = "debtor_name:Name".parse::<ColumnMapping>().unwrap();
+
+        let rows = read_sheet(csv.as_bytes(), SheetFormat::Csv, &mapping).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].row, 2);
+        assert_eq!(qr_data(&rows[0]).unwrap().debtor_name(), "Pia Rutschmann");
+        // the empty row 3 is skipped and the invalid amount only fails row 4
+        assert_eq!(rows[1].row, 4);
+        match qr_data(&rows[1]) {
+            Err(InvalidRequestInputError(msg)) => {
+                assert!(msg.starts_with("Decimal amount '5'"), "{}", msg)
+            }
+            other => panic!("expected invalid input error, got {:?}", other),
+        }
+
+        match read_sheet(csv.as_bytes(), SheetFormat::Csv, &ColumnMapping::default()) {
+            Err(InvalidRequestInputError(msg)) => {
+                assert_eq!(msg, "Missing column 'debtor_name' for field debtor_name")
+            }
+            other => panic!("expected invalid input error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "xlsx")]
+    #[test]
+    fn test_read_sheet_xlsx() {
+        let mut header = QR_DATA_FIELDS[..14].to_vec();
+        header[6] = "Name";
+        header[11] = "Betrag";
+        let xlsx = xlsx_workbook(&[
+            header,
+            vec![
+                "CH9300762011623852957",
+                "Robert Schneider AG",
+                "Rue du Lac 1268",
+                "#2501",
+                "Biel",
+                "CH",
+                "Max Muster",
+                "Musterstrasse 1",
+                "#8000",
+                "Zürich",
+                "CH",
+                "#199.5",
+                "CHF",
+                "NON",
+            ],
+        ]);
+        let mapping = "debtor_name:Name,amount:Betrag"
+            .parse::<ColumnMapping>()
+            .unwrap();
+
+        let rows = read_sheet(&xlsx, SheetFormat::Xlsx, &mapping).unwrap();
+        assert_eq!(rows.len(), 1);
+        let qr_data = qr_data(&rows[0]).unwrap();
+        assert_eq!(qr_data.amount().to_string(), "199.50");
+        assert_eq!(qr_data.debtor_name(), "Max Muster");
+        assert_eq!(build_qr_payload(&qr_data).lines().nth(7), Some("2501 Biel"));
+    }
+
+    fn qr_data(row: &SheetRow) -> Result<QrData, Error> {
+        let json = serde_json::from_value::<QrDataJson>(row.object.clone()).unwrap();
+        QrData::try_from(json)
+    }
+
+    /// Creates a workbook containing the given rows, cells starting with # are written as numbers.
+    #[cfg(feature = "xlsx")]
+    fn xlsx_workbook(rows: &[Vec<&str>]) -> Vec<u8> {
+        use std::io::Write;
+
+        let sheet_rows = rows
+            .iter()
+            .map(|cells| {
+                let cells = cells
+                    .iter()
+                    .map(|cell| match cell.strip_prefix('#') {
+                        Some(number) => format!("<c><v>{}</v></c>", number),
+                        None => format!("<c t=\"inlineStr\"><is><t>{}</t></is></c>", cell),
+                    })
+                    .collect::<String>();
+                format!("<row>{}</row>", cells)
+            })
+            .collect::<String>();
+
+        let files = [
+            (
+                "[Content_Types].xml",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/><Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/></Types>"#.to_string(),
+            ),
+            (
+                "_rels/.rels",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string(),
+            ),
+            (
+                "xl/workbook.xml",
+                r#"<?xml version="1.0" encoding="UTF-8"?><workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets><sheet name="Debitoren" sheetId="1" r:id="rId1"/></sheets></workbook>"#.to_string(),
+            ),
+            (
+                "xl/_rels/workbook.xml.rels",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/></Relationships>"#.to_string(),
+            ),
+            (
+                "xl/worksheets/sheet1.xml",
+                format!(
+                    r#"<?xml version="1.0" encoding="UTF-8"?><worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>{}</sheetData></worksheet>"#,
+                    sheet_rows
+                ),
+            ),
+        ];
+
+        let mut zip_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in files.iter() {
+            zip_writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip_writer.write_all(content.as_bytes()).unwrap();
+        }
+        zip_writer.finish().unwrap().into_inner()
+    }
+}
@@ -0,0 +1,466 @@
+//! Signing and verification of the JWTs used as access tokens.
+//!
+//! Tokens are signed with an RSA (RS256) or Ed25519 (EdDSA) private key and carry the id of the key
+//! in the `kid` header. Tokens are accepted if they are signed by any of the configured public keys,
+//! so that the signing key can be rotated without invalidating the tokens issued before, and the
+//! public keys are published as a JSON Web Key Set so that other services can verify the tokens.
+//! Without a private key, tokens are signed with the legacy HS512 secret JWT_SECRET.
+
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use simple_asn1::{oid, ASN1Block};
+use warp::{Rejection, Reply};
+
+use crate::error::Error;
+
+lazy_static! {
+    /// The keys configured by the JWT_PRIVATE_KEY_FILE, JWT_KEY_ID, JWT_PUBLIC_KEYS_DIR and
+    /// JWT_SECRET environment variables, see [`JwtKeys::from_env`].
+    pub static ref JWT_KEYS: JwtKeys = JwtKeys::from_env();
+}
+
+/// The key used to sign new tokens.
+struct SigningKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+/// A public key accepted to verify tokens with the matching `kid`.
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    jwk: Jwk,
+}
+
+/// The components of a public key read from a PEM file.
+#[derive(Debug, PartialEq)]
+enum PublicKey {
+    Rsa { modulus: Vec<u8>, exponent: Vec<u8> },
+    Ed25519(Vec<u8>),
+}
+
+/// The signing key and all keys accepted for verification.
+pub struct JwtKeys {
+    signing_key: SigningKey,
+    verification_keys: HashMap<String, VerificationKey>,
+    /// The HS512 key accepted for tokens without `kid`, issued before moving to asymmetric keys.
+    legacy_key: Option<DecodingKey>,
+}
+
+impl JwtKeys {
+    /// Loads the keys from the environment. If JWT_PRIVATE_KEY_FILE is set, tokens are signed with
+    /// that PEM encoded private key, whose id is set by JWT_KEY_ID, and verified with the public keys
+    /// in the JWT_PUBLIC_KEYS_DIR directory, which must include the public key of the private key.
+    /// If JWT_SECRET is set as well, tokens signed with the secret are still accepted.
+    ///
+    /// Else tokens are signed and verified using the JWT_SECRET with HS512.
+    pub fn from_env() -> Self {
+        let legacy_secret = std::env::var("JWT_SECRET")
+            .ok()
+            .map(|val| u64::from_str(&val).expect("JWT_SECRET var is not a valid u64 value"));
+
+        let private_key_file = match std::env::var("JWT_PRIVATE_KEY_FILE") {
+            Ok(private_key_file) => private_key_file,
+            Err(_) => {
+                let secret = legacy_secret.expect(
+                    "Missing environment variable JWT_PRIVATE_KEY_FILE or JWT_SECRET must be set to generate JWT tokens.",
+                );
+                return Self::from_secret(secret);
+            }
+        };
+        let key_id = std::env::var("JWT_KEY_ID").expect(
+            "Missing environment variable JWT_KEY_ID must be set when JWT_PRIVATE_KEY_FILE is set",
+        );
+        let public_keys_dir = std::env::var("JWT_PUBLIC_KEYS_DIR").expect(
+            "Missing environment variable JWT_PUBLIC_KEYS_DIR must be set when JWT_PRIVATE_KEY_FILE is set",
+        );
+
+        let private_key = fs::read(&private_key_file).unwrap_or_else(|e| {
+            panic!(
+                "Failed to read JWT_PRIVATE_KEY_FILE '{}': {}",
+                private_key_file, e
+            )
+        });
+        let public_keys = read_public_keys(Path::new(&public_keys_dir)).unwrap_or_else(|e| {
+            panic!(
+                "Failed to read JWT_PUBLIC_KEYS_DIR '{}': {}",
+                public_keys_dir, e
+            )
+        });
+
+        Self::from_pem(&private_key, key_id, &public_keys, legacy_secret)
+            .unwrap_or_else(|e| panic!("Invalid JWT keys: {}", e))
+    }
+
+    /// Creates the keys signing and verifying tokens using HS512 with the provided secret.
+    pub fn from_secret(secret: u64) -> Self {
+        Self {
+            signing_key: SigningKey {
+                kid: None,
+                algorithm: Algorithm::HS512,
+                key: EncodingKey::from_secret(&secret.to_be_bytes()),
+            },
+            verification_keys: HashMap::new(),
+            legacy_key: Some(DecodingKey::from_secret(&secret.to_be_bytes())),
+        }
+    }
+
+    /// Creates the keys signing tokens with the PEM encoded private key with id `key_id` and verifying
+    /// tokens with the PEM encoded public keys by their id, which must include `key_id`. The
+    /// algorithm of each key is determined by its type, RS256 for RSA and EdDSA for Ed25519 keys.
+    pub fn from_pem(
+        private_key: &[u8],
+        key_id: String,
+        public_keys: &[(String, Vec<u8>)],
+        legacy_secret: Option<u64>,
+    ) -> Result<Self, String> {
+        let mut verification_keys = HashMap::new();
+        for (kid, pem) in public_keys {
+            let public_key = parse_public_key(pem)
+                .map_err(|e| format!("invalid public key '{}': {}", kid, e))?;
+            verification_keys.insert(kid.clone(), verification_key(kid, public_key));
+        }
+
+        let algorithm = verification_keys
+            .get(&key_id)
+            .ok_or_else(|| format!("missing public key for JWT_KEY_ID '{}'", key_id))?
+            .algorithm;
+        let key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(private_key),
+            _ => EncodingKey::from_rsa_pem(private_key),
+        }
+        .map_err(|e| format!("invalid private key: {}", e))?;
+
+        let keys = Self {
+            signing_key: SigningKey {
+                kid: Some(key_id),
+                algorithm,
+                key,
+            },
+            verification_keys,
+            legacy_key: legacy_secret.map(|secret| DecodingKey::from_secret(&secret.to_be_bytes())),
+        };
+
+        // fail early if the private key does not belong to the public key of its id
+        let probe = keys
+            .encode(&ProbeClaims { exp: usize::MAX })
+            .map_err(|_| String::from("failed to sign with the private key"))?;
+        keys.decode::<ProbeClaims>(&probe).map_err(|_| {
+            String::from("the private key does not match the public key of JWT_KEY_ID")
+        })?;
+
+        Ok(keys)
+    }
+
+    /// Signs the claims with the signing key.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        let mut header = Header::new(self.signing_key.algorithm);
+        header.kid = self.signing_key.kid.clone();
+
+        encode(&header, claims, &self.signing_key.key).map_err(|_| Error::JwtCreationError)
+    }
+
+    /// Verifies the token with the public key identified by its `kid`, or the legacy secret if it
+    /// has none, and returns its claims if the signature is valid and the token has not expired.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, Error> {
+        let header = decode_header(token).map_err(|_| Error::InvalidJwtError)?;
+        let (key, algorithm) = match header.kid {
+            Some(ref kid) => {
+                let verification_key = self
+                    .verification_keys
+                    .get(kid)
+                    .ok_or(Error::InvalidJwtError)?;
+                (&verification_key.key, verification_key.algorithm)
+            }
+            None => (
+                self.legacy_key.as_ref().ok_or(Error::InvalidJwtError)?,
+                Algorithm::HS512,
+            ),
+        };
+
+        decode::<T>(token, key, &Validation::new(algorithm))
+            .map(|token_data| token_data.claims)
+            .map_err(|_| Error::InvalidJwtError)
+    }
+
+    /// Returns the public keys as JSON Web Key Set, ordered by their id.
+    pub fn jwks(&self) -> JwkSet {
+        let mut keys = self
+            .verification_keys
+            .values()
+            .map(|verification_key| verification_key.jwk.clone())
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+
+        JwkSet { keys }
+    }
+}
+
+/// Claims of the token signed when loading the keys to check that the private key matches.
+#[derive(Deserialize, Serialize)]
+struct ProbeClaims {
+    exp: usize,
+}
+
+/// Handler for the /.well-known/jwks.json endpoint returning the JSON Web Key Set of all public keys
+/// accepted to verify tokens.
+pub async fn jwks_handler() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&JWT_KEYS.jwks()))
+}
+
+/// Reads all `{kid}.pem` files in the directory, returning the contents with the file stem as id.
+fn read_public_keys(dir: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let mut public_keys = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "pem")
+        {
+            if let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) {
+                public_keys.push((kid.to_string(), fs::read(&path)?));
+            }
+        }
+    }
+
+    Ok(public_keys)
+}
+
+fn verification_key(kid: &str, public_key: PublicKey) -> VerificationKey {
+    let (algorithm, key, parameters) = match public_key {
+        PublicKey::Rsa { modulus, exponent } => (
+            Algorithm::RS256,
+            DecodingKey::from_rsa_raw_components(&modulus, &exponent),
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(&modulus),
+                e: URL_SAFE_NO_PAD.encode(&exponent),
+            }),
+        ),
+        PublicKey::Ed25519(x) => (
+            Algorithm::EdDSA,
+            DecodingKey::from_ed_der(&x),
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&x),
+            }),
+        ),
+    };
+
+    VerificationKey {
+        algorithm,
+        key,
+        jwk: Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(algorithm),
+                key_id: Some(kid.to_string()),
+                ..CommonParameters::default()
+            },
+            algorithm: parameters,
+        },
+    }
+}
+
+/// Parses a PEM encoded RSA public key, either as SubjectPublicKeyInfo (`PUBLIC KEY`) or PKCS#1
+/// (`RSA PUBLIC KEY`), or Ed25519 public key.
+fn parse_public_key(pem: &[u8]) -> Result<PublicKey, String> {
+    let pem = pem::parse(pem).map_err(|e| e.to_string())?;
+    let blocks = simple_asn1::from_der(&pem.contents).map_err(|e| e.to_string())?;
+
+    match pem.tag.as_str() {
+        "RSA PUBLIC KEY" => parse_rsa_public_key(&blocks),
+        "PUBLIC KEY" => {
+            let (algorithm, key) = match blocks.as_slice() {
+                [ASN1Block::Sequence(_, info)] => match info.as_slice() {
+                    [ASN1Block::Sequence(_, algorithm), ASN1Block::BitString(_, _, key)] => {
+                        match algorithm.first() {
+                            Some(ASN1Block::ObjectIdentifier(_, algorithm)) => (algorithm, key),
+                            _ => return Err(String::from("missing key algorithm")),
+                        }
+                    }
+                    _ => return Err(String::from("invalid SubjectPublicKeyInfo")),
+                },
+                _ => return Err(String::from("invalid SubjectPublicKeyInfo")),
+            };
+
+            if *algorithm == oid!(1, 2, 840, 113_549, 1, 1, 1) {
+                let blocks = simple_asn1::from_der(key).map_err(|e| e.to_string())?;
+                parse_rsa_public_key(&blocks)
+            } else if *algorithm == oid!(1, 3, 101, 112) {
+                Ok(PublicKey::Ed25519(key.clone()))
+            } else {
+                Err(String::from("only RSA and Ed25519 keys are supported"))
+            }
+        }
+        tag => Err(format!("expected a public key but found '{}'", tag)),
+    }
+}
+
+fn parse_rsa_public_key(blocks: &[ASN1Block]) -> Result<PublicKey, String> {
+    match blocks {
+        [ASN1Block::Sequence(_, components)] => match components.as_slice() {
+            [ASN1Block::Integer(_, modulus), ASN1Block::Integer(_, exponent)] => {
+                Ok(PublicKey::Rsa {
+                    modulus: modulus.to_bytes_be().1,
+                    exponent: exponent.to_bytes_be().1,
+                })
+            }
+            _ => Err(String::from("invalid RSA public key")),
+        },
+        _ => Err(String::from("invalid RSA public key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_PRIVATE_KEY: &[u8] = include_bytes!("resources/jwt_test_keys/rsa-2026.key");
+    const RSA_PUBLIC_KEY: &[u8] = include_bytes!("resources/jwt_test_keys/rsa-2026.pem");
+    const ED_PRIVATE_KEY: &[u8] = include_bytes!("resources/jwt_test_keys/ed-2026.key");
+    const ED_PUBLIC_KEY: &[u8] = include_bytes!("resources/jwt_test_keys/ed-2026.pem");
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Claims {
+        exp: usize,
+        sub: String,
+    }
+
+    fn public_keys() -> Vec<(String, Vec<u8>)> {
+        vec![
+            (String::from("rsa-2026"), RSA_PUBLIC_KEY.to_vec()),
+            (String::from("ed-2026"), ED_PUBLIC_KEY.to_vec()),
+        ]
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            exp: 4_102_444_800,
+            sub: String::from("robinfriedli"),
+        }
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let rsa_keys = JwtKeys::from_pem(
+            RSA_PRIVATE_KEY,
+            String::from("rsa-2026"),
+            &public_keys(),
+            Some(42),
+        )
+        .unwrap();
+        let ed_keys = JwtKeys::from_pem(
+            ED_PRIVATE_KEY,
+            String::from("ed-2026"),
+            &public_keys(),
+            None,
+        )
+        .unwrap();
+        let legacy_keys = JwtKeys::from_secret(42);
+
+        let rsa_token = rsa_keys.encode(&claims()).unwrap();
+        let ed_token = ed_keys.encode(&claims()).unwrap();
+        let legacy_token = legacy_keys.encode(&claims()).unwrap();
+        assert_eq!(
+            decode_header(&rsa_token).unwrap().kid.as_deref(),
+            Some("rsa-2026")
+        );
+        assert_eq!(decode_header(&ed_token).unwrap().alg, Algorithm::EdDSA);
+
+        for keys in &[&rsa_keys, &ed_keys] {
+            assert_eq!(keys.decode::<Claims>(&rsa_token).unwrap(), claims());
+            assert_eq!(keys.decode::<Claims>(&ed_token).unwrap(), claims());
+        }
+        assert_eq!(rsa_keys.decode::<Claims>(&legacy_token).unwrap(), claims());
+        assert!(ed_keys.decode::<Claims>(&legacy_token).is_err());
+        assert!(legacy_keys.decode::<Claims>(&rsa_token).is_err());
+
+        // a token with an unknown kid or tampered payload is rejected
+        let rsa_only_keys = JwtKeys::from_pem(
+            RSA_PRIVATE_KEY,
+            String::from("rsa-2026"),
+            &public_keys()[..1],
+            None,
+        )
+        .unwrap();
+        assert!(rsa_only_keys.decode::<Claims>(&ed_token).is_err());
+        let mut parts = rsa_token.split('.').collect::<Vec<_>>();
+        let forged_payload = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&Claims {
+                exp: usize::MAX,
+                sub: String::from("admin"),
+            })
+            .unwrap(),
+        );
+        parts[1] = &forged_payload;
+        assert!(rsa_keys.decode::<Claims>(&parts.join(".")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        // the private key must match the public key of its id
+        assert!(JwtKeys::from_pem(
+            RSA_PRIVATE_KEY,
+            String::from("ed-2026"),
+            &public_keys(),
+            None
+        )
+        .is_err());
+        assert!(JwtKeys::from_pem(
+            ED_PRIVATE_KEY,
+            String::from("ed-2025"),
+            &public_keys(),
+            None
+        )
+        .is_err());
+        assert!(parse_public_key(RSA_PRIVATE_KEY).is_err());
+    }
+
+    #[test]
+    fn test_jwks() {
+        let keys = JwtKeys::from_pem(
+            ED_PRIVATE_KEY,
+            String::from("ed-2026"),
+            &public_keys(),
+            None,
+        )
+        .unwrap();
+        let jwks = serde_json::to_value(keys.jwks()).unwrap();
+
+        assert_eq!(jwks["keys"][0]["kid"], "ed-2026");
+        assert_eq!(jwks["keys"][0]["kty"], "OKP");
+        assert_eq!(jwks["keys"][0]["crv"], "Ed25519");
+        assert_eq!(jwks["keys"][0]["alg"], "EdDSA");
+        assert_eq!(jwks["keys"][0]["use"], "sig");
+        assert_eq!(jwks["keys"][1]["kid"], "rsa-2026");
+        assert_eq!(jwks["keys"][1]["kty"], "RSA");
+        assert_eq!(jwks["keys"][1]["alg"], "RS256");
+        assert_eq!(jwks["keys"][1]["e"], "AQAB");
+
+        // the published keys can be used to verify tokens
+        let token = keys.encode(&claims()).unwrap();
+        let jwk_set: JwkSet = serde_json::from_value(jwks).unwrap();
+        let jwk = jwk_set.find("ed-2026").unwrap();
+        let decoded = decode::<Claims>(
+            &token,
+            &DecodingKey::from_jwk(jwk).unwrap(),
+            &Validation::new(Algorithm::EdDSA),
+        )
+        .unwrap();
+        assert_eq!(decoded.claims, claims());
+    }
+}
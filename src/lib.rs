@@ -0,0 +1,107 @@
+//! Generates swiss QR-bill payment slips as defined by the
+//! [six specification](https://www.paymentstandards.ch/dam/downloads/ig-qr-bill-de.pdf).
+//!
+//! Validating [`QrData`](templating::QrData), building the qr code payload and rendering the qr
+//! code svg and the slip HTML only depend on the core of this crate, all other parts are gated
+//! behind cargo features:
+//!
+//! * `pdf`: converts the slip HTML to PDF files using wkhtmltopdf, see [`pdf`]
+//! * `png`: renders qr codes as png images
+//! * `python`: qr generation using the python script or plugins and the python bindings
+//! * `extension-module`: builds the python bindings as extension module
+//! * `xlsx`: reading slips from Excel workbooks, see [`input::read_sheet`]
+//! * `server`: the warp web service including authentication, requires `pdf`, `python` and `xlsx`
+//! * `cli`: the `qr_slip_cli` binary generating slips from files, requires `pdf` and `png`
+//!
+//! All features but `extension-module` are enabled by default.
+
+#[cfg(feature = "server")]
+#[macro_use]
+extern crate diesel;
+
+#[cfg(feature = "python")]
+use std::str::FromStr;
+
+#[cfg(feature = "server")]
+use diesel::{
+    pg::PgConnection,
+    r2d2::{self, ConnectionManager, Pool, PooledConnection},
+};
+#[cfg(any(feature = "server", feature = "python"))]
+use lazy_static::lazy_static;
+
+#[cfg(feature = "server")]
+use error::Error;
+
+pub mod archive;
+#[cfg(feature = "server")]
+pub mod auth;
+pub mod cache;
+pub mod error;
+pub mod i18n;
+pub mod input;
+#[cfg(feature = "server")]
+pub mod jwt;
+#[cfg(feature = "server")]
+pub mod model;
+#[cfg(feature = "server")]
+pub mod notifier;
+#[cfg(feature = "python")]
+pub mod parity;
+#[cfg(feature = "server")]
+pub mod password;
+pub mod payment;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+pub mod plugin;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "server")]
+pub mod schema;
+pub mod templating;
+#[cfg(feature = "server")]
+pub mod throttle;
+#[cfg(feature = "server")]
+pub mod totp;
+#[cfg(feature = "server")]
+pub mod upload;
+pub mod validation;
+
+#[cfg(feature = "server")]
+pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+#[cfg(feature = "server")]
+lazy_static! {
+    pub static ref CONNECTION_POOL: Pool<ConnectionManager<PgConnection>> = {
+        let database_url = std::env::var("DATABASE_URL")
+            .expect("Missing environment variable DATABASE_URL must be set to connect to postgres");
+        let database_connection_manager =
+            r2d2::ConnectionManager::<PgConnection>::new(database_url);
+        r2d2::Builder::new()
+            .min_idle(Some(10))
+            .max_size(50)
+            .build(database_connection_manager)
+            .expect("Failed to initialise connection pool")
+    };
+}
+
+#[cfg(feature = "python")]
+lazy_static! {
+    pub static ref USE_PY_QR_GENERATOR: bool = {
+        std::env::var("USE_PY_QR_GENERATOR").map_or(false, |val| {
+            bool::from_str(&val).expect("USE_PY_QR_GENERATOR is not a valid bool value")
+        })
+    };
+}
+
+#[cfg(feature = "python")]
+pub const QR_GENERATOR_MODULE: &str = "qr_generator";
+#[cfg(feature = "python")]
+pub const QR_GENERATOR_SCRIPT: &str = std::include_str!("resources/py/qr_generator.py");
+
+#[cfg(feature = "server")]
+pub fn acquire_db_connection() -> Result<DbConnection, warp::Rejection> {
+    CONNECTION_POOL
+        .get()
+        .map_err(|_| warp::reject::custom(Error::DatabaseConnectionError))
+}
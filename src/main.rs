@@ -1,56 +1,18 @@
-#[macro_use]
-extern crate diesel;
 #[cfg(feature = "auto_migration")]
 #[macro_use]
 extern crate diesel_migrations;
 
-use std::str::FromStr;
-
-use diesel::{
-    pg::PgConnection,
-    r2d2::{self, ConnectionManager, Pool, PooledConnection},
-};
 use dotenv::dotenv;
-use lazy_static::lazy_static;
 use pyo3::prelude::*;
-use warp::{http::header, Filter};
-
-use error::Error;
-
-pub mod auth;
-pub mod error;
-pub mod model;
-pub mod schema;
-pub mod templating;
-
-pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
-
-lazy_static! {
-    pub static ref CONNECTION_POOL: Pool<ConnectionManager<PgConnection>> = {
-        let database_url = std::env::var("DATABASE_URL")
-            .expect("Missing environment variable DATABASE_URL must be set to connect to postgres");
-        let database_connection_manager =
-            r2d2::ConnectionManager::<PgConnection>::new(database_url);
-        r2d2::Builder::new()
-            .min_idle(Some(10))
-            .max_size(50)
-            .build(database_connection_manager)
-            .expect("Failed to initialise connection pool")
-    };
-    pub static ref JWT_SECRET: u64 = {
-        let secret_str = std::env::var("JWT_SECRET")
-            .expect("Missing environment variable JWT_SECRET must be set to generate JWT tokens.");
-        u64::from_str(&secret_str).expect("JWT_SECRET var is not a valid u64 value")
-    };
-    pub static ref USE_PY_QR_GENERATOR: bool = {
-        std::env::var("USE_PY_QR_GENERATOR").map_or(false, |val| {
-            bool::from_str(&val).expect("USE_PY_QR_GENERATOR is not a valid bool value")
-        })
-    };
-}
+use warp::Filter;
 
-pub const QR_GENERATOR_MODULE: &str = "qr_generator";
-const QR_GENERATOR_SCRIPT: &str = std::include_str!("resources/py/qr_generator.py");
+#[cfg(feature = "auto_migration")]
+use qr_slip::acquire_db_connection;
+use qr_slip::{
+    auth, cache, error, i18n, jwt, notifier, parity, password, pdf, plugin, templating, throttle,
+    totp, upload, validation, CONNECTION_POOL, QR_GENERATOR_MODULE, QR_GENERATOR_SCRIPT,
+    USE_PY_QR_GENERATOR,
+};
 
 #[cfg(feature = "auto_migration")]
 diesel_migrations::embed_migrations!();
@@ -63,10 +25,23 @@ fn main() {
 
     // initialise certain lazy statics on startup
     lazy_static::initialize(&CONNECTION_POOL);
-    lazy_static::initialize(&JWT_SECRET);
+    lazy_static::initialize(&jwt::JWT_KEYS);
+    lazy_static::initialize(&auth::REFRESH_SESSION_LIFETIME_HOURS);
+    lazy_static::initialize(&auth::REFRESH_TOKEN_PURGE_INTERVAL_SECS);
+    lazy_static::initialize(&auth::TRUST_X_REAL_IP);
+    lazy_static::initialize(&auth::DUMMY_PASSWORD_HASH);
+    lazy_static::initialize(&throttle::LOGIN_THROTTLE_CONFIG);
+    lazy_static::initialize(&totp::TOTP_ISSUER);
+    lazy_static::initialize(&notifier::NOTIFIER);
+    lazy_static::initialize(&notifier::PASSWORD_RESET_TOKEN_EXPIRY_MINUTES);
+    lazy_static::initialize(&password::PASSWORD_POLICY);
     lazy_static::initialize(&USE_PY_QR_GENERATOR);
+    lazy_static::initialize(&plugin::QR_GENERATOR_PLUGIN_DIR);
     lazy_static::initialize(&templating::QR_SLIP_TEMPLATES);
-    lazy_static::initialize(&templating::PDF_APPLICATION_WORKER_MANAGER);
+    lazy_static::initialize(&templating::TEMPLATE_VERSION);
+    lazy_static::initialize(&cache::CONTENT_CACHE);
+    lazy_static::initialize(&pdf::PDF_QUEUE_CAPACITY);
+    lazy_static::initialize(&pdf::PDF_APPLICATION_WORKER_MANAGER);
 
     if *USE_PY_QR_GENERATOR {
         // compile qr generator module
@@ -97,16 +72,84 @@ fn main() {
 /// Start a tokio runtime that runs a warp server.
 #[tokio::main]
 async fn setup_tokio_runtime() {
+    tokio::spawn(auth::run_refresh_token_purge());
+
     let login_route = warp::path("login")
         .and(warp::post())
         .and(warp::body::json())
+        .and(auth::with_client_info())
         .and_then(auth::login_handler);
 
+    let login_totp_route = warp::path("login-totp")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(auth::with_client_info())
+        .and_then(auth::login_totp_handler);
+
     let refresh_login_router = warp::path("refresh-login")
         .and(warp::post())
         .and(warp::cookie("refresh_token"))
+        .and(auth::with_client_info())
         .and_then(auth::refresh_login_handler);
 
+    let logout_route = warp::path("logout")
+        .and(warp::post())
+        .and(warp::cookie::optional("refresh_token"))
+        .and_then(auth::logout_handler);
+
+    let logout_all_route = warp::path("logout-all")
+        .and(warp::post())
+        .and(auth::with_principal())
+        .and_then(auth::logout_all_handler);
+
+    let change_password_route = warp::path("change-password")
+        .and(warp::post())
+        .and(auth::with_principal())
+        .and(warp::body::json())
+        .and(auth::with_client_info())
+        .and_then(auth::change_password_handler);
+
+    let enroll_totp_route = warp::path("enroll-totp")
+        .and(warp::post())
+        .and(auth::with_principal())
+        .and_then(totp::enroll_totp_handler);
+
+    let confirm_totp_route = warp::path("confirm-totp")
+        .and(warp::post())
+        .and(auth::with_principal())
+        .and(warp::body::json())
+        .and_then(totp::confirm_totp_handler);
+
+    let disable_totp_route = warp::path("disable-totp")
+        .and(warp::post())
+        .and(auth::with_principal())
+        .and(warp::body::json())
+        .and(auth::with_client_info())
+        .and_then(totp::disable_totp_handler);
+
+    let request_password_reset_route = warp::path("request-password-reset")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(auth::with_client_info())
+        .and_then(auth::request_password_reset_handler);
+
+    let reset_password_route = warp::path("reset-password")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(auth::reset_password_handler);
+
+    let sessions_route = warp::path("sessions")
+        .and(warp::get())
+        .and(auth::with_principal())
+        .and(warp::cookie::optional("refresh_token"))
+        .and_then(auth::get_sessions_handler);
+
+    let revoke_session_route = warp::path("revoke-session")
+        .and(warp::delete())
+        .and(auth::with_principal())
+        .and(warp::path::param())
+        .and_then(auth::revoke_session_handler);
+
     let register_route = warp::path("register")
         .and(warp::post())
         .and(warp::body::json())
@@ -131,9 +174,52 @@ async fn setup_tokio_runtime() {
 
     let generate_qr_slip_route = warp::path("generate-slip")
         .and(warp::post())
+        .and(warp::query())
+        .and(auth::with_principal_optional())
+        .and(i18n::with_language())
         .and(warp::body::json())
-        .and_then(templating::generate_slip_handler)
-        .map(|reply| warp::reply::with_header(reply, header::CONTENT_TYPE, "application/pdf"));
+        .and_then(templating::generate_slip_handler);
+
+    let upload_slips_route = warp::path("upload-slips")
+        .and(warp::post())
+        .and(warp::query())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(auth::with_principal_optional())
+        .and(i18n::with_language())
+        .and(warp::body::content_length_limit(upload::UPLOAD_SIZE_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(upload::upload_slips_handler);
+
+    let validate_slips_route = warp::path("validate-slips")
+        .and(warp::post())
+        .and(warp::query())
+        .and(auth::with_principal_optional())
+        .and(i18n::with_language())
+        .and(warp::body::json())
+        .and_then(validation::validate_slips_handler);
+
+    let pdf_worker_metrics_route = warp::path("pdf-worker-metrics")
+        .and(warp::get())
+        .and(auth::with_admin())
+        .and_then(pdf::pdf_worker_metrics_handler);
+
+    let jwks_route = warp::path(".well-known")
+        .and(warp::path("jwks.json"))
+        .and(warp::get())
+        .and_then(jwt::jwks_handler);
+
+    let login_throttles_route = warp::path("admin")
+        .and(warp::path("login-throttles"))
+        .and(warp::get())
+        .and(auth::with_admin())
+        .and_then(throttle::login_throttles_handler);
+
+    let generator_parity_route = warp::path("admin")
+        .and(warp::path("generator-parity"))
+        .and(warp::post())
+        .and(auth::with_admin())
+        .and(warp::body::json())
+        .and_then(parity::generator_parity_handler);
 
     #[cfg(debug_assertions)]
     let dbg_qr_pdf_route = warp::path("dbg-qr-pdf")
@@ -154,12 +240,29 @@ async fn setup_tokio_runtime() {
         .and_then(templating::dbg_qr_svg_handler);
 
     let routes = login_route
+        .or(login_totp_route)
         .or(refresh_login_router)
+        .or(logout_route)
+        .or(logout_all_route)
+        .or(change_password_route)
+        .or(enroll_totp_route)
+        .or(confirm_totp_route)
+        .or(disable_totp_route)
+        .or(request_password_reset_route)
+        .or(reset_password_route)
+        .or(sessions_route)
+        .or(revoke_session_route)
         .or(register_route)
         .or(create_user_route)
         .or(get_users_route)
         .or(delete_users_route)
-        .or(generate_qr_slip_route);
+        .or(generate_qr_slip_route)
+        .or(upload_slips_route)
+        .or(validate_slips_route)
+        .or(pdf_worker_metrics_route)
+        .or(jwks_route)
+        .or(generator_parity_route)
+        .or(login_throttles_route);
 
     #[cfg(debug_assertions)]
     let all_routes = routes
@@ -170,18 +273,10 @@ async fn setup_tokio_runtime() {
     #[cfg(not(debug_assertions))]
     let all_routes = routes;
 
-    let filter = all_routes
-        .recover(error::handle_rejection)
-        .with(warp::log("qr_slip::api"));
+    let filter = error::recover_localized(all_routes).with(warp::log("qr_slip::api"));
     warp::serve(filter).run(([0, 0, 0, 0], 8000)).await;
 }
 
-pub fn acquire_db_connection() -> Result<DbConnection, warp::Rejection> {
-    CONNECTION_POOL
-        .get()
-        .map_err(|_| warp::reject::custom(Error::DatabaseConnectionError))
-}
-
 fn setup_logger() {
     // create logs dir as fern does not appear to handle that itself
     if !std::path::Path::new("logs/").exists() {
@@ -3,7 +3,10 @@ use diesel::{Associations, Identifiable, Insertable, Queryable};
 use serde::Serialize;
 use uuid::Uuid;
 
-use crate::schema::{principal, qr_user, refresh_token};
+use crate::schema::{
+    login_throttle, password_reset_token, principal, qr_user, refresh_token, totp_challenge,
+    totp_recovery_code,
+};
 
 #[derive(Associations, Identifiable, Queryable, Serialize)]
 #[belongs_to(Principal, foreign_key = "fk_principal")]
@@ -40,6 +43,18 @@ pub struct Principal {
     pub pk: i32,
     pub user_name: String,
     pub password: String,
+    pub is_admin: bool,
+    pub qr_generator: Option<String>,
+    /// The address password reset tokens are sent to, resetting the password is not possible if
+    /// not set.
+    pub email: Option<String>,
+    /// The secret of the TOTP second factor, set when enrolling even before it is enabled.
+    pub totp_secret: Option<Vec<u8>>,
+    /// Whether logging in requires a TOTP code, set once the enrolment has been confirmed.
+    pub totp_enabled: bool,
+    /// The time step of the last accepted TOTP code, codes of this or an earlier step are rejected
+    /// so that each code can only be used once.
+    pub totp_last_used_step: Option<i64>,
 }
 
 #[derive(Insertable)]
@@ -47,8 +62,11 @@ pub struct Principal {
 pub struct NewPrincipal {
     pub user_name: String,
     pub password: String,
+    pub email: Option<String>,
 }
 
+/// A refresh token, which is replaced by a new token of the same family, i.e. login session, each
+/// time it is used.
 #[derive(Associations, Identifiable, Queryable)]
 #[belongs_to(Principal, foreign_key = "fk_principal")]
 #[table_name = "refresh_token"]
@@ -56,9 +74,25 @@ pub struct NewPrincipal {
 pub struct RefreshToken {
     pub pk: i32,
     pub uuid: Uuid,
+    /// The sliding expiry of the token, never after the session_expiry.
     pub expiry: DateTime<Utc>,
     pub invalidated: bool,
     pub fk_principal: i32,
+    /// Shared by all tokens created by rotating the token issued at login.
+    pub family: Uuid,
+    /// The absolute expiry of the login session, after which no token of the family can be used.
+    pub session_expiry: DateTime<Utc>,
+    /// Set when the token has been used and replaced by a new token, using it again means that it
+    /// has been stolen.
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// The time of the login that started the session, shared by all tokens of the family.
+    pub created_at: DateTime<Utc>,
+    /// The time the token was issued, i.e. the last time the session was used to log in or refresh.
+    pub last_used_at: DateTime<Utc>,
+    /// The User-Agent header of the request that issued the token.
+    pub user_agent: Option<String>,
+    /// The IP address of the client that issued the token.
+    pub ip_address: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -68,4 +102,100 @@ pub struct NewRefreshToken {
     pub expiry: DateTime<Utc>,
     pub invalidated: bool,
     pub fk_principal: i32,
+    pub family: Uuid,
+    pub session_expiry: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// A single-use token that allows setting a new password without knowing the current password,
+/// only the SHA-256 hash of the token sent to the principal is stored.
+#[derive(Associations, Identifiable, Queryable)]
+#[belongs_to(Principal, foreign_key = "fk_principal")]
+#[table_name = "password_reset_token"]
+#[primary_key(pk)]
+pub struct PasswordResetToken {
+    pub pk: i32,
+    pub token_hash: String,
+    pub expiry: DateTime<Utc>,
+    /// Set when the token has been used to reset the password.
+    pub used_at: Option<DateTime<Utc>>,
+    pub fk_principal: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "password_reset_token"]
+pub struct NewPasswordResetToken {
+    pub token_hash: String,
+    pub expiry: DateTime<Utc>,
+    pub fk_principal: i32,
+}
+
+/// The failed login attempts for a client IP address or user name, see [`crate::throttle`].
+#[derive(Identifiable, Queryable)]
+#[table_name = "login_throttle"]
+#[primary_key(pk)]
+pub struct LoginThrottle {
+    pub pk: i32,
+    /// Either `ip` or `user_name`.
+    pub scope: String,
+    /// The IP address or user name.
+    pub key: String,
+    /// The number of consecutive failed attempts, reset after a successful login for user names.
+    pub failed_attempts: i32,
+    pub last_failure: DateTime<Utc>,
+    /// Login attempts are rejected without checking the password until this time.
+    pub blocked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "login_throttle"]
+pub struct NewLoginThrottle<'a> {
+    pub scope: &'a str,
+    pub key: &'a str,
+    pub failed_attempts: i32,
+    pub last_failure: DateTime<Utc>,
+}
+
+/// One-time code that can be used instead of a TOTP code, e.g. when the authenticator is lost.
+#[derive(Identifiable, Queryable)]
+#[table_name = "totp_recovery_code"]
+#[primary_key(pk)]
+pub struct TotpRecoveryCode {
+    pub pk: i32,
+    pub code_hash: String,
+    /// Set when the code has been used to log in.
+    pub used_at: Option<DateTime<Utc>>,
+    pub fk_principal: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "totp_recovery_code"]
+pub struct NewTotpRecoveryCode {
+    pub code_hash: String,
+    pub fk_principal: i32,
+}
+
+/// Issued by /login to principals with TOTP enabled after checking the password, the login is
+/// completed by providing the token with a TOTP or recovery code to /login-totp.
+#[derive(Identifiable, Queryable)]
+#[table_name = "totp_challenge"]
+#[primary_key(pk)]
+pub struct TotpChallenge {
+    pub pk: i32,
+    pub token_hash: String,
+    pub expiry: DateTime<Utc>,
+    /// The number of invalid codes provided for this challenge.
+    pub failed_attempts: i32,
+    pub fk_principal: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "totp_challenge"]
+pub struct NewTotpChallenge {
+    pub token_hash: String,
+    pub expiry: DateTime<Utc>,
+    pub fk_principal: i32,
 }
@@ -0,0 +1,188 @@
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use lettre::{
+    smtp::authentication::Credentials, ClientSecurity, ClientTlsParameters, SmtpClient, Transport,
+};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+
+use crate::error::Error;
+
+/// Port on which SMTP servers expect TLS from the start of the connection instead of STARTTLS.
+const SUBMISSIONS_PORT: u16 = 465;
+
+lazy_static! {
+    /// The notifier used to deliver messages to principals, selected by the NOTIFIER environment
+    /// variable, either `log` (default) or `smtp`.
+    pub static ref NOTIFIER: Box<dyn Notifier> = {
+        match std::env::var("NOTIFIER").as_deref() {
+            Ok("smtp") => Box::new(SmtpNotifier::from_env()),
+            Ok("log") | Err(_) => Box::new(LogNotifier),
+            Ok(other) => panic!("NOTIFIER '{}' is not one of 'log' or 'smtp'", other),
+        }
+    };
+    /// URL of the client page used to reset the password, `{token}` is replaced by the reset token.
+    pub static ref PASSWORD_RESET_URL: Option<String> = std::env::var("PASSWORD_RESET_URL").ok();
+    /// Time after which password reset tokens expire, defaults to one hour.
+    pub static ref PASSWORD_RESET_TOKEN_EXPIRY_MINUTES: i64 = {
+        std::env::var("PASSWORD_RESET_TOKEN_EXPIRY_MINUTES").map_or(60, |val| {
+            let minutes = val
+                .parse::<i64>()
+                .expect("PASSWORD_RESET_TOKEN_EXPIRY_MINUTES is not a valid i64");
+            assert!(
+                minutes > 0,
+                "PASSWORD_RESET_TOKEN_EXPIRY_MINUTES must be positive"
+            );
+            minutes
+        })
+    };
+}
+
+/// A message sent to a principal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Notification {
+    /// Creates the notification delivering a password reset token. The token is inserted into the
+    /// PASSWORD_RESET_URL if configured so that the principal only has to follow a link.
+    pub fn password_reset(recipient: String, user_name: &str, token: &str) -> Self {
+        let instructions = match *PASSWORD_RESET_URL {
+            Some(ref url) => format!(
+                "open the following link to choose a new password:\n\n{}",
+                url.replace("{token}", token)
+            ),
+            None => format!(
+                "use the following token to choose a new password:\n\n{}",
+                token
+            ),
+        };
+
+        Self {
+            recipient,
+            subject: String::from("Reset your password"),
+            body: format!(
+                "A password reset has been requested for the user '{}', {}\n\n\
+                 The token expires in {} minutes and can only be used once. If you did not request a \
+                 password reset, you can ignore this message.",
+                user_name, instructions, *PASSWORD_RESET_TOKEN_EXPIRY_MINUTES
+            ),
+        }
+    }
+}
+
+/// Delivers [`Notification`]s to their recipient. Sending is blocking, so notifiers must be called
+/// outside of the async executor.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+/// Notifier that only logs notifications, meant for development as it writes tokens to the log.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        log::info!(
+            target: "qr_slip::notifier",
+            "Notification to {}: {}\n{}",
+            notification.recipient,
+            notification.subject,
+            notification.body
+        );
+        Ok(())
+    }
+}
+
+/// Notifier sending notifications as plain text emails through an SMTP server.
+pub struct SmtpNotifier {
+    client: SmtpClient,
+    from: String,
+}
+
+impl SmtpNotifier {
+    /// Creates the notifier for the server configured by the SMTP_HOST, SMTP_PORT (defaults to 25),
+    /// SMTP_TLS (defaults to true), SMTP_USERNAME, SMTP_PASSWORD and SMTP_FROM environment variables.
+    ///
+    /// When SMTP_TLS is enabled, TLS is used from the start of the connection on port 465 or else
+    /// required to be started using STARTTLS. Disabling TLS is only meant for local mail catchers.
+    pub fn from_env() -> Self {
+        let host = std::env::var("SMTP_HOST")
+            .expect("Missing environment variable SMTP_HOST must be set when NOTIFIER is 'smtp'");
+        let port = std::env::var("SMTP_PORT").map_or(25, |val| {
+            val.parse::<u16>()
+                .expect("SMTP_PORT is not a valid u16 value")
+        });
+        let tls = std::env::var("SMTP_TLS").map_or(true, |val| {
+            bool::from_str(&val).expect("SMTP_TLS is not a valid bool value")
+        });
+        let from = std::env::var("SMTP_FROM")
+            .expect("Missing environment variable SMTP_FROM must be set when NOTIFIER is 'smtp'");
+
+        let security = if tls {
+            let connector = TlsConnector::new().expect("Failed to create TLS connector");
+            let tls_parameters = ClientTlsParameters::new(host.clone(), connector);
+            if port == SUBMISSIONS_PORT {
+                ClientSecurity::Wrapper(tls_parameters)
+            } else {
+                ClientSecurity::Required(tls_parameters)
+            }
+        } else {
+            ClientSecurity::None
+        };
+
+        let mut client = SmtpClient::new((host.as_str(), port), security)
+            .unwrap_or_else(|e| panic!("Failed to resolve SMTP_HOST '{}': {}", host, e));
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("SMTP_USERNAME"),
+            std::env::var("SMTP_PASSWORD"),
+        ) {
+            client = client.credentials(Credentials::new(username, password));
+        }
+
+        Self { client, from }
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let email = EmailBuilder::new()
+            .to(notification.recipient.as_str())
+            .from(self.from.as_str())
+            .subject(notification.subject.as_str())
+            .text(notification.body.as_str())
+            .build()
+            .map_err(|e| Error::NotificationError(e.to_string()))?;
+
+        self.client
+            .clone()
+            .transport()
+            .send(email.into())
+            .map(|_| ())
+            .map_err(|e| Error::NotificationError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_reset_notification() {
+        let notification = Notification::password_reset(
+            String::from("robin@example.com"),
+            "robinfriedli",
+            "8f14e45f-ceea-467f-a0e6-c1b2f3d4e5a6",
+        );
+
+        assert_eq!(notification.recipient, "robin@example.com");
+        assert!(notification.body.contains("'robinfriedli'"));
+        assert!(notification
+            .body
+            .contains("8f14e45f-ceea-467f-a0e6-c1b2f3d4e5a6"));
+        assert!(notification.body.contains("expires in 60 minutes"));
+    }
+}
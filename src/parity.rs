@@ -0,0 +1,523 @@
+use qrcode::{bits::Bits, canvas::is_functional, ec::construct_codewords, types::Mode};
+use qrcode::{EcLevel, Version};
+use serde::Serialize;
+#[cfg(feature = "server")]
+use warp::{Rejection, Reply};
+
+use crate::error::Error;
+#[cfg(feature = "server")]
+use crate::model::Principal;
+use crate::templating::{
+    build_python_qr_payload, build_qr_payload, render_native_qr_svg, render_python_qr_svg, QrData,
+};
+
+/// Corpus of [`QrData`] objects used by the parity check when no corpus is provided, covering all
+/// reference types, optional fields, special characters and maximum field lengths.
+const QR_DATA_CORPUS: &str = std::include_str!("resources/qr_data_corpus.json");
+
+/// Coordinates (x, y) of the format information modules next to the top left finder pattern, starting
+/// with the most significant bit.
+const FORMAT_INFO_COORDS: [(usize, usize); 15] = [
+    (0, 8),
+    (1, 8),
+    (2, 8),
+    (3, 8),
+    (4, 8),
+    (5, 8),
+    (7, 8),
+    (8, 8),
+    (8, 7),
+    (8, 5),
+    (8, 4),
+    (8, 3),
+    (8, 2),
+    (8, 1),
+    (8, 0),
+];
+
+const ALPHANUMERIC_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// Struct returned by the /admin/generator-parity endpoint.
+#[derive(Serialize)]
+pub struct ParityReport {
+    /// Whether both generators produced the expected payload for all [`QrData`] objects.
+    pub passed: bool,
+    /// The number of [`QrData`] objects that were checked.
+    pub checked: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+/// A single check that failed for the [`QrData`] object at the given index of the corpus.
+#[derive(Serialize)]
+pub struct Divergence {
+    pub index: usize,
+    /// The generator that diverged, either native or python.
+    pub generator: &'static str,
+    /// The check that failed, either payload, if the payload built by the python script differs, or
+    /// svg, if the payload decoded from the generated svg differs.
+    pub check: &'static str,
+    pub message: String,
+}
+
+/// Handler for the /admin/generator-parity endpoint which runs [`check_generator_parity`] for the
+/// provided [`QrData`] objects, or the built-in corpus if the provided sequence is empty.
+#[cfg(feature = "server")]
+pub async fn generator_parity_handler(
+    _admin: Principal,
+    mut qr_data_vec: Vec<QrData>,
+) -> Result<impl Reply, Rejection> {
+    if qr_data_vec.is_empty() {
+        qr_data_vec = load_corpus()?;
+    }
+
+    for qr_data in qr_data_vec.iter_mut() {
+        qr_data.verify()?;
+    }
+
+    let report = tokio::task::spawn_blocking(move || check_generator_parity(&qr_data_vec))
+        .await
+        .map_err(|e| Error::PythonError(e.to_string()))?;
+
+    Ok(warp::reply::json(&report))
+}
+
+/// Deserializes the built-in corpus of [`QrData`] objects.
+pub fn load_corpus() -> Result<Vec<QrData>, Error> {
+    serde_json::from_str(QR_DATA_CORPUS).map_err(|_| Error::SerialisationError)
+}
+
+/// Renders the given, verified, [`QrData`] objects using both the native generator and the python
+/// script, decodes the resulting svg graphics and compares the decoded data to the payload built by
+/// [`build_qr_payload`]. Additionally, compares the payload built by the python script directly, so
+/// that payload differences are reported even if the qrcode python package is not installed.
+pub fn check_generator_parity(qr_data_vec: &[QrData]) -> ParityReport {
+    let mut divergences = Vec::new();
+
+    for (index, qr_data) in qr_data_vec.iter().enumerate() {
+        let expected = build_qr_payload(qr_data);
+        let mut report = |generator, check, result: Result<String, String>| {
+            let message = match result {
+                Ok(ref actual) if actual == &expected => return,
+                Ok(actual) => describe_difference(&expected, &actual),
+                Err(e) => e,
+            };
+
+            divergences.push(Divergence {
+                index,
+                generator,
+                check,
+                message,
+            });
+        };
+
+        report(
+            "python",
+            "payload",
+            build_python_qr_payload(qr_data).map_err(|e| e.to_string()),
+        );
+        report(
+            "native",
+            "svg",
+            render_native_qr_svg(qr_data)
+                .map_err(|e| e.to_string())
+                .and_then(|svg| decode_qr_svg(&svg)),
+        );
+        report(
+            "python",
+            "svg",
+            render_python_qr_svg(qr_data)
+                .map_err(|e| e.to_string())
+                .and_then(|svg| decode_qr_svg(&svg)),
+        );
+    }
+
+    ParityReport {
+        passed: divergences.is_empty(),
+        checked: qr_data_vec.len(),
+        divergences,
+    }
+}
+
+/// Describes the first line that differs between the expected and actual payload.
+fn describe_difference(expected: &str, actual: &str) -> String {
+    let mut expected_lines = expected.split('\n');
+    let mut actual_lines = actual.split('\n');
+
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => continue,
+            (Some(e), Some(a)) => {
+                return format!("line {} differs, expected {:?} but found {:?}", line, e, a)
+            }
+            (Some(e), None) => return format!("line {} is missing, expected {:?}", line, e),
+            (None, Some(a)) => return format!("unexpected line {}: {:?}", line, a),
+            (None, None) => break,
+        }
+    }
+
+    String::from("payloads differ")
+}
+
+/// Decodes the data of a qr code svg graphic as created by the native generator or the python script,
+/// where one unit of the view box equals one module and the modules are drawn by one path consisting
+/// of rectangles of the form `M{x} {y}h{width}v1h-{width}z`.
+pub fn decode_qr_svg(svg: &str) -> Result<String, String> {
+    let view_box = attribute(svg, "viewBox").ok_or("svg does not have a viewBox")?;
+    let view_box = view_box
+        .split(' ')
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| format!("viewBox is invalid: {}", e))?;
+    if view_box.len() != 4 {
+        return Err(String::from("viewBox is invalid"));
+    }
+    // the view box starts at the negative size of the quiet zone
+    let width = (view_box[2] + 2.0 * view_box[0]).round() as usize;
+
+    let path = svg
+        .find("<path")
+        .map(|pos| &svg[pos..])
+        .ok_or("svg has no path")?;
+    let d = attribute(path, "d").ok_or("path has no d attribute")?;
+
+    let mut modules = vec![false; width * width];
+    for rect in d.split('M').skip(1) {
+        let parse_rect = || -> Option<(usize, usize, usize)> {
+            let (position, rest) = rect.split_once('h')?;
+            let (x, y) = position.split_once(' ')?;
+            let run = &rest[..rest.find('v')?];
+            Some((x.parse().ok()?, y.parse().ok()?, run.parse().ok()?))
+        };
+        let (x, y, run) =
+            parse_rect().ok_or_else(|| format!("unsupported path element {}", rect))?;
+
+        if y >= width || x + run > width {
+            return Err(format!("path element {} exceeds the qr code", rect));
+        }
+        for module in &mut modules[y * width + x..y * width + x + run] {
+            *module = true;
+        }
+    }
+
+    decode_qr_matrix(&modules, width)
+}
+
+fn attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!(" {}=\"", name);
+    let start = element.find(&pattern)? + pattern.len();
+    let end = start + element[start..].find('"')?;
+    Some(&element[start..end])
+}
+
+/// Decodes the data of a qr code given as a row major matrix of modules where true represents a dark
+/// module. Fails if the error correction codewords do not match the data, which means that the qr code
+/// is damaged.
+pub fn decode_qr_matrix(modules: &[bool], width: usize) -> Result<String, String> {
+    if width < 21 || width % 4 != 1 || modules.len() != width * width {
+        return Err(format!("invalid qr code width {}", width));
+    }
+    let version = Version::Normal(((width - 17) / 4) as i16);
+    let is_dark = |x: usize, y: usize| modules[y * width + x];
+
+    let (ec_level, mask) = read_format_info(&is_dark)?;
+
+    // read the codewords in the placement order, two columns at a time from right to left while
+    // alternating between upwards and downwards direction, skipping the vertical timing pattern
+    let mut bits = Vec::with_capacity(width * width);
+    let mut right_column = width - 1;
+    let mut upwards = true;
+    loop {
+        for i in 0..width {
+            let y = if upwards { width - 1 - i } else { i };
+            for x in [right_column, right_column - 1].iter().copied() {
+                if !is_function_module(version, width, x, y) {
+                    bits.push(is_dark(x, y) ^ is_masked(mask, x, y));
+                }
+            }
+        }
+
+        if right_column < 3 {
+            break;
+        }
+        right_column -= 2;
+        if right_column == 6 {
+            right_column = 5;
+        }
+        upwards = !upwards;
+    }
+    let codewords = bits
+        .chunks_exact(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, bit| acc << 1 | *bit as u8))
+        .collect::<Vec<u8>>();
+
+    let data = deinterleave_data(&codewords, version, ec_level)?;
+    read_segments(&data, version)
+}
+
+/// Reads the error correction level and mask pattern, accepting format information with up to three
+/// damaged modules.
+fn read_format_info(is_dark: &dyn Fn(usize, usize) -> bool) -> Result<(EcLevel, u8), String> {
+    let format_info = FORMAT_INFO_COORDS
+        .iter()
+        .fold(0u16, |acc, &(x, y)| acc << 1 | is_dark(x, y) as u16);
+
+    let (format, distance) = (0..32u16)
+        .map(|format| {
+            (
+                format,
+                (encode_format_info(format) ^ format_info).count_ones(),
+            )
+        })
+        .min_by_key(|(_, distance)| *distance)
+        .unwrap_or_default();
+    if distance > 3 {
+        return Err(String::from("format information is damaged"));
+    }
+
+    // the error correction level is encoded as L = 01, M = 00, Q = 11, H = 10
+    let ec_level = match (format >> 3) ^ 1 {
+        0 => EcLevel::L,
+        1 => EcLevel::M,
+        2 => EcLevel::Q,
+        _ => EcLevel::H,
+    };
+
+    Ok((ec_level, (format & 0b111) as u8))
+}
+
+/// Appends the BCH error correction bits to the 5 bit format and applies the format mask.
+fn encode_format_info(format: u16) -> u16 {
+    let mut remainder = format << 10;
+    for bit in (10..15).rev() {
+        if remainder & (1 << bit) != 0 {
+            remainder ^= 0b101_0011_0111 << (bit - 10);
+        }
+    }
+
+    (format << 10 | remainder) ^ 0b101_0100_0001_0010
+}
+
+fn is_function_module(version: Version, width: usize, x: usize, y: usize) -> bool {
+    // is_functional of the qrcode crate does not include the version information of version 7 and up
+    let is_version_info = matches!(version, Version::Normal(v) if v >= 7)
+        && ((x < 6 && y >= width - 11 && y < width - 8)
+            || (y < 6 && x >= width - 11 && x < width - 8));
+
+    is_version_info || is_functional(version, width as i16, x as i16, y as i16)
+}
+
+/// Returns whether the module at the given coordinates is inverted by the mask pattern, which is the
+/// case if the formula of the pattern evaluates to 0.
+fn is_masked(mask: u8, x: usize, y: usize) -> bool {
+    let value = match mask {
+        0b000 => (x + y) % 2,
+        0b001 => y % 2,
+        0b010 => x % 3,
+        0b011 => (x + y) % 3,
+        0b100 => (y / 2 + x / 3) % 2,
+        0b101 => (x * y) % 2 + (x * y) % 3,
+        0b110 => ((x * y) % 2 + (x * y) % 3) % 2,
+        _ => ((x + y) % 2 + (x * y) % 3) % 2,
+    };
+
+    value == 0
+}
+
+/// Restores the order of the interleaved data codewords and verifies them against the error
+/// correction codewords.
+fn deinterleave_data(
+    codewords: &[u8],
+    version: Version,
+    ec_level: EcLevel,
+) -> Result<Vec<u8>, String> {
+    let qr_err = |e: qrcode::types::QrError| e.to_string();
+    let data_len = Bits::new(version).max_len(ec_level).map_err(qr_err)? / 8;
+
+    // find the original position of each interleaved codeword by interleaving the positions
+    let positions_low = (0..data_len).map(|i| i as u8).collect::<Vec<u8>>();
+    let positions_high = (0..data_len).map(|i| (i >> 8) as u8).collect::<Vec<u8>>();
+    let (interleaved_low, _) =
+        construct_codewords(&positions_low, version, ec_level).map_err(qr_err)?;
+    let (interleaved_high, _) =
+        construct_codewords(&positions_high, version, ec_level).map_err(qr_err)?;
+
+    if codewords.len() < data_len {
+        return Err(String::from("qr code does not contain enough codewords"));
+    }
+    let mut data = vec![0; data_len];
+    for (i, codeword) in codewords[..data_len].iter().enumerate() {
+        let position = interleaved_low[i] as usize | (interleaved_high[i] as usize) << 8;
+        data[position] = *codeword;
+    }
+
+    let (_, expected_ec) = construct_codewords(&data, version, ec_level).map_err(qr_err)?;
+    if codewords[data_len..].get(..expected_ec.len()) != Some(&expected_ec[..]) {
+        return Err(String::from(
+            "error correction codewords do not match the data, the qr code is damaged",
+        ));
+    }
+
+    Ok(data)
+}
+
+/// Reads the numeric, alphanumeric and byte segments of the data and interprets the result as UTF-8.
+fn read_segments(data: &[u8], version: Version) -> Result<String, String> {
+    let mut reader = BitReader { data, position: 0 };
+    let mut bytes = Vec::new();
+
+    while reader.remaining() >= 4 {
+        match reader.read(4)? {
+            0b0000 => break,
+            0b0001 => {
+                let mut len = reader.read(Mode::Numeric.length_bits_count(version))?;
+                while len > 0 {
+                    let digits = len.min(3);
+                    let value = reader.read([0, 4, 7, 10][digits])?;
+                    bytes.extend(format!("{:0width$}", value, width = digits).bytes());
+                    len -= digits;
+                }
+            }
+            0b0010 => {
+                let mut len = reader.read(Mode::Alphanumeric.length_bits_count(version))?;
+                while len > 0 {
+                    let chars = len.min(2);
+                    let value = reader.read([0, 6, 11][chars])?;
+                    if chars == 2 {
+                        bytes.push(alphanumeric_char(value / 45)?);
+                    }
+                    bytes.push(alphanumeric_char(value % 45)?);
+                    len -= chars;
+                }
+            }
+            0b0100 => {
+                let len = reader.read(Mode::Byte.length_bits_count(version))?;
+                for _ in 0..len {
+                    bytes.push(reader.read(8)? as u8);
+                }
+            }
+            mode => return Err(format!("unsupported segment mode {:04b}", mode)),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|e| format!("decoded data is not valid UTF-8: {}", e))
+}
+
+fn alphanumeric_char(value: usize) -> Result<u8, String> {
+    ALPHANUMERIC_CHARS
+        .get(value)
+        .copied()
+        .ok_or_else(|| format!("invalid alphanumeric value {}", value))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    fn read(&mut self, bits: usize) -> Result<usize, String> {
+        if bits > self.remaining() {
+            return Err(String::from("unexpected end of data"));
+        }
+
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = self.data[self.position / 8] >> (7 - self.position % 8) & 1;
+            value = value << 1 | bit as usize;
+            self.position += 1;
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qrcode::{Color, QrCode};
+
+    use super::*;
+    use crate::templating::encode_qr_payload;
+
+    fn verified_corpus() -> Vec<QrData> {
+        let mut corpus = load_corpus().unwrap();
+        for qr_data in corpus.iter_mut() {
+            qr_data.verify().unwrap();
+        }
+        corpus
+    }
+
+    fn modules(qr_code: &QrCode) -> Vec<bool> {
+        qr_code
+            .to_colors()
+            .iter()
+            .map(|color| *color == Color::Dark)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_native_svg() {
+        for qr_data in verified_corpus() {
+            let svg = render_native_qr_svg(&qr_data).unwrap();
+            assert_eq!(decode_qr_svg(&svg).unwrap(), build_qr_payload(&qr_data));
+        }
+    }
+
+    #[test]
+    fn test_decode_all_masks_and_levels() {
+        let data = "SPC\n0200\n1\n12345678901234567890\nHELLO WORLD\nÄÖÜ äöü";
+        for ec_level in [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H].iter() {
+            for version in [1, 6, 7, 25].iter() {
+                let qr_code = QrCode::with_version(data, Version::Normal(*version), *ec_level);
+                let qr_code = match qr_code {
+                    Ok(qr_code) => qr_code,
+                    // data does not fit into small versions with high error correction levels
+                    Err(_) => continue,
+                };
+                let decoded = decode_qr_matrix(&modules(&qr_code), qr_code.width()).unwrap();
+                assert_eq!(decoded, data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_damaged() {
+        let qr_data = &verified_corpus()[0];
+        let qr_code = encode_qr_payload(qr_data).unwrap_or_else(|_| panic!("encoding failed"));
+        let width = qr_code.width();
+        let mut damaged = modules(&qr_code);
+
+        // flip a data module in the bottom right corner
+        damaged[width * width - 1] = !damaged[width * width - 1];
+
+        assert!(decode_qr_matrix(&damaged, width).is_err());
+    }
+
+    #[test]
+    fn test_python_payload_parity() {
+        for (index, qr_data) in verified_corpus().iter().enumerate() {
+            let payload = build_python_qr_payload(qr_data)
+                .unwrap_or_else(|e| panic!("python failed for {}: {}", index, e));
+            assert_eq!(payload, build_qr_payload(qr_data), "corpus entry {}", index);
+        }
+    }
+
+    /// Requires the qrcode python package, run using `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_generator_parity() {
+        let report = check_generator_parity(&verified_corpus());
+
+        for divergence in report.divergences.iter() {
+            eprintln!(
+                "{} {} {}: {}",
+                divergence.index, divergence.generator, divergence.check, divergence.message
+            );
+        }
+        assert!(report.passed);
+        assert_eq!(report.checked, 5);
+    }
+}
@@ -0,0 +1,115 @@
+//! The policy passwords have to satisfy when registering or changing the password of a principal.
+
+use std::{borrow::Cow, collections::HashSet, fs};
+
+use lazy_static::lazy_static;
+use validator::ValidationError;
+
+/// Common passwords that are always rejected, one per line.
+const DEFAULT_BLOCKLIST: &str = std::include_str!("resources/password_blocklist.txt");
+
+lazy_static! {
+    /// The policy configured by the PASSWORD_MIN_LENGTH (defaults to 8) and PASSWORD_BLOCKLIST_FILE
+    /// environment variables. The blocklist file contains additional passwords, e.g. from a list of
+    /// breached passwords, one per line, that are rejected on top of the built-in blocklist.
+    pub static ref PASSWORD_POLICY: PasswordPolicy = {
+        let min_length = std::env::var("PASSWORD_MIN_LENGTH").map_or(8, |val| {
+            val.parse::<usize>()
+                .expect("PASSWORD_MIN_LENGTH is not a valid usize")
+        });
+
+        let mut policy = PasswordPolicy::new(min_length, DEFAULT_BLOCKLIST);
+        if let Ok(path) = std::env::var("PASSWORD_BLOCKLIST_FILE") {
+            let blocklist = fs::read_to_string(&path).unwrap_or_else(|e| {
+                panic!("Failed to read PASSWORD_BLOCKLIST_FILE '{}': {}", path, e)
+            });
+            policy.add_blocklist(&blocklist);
+        }
+
+        policy
+    };
+}
+
+/// Requires passwords to have a minimum number of characters and to not be included in a blocklist,
+/// which is compared case-insensitively.
+pub struct PasswordPolicy {
+    min_length: usize,
+    blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    /// Creates a policy rejecting passwords shorter than `min_length` characters and the passwords
+    /// listed in `blocklist`, one per line.
+    pub fn new(min_length: usize, blocklist: &str) -> Self {
+        let mut policy = Self {
+            min_length,
+            blocklist: HashSet::new(),
+        };
+        policy.add_blocklist(blocklist);
+        policy
+    }
+
+    /// Adds the passwords listed in `blocklist`, one per line, ignoring surrounding whitespace and
+    /// empty lines.
+    pub fn add_blocklist(&mut self, blocklist: &str) {
+        self.blocklist.extend(
+            blocklist
+                .lines()
+                .map(str::trim)
+                .filter(|password| !password.is_empty())
+                .map(str::to_lowercase),
+        );
+    }
+
+    /// Checks the password, returning a `length` error with the `min` param if it is too short or a
+    /// `blocklisted_password` error if it is blocklisted.
+    pub fn check(&self, password: &str) -> Result<(), ValidationError> {
+        if password.chars().count() < self.min_length {
+            let mut error = ValidationError::new("length");
+            error.add_param(Cow::from("min"), &self.min_length);
+            return Err(error);
+        }
+
+        if self.blocklist.contains(&password.to_lowercase()) {
+            let mut error = ValidationError::new("blocklisted_password");
+            error.message = Some(Cow::from(
+                "The password is too common or has been exposed in a data breach",
+            ));
+            return Err(error);
+        }
+
+        Ok(())
+    }
+}
+
+/// Validator checking the password against the [`PASSWORD_POLICY`].
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    PASSWORD_POLICY.check(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_policy() {
+        let mut policy = PasswordPolicy::new(8, DEFAULT_BLOCKLIST);
+        policy.add_blocklist("\n  Correct Horse Battery Staple \n\n");
+
+        assert_eq!(policy.check("").unwrap_err().code, "length");
+        assert_eq!(policy.check("Tr0ub4d").unwrap_err().code, "length");
+        assert!(policy.check("äöüéàèçñ").is_ok());
+        assert_eq!(
+            policy.check("PassWord123").unwrap_err().code,
+            "blocklisted_password"
+        );
+        assert_eq!(
+            policy
+                .check("correct horse battery staple")
+                .unwrap_err()
+                .code,
+            "blocklisted_password"
+        );
+        assert!(policy.check("Tr0ub4dor&3").is_ok());
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use iban::IbanLike;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Error::InvalidRequestInputError};
+
+/// Maximum amount permitted by the specification in hundredths, i.e. 999999999.99.
+const MAX_AMOUNT_CENTS: u64 = 99_999_999_999;
+
+/// IBAN of the creditor, which must be a valid IBAN with the country code CH or LI.
+///
+/// Parsed from and displayed in the paper format with groups of four characters, the qr code contains
+/// the [electronic](Iban::electronic) format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Iban {
+    electronic: String,
+    paper: String,
+}
+
+impl Iban {
+    /// Returns the IBAN without spaces as encoded in the qr code.
+    pub fn electronic(&self) -> &str {
+        &self.electronic
+    }
+
+    /// Returns true if this is a QR-IBAN, which requires a [`Reference::Qrr`], i.e. the
+    /// 1-based positions 5 - 9 are between 30000 and 31999.
+    pub fn is_qr_iban(&self) -> bool {
+        match self.electronic.get(4..9).map(str::parse::<u32>) {
+            Some(Ok(iid)) => (30000..=31999).contains(&iid),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Iban {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let iban = s.parse::<iban::Iban>().map_err(|e| {
+            InvalidRequestInputError(format!("Provided IBAN '{}' is invalid: {}", s, e))
+        })?;
+        let country = iban.country_code();
+
+        if !(country == "CH" || country == "LI") {
+            return Err(InvalidRequestInputError(String::from(
+                "Country code of IBAN must be CH or LI",
+            )));
+        }
+
+        Ok(Iban {
+            electronic: iban.electronic_str().to_string(),
+            paper: iban.to_string(),
+        })
+    }
+}
+
+impl Display for Iban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.paper)
+    }
+}
+
+/// Amount of the payment in hundredths of the [`Currency`], between 0.01 and 999999999.99.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Amount(u64);
+
+impl Amount {
+    /// Creates an amount from hundredths of the currency, e.g. 150 for 1.50.
+    pub fn from_cents(cents: u64) -> Result<Self, Error> {
+        if cents == 0 {
+            return Err(InvalidRequestInputError(String::from(
+                "Amount must be at least 0.01",
+            )));
+        }
+
+        if cents > MAX_AMOUNT_CENTS {
+            return Err(InvalidRequestInputError(String::from(
+                "Amount may not exceed 999999999.99",
+            )));
+        }
+
+        Ok(Amount(cents))
+    }
+
+    pub fn cents(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Parses a decimal amount with exactly two fractional digits and no leading 0s, e.g. 1250.00 or
+/// 0.50, as expected by the specification.
+impl FromStr for Amount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            InvalidRequestInputError(format!(
+                "Decimal amount '{}' not formatted correctly, {}",
+                s, reason
+            ))
+        };
+
+        let (integral, fractional) = match s.split('.').collect::<Vec<&str>>().as_slice() {
+            [integral, fractional] => (*integral, *fractional),
+            _ => return Err(invalid("expected both integral and fractional parts")),
+        };
+
+        if integral.is_empty() || (integral.len() > 1 && integral.starts_with('0')) {
+            return Err(invalid("amount must not start with leading 0s"));
+        }
+
+        if fractional.len() != 2 {
+            return Err(invalid("amount must contain 2 fractional digits"));
+        }
+
+        if !integral
+            .chars()
+            .chain(fractional.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid("amount must only contain digits"));
+        }
+
+        if integral.len() > 9 {
+            return Err(invalid("amount may not exceed 999999999.99"));
+        }
+
+        let integral = integral
+            .parse::<u64>()
+            .map_err(|_| invalid("invalid integral"))?;
+        let fractional = fractional
+            .parse::<u64>()
+            .map_err(|_| invalid("invalid fractional"))?;
+
+        Amount::from_cents(integral * 100 + fractional)
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+/// Currency of the payment, the specification only permits CHF and EUR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Currency {
+    Chf,
+    Eur,
+}
+
+impl FromStr for Currency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CHF" => Ok(Currency::Chf),
+            "EUR" => Ok(Currency::Eur),
+            _ => Err(InvalidRequestInputError(String::from(
+                "Currency must be either CHF or EUR",
+            ))),
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Currency::Chf => "CHF",
+            Currency::Eur => "EUR",
+        })
+    }
+}
+
+/// The reference of the payment along with its type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    /// QR reference, a 27 digit numerical value, which must be used with a QR-IBAN.
+    Qrr(String),
+    /// Creditor reference (ISO 11649), a 5 - 25 character alphanumerical value.
+    Scor(String),
+    /// No reference.
+    Non,
+}
+
+impl Reference {
+    /// Creates a [`Reference::Qrr`] after verifying the reference number.
+    pub fn qrr(reference_number: &str) -> Result<Self, Error> {
+        if reference_number.len() != 27 {
+            return Err(InvalidRequestInputError(String::from(
+                "Reference number must be of length 27 when the reference type is QRR",
+            )));
+        }
+
+        if !reference_number.chars().all(|c| c.is_ascii_digit()) {
+            return Err(InvalidRequestInputError(String::from(
+                "Reference number must be numerical when the reference type is QRR",
+            )));
+        }
+
+        Ok(Reference::Qrr(reference_number.to_string()))
+    }
+
+    /// Creates a [`Reference::Scor`] after verifying the reference number.
+    pub fn scor(reference_number: &str) -> Result<Self, Error> {
+        if reference_number.len() < 5 || reference_number.len() > 25 {
+            return Err(InvalidRequestInputError(String::from(
+                "Reference number must be of length 5 - 25 when the reference type is SCOR",
+            )));
+        }
+
+        if !reference_number.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(InvalidRequestInputError(String::from(
+                "Reference number must be alphanumeric when the reference type is SCOR",
+            )));
+        }
+
+        Ok(Reference::Scor(reference_number.to_string()))
+    }
+
+    /// Creates a reference from the reference type and number as provided in the JSON format, where
+    /// an empty reference number is treated like a missing one.
+    pub fn from_parts(reference_type: &str, reference_number: Option<&str>) -> Result<Self, Error> {
+        let reference_number =
+            reference_number.filter(|reference_number| !reference_number.is_empty());
+
+        match (reference_type, reference_number) {
+            ("QRR", Some(reference_number)) => Reference::qrr(reference_number),
+            ("SCOR", Some(reference_number)) => Reference::scor(reference_number),
+            ("NON", None) => Ok(Reference::Non),
+            ("QRR", None) | ("SCOR", None) => Err(InvalidRequestInputError(format!(
+                "Reference number must be provided when the reference type is {}",
+                reference_type
+            ))),
+            ("NON", Some(_)) => Err(InvalidRequestInputError(String::from(
+                "Reference number must not be provided when the reference type is NON",
+            ))),
+            _ => Err(InvalidRequestInputError(String::from(
+                "Reference type must be QRR, SCOR or NON",
+            ))),
+        }
+    }
+
+    pub fn reference_type(&self) -> &'static str {
+        match self {
+            Reference::Qrr(_) => "QRR",
+            Reference::Scor(_) => "SCOR",
+            Reference::Non => "NON",
+        }
+    }
+
+    pub fn reference_number(&self) -> Option<&str> {
+        match self {
+            Reference::Qrr(reference_number) | Reference::Scor(reference_number) => {
+                Some(reference_number)
+            }
+            Reference::Non => None,
+        }
+    }
+
+    /// Verifies that the reference type matches the IBAN, QR-IBANs must be used with QR references
+    /// and only with QR references.
+    pub fn verify_iban(&self, iban: &Iban) -> Result<(), Error> {
+        match (self, iban.is_qr_iban()) {
+            (Reference::Qrr(_), false) => Err(InvalidRequestInputError(String::from(
+                "IBAN must be a QR-IBAN (1-based position 5-9 must be between 30000 and 31999) when the reference type is QRR",
+            ))),
+            (Reference::Scor(_), true) | (Reference::Non, true) => Err(InvalidRequestInputError(
+                String::from("Reference type must be QRR if the IBAN is a QR-IBAN"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+macro_rules! serde_via_str {
+    ($($ty:ty),*) => {
+        $(
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    String::deserialize(deserializer)?
+                        .parse()
+                        .map_err(serde::de::Error::custom)
+                }
+            }
+        )*
+    };
+}
+
+serde_via_str!(Iban, Amount);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount() {
+        assert_eq!("5000.00".parse::<Amount>().unwrap().cents(), 500000);
+        // amounts below 1.00 have a single 0 as integral part, which used to be rejected as a
+        // leading 0
+        assert_eq!("0.50".parse::<Amount>().unwrap().to_string(), "0.50");
+        assert_eq!("0.01".parse::<Amount>().unwrap().cents(), 1);
+        assert_eq!("0.99".parse::<Amount>().unwrap().cents(), 99);
+        assert_eq!(
+            "999999999.99".parse::<Amount>().unwrap(),
+            Amount::from_cents(MAX_AMOUNT_CENTS).unwrap()
+        );
+        assert_eq!(Amount::from_cents(1205).unwrap().to_string(), "12.05");
+
+        for invalid in &[
+            "",
+            "5",
+            "5.0",
+            "5.000",
+            "05.00",
+            "00.50",
+            ".50",
+            "0.00",
+            "-1.00",
+            "+1.00",
+            "1.-1",
+            "1000000000.00",
+        ] {
+            assert!(invalid.parse::<Amount>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_iban() {
+        let iban = "CH44 3199 9123 0008 8901 2".parse::<Iban>().unwrap();
+        assert_eq!(iban.electronic(), "CH4431999123000889012");
+        assert_eq!(iban.to_string(), "CH44 3199 9123 0008 8901 2");
+        assert!(iban.is_qr_iban());
+
+        assert!(!"CH9300762011623852957"
+            .parse::<Iban>()
+            .unwrap()
+            .is_qr_iban());
+        assert!("DE89370400440532013000".parse::<Iban>().is_err());
+        assert!("CH9300762011623852958".parse::<Iban>().is_err());
+    }
+
+    #[test]
+    fn test_reference() {
+        let qr_iban = "CH4431999123000889012".parse::<Iban>().unwrap();
+        let iban = "CH9300762011623852957".parse::<Iban>().unwrap();
+
+        let qrr = Reference::from_parts("QRR", Some("210000000003139471430009017")).unwrap();
+        assert!(qrr.verify_iban(&qr_iban).is_ok());
+        assert!(qrr.verify_iban(&iban).is_err());
+
+        let non = Reference::from_parts("NON", Some("")).unwrap();
+        assert_eq!(non, Reference::Non);
+        assert!(non.verify_iban(&iban).is_ok());
+        assert!(non.verify_iban(&qr_iban).is_err());
+
+        assert!(Reference::from_parts("QRR", Some("21000000000313947143000901A")).is_err());
+        assert!(Reference::from_parts("SCOR", Some("RF18 5390")).is_err());
+        // SCOR references only consist of ASCII letters and digits, other alphanumeric characters
+        // used to be accepted
+        assert!(Reference::from_parts("SCOR", Some("RF18539007547034")).is_ok());
+        assert!(Reference::from_parts("SCOR", Some("RF18Ä5390")).is_err());
+        assert!(Reference::from_parts("SCOR", Some("RF18é5390")).is_err());
+        assert!(Reference::from_parts("SCOR", Some("RF18５３９０")).is_err());
+        assert!(Reference::from_parts("SCOR", None).is_err());
+        assert!(Reference::from_parts("NON", Some("RF18539007547034")).is_err());
+        assert!(Reference::from_parts("IPI", None).is_err());
+    }
+}
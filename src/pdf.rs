@@ -0,0 +1,1085 @@
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::{cell::RefCell, sync::atomic::AtomicUsize};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{self, Display},
+    future::Future,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Sender, TrySendError};
+use futures_channel::oneshot;
+use lazy_static::lazy_static;
+use lopdf::{Dictionary, Document, Object};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+#[cfg(feature = "server")]
+use warp::{Rejection, Reply};
+use wkhtmltopdf::PdfApplication;
+
+use crate::cache::{CacheKey, CONTENT_CACHE};
+use crate::error::Error;
+#[cfg(feature = "server")]
+use crate::model::Principal;
+use crate::templating::{generate_html_slip, QrData, TEMPLATE_VERSION};
+
+lazy_static! {
+    pub static ref PDF_APPLICATION_WORKER_MANAGER: PdfApplicationWorkerManager =
+        PdfApplicationWorkerManager::new();
+    pub static ref PDF_WORKER_POOL_SIZE: usize = {
+        std::env::var("PDF_WORKER_POOL_SIZE").map_or(0, |val| {
+            val.parse::<usize>()
+                .expect("PDF_WORKER_POOL_SIZE is not a valid usize")
+        })
+    };
+    /// The number of slips rendered by one PDF worker process when splitting large batches, rounded
+    /// up to an even number so that the pages of each chunk are filled the same way as when rendering
+    /// the batch at once.
+    pub static ref PDF_BATCH_CHUNK_SIZE: usize = {
+        let chunk_size = std::env::var("PDF_BATCH_CHUNK_SIZE").map_or(20, |val| {
+            val.parse::<usize>()
+                .expect("PDF_BATCH_CHUNK_SIZE is not a valid usize")
+        });
+        std::cmp::max(chunk_size + chunk_size % 2, 2)
+    };
+    pub static ref PDF_QUEUE_CAPACITY: usize = {
+        std::env::var("PDF_QUEUE_CAPACITY").map_or(32, |val| {
+            val.parse::<usize>()
+                .expect("PDF_QUEUE_CAPACITY is not a valid usize")
+        })
+    };
+}
+
+/// Generates the HTML file containing all qr slips for the provided [`QrData`] elements and converts
+/// it to a PDF file.
+///
+/// When running a PDF worker process pool, batches with more than PDF_BATCH_CHUNK_SIZE elements are
+/// split into chunks that are rendered in parallel by the pool and then merged into one PDF file.
+///
+/// Generated PDF files are stored in the [`CONTENT_CACHE`] so that repeated requests for the same
+/// slips do not run wkhtmltopdf again.
+pub async fn generate_pdf_for_all(
+    qr_data_vec: &[QrData],
+    qr_svg_vec: &[String],
+) -> Result<Vec<u8>, Error> {
+    let cache_key = pdf_cache_key(qr_data_vec, qr_svg_vec);
+    if let Some(pdf) = CONTENT_CACHE.get(&cache_key) {
+        return Ok(pdf);
+    }
+
+    let chunk_size = *PDF_BATCH_CHUNK_SIZE;
+    let pdf_result = if PDF_APPLICATION_WORKER_MANAGER.worker_count() > 1
+        && qr_data_vec.len() > chunk_size
+    {
+        let html_chunks = qr_data_vec
+            .chunks(chunk_size)
+            .zip(qr_svg_vec.chunks(chunk_size))
+            .map(|(qr_data_chunk, qr_svg_chunk)| generate_html_slip(qr_data_chunk, qr_svg_chunk))
+            .collect::<Result<Vec<String>, Error>>()?;
+
+        log::debug!(
+            "Split batch of {} slips into {} chunks",
+            qr_data_vec.len(),
+            html_chunks.len()
+        );
+
+        PDF_APPLICATION_WORKER_MANAGER
+            .generate_merged_pdf_from_html(html_chunks)
+            .await
+    } else {
+        let html = generate_html_slip(qr_data_vec, qr_svg_vec)?;
+        PDF_APPLICATION_WORKER_MANAGER
+            .generate_pdf_from_html(html)
+            .await
+    };
+
+    let pdf = pdf_result?;
+    CONTENT_CACHE.insert(&cache_key, &pdf);
+
+    Ok(pdf)
+}
+
+/// Generates a separate PDF file for each of the provided [`QrData`] elements, returning the PDF files
+/// in the same order. Like [`generate_pdf_for_all`], PDF files are stored in the [`CONTENT_CACHE`]
+/// and only slips that are not cached are rendered.
+pub async fn generate_pdf_for_each(
+    qr_data_vec: &[QrData],
+    qr_svg_vec: &[String],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let cache_keys = qr_data_vec
+        .iter()
+        .zip(qr_svg_vec)
+        .map(|(qr_data, qr_svg)| {
+            pdf_cache_key(std::slice::from_ref(qr_data), std::slice::from_ref(qr_svg))
+        })
+        .collect::<Vec<CacheKey>>();
+    let mut pdfs = cache_keys
+        .iter()
+        .map(|cache_key| CONTENT_CACHE.get(cache_key))
+        .collect::<Vec<Option<Vec<u8>>>>();
+
+    let mut missing_indices = Vec::new();
+    let mut html_vec = Vec::new();
+    for (idx, pdf) in pdfs.iter().enumerate() {
+        if pdf.is_none() {
+            missing_indices.push(idx);
+            html_vec.push(generate_html_slip(
+                std::slice::from_ref(&qr_data_vec[idx]),
+                std::slice::from_ref(&qr_svg_vec[idx]),
+            )?);
+        }
+    }
+
+    let rendered_pdfs = PDF_APPLICATION_WORKER_MANAGER
+        .generate_pdfs_from_html(html_vec)
+        .await?;
+    for (idx, pdf) in missing_indices.into_iter().zip(rendered_pdfs) {
+        CONTENT_CACHE.insert(&cache_keys[idx], &pdf);
+        pdfs[idx] = Some(pdf);
+    }
+
+    Ok(pdfs.into_iter().flatten().collect())
+}
+
+/// Creates the cache key for the PDF file containing the slips for the provided [`QrData`] elements,
+/// which depends on the qr code svgs, as they may be created by different generators, and the templates
+/// and wkhtmltopdf options used to render the PDF file.
+fn pdf_cache_key(qr_data_vec: &[QrData], qr_svg_vec: &[String]) -> CacheKey {
+    CacheKey::new(
+        "pdf",
+        &(&*TEMPLATE_VERSION, &PDF_LAYOUT, qr_data_vec, qr_svg_vec),
+    )
+}
+
+pub type PdfResult = Result<Vec<u8>, PdfApplicationError>;
+
+/// Spawns the job for each input, with at most `max_in_flight` jobs running at once, and returns the
+/// PDF files in the order of the inputs.
+///
+/// If a job fails, no further jobs are spawned and the jobs that are still running are awaited before
+/// returning the error, so that they do not keep running detached and holding slots of the worker
+/// pool after the request has failed.
+async fn join_pdf_jobs<T, F, Fut>(
+    inputs: Vec<T>,
+    max_in_flight: usize,
+    mut job: F,
+) -> Result<Vec<Vec<u8>>, PdfApplicationError>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = PdfResult> + Send + 'static,
+{
+    let max_in_flight = std::cmp::max(max_in_flight, 1);
+    let mut in_flight: VecDeque<JoinHandle<PdfResult>> = VecDeque::with_capacity(max_in_flight);
+    let mut pdfs = Vec::with_capacity(inputs.len());
+    let mut inputs = inputs.into_iter();
+
+    loop {
+        while in_flight.len() < max_in_flight {
+            match inputs.next() {
+                Some(input) => in_flight.push_back(tokio::spawn(job(input))),
+                None => break,
+            }
+        }
+
+        let join_handle = match in_flight.pop_front() {
+            Some(join_handle) => join_handle,
+            None => return Ok(pdfs),
+        };
+        let result = join_handle
+            .await
+            .map_err(|e| PdfApplicationError::RawError(e.to_string()))
+            .and_then(|result| result);
+
+        match result {
+            Ok(pdf) => pdfs.push(pdf),
+            Err(e) => {
+                for join_handle in in_flight {
+                    let _ = join_handle.await;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// A job submitted to the PDF worker thread, containing the HTML to convert, the sender used to
+/// return the result and the time at which the job was submitted to measure the time it spent waiting
+/// in the queue.
+type PdfJob = (String, oneshot::Sender<PdfResult>, Instant);
+
+/// Struct managing a worker thread, or worker process pool if PDF_WORKER_POOL_SIZE is set to a non-zero
+/// value, that manages a wkhtmltopdf PdfApplication which builds PDF files from HTML input.
+/// The worker process pool option is only supported on macOS or Linux.
+///
+/// The number of jobs that may wait for a worker is limited by PDF_QUEUE_CAPACITY, submitting a job
+/// while the queue is full fails immediately with [`PdfApplicationError::QueueFull`].
+pub struct PdfApplicationWorkerManager {
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    pool: Option<procspawn::Pool>,
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    html_channel: Option<Sender<PdfJob>>,
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    html_channel: Sender<PdfJob>,
+    /// Number of jobs submitted to the process pool that have not completed yet.
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    pending_pool_jobs: Arc<PendingJobs>,
+    metrics: Arc<PdfWorkerMetrics>,
+}
+
+impl PdfApplicationWorkerManager {
+    /// Creates a new PdfApplicationWorkerManager by spawning the worker thread if PDF_WORKER_POOL_SIZE
+    /// is not set to a non-zero or sets up a process pool with the size provided by PDF_WORKER_POOL_SIZE.
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    pub fn new() -> Self {
+        if *PDF_WORKER_POOL_SIZE > 0 {
+            let pool = match procspawn::Pool::new(*PDF_WORKER_POOL_SIZE) {
+                Ok(pool) => pool,
+                Err(e) => panic!(
+                    "Failed to spawn PdfApplication worker process: '{}'",
+                    e.to_string()
+                ),
+            };
+
+            log::info!(
+                "Set up PDF worker process pool with {} processes and a queue capacity of {}",
+                *PDF_WORKER_POOL_SIZE,
+                *PDF_QUEUE_CAPACITY
+            );
+
+            Self {
+                pool: Some(pool),
+                html_channel: None,
+                pending_pool_jobs: Arc::new(PendingJobs::default()),
+                metrics: Arc::new(PdfWorkerMetrics::default()),
+            }
+        } else {
+            Self::new_single_threaded_worker()
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    pub fn new() -> Self {
+        if *PDF_WORKER_POOL_SIZE > 0 {
+            log::warn!("PDF_WORKER_POOL_SIZE set but the current platform does not support procspawn, falling back to single worker thread.");
+        }
+        Self::new_single_threaded_worker()
+    }
+
+    /// Submits a task to the worker thread or process pool to convert the provided HTML string to
+    /// a Vec of bytes representing a PDF file.
+    ///
+    /// Returns [`PdfApplicationError::QueueFull`] without waiting if the queue has reached its capacity.
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    pub async fn generate_pdf_from_html(&self, html: String) -> PdfResult {
+        if let Some(ref pool) = self.pool {
+            // the pool runs PDF_WORKER_POOL_SIZE jobs at once, all jobs exceeding that are queued
+            let pending_limit = *PDF_QUEUE_CAPACITY + *PDF_WORKER_POOL_SIZE;
+            let reservation = match PendingJobs::try_reserve(&self.pending_pool_jobs, pending_limit)
+            {
+                Some(reservation) => reservation,
+                None => return self.reject_job(),
+            };
+
+            let submitted_at = Instant::now();
+            let join_handle: procspawn::JoinHandle<(Result<Vec<u8>, String>, u64)> =
+                pool.spawn(html, |html| {
+                    std::thread_local! {
+                        static PDF_APPLICATION: RefCell<PdfApplication> = match PdfApplication::new() {
+                            Ok(p) => {
+                                log::debug!("Initialised PdfApplication for worker process");
+                                RefCell::new(p)
+                            },
+                            Err(e) => {
+                                panic!("Failed to initialise PdfApplication: '{}'", e.to_string())
+                            }
+                        };
+                    };
+
+                    log::debug!("PDF worker process received html");
+                    let render_start = Instant::now();
+                    let result = PDF_APPLICATION.with(|pdf_application| {
+                        convert_html_to_pdf(&mut *pdf_application.borrow_mut(), &html).map_err(|e| e.to_string())
+                    });
+
+                    // only the render duration can be measured inside the worker process, the wait
+                    // time is derived from the total duration of the job
+                    (result, render_start.elapsed().as_micros() as u64)
+                });
+
+            // joining blocks until the worker process is done, so it is offloaded to tokio's blocking
+            // thread pool instead of blocking the executor thread running this future. The reservation
+            // is released inside the blocking task so that the job is completed even if this future is
+            // dropped before the worker process finishes.
+            let metrics = self.metrics.clone();
+            tokio::task::spawn_blocking(move || {
+                let result = join_handle.join();
+                drop(reservation);
+
+                match result {
+                    Ok((result, render_micros)) => {
+                        let render_time = Duration::from_micros(render_micros);
+                        let wait_time = submitted_at
+                            .elapsed()
+                            .checked_sub(render_time)
+                            .unwrap_or_default();
+                        metrics.record_job(wait_time, render_time, result.is_ok());
+                        result.map_err(PdfApplicationError::RawError)
+                    }
+                    Err(_) => {
+                        metrics.record_spawn_failure();
+                        Err(PdfApplicationError::SpawnError)
+                    }
+                }
+            })
+            .await
+            .unwrap_or(Err(PdfApplicationError::SpawnError))
+        } else if let Some(ref html_channel) = self.html_channel {
+            self.submit_to_worker_thread(html_channel, html).await
+        } else {
+            panic!("Either pool or html_channel must be set")
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    pub async fn generate_pdf_from_html(&self, html: String) -> PdfResult {
+        self.submit_to_worker_thread(&self.html_channel, html).await
+    }
+
+    /// Converts each of the provided HTML strings to a separate PDF file, returning the PDF files in
+    /// the same order.
+    ///
+    /// Each HTML string is submitted as a separate job, so the documents are rendered in parallel when
+    /// using a process pool. At most [`worker_count`](PdfApplicationWorkerManager::worker_count) jobs
+    /// are submitted at once to avoid filling the queue with the jobs of a single request, see
+    /// [`join_pdf_jobs`].
+    pub async fn generate_pdfs_from_html(
+        &'static self,
+        html_vec: Vec<String>,
+    ) -> Result<Vec<Vec<u8>>, PdfApplicationError> {
+        join_pdf_jobs(html_vec, self.worker_count(), |html| {
+            self.generate_pdf_from_html(html)
+        })
+        .await
+    }
+
+    /// Converts each of the provided HTML chunks to a PDF file concurrently and merges the results into
+    /// one PDF file containing the pages of all chunks in the provided order.
+    pub async fn generate_merged_pdf_from_html(
+        &'static self,
+        html_chunks: Vec<String>,
+    ) -> PdfResult {
+        let pdfs = self.generate_pdfs_from_html(html_chunks).await?;
+
+        tokio::task::spawn_blocking(move || merge_pdfs(pdfs))
+            .await
+            .map_err(|e| PdfApplicationError::RawError(e.to_string()))?
+    }
+
+    /// Returns the number of jobs that may be rendered at the same time.
+    pub fn worker_count(&self) -> usize {
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        if self.pool.is_some() {
+            return *PDF_WORKER_POOL_SIZE;
+        }
+
+        1
+    }
+
+    /// Returns a snapshot of the current queue depth and the accumulated timings of all jobs processed
+    /// by the worker thread or process pool.
+    pub fn metrics(&self) -> PdfWorkerMetricsSnapshot {
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        let (mode, queue_depth) = match (&self.pool, &self.html_channel) {
+            (Some(pool), _) => ("process_pool", pool.queued_count()),
+            (None, Some(html_channel)) => ("thread", html_channel.len()),
+            (None, None) => panic!("Either pool or html_channel must be set"),
+        };
+
+        #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+        let (mode, queue_depth) = ("thread", self.html_channel.len());
+
+        self.metrics
+            .snapshot(mode, self.worker_count(), queue_depth, *PDF_QUEUE_CAPACITY)
+    }
+
+    async fn submit_to_worker_thread(
+        &self,
+        html_channel: &Sender<PdfJob>,
+        html: String,
+    ) -> PdfResult {
+        let (result_sender, result_receiver) = oneshot::channel::<PdfResult>();
+        match html_channel.try_send((html, result_sender, Instant::now())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return self.reject_job(),
+            Err(TrySendError::Disconnected(_)) => panic!("Html channel disconnected unexpectedly"),
+        }
+
+        result_receiver
+            .await
+            .expect("Pdf result channel has closed unexpectedly")
+    }
+
+    fn reject_job(&self) -> PdfResult {
+        self.metrics.record_rejection();
+        log::warn!("Rejecting PDF job because the PDF worker queue is full");
+        Err(PdfApplicationError::QueueFull)
+    }
+
+    fn new_single_threaded_worker() -> Self {
+        let (html_sender, html_receiver) =
+            crossbeam_channel::bounded::<PdfJob>(*PDF_QUEUE_CAPACITY);
+        let metrics = Arc::new(PdfWorkerMetrics::default());
+        let worker_metrics = metrics.clone();
+
+        std::thread::Builder::new()
+            .name(String::from("pdf_worker"))
+            .spawn(move || {
+                let mut pdf_application = match PdfApplication::new() {
+                    Ok(pdf_application) => {
+                        log::debug!("Initialised PdfApplication for worker thread");
+                        pdf_application
+                    }
+                    Err(e) => panic!("Failed to initialise wkhtmltopdf: {}", e.to_string()),
+                };
+
+                loop {
+                    let (html, result_sender, submitted_at) = html_receiver
+                        .recv()
+                        .expect("Html channel disconnected unexpectedly");
+
+                    log::debug!("PDF worker thread received html");
+                    let wait_time = submitted_at.elapsed();
+                    let render_start = Instant::now();
+                    let pdf_result = convert_html_to_pdf(&mut pdf_application, &html);
+                    worker_metrics.record_job(
+                        wait_time,
+                        render_start.elapsed(),
+                        pdf_result.is_ok(),
+                    );
+
+                    result_sender
+                        .send(pdf_result)
+                        .expect("Pdf result channel has closed unexpectedly");
+                }
+            })
+            .expect("Failed to spawn pdf_worker thread");
+
+        log::info!(
+            "Set up PDF worker thread with a queue capacity of {}.",
+            *PDF_QUEUE_CAPACITY
+        );
+
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        return Self {
+            pool: None,
+            html_channel: Some(html_sender),
+            pending_pool_jobs: Arc::new(PendingJobs::default()),
+            metrics,
+        };
+
+        #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+        return Self {
+            html_channel: html_sender,
+            metrics,
+        };
+    }
+}
+
+/// Counts the jobs submitted to the PDF worker process pool that have not completed yet.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[derive(Default)]
+struct PendingJobs(AtomicUsize);
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl PendingJobs {
+    /// Reserves a slot for a new job if fewer than `limit` jobs are pending. The slot is released
+    /// when the returned [`PendingJobReservation`] is dropped.
+    fn try_reserve(pending_jobs: &Arc<Self>, limit: usize) -> Option<PendingJobReservation> {
+        pending_jobs
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                if pending < limit {
+                    Some(pending + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| PendingJobReservation(pending_jobs.clone()))
+    }
+}
+
+/// A slot reserved by [`PendingJobs::try_reserve`] for a job that has not completed yet.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+struct PendingJobReservation(Arc<PendingJobs>);
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl Drop for PendingJobReservation {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counters accumulated by the PDF worker thread or process pool, shared between the worker and the
+/// [`PdfApplicationWorkerManager`].
+#[derive(Default)]
+pub struct PdfWorkerMetrics {
+    completed_jobs: AtomicU64,
+    failed_jobs: AtomicU64,
+    rejected_jobs: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+    total_render_micros: AtomicU64,
+    max_render_micros: AtomicU64,
+}
+
+impl PdfWorkerMetrics {
+    fn record_job(&self, wait_time: Duration, render_time: Duration, succeeded: bool) {
+        let wait_micros = wait_time.as_micros() as u64;
+        let render_micros = render_time.as_micros() as u64;
+
+        log::debug!(
+            "PDF job waited {} ms in queue and took {} ms to render",
+            wait_micros / 1000,
+            render_micros / 1000
+        );
+
+        if succeeded {
+            self.completed_jobs.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_jobs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.total_wait_micros
+            .fetch_add(wait_micros, Ordering::Relaxed);
+        self.max_wait_micros
+            .fetch_max(wait_micros, Ordering::Relaxed);
+        self.total_render_micros
+            .fetch_add(render_micros, Ordering::Relaxed);
+        self.max_render_micros
+            .fetch_max(render_micros, Ordering::Relaxed);
+    }
+
+    fn record_rejection(&self) {
+        self.rejected_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    fn record_spawn_failure(&self) {
+        self.failed_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(
+        &self,
+        mode: &'static str,
+        worker_count: usize,
+        queue_depth: usize,
+        queue_capacity: usize,
+    ) -> PdfWorkerMetricsSnapshot {
+        let completed_jobs = self.completed_jobs.load(Ordering::Relaxed);
+        let failed_jobs = self.failed_jobs.load(Ordering::Relaxed);
+        let processed_jobs = completed_jobs + failed_jobs;
+        let average_millis = |total_micros: u64| {
+            if processed_jobs > 0 {
+                total_micros as f64 / processed_jobs as f64 / 1000.0
+            } else {
+                0.0
+            }
+        };
+
+        PdfWorkerMetricsSnapshot {
+            mode,
+            worker_count,
+            queue_depth,
+            queue_capacity,
+            completed_jobs,
+            failed_jobs,
+            rejected_jobs: self.rejected_jobs.load(Ordering::Relaxed),
+            average_wait_millis: average_millis(self.total_wait_micros.load(Ordering::Relaxed)),
+            max_wait_millis: self.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            average_render_millis: average_millis(self.total_render_micros.load(Ordering::Relaxed)),
+            max_render_millis: self.max_render_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Struct returned by the /pdf-worker-metrics endpoint describing the current state of the PDF
+/// worker queue and the timings of all jobs processed since startup.
+#[derive(Serialize, Debug)]
+pub struct PdfWorkerMetricsSnapshot {
+    pub mode: &'static str,
+    pub worker_count: usize,
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    pub completed_jobs: u64,
+    pub failed_jobs: u64,
+    pub rejected_jobs: u64,
+    pub average_wait_millis: f64,
+    pub max_wait_millis: f64,
+    pub average_render_millis: f64,
+    pub max_render_millis: f64,
+}
+
+/// Handler for the /pdf-worker-metrics endpoint that returns a [`PdfWorkerMetricsSnapshot`] as json,
+/// only available to admins.
+#[cfg(feature = "server")]
+pub async fn pdf_worker_metrics_handler(_admin: Principal) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&PDF_APPLICATION_WORKER_MANAGER.metrics()))
+}
+
+/// Options passed to wkhtmltopdf when converting HTML to PDF, part of the cache key of PDF files.
+#[derive(Serialize)]
+struct PdfLayout {
+    title: &'static str,
+    portrait: bool,
+    margin_millimeters: u32,
+}
+
+const PDF_LAYOUT: PdfLayout = PdfLayout {
+    title: "Qr Slip",
+    portrait: true,
+    margin_millimeters: 0,
+};
+
+/// Convert the given HTML string to a Vec of bytes representing a PDF file using the given PdfApplication.
+fn convert_html_to_pdf(pdf_application: &mut PdfApplication, html: &str) -> PdfResult {
+    use wkhtmltopdf::Orientation;
+    use wkhtmltopdf::Size;
+
+    let orientation = if PDF_LAYOUT.portrait {
+        Orientation::Portrait
+    } else {
+        Orientation::Landscape
+    };
+
+    log::debug!("Converting html to pdf");
+    let pdf_result = pdf_application
+        .builder()
+        .title(PDF_LAYOUT.title)
+        .orientation(orientation)
+        .margin(Size::Millimeters(PDF_LAYOUT.margin_millimeters))
+        .build_from_html(html)
+        .map(|output| {
+            output
+                .bytes()
+                .collect::<Result<Vec<u8>, io::Error>>()
+                .map_err(PdfApplicationError::IoError)
+        })
+        .map_err(PdfApplicationError::WkhtmlError);
+
+    log::debug!("Done converting html to pdf");
+
+    // flatten `Result<Result<T, E>, E>` to `Result<T, E>` manually as flatten() is currently nightly only
+    match pdf_result {
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(bytes)) => Ok(bytes),
+        Err(e) => Err(e),
+    }
+}
+
+/// Merges the provided PDF files into one PDF file containing the pages of all files in the provided
+/// order. The catalog, page tree and document information of the first file are used for the merged file,
+/// the attributes that the pages inherit from the page tree of their file are copied to the pages.
+fn merge_pdfs(pdfs: Vec<Vec<u8>>) -> PdfResult {
+    let mut merged = Document::with_version("1.5");
+    let mut next_id = 1;
+    let mut pages = Vec::new();
+    let mut catalog = None;
+    let mut page_tree = None;
+    let mut info_id = None;
+
+    for pdf in pdfs {
+        let mut document = Document::load_mem(&pdf).map_err(PdfApplicationError::MergeError)?;
+        // assign ids that do not collide with the objects of previous documents
+        document.renumber_objects_with(next_id);
+        next_id = document.max_id + 1;
+
+        if info_id.is_none() {
+            info_id = document
+                .trailer
+                .get(b"Info")
+                .and_then(Object::as_reference)
+                .ok();
+        }
+
+        for page_id in document.get_pages().values() {
+            let mut page = document
+                .get_dictionary(*page_id)
+                .map_err(PdfApplicationError::MergeError)?
+                .clone();
+            // the page tree of all but the first file is dropped, so the attributes the pages inherit
+            // from it have to be set on the pages themselves
+            inherit_page_attributes(&document, &mut page)
+                .map_err(PdfApplicationError::MergeError)?;
+            pages.push((*page_id, page));
+        }
+
+        for (object_id, object) in document.objects {
+            match object.type_name().unwrap_or("") {
+                "Catalog" => {
+                    catalog.get_or_insert((object_id, object));
+                }
+                "Pages" => {
+                    page_tree.get_or_insert((object_id, object));
+                }
+                // pages are added in order below, outlines are not merged
+                "Page" | "Outlines" | "Outline" => {}
+                _ => {
+                    merged.objects.insert(object_id, object);
+                }
+            }
+        }
+    }
+
+    let (catalog_id, catalog) = catalog.ok_or(PdfApplicationError::MergeError(
+        lopdf::Error::ObjectNotFound,
+    ))?;
+    let (page_tree_id, page_tree) = page_tree.ok_or(PdfApplicationError::MergeError(
+        lopdf::Error::ObjectNotFound,
+    ))?;
+
+    let mut page_tree = page_tree
+        .as_dict()
+        .map_err(PdfApplicationError::MergeError)?
+        .clone();
+    page_tree.set("Count", pages.len() as i64);
+    page_tree.set(
+        "Kids",
+        pages
+            .iter()
+            .map(|(page_id, _)| Object::Reference(*page_id))
+            .collect::<Vec<Object>>(),
+    );
+    merged
+        .objects
+        .insert(page_tree_id, Object::Dictionary(page_tree));
+
+    for (page_id, mut page) in pages {
+        page.set("Parent", page_tree_id);
+        merged.objects.insert(page_id, Object::Dictionary(page));
+    }
+
+    let mut catalog = catalog
+        .as_dict()
+        .map_err(PdfApplicationError::MergeError)?
+        .clone();
+    catalog.set("Pages", page_tree_id);
+    catalog.remove(b"Outlines");
+    merged
+        .objects
+        .insert(catalog_id, Object::Dictionary(catalog));
+
+    merged.trailer.set("Root", catalog_id);
+    if let Some(info_id) = info_id {
+        merged.trailer.set("Info", info_id);
+    }
+
+    merged.max_id = next_id - 1;
+    merged.renumber_objects();
+    merged.compress();
+
+    let mut bytes = Vec::new();
+    merged
+        .save_to(&mut bytes)
+        .map_err(PdfApplicationError::IoError)?;
+
+    Ok(bytes)
+}
+
+/// Attributes of a page that are inherited from the page tree nodes above it if the page does not set
+/// them itself, see section 7.7.3.4 of the PDF specification.
+const INHERITABLE_PAGE_ATTRIBUTES: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Copies the inheritable attributes of the page tree nodes above the provided page of the document
+/// to the page, unless the page or a node closer to it sets them, so that the page keeps its
+/// attributes when moved to a different page tree.
+fn inherit_page_attributes(document: &Document, page: &mut Dictionary) -> Result<(), lopdf::Error> {
+    let mut visited = HashSet::new();
+    let mut parent_id = page.get(b"Parent").and_then(Object::as_reference).ok();
+
+    while let Some(node_id) = parent_id {
+        // guard against malformed files whose page tree contains a cycle
+        if !visited.insert(node_id) {
+            break;
+        }
+
+        let node = document.get_dictionary(node_id)?;
+        for attribute in INHERITABLE_PAGE_ATTRIBUTES.iter() {
+            if !page.has(attribute) {
+                if let Ok(value) = node.get(attribute) {
+                    page.set(attribute.to_vec(), value.clone());
+                }
+            }
+        }
+
+        parent_id = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+
+    Ok(())
+}
+
+impl Default for PdfApplicationWorkerManager {
+    fn default() -> Self {
+        PdfApplicationWorkerManager::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum PdfApplicationError {
+    WkhtmlError(wkhtmltopdf::Error),
+    IoError(io::Error),
+    /// When an error is received across process boundaries only the message is serialised,
+    /// the message is then wrapped in this enum variant to transform it back to a PdfApplicationError.
+    RawError(String),
+    /// Error returned when spawning a PDF worker process fails
+    SpawnError,
+    /// Error returned when a job is submitted while the PDF worker queue is full
+    QueueFull,
+    /// Error returned when merging the PDF files rendered for the chunks of a batch fails
+    MergeError(lopdf::Error),
+}
+
+impl std::error::Error for PdfApplicationError {}
+
+impl Display for PdfApplicationError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PdfApplicationError::WkhtmlError(ref e) => formatter.write_str(&e.to_string()),
+            PdfApplicationError::IoError(ref e) => formatter.write_str(&e.to_string()),
+            PdfApplicationError::RawError(ref e) => formatter.write_str(e),
+            PdfApplicationError::SpawnError => {
+                formatter.write_str("Failed to spawn PdfApplication worker process")
+            }
+            PdfApplicationError::QueueFull => formatter.write_str("The PDF worker queue is full"),
+            PdfApplicationError::MergeError(ref e) => formatter.write_str(&e.to_string()),
+        }
+    }
+}
+
+impl From<PdfApplicationError> for Error {
+    fn from(e: PdfApplicationError) -> Self {
+        match e {
+            PdfApplicationError::QueueFull => Error::PdfQueueFullError,
+            _ => Error::PdfError(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lopdf::{dictionary, Stream};
+
+    use super::*;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn test_pending_job_reservation() {
+        let pending_jobs = Arc::new(PendingJobs::default());
+
+        let first = PendingJobs::try_reserve(&pending_jobs, 2).unwrap();
+        let second = PendingJobs::try_reserve(&pending_jobs, 2).unwrap();
+        assert!(PendingJobs::try_reserve(&pending_jobs, 2).is_none());
+        assert!(PendingJobs::try_reserve(&pending_jobs, 3).is_some());
+
+        drop(first);
+        let third = PendingJobs::try_reserve(&pending_jobs, 2).unwrap();
+        assert!(PendingJobs::try_reserve(&pending_jobs, 2).is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(pending_jobs.0.load(Ordering::SeqCst), 0);
+        assert!(PendingJobs::try_reserve(&pending_jobs, 0).is_none());
+    }
+
+    /// Creates a PDF file with one page per provided `MediaBox`, which is set on the page if
+    /// `Some`, or else inherited from the page tree along with the resources and rotation.
+    fn create_pdf(
+        media_boxes: &[Option<[i64; 4]>],
+        tree_media_box: [i64; 4],
+        rotate: i64,
+    ) -> Vec<u8> {
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+        let font_id = document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+        let content_id = document.add_object(Stream::new(
+            Dictionary::new(),
+            b"BT /F1 12 Tf 10 10 Td (Qr Slip) Tj ET".to_vec(),
+        ));
+
+        let kids = media_boxes
+            .iter()
+            .map(|media_box| {
+                let mut page = dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                };
+                if let Some(media_box) = media_box {
+                    page.set(
+                        "MediaBox",
+                        media_box.iter().map(|&v| v.into()).collect::<Vec<Object>>(),
+                    );
+                }
+                document.add_object(page).into()
+            })
+            .collect::<Vec<Object>>();
+
+        let pages = dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as i64,
+            "Kids" => kids,
+            "MediaBox" => tree_media_box.iter().map(|&v| v.into()).collect::<Vec<Object>>(),
+            "Rotate" => rotate,
+            "Resources" => dictionary! {
+                "Font" => dictionary! { "F1" => font_id },
+            },
+        };
+        document.objects.insert(pages_id, Object::Dictionary(pages));
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        let mut bytes = Vec::new();
+        document.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_merge_pdfs() {
+        const A4: [i64; 4] = [0, 0, 595, 842];
+        const A5: [i64; 4] = [0, 0, 420, 595];
+        const LETTER: [i64; 4] = [0, 0, 612, 792];
+
+        let merged = merge_pdfs(vec![
+            create_pdf(&[None, None], A4, 0),
+            create_pdf(&[None, Some(LETTER)], A5, 90),
+        ])
+        .unwrap();
+
+        let document = Document::load_mem(&merged).unwrap();
+        let pages = document.get_pages();
+        assert_eq!(pages.len(), 4);
+
+        let page_tree_id = document
+            .catalog()
+            .unwrap()
+            .get(b"Pages")
+            .and_then(Object::as_reference)
+            .unwrap();
+        let page_attributes = pages
+            .values()
+            .map(|page_id| {
+                let mut page = document.get_dictionary(*page_id).unwrap().clone();
+                assert_eq!(
+                    page.get(b"Parent").and_then(Object::as_reference).unwrap(),
+                    page_tree_id
+                );
+
+                // resolve the attributes the page inherits from the merged page tree
+                inherit_page_attributes(&document, &mut page).unwrap();
+                assert!(page.get(b"Resources").is_ok());
+                let media_box = page
+                    .get(b"MediaBox")
+                    .and_then(Object::as_array)
+                    .unwrap()
+                    .iter()
+                    .map(|v| v.as_i64().unwrap())
+                    .collect::<Vec<i64>>();
+                let rotate = page.get(b"Rotate").and_then(Object::as_i64).unwrap();
+                (media_box, rotate)
+            })
+            .collect::<Vec<(Vec<i64>, i64)>>();
+
+        assert_eq!(
+            page_attributes,
+            vec![
+                (A4.to_vec(), 0),
+                (A4.to_vec(), 0),
+                (A5.to_vec(), 90),
+                (LETTER.to_vec(), 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metrics_snapshot() {
+        let metrics = PdfWorkerMetrics::default();
+        metrics.record_job(Duration::from_millis(10), Duration::from_millis(300), true);
+        metrics.record_job(Duration::from_millis(30), Duration::from_millis(100), false);
+        metrics.record_rejection();
+
+        let snapshot = metrics.snapshot("thread", 1, 0, 32);
+        assert_eq!(snapshot.completed_jobs, 1);
+        assert_eq!(snapshot.failed_jobs, 1);
+        assert_eq!(snapshot.rejected_jobs, 1);
+        assert_eq!(snapshot.average_wait_millis, 20.0);
+        assert_eq!(snapshot.max_wait_millis, 30.0);
+        assert_eq!(snapshot.average_render_millis, 200.0);
+        assert_eq!(snapshot.max_render_millis, 300.0);
+
+        assert!(matches!(
+            Error::from(PdfApplicationError::QueueFull),
+            Error::PdfQueueFullError
+        ));
+        assert!(matches!(
+            Error::from(PdfApplicationError::SpawnError),
+            Error::PdfError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_join_pdf_jobs() {
+        let job = |running: Arc<AtomicU64>, fail: Option<usize>| {
+            move |idx: usize| {
+                let running = running.clone();
+                async move {
+                    running.fetch_add(1, Ordering::SeqCst);
+                    // later jobs take longer so that they are still running when the failure is seen
+                    tokio::time::sleep(Duration::from_millis(10 * idx as u64)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+
+                    if fail == Some(idx) {
+                        Err(PdfApplicationError::RawError(format!("job {} failed", idx)))
+                    } else {
+                        Ok(vec![idx as u8])
+                    }
+                }
+            }
+        };
+
+        let running = Arc::new(AtomicU64::new(0));
+        let pdfs = join_pdf_jobs((0..7).collect(), 3, job(running.clone(), None))
+            .await
+            .unwrap();
+        assert_eq!(pdfs, (0..7).map(|idx| vec![idx]).collect::<Vec<_>>());
+
+        let spawned = Arc::new(AtomicU64::new(0));
+        let counting_job = {
+            let spawned = spawned.clone();
+            let job = job(running.clone(), Some(1));
+            move |idx| {
+                spawned.fetch_add(1, Ordering::SeqCst);
+                job(idx)
+            }
+        };
+        match join_pdf_jobs((0..7).collect(), 3, counting_job).await {
+            Err(PdfApplicationError::RawError(msg)) => assert_eq!(msg, "job 1 failed"),
+            _ => panic!("expected the failed job to fail all jobs"),
+        }
+        // the jobs in flight have completed before the error is returned and no further jobs are
+        // spawned after the failure
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+        assert_eq!(spawned.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,450 @@
+#[cfg(feature = "python")]
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[cfg(feature = "python")]
+use lazy_static::lazy_static;
+#[cfg(feature = "python")]
+use pyo3::{prelude::*, types::PyModule};
+
+#[cfg(feature = "python")]
+use crate::cache::hash_bytes;
+#[cfg(feature = "python")]
+use crate::error::{
+    Error,
+    Error::{InvalidRequestInputError, PythonError},
+};
+#[cfg(feature = "server")]
+use crate::model::Principal;
+#[cfg(feature = "python")]
+use crate::templating::{py_err_into_error, QrData};
+
+#[cfg(feature = "python")]
+lazy_static! {
+    /// Directory containing python modules that may be selected as qr generator plugins, set by the
+    /// QR_GENERATOR_PLUGIN_DIR environment variable. Plugins are disabled if not set.
+    pub static ref QR_GENERATOR_PLUGIN_DIR: Option<PathBuf> =
+        std::env::var("QR_GENERATOR_PLUGIN_DIR").ok().map(PathBuf::from);
+    /// Names of the plugins that may be requested without being logged in, set by the comma
+    /// separated QR_GENERATOR_PUBLIC_PLUGINS environment variable. Defaults to none, so that only
+    /// logged in principals may request plugins.
+    pub static ref QR_GENERATOR_PUBLIC_PLUGINS: HashSet<String> =
+        std::env::var("QR_GENERATOR_PUBLIC_PLUGINS").map_or_else(
+            |_| HashSet::new(),
+            |val| {
+                val.split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect()
+            },
+        );
+    static ref LOADED_PLUGINS: Mutex<HashMap<String, Arc<QrGeneratorPlugin>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The generator used to create the qr code svg for a
+/// [`QrData`](crate::templating::QrData) object.
+pub enum QrGenerator {
+    /// The native generator using the qrcode crate.
+    Native,
+    /// The built-in python script resources/py/qr_generator.py.
+    #[cfg(feature = "python")]
+    Script,
+    /// A python module loaded from the QR_GENERATOR_PLUGIN_DIR.
+    #[cfg(feature = "python")]
+    Plugin(Arc<QrGeneratorPlugin>),
+}
+
+impl QrGenerator {
+    /// Selects the generator requested by name, or else the generator configured for the principal,
+    /// or else the default generator depending on USE_PY_QR_GENERATOR.
+    #[cfg(feature = "python")]
+    pub fn select(requested: Option<&str>, configured: Option<&str>) -> Result<QrGenerator, Error> {
+        match requested.or(configured) {
+            Some(name) => Ok(QrGenerator::Plugin(load_plugin(name)?)),
+            None => Ok(QrGenerator::default()),
+        }
+    }
+
+    /// Selects the generator for a request of the given principal, or an anonymous request if
+    /// `None`, like [`QrGenerator::select`] using the plugin configured for the principal. Anonymous
+    /// requests may only request the plugins listed in QR_GENERATOR_PUBLIC_PLUGINS, as running a
+    /// plugin executes python code in the server process, else a MissingAuthHeaderError is returned.
+    #[cfg(feature = "server")]
+    pub fn select_for_principal(
+        requested: Option<&str>,
+        principal: Option<&Principal>,
+    ) -> Result<QrGenerator, Error> {
+        if principal.is_none() {
+            check_public_plugin(requested, &QR_GENERATOR_PUBLIC_PLUGINS)?;
+        }
+
+        Self::select(
+            requested,
+            principal.and_then(|principal| principal.qr_generator.as_deref()),
+        )
+    }
+
+    /// Identifies the generator and, for plugins, the version of its source as part of cache keys.
+    pub fn cache_id(&self) -> String {
+        match self {
+            QrGenerator::Native => String::from("native"),
+            #[cfg(feature = "python")]
+            QrGenerator::Script => String::from("script"),
+            #[cfg(feature = "python")]
+            QrGenerator::Plugin(plugin) => format!("plugin:{}:{}", plugin.name, plugin.source_hash),
+        }
+    }
+}
+
+impl Default for QrGenerator {
+    #[cfg(feature = "python")]
+    fn default() -> Self {
+        if *crate::USE_PY_QR_GENERATOR {
+            QrGenerator::Script
+        } else {
+            QrGenerator::Native
+        }
+    }
+
+    #[cfg(not(feature = "python"))]
+    fn default() -> Self {
+        QrGenerator::Native
+    }
+}
+
+/// A python module loaded from the QR_GENERATOR_PLUGIN_DIR that defines a `create_qr_code` function
+/// with the same signature as the function of the built-in script and optionally defines the hooks
+/// `validate`, which receives the [`QrData`] and returns None if valid or else a message describing
+/// why it is invalid, and `post_process_svg`, which receives the svg and the [`QrData`] and returns
+/// the modified svg.
+#[cfg(feature = "python")]
+pub struct QrGeneratorPlugin {
+    pub name: String,
+    /// Hash of the source code of the module so that changes invalidate cached svgs.
+    pub source_hash: String,
+    modified: Option<SystemTime>,
+    module: Py<PyModule>,
+    has_validate: bool,
+    has_post_process_svg: bool,
+}
+
+#[cfg(feature = "python")]
+impl QrGeneratorPlugin {
+    /// Runs the validate hook, the create_qr_code function and the post_process_svg hook of the
+    /// plugin for the given [`QrData`] object.
+    pub fn create_qr_svg(&self, qr_data: &QrData) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let module = self.module.as_ref(py);
+
+            if self.has_validate {
+                let result = module
+                    .call1("validate", (qr_data.clone(),))
+                    .map_err(|e| py_err_into_error(e, py))?;
+                if !result.is_none() {
+                    let message: String = result.extract().map_err(|e| py_err_into_error(e, py))?;
+                    return Err(InvalidRequestInputError(format!(
+                        "Validation failed for QrData in QR generator plugin '{}': {}",
+                        self.name, message
+                    )));
+                }
+            }
+
+            let mut svg: String = module
+                .call1("create_qr_code", (qr_data.clone(),))
+                .map_err(|e| py_err_into_error(e, py))?
+                .extract()
+                .map_err(|e| py_err_into_error(e, py))?;
+
+            if self.has_post_process_svg {
+                svg = module
+                    .call1("post_process_svg", (svg, qr_data.clone()))
+                    .map_err(|e| py_err_into_error(e, py))?
+                    .extract()
+                    .map_err(|e| py_err_into_error(e, py))?;
+            }
+
+            Ok(svg)
+        })
+    }
+}
+
+/// Returns a MissingAuthHeaderError if a plugin is requested that is not one of the provided public
+/// plugins.
+#[cfg(feature = "server")]
+fn check_public_plugin(
+    requested: Option<&str>,
+    public_plugins: &HashSet<String>,
+) -> Result<(), Error> {
+    match requested {
+        Some(name) if !public_plugins.contains(name) => Err(Error::MissingAuthHeaderError),
+        _ => Ok(()),
+    }
+}
+
+/// Loads the plugin with the given name from the file {name}.py in the QR_GENERATOR_PLUGIN_DIR.
+///
+/// Loaded plugins are kept in memory and only compiled again if the file has been modified since, so
+/// that plugins can be added or changed without restarting the service.
+#[cfg(feature = "python")]
+pub fn load_plugin(name: &str) -> Result<Arc<QrGeneratorPlugin>, Error> {
+    let dir = QR_GENERATOR_PLUGIN_DIR.as_ref().ok_or_else(|| {
+        InvalidRequestInputError(String::from(
+            "QR generator plugins are disabled as QR_GENERATOR_PLUGIN_DIR is not set",
+        ))
+    })?;
+
+    load_plugin_from_dir(dir, name)
+}
+
+/// Loads the plugin with the given name from the file {name}.py in the provided directory, see
+/// [`load_plugin`].
+#[cfg(feature = "python")]
+fn load_plugin_from_dir(dir: &Path, name: &str) -> Result<Arc<QrGeneratorPlugin>, Error> {
+    // only accept module names to prevent loading files outside of the plugin directory
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(InvalidRequestInputError(format!(
+            "Invalid QR generator plugin name '{}'",
+            name
+        )));
+    }
+
+    let path = dir.join(format!("{}.py", name));
+    let metadata = fs::metadata(&path)
+        .map_err(|_| InvalidRequestInputError(format!("Unknown QR generator plugin '{}'", name)))?;
+    let modified = metadata.modified().ok();
+
+    let mut loaded_plugins = LOADED_PLUGINS.lock().unwrap();
+    if let Some(plugin) = loaded_plugins.get(name) {
+        if modified.is_some() && plugin.modified == modified {
+            return Ok(plugin.clone());
+        }
+    }
+
+    let source = fs::read_to_string(&path).map_err(|e| {
+        PythonError(format!(
+            "Failed to read QR generator plugin '{}': {}",
+            name, e
+        ))
+    })?;
+
+    let plugin = Python::with_gil(|py| {
+        // python executes the code in the module already registered under the same name, so the
+        // previous version is removed first to not keep functions that have been removed since
+        let module_name = format!("qr_generator_plugin_{}", name);
+        py.import("sys")
+            .and_then(|sys| sys.getattr("modules"))
+            .and_then(|modules| modules.call_method1("pop", (&module_name, py.None())))
+            .map_err(|e| py_err_into_error(e, py))?;
+
+        let module = PyModule::from_code(py, &source, &path.to_string_lossy(), &module_name)
+            .map_err(|e| py_err_into_error(e, py))?;
+
+        if !module.hasattr("create_qr_code").unwrap_or(false) {
+            return Err(PythonError(format!(
+                "QR generator plugin '{}' does not define create_qr_code",
+                name
+            )));
+        }
+
+        Ok(QrGeneratorPlugin {
+            name: name.to_string(),
+            source_hash: hash_bytes(source.as_bytes()),
+            modified,
+            has_validate: module.hasattr("validate").unwrap_or(false),
+            has_post_process_svg: module.hasattr("post_process_svg").unwrap_or(false),
+            module: module.into(),
+        })
+    })?;
+
+    log::info!("Loaded QR generator plugin '{}'", name);
+    let plugin = Arc::new(plugin);
+    loaded_plugins.insert(name.to_string(), plugin.clone());
+
+    Ok(plugin)
+}
+
+#[cfg(all(test, feature = "python"))]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::payment::{Amount, Currency};
+
+    const PLUGIN: &str = r#"
+def create_qr_code(json):
+    return "<svg>" + json["debtor_name"] + "</svg>"
+
+
+def validate(json):
+    if json["debtor_name"] == "Invalid":
+        return "debtor rejected"
+    return None
+
+
+def post_process_svg(svg, json):
+    return svg.replace("<svg>", '<svg data-city="' + json["debtor_city"] + '">')
+"#;
+
+    fn qr_data(debtor_name: &str) -> QrData {
+        QrData::builder()
+            .creditor_iban("CH9300762011623852957".parse().unwrap())
+            .creditor("Pia Rutschmann", "Marktgasse 28", "9400", "Rorschach", "CH")
+            .debtor(debtor_name, "Musterstrasse 1", "8000", "Zürich", "CH")
+            .amount(Amount::from_cents(19995).unwrap())
+            .currency(Currency::Chf)
+            .build()
+            .unwrap()
+    }
+
+    /// Writes the plugin source to a new directory, returning the directory and a name unique across
+    /// tests as loaded plugins are cached by name.
+    fn write_plugin(dir: Option<PathBuf>, name: Option<&str>, source: &str) -> (PathBuf, String) {
+        let dir = dir.unwrap_or_else(|| {
+            let dir = std::env::temp_dir().join(format!("qr_slip_plugins_{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        });
+        let name = name
+            .map(String::from)
+            .unwrap_or_else(|| format!("plugin_{}", Uuid::new_v4().to_simple()));
+
+        fs::write(dir.join(format!("{}.py", name)), source).unwrap();
+
+        (dir, name)
+    }
+
+    fn is_invalid_input(result: Result<Arc<QrGeneratorPlugin>, Error>, expected: &str) -> bool {
+        matches!(result, Err(InvalidRequestInputError(message)) if message.contains(expected))
+    }
+
+    #[test]
+    fn test_plugin_name() {
+        let (dir, _) = write_plugin(None, Some("valid"), PLUGIN);
+        fs::write(dir.join("outside.py"), PLUGIN).unwrap();
+        let nested = dir.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+
+        for name in &[
+            "",
+            "../outside",
+            "..",
+            "nested/../valid",
+            "/etc/passwd",
+            "valid.py",
+            "valid ",
+            "välid",
+            "valid\u{0}",
+        ] {
+            assert!(
+                is_invalid_input(
+                    load_plugin_from_dir(&nested, name),
+                    "Invalid QR generator plugin name"
+                ),
+                "accepted '{}'",
+                name
+            );
+        }
+
+        assert!(is_invalid_input(
+            load_plugin_from_dir(&dir, "missing"),
+            "Unknown QR generator plugin"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugin_hooks() {
+        let (dir, name) = write_plugin(None, None, PLUGIN);
+        let plugin = load_plugin_from_dir(&dir, &name).unwrap();
+        assert!(plugin.has_validate);
+        assert!(plugin.has_post_process_svg);
+
+        assert_eq!(
+            plugin.create_qr_svg(&qr_data("Hans Muster")).unwrap(),
+            "<svg data-city=\"Zürich\">Hans Muster</svg>"
+        );
+        match plugin.create_qr_svg(&qr_data("Invalid")) {
+            Err(InvalidRequestInputError(message)) => assert!(message.ends_with("debtor rejected")),
+            _ => panic!("expected the validate hook to reject the QrData"),
+        }
+
+        // the hooks are optional, but create_qr_code is required
+        let (_, name) = write_plugin(
+            Some(dir.clone()),
+            None,
+            "def create_qr_code(json):\n    return '<svg/>'\n",
+        );
+        let plugin = load_plugin_from_dir(&dir, &name).unwrap();
+        assert!(!plugin.has_validate && !plugin.has_post_process_svg);
+        assert_eq!(plugin.create_qr_svg(&qr_data("Invalid")).unwrap(), "<svg/>");
+
+        let (_, name) = write_plugin(Some(dir.clone()), None, "x = 1\n");
+        assert!(matches!(
+            load_plugin_from_dir(&dir, &name),
+            Err(PythonError(message)) if message.contains("does not define create_qr_code")
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugin_reload() {
+        let (dir, name) = write_plugin(None, None, PLUGIN);
+        let path = dir.join(format!("{}.py", name));
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        let plugin = load_plugin_from_dir(&dir, &name).unwrap();
+        assert!(Arc::ptr_eq(
+            &plugin,
+            &load_plugin_from_dir(&dir, &name).unwrap()
+        ));
+        let cache_id = QrGenerator::Plugin(plugin.clone()).cache_id();
+
+        let source = "def create_qr_code(json):\n    return '<svg>reloaded</svg>'\n";
+        // rewrite until the modification time changes on file systems with a coarse resolution
+        while fs::metadata(&path).unwrap().modified().unwrap() == modified {
+            thread::sleep(Duration::from_millis(10));
+            write_plugin(Some(dir.clone()), Some(&name), source);
+        }
+        let reloaded = load_plugin_from_dir(&dir, &name).unwrap();
+        assert!(!Arc::ptr_eq(&plugin, &reloaded));
+        assert_eq!(
+            reloaded.create_qr_svg(&qr_data("Hans Muster")).unwrap(),
+            "<svg>reloaded</svg>"
+        );
+        assert_ne!(QrGenerator::Plugin(reloaded).cache_id(), cache_id);
+        // requests still holding the previous version are not affected
+        assert_eq!(
+            plugin.create_qr_svg(&qr_data("Hans Muster")).unwrap(),
+            "<svg data-city=\"Zürich\">Hans Muster</svg>"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_check_public_plugin() {
+        let public_plugins = vec![String::from("public")]
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        assert!(check_public_plugin(None, &public_plugins).is_ok());
+        assert!(check_public_plugin(Some("public"), &public_plugins).is_ok());
+        assert!(matches!(
+            check_public_plugin(Some("private"), &public_plugins),
+            Err(Error::MissingAuthHeaderError)
+        ));
+        assert!(check_public_plugin(Some("public"), &HashSet::new()).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! Python bindings built as the `qr_slip` extension module when compiling with the `extension-module`
+//! feature, giving python users the same validation and output as the /generate-slip endpoint.
+//!
+//! [`QrData`] objects are passed as dicts with the same keys as the JSON objects accepted by the web
+//! service. Invalid input raises a `ValueError`, all other errors raise a `RuntimeError`.
+
+#[cfg(feature = "pdf")]
+use lazy_static::lazy_static;
+#[cfg(feature = "pdf")]
+use pyo3::types::PyBytes;
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    wrap_pyfunction,
+};
+#[cfg(feature = "pdf")]
+use tokio::runtime::Runtime;
+
+use crate::error::Error;
+use crate::plugin::QrGenerator;
+use crate::templating::{self, QrData};
+
+#[cfg(feature = "pdf")]
+lazy_static! {
+    /// Runtime used to await the PDF worker, as python callers are not running a tokio runtime.
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build tokio runtime");
+}
+
+#[pymodule]
+fn qr_slip(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(verify, m)?)?;
+    m.add_function(wrap_pyfunction!(transliterate, m)?)?;
+    m.add_function(wrap_pyfunction!(build_qr_payload, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_qr_svg, m)?)?;
+    #[cfg(feature = "pdf")]
+    m.add_function(wrap_pyfunction!(generate_pdf, m)?)?;
+
+    Ok(())
+}
+
+/// Verifies the QrData dict according to the specification and returns it with the IBAN formatted.
+#[pyfunction]
+fn verify(mut qr_data: QrData) -> PyResult<QrData> {
+    qr_data.verify().map_err(error_into_py_err)?;
+
+    Ok(qr_data)
+}
+
+/// Returns the QrData dict with all characters not permitted by the specification transliterated.
+#[pyfunction]
+fn transliterate(mut qr_data: QrData) -> QrData {
+    qr_data.transliterate();
+    qr_data
+}
+
+/// Verifies the QrData dict and returns the data encoded in the qr code.
+#[pyfunction]
+fn build_qr_payload(mut qr_data: QrData) -> PyResult<String> {
+    qr_data.verify().map_err(error_into_py_err)?;
+
+    Ok(templating::build_qr_payload(&qr_data))
+}
+
+/// Verifies the QrData dict and returns the qr code as svg, generated by the plugin with the given
+/// name if set, else by the default generator.
+#[pyfunction]
+fn generate_qr_svg(mut qr_data: QrData, generator: Option<&str>) -> PyResult<String> {
+    let generator = QrGenerator::select(generator, None).map_err(error_into_py_err)?;
+    qr_data.verify().map_err(error_into_py_err)?;
+
+    templating::generate_qr_svg(&qr_data, &generator).map_err(error_into_py_err)
+}
+
+/// Verifies the list of QrData dicts and returns a PDF file containing a slip for each element as
+/// bytes, like the /generate-slip endpoint.
+#[cfg(feature = "pdf")]
+#[pyfunction]
+fn generate_pdf(
+    py: Python,
+    mut qr_data_vec: Vec<QrData>,
+    generator: Option<&str>,
+) -> PyResult<PyObject> {
+    let generator = QrGenerator::select(generator, None).map_err(error_into_py_err)?;
+    let qr_svg_vec = templating::generate_qr_svg_for_all(&mut qr_data_vec, &generator)
+        .map_err(error_into_py_err)?;
+
+    // release the GIL while waiting for wkhtmltopdf
+    let pdf = py
+        .allow_threads(|| {
+            RUNTIME.block_on(crate::pdf::generate_pdf_for_all(&qr_data_vec, &qr_svg_vec))
+        })
+        .map_err(error_into_py_err)?;
+
+    Ok(PyBytes::new(py, &pdf).into())
+}
+
+fn error_into_py_err(e: Error) -> PyErr {
+    match e {
+        Error::InvalidRequestInputError(_)
+        | Error::InvalidFieldsError(_)
+        | Error::QrCodeError(_) => PyValueError::new_err(e.to_string()),
+        _ => PyRuntimeError::new_err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::types::PyModule;
+
+    use super::*;
+
+    fn qr_data_json() -> serde_json::Value {
+        serde_json::json!({
+            "creditor_iban": "CH4431999123000889012",
+            "creditor_name": "Tobias Rothlin",
+            "creditor_address": "Peterliwiese 33",
+            "creditor_zip_code": "8855",
+            "creditor_city": "Wangen SZ",
+            "creditor_country": "CH",
+            "debtor_name": "Hans Muster",
+            "debtor_address": "Sonnenstrasse 31",
+            "debtor_zip_code": "2000",
+            "debtor_city": "Neuchâtel",
+            "debtor_country": "CH",
+            "amount": "5000.00",
+            "currency": "CHF",
+            "reference_type": "QRR",
+            "reference_number": "210000000003139471430009017",
+            "additional_information": null
+        })
+    }
+
+    /// Calls the function of the extension module with the given name with the provided json value
+    /// converted to a python dict, like a python caller would.
+    fn call<'py>(
+        py: Python<'py>,
+        function: &str,
+        json: &serde_json::Value,
+    ) -> PyResult<&'py PyAny> {
+        let module = PyModule::new(py, "qr_slip")?;
+        qr_slip(py, module)?;
+        let dict = py
+            .import("json")?
+            .call_method1("loads", (json.to_string(),))?;
+
+        module.call1(function, (dict,))
+    }
+
+    #[test]
+    fn test_verify() {
+        Python::with_gil(|py| {
+            let verified = call(py, "verify", &qr_data_json()).unwrap();
+            assert_eq!(
+                verified
+                    .get_item("creditor_iban")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "CH4431999123000889012"
+            );
+            assert_eq!(
+                verified
+                    .get_item("debtor_name")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "Hans Muster"
+            );
+
+            // invalid typed fields are rejected when converting the dict
+            let mut invalid_amount = qr_data_json();
+            invalid_amount["amount"] = serde_json::json!("5000");
+            let err = call(py, "verify", &invalid_amount).unwrap_err();
+            assert!(err.is_instance::<PyValueError>(py));
+
+            // invalid text fields are rejected by verify
+            let mut too_long = qr_data_json();
+            too_long["debtor_name"] = serde_json::json!("N".repeat(71));
+            let err = call(py, "verify", &too_long).unwrap_err();
+            assert!(err.is_instance::<PyValueError>(py));
+            assert!(err.to_string().contains("debtor_name"));
+
+            let mut missing_field = qr_data_json();
+            missing_field
+                .as_object_mut()
+                .unwrap()
+                .remove("creditor_name");
+            assert!(call(py, "verify", &missing_field).is_err());
+        });
+    }
+
+    #[test]
+    fn test_transliterate_and_payload() {
+        Python::with_gil(|py| {
+            let mut json = qr_data_json();
+            json["debtor_name"] = serde_json::json!("Ödön Łódź");
+            let transliterated = call(py, "transliterate", &json).unwrap();
+            assert_eq!(
+                transliterated
+                    .get_item("debtor_name")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "Ödön Lódz"
+            );
+
+            let payload = call(py, "build_qr_payload", &qr_data_json())
+                .unwrap()
+                .extract::<String>()
+                .unwrap();
+            let lines = payload.split('\n').collect::<Vec<&str>>();
+            assert_eq!(lines[3], "CH4431999123000889012");
+            assert_eq!(&lines[18..20], &["5000.00", "CHF"]);
+            assert_eq!(lines[28], "210000000003139471430009017");
+
+            let svg = call(py, "generate_qr_svg", &qr_data_json())
+                .unwrap()
+                .extract::<String>()
+                .unwrap();
+            assert!(svg.contains("<svg"));
+        });
+    }
+
+    #[test]
+    fn test_error_into_py_err() {
+        Python::with_gil(|py| {
+            let is_value_error = |e: Error| error_into_py_err(e).is_instance::<PyValueError>(py);
+            let is_runtime_error =
+                |e: Error| error_into_py_err(e).is_instance::<PyRuntimeError>(py);
+
+            assert!(is_value_error(Error::InvalidRequestInputError(
+                String::from("invalid")
+            )));
+            assert!(is_value_error(Error::InvalidFieldsError(Vec::new())));
+            assert!(is_value_error(Error::QrCodeError(String::from("too long"))));
+            assert!(is_runtime_error(Error::PdfError(String::from("failed"))));
+            assert!(is_runtime_error(Error::PdfQueueFullError));
+            assert!(is_runtime_error(Error::PythonError(String::from("failed"))));
+
+            let err = error_into_py_err(Error::PdfError(String::from("wkhtmltopdf failed")));
+            assert!(err.to_string().contains("wkhtmltopdf failed"));
+        });
+    }
+}
@@ -1,51 +1,106 @@
 import io
 
-import qrcode
-import qrcode.image.svg
+
+def build_qr_payload(json):
+    return "\n".join([
+        "SPC",
+        "0200",
+        "1",
+        json["creditor_iban"].replace(" ", ""),
+        "K",
+        json["creditor_name"],
+        json["creditor_address"],
+        json["creditor_zip_code"] + " " + json["creditor_city"],
+        "",
+        "",
+        json["creditor_country"],
+        "",
+        "",
+        "",
+        "",
+        "",
+        "",
+        "",
+        json["amount"],
+        json["currency"],
+        "K",
+        json["debtor_name"],
+        json["debtor_address"],
+        json["debtor_zip_code"] + " " + json["debtor_city"],
+        "",
+        "",
+        json["debtor_country"],
+        json["reference_type"],
+        json["reference_number"] or "",
+        json["additional_information"] or "",
+        "EPD",
+    ])
 
 
 def create_qr_code(json):
-    qr_data = "SPC\n" \
-              "0200\n" \
-              "1\n" \
-              + json["creditor_iban"] \
-              + "\nK\n" \
-              + json["creditor_name"] + "\n" \
-              + json["creditor_address"] + "\n" \
-              + json["creditor_zip_code"] + " " + json["creditor_city"] + "\n" \
-              + "\n\n" \
-              + json["creditor_country"] \
-              + "\n\n\n\n\n\n\n\n" \
-              + json["amount"] + "\n" \
-              + json["currency"] + "\n" \
-              + "K\n" \
-              + json["debtor_name"] + "\n" \
-              + json["debtor_address"] + "\n" \
-              + json["debtor_zip_code"] + " " + json["debtor_city"] + "\n" \
-              + "\n\n" \
-              + json["debtor_country"] + "\n" \
-              + json["reference_type"] + "\n" \
-              + json["reference_number"] + "\n" \
-              + json["additional_information"] + "\n" \
-              + "EPD"
-
-    img = qrcode.make(qr_data, image_factory=qrcode.image.svg.SvgImage)
-    buffered = io.BytesIO()
-    img.save(buffered, "SVG")
-
-    xml_str = buffered.getvalue().decode("utf-8")
-
-    closing_tag_pos = xml_str.rfind("</svg>")
-
-    if closing_tag_pos >= 0:
-        swiss_cross = """
-        <rect x="25.9mm" y="25.9mm" class="st0" width="9.2mm" height="9.2mm"/>
-        <rect x="27.5mm" y="27.5mm" width="6mm" height="6mm"/>
-        <rect x="28.5mm" y="30mm" class="st0" width="4mm" height="1mm"/>
-        <rect x="30mm" y="28.5mm" class="st0" width="1mm" height="4mm"/>
-        <style type="text/css">.st0{fill:#FFFFFF;}</style>
-        """
-
-        xml_str = xml_str[:closing_tag_pos] + swiss_cross + xml_str[closing_tag_pos:]
-
-    return xml_str
+    # imported here so that the payload can be built without the qrcode package being installed
+    import qrcode
+    import qrcode.constants
+
+    qr_data = build_qr_payload(json)
+
+    qr = qrcode.QRCode(error_correction=qrcode.constants.ERROR_CORRECT_M, border=0)
+    qr.add_data(qr_data)
+    qr.make(fit=True)
+
+    if qr.version > MAX_QR_VERSION:
+        raise ValueError("Encoding the data requires QR code version %d, but the maximum permitted version is %d"
+                         % (qr.version, MAX_QR_VERSION))
+
+    return render_swiss_qr_svg(qr.get_matrix())
+
+
+MAX_QR_VERSION = 25
+
+# dimensions as prescribed by the specification, independent of the qr code version
+QR_CODE_SIZE_MM = 46.0
+QR_QUIET_ZONE_MM = 5.0
+SWISS_CROSS_SIZE_MM = 7.0
+SWISS_CROSS_BORDER_MM = 0.5
+
+
+def render_swiss_qr_svg(matrix):
+    # one unit of the view box equals one module
+    width = len(matrix)
+    modules_per_mm = width / QR_CODE_SIZE_MM
+    quiet_zone = QR_QUIET_ZONE_MM * modules_per_mm
+    size = width + 2 * quiet_zone
+
+    modules = io.StringIO()
+    for y, row in enumerate(matrix):
+        x = 0
+        while x < width:
+            if row[x]:
+                run_start = x
+                while x < width and row[x]:
+                    x += 1
+                modules.write("M%d %dh%dv1h-%dz" % (run_start, y, x - run_start, x - run_start))
+            else:
+                x += 1
+
+    center = width / 2
+    cross_size = SWISS_CROSS_SIZE_MM * modules_per_mm
+    square_size = (SWISS_CROSS_SIZE_MM - 2 * SWISS_CROSS_BORDER_MM) * modules_per_mm
+    # proportions of the swiss flag, the arms of the cross are 6/32 wide and span 20/32 of the square
+    arm_length = square_size * 20 / 32
+    arm_width = square_size * 6 / 32
+
+    def rect(x, y, w, h, fill):
+        return '<rect x="%.4f" y="%.4f" width="%.4f" height="%.4f" fill="%s"/>\n' % (x, y, w, h, fill)
+
+    return '<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="%gmm" height="%gmm" ' \
+           'viewBox="%.4f %.4f %.4f %.4f" shape-rendering="crispEdges">\n' \
+           % (QR_CODE_SIZE_MM + 2 * QR_QUIET_ZONE_MM, QR_CODE_SIZE_MM + 2 * QR_QUIET_ZONE_MM,
+              -quiet_zone, -quiet_zone, size, size) \
+           + rect(-quiet_zone, -quiet_zone, size, size, "#fff") \
+           + '<path fill="#000" d="%s"/>\n' % modules.getvalue() \
+           + rect(center - cross_size / 2, center - cross_size / 2, cross_size, cross_size, "#fff") \
+           + rect(center - square_size / 2, center - square_size / 2, square_size, square_size, "#000") \
+           + rect(center - arm_length / 2, center - arm_width / 2, arm_length, arm_width, "#fff") \
+           + rect(center - arm_width / 2, center - arm_length / 2, arm_width, arm_length, "#fff") \
+           + '</svg>'
@@ -1,8 +1,35 @@
+table! {
+    login_throttle (pk) {
+        pk -> Int4,
+        scope -> Varchar,
+        key -> Varchar,
+        failed_attempts -> Int4,
+        last_failure -> Timestamptz,
+        blocked_until -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    password_reset_token (pk) {
+        pk -> Int4,
+        token_hash -> Varchar,
+        expiry -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        fk_principal -> Int4,
+    }
+}
+
 table! {
     principal (pk) {
         pk -> Int4,
         user_name -> Varchar,
         password -> Varchar,
+        is_admin -> Bool,
+        qr_generator -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
+        totp_secret -> Nullable<Bytea>,
+        totp_enabled -> Bool,
+        totp_last_used_step -> Nullable<Int8>,
     }
 }
 
@@ -26,10 +53,47 @@ table! {
         expiry -> Timestamptz,
         invalidated -> Bool,
         fk_principal -> Int4,
+        family -> Uuid,
+        session_expiry -> Timestamptz,
+        rotated_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        last_used_at -> Timestamptz,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+    }
+}
+
+table! {
+    totp_challenge (pk) {
+        pk -> Int4,
+        token_hash -> Varchar,
+        expiry -> Timestamptz,
+        failed_attempts -> Int4,
+        fk_principal -> Int4,
+    }
+}
+
+table! {
+    totp_recovery_code (pk) {
+        pk -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+        fk_principal -> Int4,
     }
 }
 
+joinable!(password_reset_token -> principal (fk_principal));
 joinable!(qr_user -> principal (fk_principal));
 joinable!(refresh_token -> principal (fk_principal));
+joinable!(totp_challenge -> principal (fk_principal));
+joinable!(totp_recovery_code -> principal (fk_principal));
 
-allow_tables_to_appear_in_same_query!(principal, qr_user, refresh_token,);
+allow_tables_to_appear_in_same_query!(
+    login_throttle,
+    password_reset_token,
+    principal,
+    qr_user,
+    refresh_token,
+    totp_challenge,
+    totp_recovery_code,
+);
@@ -1,38 +1,47 @@
-#[cfg(not(debug_assertions))]
-use std::io::Read;
-use std::{
-    error::Error,
-    fmt::{self, Display},
-    io,
-};
-#[cfg(any(target_os = "macos", target_os = "linux"))]
-use std::cell::RefCell;
-#[cfg(debug_assertions)]
-use std::{
-    fs,
-    io::{Read, Write},
-};
-
-use crossbeam_channel::Sender;
-use dict_derive::IntoPyObject;
-use futures_channel::oneshot;
-use iban::{Iban, IbanLike};
+use std::{borrow::Cow, convert::TryFrom, error::Error as StdError};
+#[cfg(all(feature = "server", debug_assertions))]
+use std::{fs, io::Write};
+
+#[cfg(feature = "python")]
+use dict_derive::{FromPyObject, IntoPyObject};
+#[cfg(feature = "png")]
+use image::{codecs::png::PngEncoder, ColorType, GrayImage, Luma};
 use lazy_static::lazy_static;
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
-use qrcode::{render::svg, EcLevel, QrCode};
+use qrcode::{Color, EcLevel, QrCode, Version};
 use serde::{Deserialize, Serialize};
 use tera::Tera;
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "server", debug_assertions))]
 use uuid::Uuid;
 use validator::{Validate, ValidationError};
-use warp::{Rejection, Reply};
-use wkhtmltopdf::PdfApplication;
+#[cfg(feature = "server")]
+use warp::{
+    http::{header, Response},
+    hyper::Body,
+    Rejection, Reply,
+};
 
-#[cfg(debug_assertions)]
+use crate::cache::{hash_bytes, CacheKey, CONTENT_CACHE};
+#[cfg(all(feature = "server", debug_assertions))]
 use crate::error::Error::IoError;
-use crate::error::Error::{
-    InvalidRequestInputError, PdfError, PythonError, QrCodeError, TeraError,
+#[cfg(feature = "python")]
+use crate::error::Error::PythonError;
+#[cfg(feature = "server")]
+use crate::error::Error::SerialisationError;
+use crate::error::{
+    Error,
+    Error::{InvalidFieldsError, InvalidRequestInputError, QrCodeError, TeraError},
 };
+#[cfg(feature = "server")]
+use crate::i18n::Language;
+#[cfg(feature = "server")]
+use crate::model::Principal;
+use crate::payment::{Amount, Currency, Iban, Reference};
+#[cfg(feature = "server")]
+use crate::pdf::{generate_pdf_for_all, generate_pdf_for_each};
+use crate::plugin::QrGenerator;
+use crate::validation::{self, FieldError};
 
 macro_rules! format_qr_code_data {
     () => {
@@ -70,36 +79,72 @@ EPD"#
     };
 }
 
+macro_rules! format_qr_svg {
+    () => {
+        r##"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size_mm}mm" height="{size_mm}mm" viewBox="{origin:.4} {origin:.4} {size:.4} {size:.4}" shape-rendering="crispEdges">
+<rect x="{origin:.4}" y="{origin:.4}" width="{size:.4}" height="{size:.4}" fill="#fff"/>
+<path fill="#000" d="{modules}"/>
+{swiss_cross}</svg>"##
+    };
+}
+
 macro_rules! format_qr_swiss_cross {
     () => {
-        r#"
-<rect x="{outer}" y="{outer}" class="st0" width="36" height="36"/>
-<rect x="{inner}" y="{inner}" width="24" height="24"/>
-<rect x="{cross_wide}" y="{cross_short}" class="st0" width="16" height="4"/>
-<rect x="{cross_short}" y="{cross_wide}" class="st0" width="4" height="16"/>
-<style type="text/css">
-.st0 {{
-    fill:#FFFFFF;
-}}
-</style>
-"#
+        r##"<rect x="{cross_pos:.4}" y="{cross_pos:.4}" width="{cross_size:.4}" height="{cross_size:.4}" fill="#fff"/>
+<rect x="{square_pos:.4}" y="{square_pos:.4}" width="{square_size:.4}" height="{square_size:.4}" fill="#000"/>
+<rect x="{arm_long_pos:.4}" y="{arm_short_pos:.4}" width="{arm_length:.4}" height="{arm_width:.4}" fill="#fff"/>
+<rect x="{arm_short_pos:.4}" y="{arm_long_pos:.4}" width="{arm_width:.4}" height="{arm_length:.4}" fill="#fff"/>
+"##
     };
 }
 
+/// Maximum version of the qr code permitted by the specification.
+const MAX_QR_VERSION: i16 = 25;
+/// Non ASCII characters permitted by the specification in addition to printable basic latin characters.
+const PERMITTED_SPECIAL_CHARS: &str = "ÄÖÜäöüßÀÁÂÇÈÉÊËÌÍÎÏÑÒÓÔÙÚÛÝàáâçèéêëìíîïñòóôùúûýÿ";
+/// Size of the qr code, excluding the quiet zone, as prescribed by the specification.
+const QR_CODE_SIZE_MM: f64 = 46.0;
+/// Size of the quiet zone surrounding the qr code.
+const QR_QUIET_ZONE_MM: f64 = 5.0;
+/// Size of the swiss cross in the center of the qr code, including its white border.
+const SWISS_CROSS_SIZE_MM: f64 = 7.0;
+/// Width of the white border surrounding the black square of the swiss cross.
+const SWISS_CROSS_BORDER_MM: f64 = 0.5;
+/// Resolution of qr codes rendered as png, i.e. 300 dpi.
+#[cfg(feature = "png")]
+const PNG_PIXELS_PER_MM: f64 = 300.0 / 25.4;
+
+/// The tera templates and their names, embedded into the binary so that rendering does not depend on
+/// the working directory.
+const TEMPLATES: [(&str, &str); 2] = [
+    (
+        "qr_slip.html",
+        include_str!("resources/templates/qr_slip.html"),
+    ),
+    (
+        "qr_slip_snippet.html",
+        include_str!("resources/templates/qr_slip_snippet.html"),
+    ),
+];
+
 lazy_static! {
     pub static ref QR_SLIP_TEMPLATES: Tera = {
-        match Tera::new("src/resources/templates/*.html") {
-            Ok(tera) => tera,
+        let mut tera = Tera::default();
+        match tera.add_raw_templates(TEMPLATES.iter().copied()) {
+            Ok(()) => tera,
             Err(e) => panic!("Could not load tera templates: '{}'", e),
         }
     };
-    pub static ref PDF_APPLICATION_WORKER_MANAGER: PdfApplicationWorkerManager =
-        PdfApplicationWorkerManager::new();
-    pub static ref PDF_WORKER_POOL_SIZE: usize = {
-        std::env::var("PDF_WORKER_POOL_SIZE").map_or(0, |val| {
-            val.parse::<usize>()
-                .expect("PDF_WORKER_POOL_SIZE is not a valid usize")
-        })
+    /// Hash of the names and content of all templates, part of the cache key of PDF files so that
+    /// changes to the templates invalidate cached PDF files.
+    pub static ref TEMPLATE_VERSION: String = {
+        let mut templates = Vec::new();
+        for (name, template) in TEMPLATES.iter() {
+            templates.extend_from_slice(name.as_bytes());
+            templates.extend_from_slice(template.as_bytes());
+        }
+
+        hash_bytes(&templates)
     };
 }
 
@@ -107,90 +152,468 @@ lazy_static! {
 /// expects an array of json objects (or in the case of /dbg-qr-svg just one) that can be deserialized
 /// to this struct. Additionally, this struct validates input data according to the
 /// [six specification](https://www.paymentstandards.ch/dam/downloads/ig-qr-bill-de.pdf).
-#[derive(Clone, Serialize, Deserialize, IntoPyObject, Debug, Validate)]
+///
+/// The IBAN, amount, currency and reference are typed and verified when deserializing or building
+/// the struct, see [`QrData::builder`]. The text fields are verified by [`QrData::verify`] so that
+/// they can be transliterated first.
+#[derive(Clone, Debug, Validate, Serialize, Deserialize)]
+#[serde(try_from = "QrDataJson", into = "QrDataJson")]
 #[validate(schema(function = "validate_qr_data", skip_on_field_errors = true))]
 pub struct QrData {
-    creditor_iban: String,
-    #[validate(length(min = 1, max = 70))]
+    creditor_iban: Iban,
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     creditor_name: String,
-    #[validate(length(min = 1, max = 70))]
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     creditor_address: String,
+    #[validate(custom = "validate_character_set")]
     creditor_zip_code: String,
+    #[validate(custom = "validate_character_set")]
     creditor_city: String,
     #[validate(length(min = 2, max = 2))]
     creditor_country: String,
-    #[validate(length(min = 1, max = 70))]
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     debtor_name: String,
-    #[validate(length(min = 1, max = 70))]
+    #[validate(length(min = 1, max = 70), custom = "validate_character_set")]
     debtor_address: String,
+    #[validate(custom = "validate_character_set")]
     debtor_zip_code: String,
+    #[validate(custom = "validate_character_set")]
     debtor_city: String,
     #[validate(length(min = 2, max = 2))]
     debtor_country: String,
-    #[validate(custom = "validate_amount")]
+    amount: Amount,
+    currency: Currency,
+    reference: Reference,
+    #[validate(length(max = 140), custom = "validate_character_set")]
+    additional_information: Option<String>,
+}
+
+/// The JSON representation of [`QrData`], where all fields are strings and the reference is split into
+/// its type and number.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "python", derive(FromPyObject, IntoPyObject))]
+pub(crate) struct QrDataJson {
+    creditor_iban: String,
+    creditor_name: String,
+    creditor_address: String,
+    creditor_zip_code: String,
+    creditor_city: String,
+    creditor_country: String,
+    debtor_name: String,
+    debtor_address: String,
+    debtor_zip_code: String,
+    debtor_city: String,
+    debtor_country: String,
     amount: String,
-    #[validate(custom = "validate_currency")]
     currency: String,
     reference_type: String,
     reference_number: Option<String>,
-    #[validate(length(max = 140))]
     additional_information: Option<String>,
 }
 
+impl TryFrom<QrDataJson> for QrData {
+    type Error = Error;
+
+    fn try_from(json: QrDataJson) -> Result<Self, Self::Error> {
+        QrData::from_json(json).map_err(|errors| errors[0].to_error())
+    }
+}
+
 impl QrData {
-    /// Verifies all conditions and additionally verifies and formats the IBAN
-    pub fn verify(&mut self) -> Result<(), Rejection> {
-        let iban = self.creditor_iban.parse::<Iban>().map_err(|e| {
-            warp::reject::custom(InvalidRequestInputError(format!(
-                "Provided IBAN '{}' is invalid: {}",
-                &self.creditor_iban, e
-            )))
-        })?;
-        let country = iban.country_code();
-
-        if !(country == "CH" || country == "LI") {
-            return Err(warp::reject::custom(InvalidRequestInputError(
-                String::from("Country code of IBAN must be CH or LI"),
-            )));
+    /// Converts the JSON representation, collecting the errors of all typed fields instead of
+    /// failing on the first one. The errors are in the order the fields are verified by
+    /// [`QrData::try_from`].
+    pub(crate) fn from_json(json: QrDataJson) -> Result<QrData, Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        let creditor_iban = json
+            .creditor_iban
+            .parse::<Iban>()
+            .map_err(|e| errors.push(FieldError::new("creditor_iban", "invalid_iban", e)))
+            .ok();
+        let reference_field = match json.reference_type.as_str() {
+            "QRR" | "SCOR" | "NON" => "reference_number",
+            _ => "reference_type",
+        };
+        let reference =
+            Reference::from_parts(&json.reference_type, json.reference_number.as_deref())
+                .map_err(|e| errors.push(FieldError::new(reference_field, "invalid_reference", e)))
+                .ok();
+        if let (Some(creditor_iban), Some(reference)) = (&creditor_iban, &reference) {
+            if let Err(e) = reference.verify_iban(creditor_iban) {
+                errors.push(FieldError::new(
+                    "reference_type",
+                    "reference_type_mismatch",
+                    e,
+                ));
+            }
+        }
+        let amount = json
+            .amount
+            .parse::<Amount>()
+            .map_err(|e| errors.push(FieldError::new("amount", "invalid_amount", e)))
+            .ok();
+        let currency = json
+            .currency
+            .parse::<Currency>()
+            .map_err(|e| errors.push(FieldError::new("currency", "invalid_currency", e)))
+            .ok();
+
+        match (creditor_iban, reference, amount, currency) {
+            (Some(creditor_iban), Some(reference), Some(amount), Some(currency))
+                if errors.is_empty() =>
+            {
+                Ok(QrData {
+                    creditor_iban,
+                    creditor_name: json.creditor_name,
+                    creditor_address: json.creditor_address,
+                    creditor_zip_code: json.creditor_zip_code,
+                    creditor_city: json.creditor_city,
+                    creditor_country: json.creditor_country,
+                    debtor_name: json.debtor_name,
+                    debtor_address: json.debtor_address,
+                    debtor_zip_code: json.debtor_zip_code,
+                    debtor_city: json.debtor_city,
+                    debtor_country: json.debtor_country,
+                    amount,
+                    currency,
+                    reference,
+                    additional_information: json.additional_information,
+                })
+            }
+            _ => Err(errors),
+        }
+    }
+}
+
+impl From<QrData> for QrDataJson {
+    fn from(qr_data: QrData) -> Self {
+        QrDataJson {
+            creditor_iban: qr_data.creditor_iban.electronic().to_owned(),
+            creditor_name: qr_data.creditor_name,
+            creditor_address: qr_data.creditor_address,
+            creditor_zip_code: qr_data.creditor_zip_code,
+            creditor_city: qr_data.creditor_city,
+            creditor_country: qr_data.creditor_country,
+            debtor_name: qr_data.debtor_name,
+            debtor_address: qr_data.debtor_address,
+            debtor_zip_code: qr_data.debtor_zip_code,
+            debtor_city: qr_data.debtor_city,
+            debtor_country: qr_data.debtor_country,
+            amount: qr_data.amount.to_string(),
+            currency: qr_data.currency.to_string(),
+            reference_type: qr_data.reference.reference_type().to_string(),
+            reference_number: qr_data.reference.reference_number().map(String::from),
+            additional_information: qr_data.additional_information,
         }
+    }
+}
+
+#[cfg(feature = "python")]
+impl<'source> FromPyObject<'source> for QrData {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        QrData::try_from(QrDataJson::extract(ob)?)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+    }
+}
+
+#[cfg(feature = "python")]
+impl IntoPy<PyObject> for QrData {
+    fn into_py(self, py: Python) -> PyObject {
+        QrDataJson::from(self).into_py(py)
+    }
+}
 
-        // replace with formatted string
-        self.creditor_iban = iban.to_string();
+impl QrData {
+    /// Creates a [`QrDataBuilder`] to construct a verified [`QrData`] object from typed values.
+    pub fn builder() -> QrDataBuilder {
+        QrDataBuilder::default()
+    }
 
-        self.validate().map_err(|e| {
-            warp::reject::custom(InvalidRequestInputError(format!(
-                "Validation failed for QrData: {}",
-                e
-            )))
-        })?;
+    /// Verifies the length and character set of all text fields, failing with
+    /// [`InvalidFieldsError`](Error::InvalidFieldsError) listing all invalid fields.
+    pub fn verify(&mut self) -> Result<(), Error> {
+        self.validate()
+            .map_err(|e| InvalidFieldsError(validation::field_errors(&e)))?;
 
         Ok(())
     }
+
+    /// Replaces all characters of the text fields that are not part of the character set permitted
+    /// by the specification with their closest ASCII representation, e.g. 'ő' becomes 'o'. Characters
+    /// without a permitted representation are removed.
+    pub fn transliterate(&mut self) {
+        let mut fields = [
+            &mut self.creditor_name,
+            &mut self.creditor_address,
+            &mut self.creditor_zip_code,
+            &mut self.creditor_city,
+            &mut self.debtor_name,
+            &mut self.debtor_address,
+            &mut self.debtor_zip_code,
+            &mut self.debtor_city,
+        ];
+        for field in fields.iter_mut() {
+            **field = transliterate(field);
+        }
+
+        if let Some(ref mut additional_information) = self.additional_information {
+            *additional_information = transliterate(additional_information);
+        }
+    }
+
+    pub fn creditor_iban(&self) -> &Iban {
+        &self.creditor_iban
+    }
+
+    pub fn debtor_name(&self) -> &str {
+        &self.debtor_name
+    }
+
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    pub fn reference_type(&self) -> &str {
+        self.reference.reference_type()
+    }
+
+    pub fn reference_number(&self) -> Option<&str> {
+        self.reference.reference_number()
+    }
+}
+
+/// Builder for [`QrData`] objects, all fields but the reference, which defaults to
+/// [`Reference::Non`], and the additional information are required.
+#[derive(Default)]
+pub struct QrDataBuilder {
+    creditor_iban: Option<Iban>,
+    creditor: Option<Address>,
+    debtor: Option<Address>,
+    amount: Option<Amount>,
+    currency: Option<Currency>,
+    reference: Option<Reference>,
+    additional_information: Option<String>,
+}
+
+/// Name and structured address of the creditor or debtor.
+struct Address {
+    name: String,
+    address: String,
+    zip_code: String,
+    city: String,
+    country: String,
+}
+
+impl QrDataBuilder {
+    pub fn creditor_iban(mut self, creditor_iban: Iban) -> Self {
+        self.creditor_iban = Some(creditor_iban);
+        self
+    }
+
+    /// Sets the name and address of the creditor, the country is the two letter ISO 3166-1 code.
+    pub fn creditor(
+        mut self,
+        name: impl Into<String>,
+        address: impl Into<String>,
+        zip_code: impl Into<String>,
+        city: impl Into<String>,
+        country: impl Into<String>,
+    ) -> Self {
+        self.creditor = Some(Address::new(name, address, zip_code, city, country));
+        self
+    }
+
+    /// Sets the name and address of the debtor, the country is the two letter ISO 3166-1 code.
+    pub fn debtor(
+        mut self,
+        name: impl Into<String>,
+        address: impl Into<String>,
+        zip_code: impl Into<String>,
+        city: impl Into<String>,
+        country: impl Into<String>,
+    ) -> Self {
+        self.debtor = Some(Address::new(name, address, zip_code, city, country));
+        self
+    }
+
+    pub fn amount(mut self, amount: Amount) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub fn reference(mut self, reference: Reference) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    pub fn additional_information(mut self, additional_information: impl Into<String>) -> Self {
+        self.additional_information = Some(additional_information.into());
+        self
+    }
+
+    /// Builds and [verifies](QrData::verify) the [`QrData`] object, failing if a required field is
+    /// missing or the reference does not match the IBAN.
+    pub fn build(self) -> Result<QrData, Error> {
+        let missing = |field| InvalidRequestInputError(format!("Missing required field {}", field));
+        let creditor_iban = self.creditor_iban.ok_or_else(|| missing("creditor_iban"))?;
+        let creditor = self.creditor.ok_or_else(|| missing("creditor"))?;
+        let debtor = self.debtor.ok_or_else(|| missing("debtor"))?;
+        let reference = self.reference.unwrap_or(Reference::Non);
+        reference.verify_iban(&creditor_iban)?;
+
+        let mut qr_data = QrData {
+            creditor_iban,
+            creditor_name: creditor.name,
+            creditor_address: creditor.address,
+            creditor_zip_code: creditor.zip_code,
+            creditor_city: creditor.city,
+            creditor_country: creditor.country,
+            debtor_name: debtor.name,
+            debtor_address: debtor.address,
+            debtor_zip_code: debtor.zip_code,
+            debtor_city: debtor.city,
+            debtor_country: debtor.country,
+            amount: self.amount.ok_or_else(|| missing("amount"))?,
+            currency: self.currency.ok_or_else(|| missing("currency"))?,
+            reference,
+            additional_information: self.additional_information,
+        };
+        qr_data.verify()?;
+
+        Ok(qr_data)
+    }
+}
+
+impl Address {
+    fn new(
+        name: impl Into<String>,
+        address: impl Into<String>,
+        zip_code: impl Into<String>,
+        city: impl Into<String>,
+        country: impl Into<String>,
+    ) -> Self {
+        Address {
+            name: name.into(),
+            address: address.into(),
+            zip_code: zip_code.into(),
+            city: city.into(),
+            country: country.into(),
+        }
+    }
+}
+
+/// The format of the response returned by the /generate-slip endpoint.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlipFormat {
+    /// One PDF file containing the slips for all provided [`QrData`] objects.
+    Pdf,
+    /// A ZIP archive containing a separate PDF file for each provided [`QrData`] object and a manifest.
+    Zip,
+}
+
+/// Struct containing the query parameters of the /generate-slip endpoint.
+#[derive(Deserialize)]
+pub struct GenerateSlipQuery {
+    /// The format of the response, defaults to [`SlipFormat::Pdf`].
+    pub format: Option<SlipFormat>,
+    /// Whether characters outside of the permitted character set should be transliterated instead
+    /// of failing validation, see [`QrData::transliterate`]. Defaults to false.
+    pub transliterate: Option<bool>,
+    /// The name of the QR generator plugin used to generate the qr codes, see [`QrGenerator::select`].
+    pub generator: Option<String>,
 }
 
 /// Handler for the /generate-slip endpoint which creates a PDF containing a slip for all provided
 /// [`QrData`] objects. The PDF is returned as bytes in the response body.
-pub async fn generate_slip_handler(mut qr_data_vec: Vec<QrData>) -> Result<impl Reply, Rejection> {
-    let qr_svg_vec = generate_qr_svg_for_all(&mut qr_data_vec)?;
-    let html = generate_html_slip(qr_data_vec, qr_svg_vec)?;
-    let pdf = PDF_APPLICATION_WORKER_MANAGER
-        .generate_pdf_from_html(html)
-        .await
-        .map_err(|e| e.get_rejection())?;
+///
+/// If the format query parameter is set to zip, each slip is rendered to a separate PDF file and the
+/// response body streams a ZIP archive with all PDF files and a manifest, see
+/// [`stream_slip_archive`](crate::archive::stream_slip_archive).
+///
+/// The qr codes are generated by the plugin selected by the generator query parameter or configured
+/// for the logged in principal, if any, else by the default generator.
+///
+/// All objects are validated before generating any slip, see
+/// [`validate_batch`](crate::validation::validate_batch). If any object is invalid, the response has
+/// status 400 and contains a [`ValidationReport`](crate::validation::ValidationReport) listing all
+/// invalid fields, with the messages in the language of the request.
+#[cfg(feature = "server")]
+pub async fn generate_slip_handler(
+    query: GenerateSlipQuery,
+    principal: Option<Principal>,
+    language: Language,
+    items: Vec<serde_json::Value>,
+) -> Result<impl Reply, Rejection> {
+    let generator =
+        QrGenerator::select_for_principal(query.generator.as_deref(), principal.as_ref())?;
+    let mut validation =
+        validation::validate_batch(&items, query.transliterate.unwrap_or(false), &generator)?;
+    if !validation.report.valid {
+        validation.report.localize(language);
+        return Ok(validation::invalid_response(&validation.report)?.into_response());
+    }
+
+    let response = slip_response(
+        query.format.unwrap_or(SlipFormat::Pdf),
+        &validation.qr_data_vec,
+        &validation.qr_svg_vec,
+    )
+    .await?;
+
+    Ok(response)
+}
+
+/// Generates the slips for the verified [`QrData`] objects and their qr codes in the given format and
+/// builds the response returning the file as attachment.
+#[cfg(feature = "server")]
+pub async fn slip_response(
+    format: SlipFormat,
+    qr_data_vec: &[QrData],
+    qr_svg_vec: &[String],
+) -> Result<Response<Body>, Error> {
+    let (body, content_type, file_name) = match format {
+        SlipFormat::Pdf => {
+            let pdf = generate_pdf_for_all(qr_data_vec, qr_svg_vec).await?;
+            (Body::from(pdf), "application/pdf", "qr_slips.pdf")
+        }
+        SlipFormat::Zip => {
+            let pdfs = generate_pdf_for_each(qr_data_vec, qr_svg_vec).await?;
+            let archive = crate::archive::stream_slip_archive(qr_data_vec.to_vec(), pdfs);
+            (archive, "application/zip", "qr_slips.zip")
+        }
+    };
 
-    Ok(pdf)
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(body)
+        .map_err(|_| SerialisationError)
 }
 
 /// Like [`generate_slip_handler`] but saves the PDF as a file in the local tmp/ directory.
 /// Endpoint only available in debug mode.
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "server", debug_assertions))]
 pub async fn dbg_qr_pdf_handler(mut qr_data_vec: Vec<QrData>) -> Result<impl Reply, Rejection> {
-    let qr_svg_vec = generate_qr_svg_for_all(&mut qr_data_vec)?;
-    let html = generate_html_slip(qr_data_vec, qr_svg_vec)?;
-    let pdf = PDF_APPLICATION_WORKER_MANAGER
-        .generate_pdf_from_html(html)
-        .await
-        .map_err(|e| e.get_rejection())?;
+    let qr_svg_vec = generate_qr_svg_for_all(&mut qr_data_vec, &QrGenerator::default())?;
+    let pdf = generate_pdf_for_all(&qr_data_vec, &qr_svg_vec).await?;
 
     save_bytes_to_file(&pdf, "pdf")?;
 
@@ -199,10 +622,10 @@ pub async fn dbg_qr_pdf_handler(mut qr_data_vec: Vec<QrData>) -> Result<impl Rep
 
 /// Like [`generate_slip_handler`] but only generates the html without converting it to PDF and saves
 /// it as a file in the local tmp/ directory. Endpoint only available in debug mode.
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "server", debug_assertions))]
 pub async fn dbg_qr_html_handler(mut qr_data_vec: Vec<QrData>) -> Result<impl Reply, Rejection> {
-    let qr_svg_vec = generate_qr_svg_for_all(&mut qr_data_vec)?;
-    let html = generate_html_slip(qr_data_vec, qr_svg_vec)?;
+    let qr_svg_vec = generate_qr_svg_for_all(&mut qr_data_vec, &QrGenerator::default())?;
+    let html = generate_html_slip(&qr_data_vec, &qr_svg_vec)?;
 
     save_bytes_to_file(html.as_bytes(), "html")?;
 
@@ -211,514 +634,715 @@ pub async fn dbg_qr_html_handler(mut qr_data_vec: Vec<QrData>) -> Result<impl Re
 
 /// Like [`generate_slip_handler`] but only generates the qr code svg and saves it as a file in the
 /// local tmp/ directory. Endpoint only available in debug mode.
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "server", debug_assertions))]
 pub async fn dbg_qr_svg_handler(mut qr_data: QrData) -> Result<impl Reply, Rejection> {
     qr_data.verify()?;
-    let qr_svg = generate_qr_svg(&qr_data)?;
+    let qr_svg = generate_qr_svg(&qr_data, &QrGenerator::default())?;
 
     save_bytes_to_file(qr_svg.as_bytes(), "svg")?;
 
     Ok(warp::reply())
 }
 
-#[cfg(debug_assertions)]
-fn save_bytes_to_file(bytes: &[u8], extension: &str) -> Result<(), Rejection> {
+#[cfg(all(feature = "server", debug_assertions))]
+fn save_bytes_to_file(bytes: &[u8], extension: &str) -> Result<(), Error> {
     let file_id = Uuid::new_v4();
 
     if !std::path::Path::new("tmp/").exists() {
-        std::fs::create_dir("tmp").map_err(|e| warp::reject::custom(IoError(e.to_string())))?;
+        std::fs::create_dir("tmp").map_err(|e| IoError(e.to_string()))?;
     }
 
     let mut file = fs::OpenOptions::new()
         .create(true)
         .write(true)
         .open(format!("tmp/{}.{}", file_id, extension))
-        .map_err(|e| warp::reject::custom(IoError(e.to_string())))?;
-    file.write_all(bytes)
-        .map_err(|e| warp::reject::custom(IoError(e.to_string())))?;
+        .map_err(|e| IoError(e.to_string()))?;
+    file.write_all(bytes).map_err(|e| IoError(e.to_string()))?;
 
     Ok(())
 }
 
 /// Generates a qr code rendered as an svg graphic for all provided [`QrData`] objects.
-pub fn generate_qr_svg_for_all(qr_data_vec: &mut [QrData]) -> Result<Vec<String>, Rejection> {
+pub fn generate_qr_svg_for_all(
+    qr_data_vec: &mut [QrData],
+    generator: &QrGenerator,
+) -> Result<Vec<String>, Error> {
     qr_data_vec
         .iter_mut()
         .map(|qr_data| {
             qr_data.verify()?;
-            generate_qr_svg(qr_data)
+            generate_qr_svg(qr_data, generator)
         })
-        .collect::<Result<Vec<String>, Rejection>>()
+        .collect::<Result<Vec<String>, Error>>()
 }
 
 /// Generates a qr code rendered as an svg graphic returned as String for the given [`QrData`] object.
 ///
-/// If USE_PY_QR_GENERATOR is set to true, the script resources/py/qr_generator.py is used to generate
-/// the svg, else the qrcode crate is used. The encoded qr code conforms to the
+/// The svg is generated by the given [`QrGenerator`], which is either the qrcode crate, the script
+/// resources/py/qr_generator.py or a plugin. The encoded qr code conforms to the
 /// [six specification](https://www.paymentstandards.ch/dam/downloads/ig-qr-bill-de.pdf).
-pub fn generate_qr_svg(qr_data: &QrData) -> Result<String, Rejection> {
-    if *crate::USE_PY_QR_GENERATOR {
-        Python::with_gil(|py| {
-            let module = PyModule::import(py, crate::QR_GENERATOR_MODULE)
-                .map_err(|e| py_err_into_rejection(e, py))?;
-
-            let create_qr_code_fn = module
-                .getattr("create_qr_code")
-                .map_err(|e| py_err_into_rejection(e, py))?;
-            let svg_string: String = create_qr_code_fn
-                .call1((qr_data.clone(),))
-                .map_err(|e| py_err_into_rejection(e, py))?
-                .extract()
-                .map_err(|e| py_err_into_rejection(e, py))?;
-            Ok(svg_string)
-        })
-    } else {
-        let qr_data = format!(
-            format_qr_code_data!(),
-            creditor_iban = &qr_data.creditor_iban,
-            creditor_name = &qr_data.creditor_name,
-            creditor_address = &qr_data.creditor_address,
-            creditor_zip_code = &qr_data.creditor_zip_code,
-            creditor_city = &qr_data.creditor_city,
-            creditor_country = &qr_data.creditor_country,
-            amount = &qr_data.amount,
-            currency = &qr_data.currency,
-            debtor_name = &qr_data.debtor_name,
-            debtor_address = &qr_data.debtor_address,
-            debtor_zip_code = &qr_data.debtor_zip_code,
-            debtor_city = &qr_data.debtor_city,
-            debtor_country = &qr_data.debtor_country,
-            reference_type = &qr_data.reference_type,
-            reference_number = qr_data.reference_number.as_deref().unwrap_or(""),
-            additional_information = qr_data.additional_information.as_deref().unwrap_or(""),
-        );
-
-        let qr_code = QrCode::with_error_correction_level(qr_data, EcLevel::Q)
-            .map_err(|e| warp::reject::custom(QrCodeError(e.to_string())))?;
-
-        let module_pixels = 4;
-        let mut qr_svg: String = qr_code
-            .render::<svg::Color>()
-            .module_dimensions(module_pixels, module_pixels)
-            .build();
-
-        let width_in_modules = qr_code.width();
-        // add two quiet zones of 4 modules each
-        let pixel_width = module_pixels as usize * (width_in_modules + 4 * 2);
-        let center = pixel_width / 2;
-
-        // insert swiss cross element ahead of the closing tag
-        if let Some(pos) = qr_svg.rfind("</svg>") {
-            qr_svg.insert_str(
-                pos,
-                &format!(
-                    format_qr_swiss_cross!(),
-                    outer = center - (36 / 2),
-                    inner = center - (24 / 2),
-                    cross_wide = center - (16 / 2),
-                    cross_short = center - (4 / 2),
-                ),
-            );
+///
+/// Generated svgs are stored in the [`CONTENT_CACHE`] so that the same qr code is only generated once.
+pub fn generate_qr_svg(qr_data: &QrData, generator: &QrGenerator) -> Result<String, Error> {
+    let cache_key = CacheKey::new("svg", &(generator.cache_id(), qr_data));
+    if let Some(qr_svg) = CONTENT_CACHE.get(&cache_key) {
+        if let Ok(qr_svg) = String::from_utf8(qr_svg) {
+            return Ok(qr_svg);
         }
+    }
+
+    let qr_svg = match generator {
+        QrGenerator::Native => render_native_qr_svg(qr_data)?,
+        #[cfg(feature = "python")]
+        QrGenerator::Script => render_python_qr_svg(qr_data)?,
+        #[cfg(feature = "python")]
+        QrGenerator::Plugin(plugin) => plugin.create_qr_svg(qr_data)?,
+    };
+    CONTENT_CACHE.insert(&cache_key, qr_svg.as_bytes());
 
-        Ok(qr_svg)
+    Ok(qr_svg)
+}
+
+/// Generates the qr code svg for the given [`QrData`] object without using the python script.
+pub fn render_native_qr_svg(qr_data: &QrData) -> Result<String, Error> {
+    let qr_code = encode_qr_payload(qr_data)?;
+
+    Ok(render_swiss_qr_svg(&qr_code))
+}
+
+/// Generates the qr code svg for the given [`QrData`] object using the create_qr_code function of
+/// the python script.
+#[cfg(feature = "python")]
+pub fn render_python_qr_svg(qr_data: &QrData) -> Result<String, Error> {
+    call_qr_generator_fn("create_qr_code", qr_data)
+}
+
+/// Builds the data encoded in the qr code for the given [`QrData`] object using the build_qr_payload
+/// function of the python script.
+#[cfg(feature = "python")]
+pub fn build_python_qr_payload(qr_data: &QrData) -> Result<String, Error> {
+    call_qr_generator_fn("build_qr_payload", qr_data)
+}
+
+#[cfg(feature = "python")]
+fn call_qr_generator_fn(name: &str, qr_data: &QrData) -> Result<String, Error> {
+    Python::with_gil(|py| {
+        let module = qr_generator_module(py).map_err(|e| py_err_into_error(e, py))?;
+
+        let function = module.getattr(name).map_err(|e| py_err_into_error(e, py))?;
+        let result: String = function
+            .call1((qr_data.clone(),))
+            .map_err(|e| py_err_into_error(e, py))?
+            .extract()
+            .map_err(|e| py_err_into_error(e, py))?;
+        Ok(result)
+    })
+}
+
+/// Returns the qr generator python module, which is compiled on startup if USE_PY_QR_GENERATOR is
+/// set to true and else compiled when first used.
+#[cfg(feature = "python")]
+fn qr_generator_module<'py>(py: Python<'py>) -> PyResult<&'py PyModule> {
+    PyModule::import(py, crate::QR_GENERATOR_MODULE).or_else(|_| {
+        PyModule::from_code(
+            py,
+            crate::QR_GENERATOR_SCRIPT,
+            "resources/py/qr_generator.py",
+            crate::QR_GENERATOR_MODULE,
+        )
+    })
+}
+
+/// Encodes the payload of the given [`QrData`] object using error correction level M as prescribed by
+/// the specification and fails with a [`QrCodeError`] if the qr code would exceed the maximum
+/// permitted version 25.
+pub fn encode_qr_payload(qr_data: &QrData) -> Result<QrCode, Error> {
+    let qr_code = QrCode::with_error_correction_level(build_qr_payload(qr_data), EcLevel::M)
+        .map_err(|e| QrCodeError(e.to_string()))?;
+
+    let version = match qr_code.version() {
+        Version::Normal(version) | Version::Micro(version) => version,
+    };
+
+    if version > MAX_QR_VERSION {
+        return Err(QrCodeError(format!(
+            "Encoding the data requires QR code version {}, but the maximum permitted version is {}",
+            version, MAX_QR_VERSION
+        )));
+    }
+
+    Ok(qr_code)
+}
+
+/// Builds the data encoded in the qr code for the given [`QrData`] object as defined by the
+/// [six specification](https://www.paymentstandards.ch/dam/downloads/ig-qr-bill-de.pdf).
+pub fn build_qr_payload(qr_data: &QrData) -> String {
+    format!(
+        format_qr_code_data!(),
+        creditor_iban = qr_data.creditor_iban.electronic(),
+        creditor_name = &qr_data.creditor_name,
+        creditor_address = &qr_data.creditor_address,
+        creditor_zip_code = &qr_data.creditor_zip_code,
+        creditor_city = &qr_data.creditor_city,
+        creditor_country = &qr_data.creditor_country,
+        amount = qr_data.amount,
+        currency = qr_data.currency,
+        debtor_name = &qr_data.debtor_name,
+        debtor_address = &qr_data.debtor_address,
+        debtor_zip_code = &qr_data.debtor_zip_code,
+        debtor_city = &qr_data.debtor_city,
+        debtor_country = &qr_data.debtor_country,
+        reference_type = qr_data.reference.reference_type(),
+        reference_number = qr_data.reference.reference_number().unwrap_or(""),
+        additional_information = qr_data.additional_information.as_deref().unwrap_or(""),
+    )
+}
+
+/// Renders the given qr code as svg with the swiss cross in its center.
+///
+/// One unit of the view box equals one module, the dimensions of the svg are set so that the qr code
+/// always measures 46 x 46 mm surrounded by a 5 mm quiet zone and the swiss cross always measures
+/// 7 x 7 mm, independent of the version of the qr code.
+fn render_swiss_qr_svg(qr_code: &QrCode) -> String {
+    let width = qr_code.width();
+    let modules_per_mm = width as f64 / QR_CODE_SIZE_MM;
+    let quiet_zone = QR_QUIET_ZONE_MM * modules_per_mm;
+
+    // draw each horizontal run of dark modules as one rectangle
+    let mut modules = String::new();
+    for (y, row) in qr_code.to_colors().chunks(width).enumerate() {
+        let mut x = 0;
+        while x < width {
+            if row[x] == Color::Dark {
+                let run_start = x;
+                while x < width && row[x] == Color::Dark {
+                    x += 1;
+                }
+                modules.push_str(&format!(
+                    "M{} {}h{}v1h-{}z",
+                    run_start,
+                    y,
+                    x - run_start,
+                    x - run_start
+                ));
+            } else {
+                x += 1;
+            }
+        }
     }
+
+    let center = width as f64 / 2.0;
+    let cross_size = SWISS_CROSS_SIZE_MM * modules_per_mm;
+    let square_size = (SWISS_CROSS_SIZE_MM - 2.0 * SWISS_CROSS_BORDER_MM) * modules_per_mm;
+    // proportions of the swiss flag, the arms of the cross are 6/32 wide and span 20/32 of the square
+    let arm_length = square_size * 20.0 / 32.0;
+    let arm_width = square_size * 6.0 / 32.0;
+
+    let swiss_cross = format!(
+        format_qr_swiss_cross!(),
+        cross_pos = center - cross_size / 2.0,
+        cross_size = cross_size,
+        square_pos = center - square_size / 2.0,
+        square_size = square_size,
+        arm_long_pos = center - arm_length / 2.0,
+        arm_short_pos = center - arm_width / 2.0,
+        arm_length = arm_length,
+        arm_width = arm_width,
+    );
+
+    format!(
+        format_qr_svg!(),
+        size_mm = QR_CODE_SIZE_MM + 2.0 * QR_QUIET_ZONE_MM,
+        origin = -quiet_zone,
+        size = width as f64 + 2.0 * quiet_zone,
+        modules = modules,
+        swiss_cross = swiss_cross,
+    )
+}
+
+/// Generates the qr code for the given [`QrData`] object as png image with the swiss cross in its
+/// center, using the same dimensions as the svg rendered by the qrcode crate at 300 dpi.
+///
+/// Unlike svgs, png images are always rendered natively, independent of the [`QrGenerator`].
+#[cfg(feature = "png")]
+pub fn render_native_qr_png(qr_data: &QrData) -> Result<Vec<u8>, Error> {
+    let qr_code = encode_qr_payload(qr_data)?;
+    let colors = qr_code.to_colors();
+    let width = qr_code.width();
+    let modules_per_mm = width as f64 / QR_CODE_SIZE_MM;
+    let quiet_zone = QR_QUIET_ZONE_MM * modules_per_mm;
+    let size_px = ((QR_CODE_SIZE_MM + 2.0 * QR_QUIET_ZONE_MM) * PNG_PIXELS_PER_MM).round() as u32;
+    let modules_per_px = (width as f64 + 2.0 * quiet_zone) / size_px as f64;
+
+    let center = width as f64 / 2.0;
+    let cross_half = SWISS_CROSS_SIZE_MM * modules_per_mm / 2.0;
+    let square_half = (SWISS_CROSS_SIZE_MM - 2.0 * SWISS_CROSS_BORDER_MM) * modules_per_mm / 2.0;
+    let arm_half_length = square_half * 20.0 / 32.0;
+    let arm_half_width = square_half * 6.0 / 32.0;
+
+    let image = GrayImage::from_fn(size_px, size_px, |px, py| {
+        // position of the center of the pixel in modules relative to the top left module
+        let x = (px as f64 + 0.5) * modules_per_px - quiet_zone;
+        let y = (py as f64 + 0.5) * modules_per_px - quiet_zone;
+        let (dx, dy) = ((x - center).abs(), (y - center).abs());
+
+        let dark = if dx < cross_half && dy < cross_half {
+            let in_square = dx < square_half && dy < square_half;
+            let in_cross = (dx < arm_half_length && dy < arm_half_width)
+                || (dx < arm_half_width && dy < arm_half_length);
+            in_square && !in_cross
+        } else if x >= 0.0 && y >= 0.0 && x < width as f64 && y < width as f64 {
+            colors[y as usize * width + x as usize] == Color::Dark
+        } else {
+            false
+        };
+
+        Luma([if dark { 0 } else { 255 }])
+    });
+
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .encode(image.as_raw(), size_px, size_px, ColorType::L8)
+        .map_err(|e| QrCodeError(e.to_string()))?;
+
+    Ok(png)
 }
 
 /// Generates the HTML file containing all qr slips for the provided [`QrData`] elements. The `qr_svg_vec`
 /// contains the generated qr code for each object in `qr_data_vec` in the same position. Both parameters
-/// are supplied to the context for the tera template which produces the HTML output.
-pub fn generate_html_slip(
-    qr_data_vec: Vec<QrData>,
-    qr_svg_vec: Vec<String>,
-) -> Result<String, Rejection> {
+/// are supplied to the context for the tera template which produces the HTML output, along with the
+/// IBAN of each object in the paper format printed on the slip.
+pub fn generate_html_slip(qr_data_vec: &[QrData], qr_svg_vec: &[String]) -> Result<String, Error> {
+    let creditor_iban_vec = qr_data_vec
+        .iter()
+        .map(|qr_data| qr_data.creditor_iban.to_string())
+        .collect::<Vec<_>>();
     let mut context = tera::Context::new();
     context.insert("qr_data_vec", &qr_data_vec);
     context.insert("qr_code_vec", &qr_svg_vec);
+    context.insert("creditor_iban_vec", &creditor_iban_vec);
     QR_SLIP_TEMPLATES
         .render("qr_slip.html", &context)
         .map_err(|e| {
-            warp::reject::custom(TeraError(format!(
+            TeraError(format!(
                 "{}: {}",
                 e,
                 e.source().map_or("None".to_owned(), |s| s.to_string())
-            )))
+            ))
         })
 }
 
+#[cfg(feature = "python")]
 #[inline]
-fn py_err_into_rejection(e: PyErr, py: Python) -> Rejection {
-    warp::reject::custom(PythonError(e.pvalue(py).to_string()))
-}
-
-pub type PdfResult = Result<Vec<u8>, PdfApplicationError>;
-
-/// Struct managing a worker thread, or worker process pool if PDF_WORKER_POOL_SIZE is set to a non-zero
-/// value, that manages a wkhtmltopdf PdfApplication which builds PDF files from HTML input.
-/// The worker process pool option is only supported on macOS or Linux.
-pub struct PdfApplicationWorkerManager {
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
-    pool: Option<procspawn::Pool>,
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
-    html_channel: Option<Sender<(String, oneshot::Sender<PdfResult>)>>,
-    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-    html_channel: Sender<(String, oneshot::Sender<PdfResult>)>,
-}
-
-impl PdfApplicationWorkerManager {
-
-    /// Creates a new PdfApplicationWorkerManager by spawning the worker thread if PDF_WORKER_POOL_SIZE
-    /// is not set to a non-zero or sets up a process pool with the size provided by PDF_WORKER_POOL_SIZE.
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
-    pub fn new() -> Self {
-        if *PDF_WORKER_POOL_SIZE > 0 {
-            let pool = match procspawn::Pool::new(*PDF_WORKER_POOL_SIZE) {
-                Ok(pool) => pool,
-                Err(e) => panic!(
-                    "Failed to spawn PdfApplication worker process: '{}'",
-                    e.to_string()
-                ),
-            };
-
-            log::info!(
-                "Set up PDF worker process pool with {} processes",
-                *PDF_WORKER_POOL_SIZE
-            );
-
-            Self {
-                pool: Some(pool),
-                html_channel: None,
-            }
-        } else {
-            Self::new_single_threaded_worker()
-        }
-    }
+pub fn py_err_into_error(e: PyErr, py: Python) -> Error {
+    PythonError(e.pvalue(py).to_string())
+}
 
-    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-    pub fn new() -> Self {
-        if *PDF_WORKER_POOL_SIZE > 0 {
-            log::warn!("PDF_WORKER_POOL_SIZE set but the current platform does not support procspawn, falling back to single worker thread.");
-        }
-        Self::new_single_threaded_worker()
-    }
-
-    /// Submits a task to the worker thread or process pool to convert the provided HTML string to
-    /// a Vec of bytes representing a PDF file.
-    #[cfg(any(target_os = "macos", target_os = "linux"))]
-    pub async fn generate_pdf_from_html(&self, html: String) -> PdfResult {
-        if let Some(ref pool) = self.pool {
-            let join_handle: procspawn::JoinHandle<Result<Vec<u8>, String>> =
-                pool.spawn(html, |html| {
-                    std::thread_local! {
-                        static PDF_APPLICATION: RefCell<PdfApplication> = match PdfApplication::new() {
-                            Ok(p) => {
-                                log::debug!("Initialised PdfApplication for worker process");
-                                RefCell::new(p)
-                            },
-                            Err(e) => {
-                                panic!("Failed to initialise PdfApplication: '{}'", e.to_string())
-                            }
-                        };
-                    };
-
-                    log::debug!("PDF worker process received html");
-                    PDF_APPLICATION.with(|pdf_application| {
-                        convert_html_to_pdf(&mut *pdf_application.borrow_mut(), &html).map_err(|e| e.to_string())
-                    })
-                });
-
-            match join_handle.join() {
-                Ok(result) => result.map_err(PdfApplicationError::RawError),
-                Err(_) => Err(PdfApplicationError::SpawnError),
-            }
-        } else if let Some(ref html_channel) = self.html_channel {
-            let (result_sender, result_receiver) = oneshot::channel::<PdfResult>();
-            html_channel
-                .send((html, result_sender))
-                .expect("Html channel disconnected unexpectedly");
-            result_receiver
-                .await
-                .expect("Pdf result channel has closed unexpectedly")
-        } else {
-            panic!("Either pool or html_channel must be set")
-        }
+fn validate_qr_data(qr_data: &QrData) -> Result<(), ValidationError> {
+    if qr_data.creditor_zip_code.len() + qr_data.creditor_city.len() > 69 {
+        return Err(zip_code_city_length_error("creditor"));
     }
 
-    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-    pub async fn generate_pdf_from_html(&self, html: String) -> PdfResult {
-        let (result_sender, result_receiver) = oneshot::channel::<PdfResult>();
-        self.html_channel
-            .send((html, result_sender))
-            .expect("Html channel disconnected unexpectedly");
-        result_receiver
-            .await
-            .expect("Pdf result channel has closed unexpectedly")
-    }
-
-    fn new_single_threaded_worker() -> Self {
-        let (html_sender, html_receiver) =
-            crossbeam_channel::unbounded::<(String, oneshot::Sender<PdfResult>)>();
-
-        std::thread::Builder::new()
-            .name(String::from("pdf_worker"))
-            .spawn(move || {
-                let mut pdf_application = match PdfApplication::new() {
-                    Ok(pdf_application) => {
-                        log::debug!("Initialised PdfApplication for worker thread");
-                        pdf_application
-                    },
-                    Err(e) => panic!("Failed to initialise wkhtmltopdf: {}", e.to_string()),
-                };
-
-                loop {
-                    let (html, result_sender) = html_receiver
-                        .recv()
-                        .expect("Html channel disconnected unexpectedly");
-
-                    log::debug!("PDF worker thread received html");
-                    // flatten `Result<Result<T, E>, E>` to `Result<T, E>` manually as flatten() is currently nightly only
-                    let flattened_pdf_result = convert_html_to_pdf(&mut pdf_application, &html);
-
-                    result_sender
-                        .send(flattened_pdf_result)
-                        .expect("Pdf result channel has closed unexpectedly");
-                }
-            })
-            .expect("Failed to spawn pdf_worker thread");
+    if qr_data.debtor_zip_code.len() + qr_data.debtor_city.len() > 69 {
+        return Err(zip_code_city_length_error("debtor"));
+    }
 
-        log::info!("Set up PDF worker thread.");
+    Ok(())
+}
 
-        #[cfg(any(target_os = "macos", target_os = "linux"))]
-        return Self {
-            pool: None,
-            html_channel: Some(html_sender),
-        };
+fn zip_code_city_length_error(party: &str) -> ValidationError {
+    let mut error = ValidationError::new("zip_code_city_length");
+    error.message = Some(Cow::from(format!(
+        "Combined length of {} zip code and city may not exceed 69",
+        party
+    )));
+    error.add_param(Cow::from("field"), &format!("{}_city", party));
+    error.add_param(Cow::from("max"), &69);
+    error
+}
 
-        #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-        return Self {
-            html_channel: html_sender,
-        };
+pub(crate) fn validate_character_set(value: &str) -> Result<(), ValidationError> {
+    if !value.chars().all(is_permitted_char) {
+        let mut error = ValidationError::new("character_set");
+        error.message = Some(Cow::from(
+            "Value contains characters outside of the latin character set permitted by the specification",
+        ));
+        return Err(error);
     }
+
+    Ok(())
 }
 
-/// Convert the given HTML string to a Vec of bytes representing a PDF file using the given PdfApplication.
-fn convert_html_to_pdf(pdf_application: &mut PdfApplication, html: &str) -> PdfResult {
-    use wkhtmltopdf::Orientation;
-    use wkhtmltopdf::Size;
-
-    log::debug!("Converting html to pdf");
-    let pdf_result = pdf_application
-        .builder()
-        .title("Qr Slip")
-        .orientation(Orientation::Portrait)
-        .margin(Size::Millimeters(0))
-        .build_from_html(html)
-        .map(|output| {
-            output
-                .bytes()
-                .collect::<Result<Vec<u8>, io::Error>>()
-                .map_err(PdfApplicationError::IoError)
-        })
-        .map_err(PdfApplicationError::WkhtmlError);
+fn is_permitted_char(c: char) -> bool {
+    (' '..='~').contains(&c) || PERMITTED_SPECIAL_CHARS.contains(c)
+}
 
-    log::debug!("Done converting html to pdf");
+fn transliterate(value: &str) -> String {
+    let mut transliterated = String::with_capacity(value.len());
 
-    // flatten `Result<Result<T, E>, E>` to `Result<T, E>` manually as flatten() is currently nightly only
-    match pdf_result {
-        Ok(Err(e)) => Err(e),
-        Ok(Ok(bytes)) => Ok(bytes),
-        Err(e) => Err(e),
+    for c in value.chars() {
+        if is_permitted_char(c) {
+            transliterated.push(c);
+        } else if let Some(replacement) = deunicode::deunicode_char(c) {
+            transliterated.extend(replacement.chars().filter(|c| is_permitted_char(*c)));
+        }
     }
+
+    transliterated
 }
 
-impl Default for PdfApplicationWorkerManager {
-    fn default() -> Self {
-        PdfApplicationWorkerManager::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qr_data(
+        name: &str,
+        address: &str,
+        zip_code: &str,
+        city: &str,
+        reference_type: &str,
+        reference_number: Option<&str>,
+        additional_information: Option<&str>,
+    ) -> QrData {
+        QrData {
+            creditor_iban: if reference_type == "QRR" {
+                "CH4431999123000889012"
+            } else {
+                "CH9300762011623852957"
+            }
+            .parse()
+            .unwrap(),
+            creditor_name: name.to_string(),
+            creditor_address: address.to_string(),
+            creditor_zip_code: zip_code.to_string(),
+            creditor_city: city.to_string(),
+            creditor_country: String::from("CH"),
+            debtor_name: name.to_string(),
+            debtor_address: address.to_string(),
+            debtor_zip_code: zip_code.to_string(),
+            debtor_city: city.to_string(),
+            debtor_country: String::from("CH"),
+            amount: "999999999.99".parse().unwrap(),
+            currency: Currency::Chf,
+            reference: Reference::from_parts(reference_type, reference_number).unwrap(),
+            additional_information: additional_information.map(String::from),
+        }
     }
-}
 
-#[derive(Debug)]
-pub enum PdfApplicationError {
-    WkhtmlError(wkhtmltopdf::Error),
-    IoError(io::Error),
-    /// When an error is received across process boundaries only the message is serialised,
-    /// the message is then wrapped in this enum variant to transform it back to a PdfApplicationError.
-    RawError(String),
-    /// Error returned when spawning a PDF worker process fails
-    SpawnError,
-}
+    fn short_qr_data() -> QrData {
+        qr_data("A", "B", "1", "C", "NON", None, None)
+    }
 
-impl std::error::Error for PdfApplicationError {}
+    fn max_length_qr_data() -> QrData {
+        qr_data(
+            &"N".repeat(70),
+            &"A".repeat(70),
+            &"9".repeat(34),
+            &"C".repeat(35),
+            "QRR",
+            Some("210000000003139471430009017"),
+            Some(&"I".repeat(140)),
+        )
+    }
 
-impl Display for PdfApplicationError {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            PdfApplicationError::WkhtmlError(ref e) => formatter.write_str(&e.to_string()),
-            PdfApplicationError::IoError(ref e) => formatter.write_str(&e.to_string()),
-            PdfApplicationError::RawError(ref e) => formatter.write_str(e),
-            PdfApplicationError::SpawnError => {
-                formatter.write_str("Failed to spawn PdfApplication worker process")
-            }
-        }
+    fn attribute(element: &str, name: &str) -> String {
+        let start = element
+            .find(&format!(" {}=\"", name))
+            .unwrap_or_else(|| panic!("missing attribute {} in {}", name, element))
+            + name.len()
+            + 3;
+        let end = start + element[start..].find('"').unwrap();
+        element[start..end].to_string()
     }
-}
 
-impl PdfApplicationError {
-    pub fn get_rejection(&self) -> Rejection {
-        warp::reject::custom(PdfError(self.to_string()))
+    fn number_attribute(element: &str, name: &str) -> f64 {
+        attribute(element, name).parse().unwrap()
     }
-}
 
-fn validate_qr_data(qr_data: &QrData) -> Result<(), ValidationError> {
-    if qr_data.creditor_zip_code.len() + qr_data.creditor_city.len() > 69 {
-        return Err(ValidationError::new(
-            "Combined length of creditor zip code and city may not exceed 69",
-        ));
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "expected {} but was {}",
+            expected,
+            actual
+        );
     }
 
-    if qr_data.debtor_zip_code.len() + qr_data.debtor_city.len() > 69 {
-        return Err(ValidationError::new(
-            "Combined length of debtor zip code and city may not exceed 69",
-        ));
+    /// Renders the given data and returns the qr code along with its svg.
+    fn render(qr_data: &QrData) -> (QrCode, String) {
+        let qr_code = encode_qr_payload(qr_data).unwrap();
+        let svg = render_swiss_qr_svg(&qr_code);
+        (qr_code, svg)
     }
 
-    match qr_data.reference_type.as_str() {
-        "QRR" => match qr_data.reference_number {
-            Some(ref reference_number) if !reference_number.is_empty() => {
-                if reference_number.len() != 27 {
-                    return Err(ValidationError::new(
-                        "Reference number must be of length 27 when the reference type is QRR",
-                    ));
-                }
+    fn assert_proportions(qr_data: &QrData) {
+        let (qr_code, svg) = render(qr_data);
+        let elements: Vec<&str> = svg.split('<').skip(1).collect();
+        let root = elements[0];
+
+        assert_eq!(attribute(root, "width"), "56mm");
+        assert_eq!(attribute(root, "height"), "56mm");
+
+        let view_box: Vec<f64> = attribute(root, "viewBox")
+            .split(' ')
+            .map(|v| v.parse().unwrap())
+            .collect();
+        assert_eq!(view_box.len(), 4);
+        assert_close(view_box[2], view_box[3]);
+
+        let mm_per_unit = 56.0 / view_box[2];
+        let width = qr_code.width() as f64;
+        assert_close(width * mm_per_unit, QR_CODE_SIZE_MM);
+        assert_close(-view_box[0] * mm_per_unit, QR_QUIET_ZONE_MM);
+
+        // the first rectangle after the modules is the white border of the swiss cross
+        let cross: Vec<&str> = elements
+            .iter()
+            .skip_while(|e| !e.starts_with("path"))
+            .filter(|e| e.starts_with("rect"))
+            .cloned()
+            .collect();
+        assert_eq!(cross.len(), 4);
+
+        let cross_size = number_attribute(cross[0], "width");
+        assert_close(cross_size * mm_per_unit, SWISS_CROSS_SIZE_MM);
+        assert_close(
+            number_attribute(cross[0], "height") * mm_per_unit,
+            SWISS_CROSS_SIZE_MM,
+        );
+        assert_close(
+            number_attribute(cross[0], "x") + cross_size / 2.0,
+            width / 2.0,
+        );
+        assert_close(
+            number_attribute(cross[0], "y") + cross_size / 2.0,
+            width / 2.0,
+        );
 
-                if !reference_number.chars().all(|c| c.is_digit(10)) {
-                    return Err(ValidationError::new(
-                        "Reference number must be numerical when the reference type is QRR",
-                    ));
-                }
+        let square_size = number_attribute(cross[1], "width");
+        assert_close(
+            square_size * mm_per_unit,
+            SWISS_CROSS_SIZE_MM - 2.0 * SWISS_CROSS_BORDER_MM,
+        );
 
-                if !is_qr_iban(&qr_data.creditor_iban) {
-                    return Err(ValidationError::new("IBAN must be a QR-IBAN (1-based position 5-9 must be between 30000 and 31999) when the reference type is QRR"));
-                }
-            }
-            _ => {
-                return Err(ValidationError::new(
-                    "Reference number must be provided when the reference type is QRR",
-                ));
-            }
-        },
-        "SCOR" => {
-            match qr_data.reference_number {
-                Some(ref reference_number) if !reference_number.is_empty() => {
-                    if reference_number.len() < 5 || reference_number.len() > 25 {
-                        return Err(ValidationError::new("Reference number must be of length 5 - 25 when the reference type is SCOR"));
-                    }
-
-                    if !reference_number.chars().all(|c| c.is_alphanumeric()) {
-                        return Err(ValidationError::new(
-                            "Reference number must be alphanumeric when the reference type is SCOR",
-                        ));
-                    }
-
-                    if is_qr_iban(&qr_data.creditor_iban) {
-                        return Err(ValidationError::new(
-                            "Reference type must be QRR if the IBAN is a QR-IBAN",
-                        ));
-                    }
-                }
-                _ => {
-                    return Err(ValidationError::new(
-                        "Reference number must be provided when the reference type is SCOR",
-                    ));
-                }
-            }
-        }
-        "NON" => match qr_data.reference_number {
-            Some(ref reference_number) if !reference_number.is_empty() => {
-                return Err(ValidationError::new(
-                    "Reference number must not be provided when the reference type is NON",
-                ));
-            }
-            _ => {
-                if is_qr_iban(&qr_data.creditor_iban) {
-                    return Err(ValidationError::new(
-                        "Reference type must be QRR if the IBAN is a QR-IBAN",
-                    ));
-                }
-            }
-        },
-        _ => {
-            return Err(ValidationError::new(
-                "Reference type must be QRR, SCOR or NON",
-            ));
-        }
+        let arm_length = number_attribute(cross[2], "width");
+        let arm_width = number_attribute(cross[2], "height");
+        assert_close(arm_length / square_size, 20.0 / 32.0);
+        assert_close(arm_width / square_size, 6.0 / 32.0);
+        assert_close(number_attribute(cross[3], "width"), arm_width);
+        assert_close(number_attribute(cross[3], "height"), arm_length);
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_proportions_short_payload() {
+        assert_proportions(&short_qr_data());
+    }
 
-#[inline]
-fn is_qr_iban(iban: &str) -> bool {
-    let iid = match (&iban[4..9]).parse::<u32>() {
-        Ok(iid) => iid,
-        Err(_) => return false,
-    };
+    #[test]
+    fn test_proportions_max_length_payload() {
+        assert_proportions(&max_length_qr_data());
+    }
 
-    (30000..=31999).contains(&iid)
-}
+    #[test]
+    fn test_payload_length_changes_version() {
+        let (short, _) = render(&short_qr_data());
+        let (long, _) = render(&max_length_qr_data());
 
-fn validate_amount(amount: &str) -> Result<(), ValidationError> {
-    let split = amount.split('.').collect::<Vec<&str>>();
+        assert!(long.width() > short.width());
+    }
 
-    if split.len() != 2 {
-        return Err(ValidationError::new(
-            "Decimal amount not formatted correctly, expected both integral and fractional parts",
-        ));
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_png() {
+        let png = render_native_qr_png(&short_qr_data()).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+
+        // 56 mm at 300 dpi
+        assert_eq!(image.dimensions(), (661, 661));
+        assert_eq!(image.get_pixel(0, 0), &Luma([255]));
+        // the center of the swiss cross is white, the corners of its black square are dark
+        assert_eq!(image.get_pixel(330, 330), &Luma([255]));
+        let square_corner = (330.0 - 3.0 * PNG_PIXELS_PER_MM) as u32 + 2;
+        assert_eq!(image.get_pixel(square_corner, square_corner), &Luma([0]));
+        // the finder pattern in the top left corner starts after the quiet zone
+        let finder = (QR_QUIET_ZONE_MM * PNG_PIXELS_PER_MM) as u32 + 2;
+        assert_eq!(image.get_pixel(finder, finder), &Luma([0]));
     }
 
-    let integral_str = split[0];
-    let fractional_str = split[1];
+    #[test]
+    fn test_max_version_exceeded() {
+        let umlauts = |len| "ä".repeat(len);
+        let qr_data = qr_data(
+            &umlauts(70),
+            &umlauts(70),
+            &"9".repeat(34),
+            &"C".repeat(35),
+            "QRR",
+            Some("210000000003139471430009017"),
+            Some(&umlauts(140)),
+        );
 
-    if integral_str.starts_with('0') {
-        return Err(ValidationError::new(
-            "Decimal amount not formatted correctly, amount must not start with leading 0s",
-        ));
+        qr_data.validate().unwrap();
+        match encode_qr_payload(&qr_data) {
+            Ok(_) => panic!("expected encoding to fail"),
+            Err(e) => assert!(matches!(e, QrCodeError(_))),
+        }
     }
 
-    if fractional_str.len() != 2 {
-        return Err(ValidationError::new(
-            "Decimal amount not formatted correctly, amount must contain 2 fractional digits",
-        ));
+    #[test]
+    fn test_character_set() {
+        assert!(validate_character_set("Hans Müller-Zäch, Rue de l'Église 3").is_ok());
+        assert!(validate_character_set("Ödön Bőr").is_err());
+        assert!(validate_character_set("Иван").is_err());
+        assert!(validate_character_set("Hans 🙂").is_err());
+        assert!(validate_character_set("Zeile 1\nZeile 2").is_err());
+
+        let mut qr_data = qr_data("Ödön Bőr", "Łódź 1", "1", "Zürich", "NON", None, Some("🙂"));
+        assert!(qr_data.validate().is_err());
+
+        qr_data.transliterate();
+        assert_eq!(qr_data.creditor_name, "Ödön Bor");
+        assert_eq!(qr_data.creditor_address, "Lódz 1");
+        assert_eq!(qr_data.creditor_city, "Zürich");
+        assert!(qr_data.validate().is_ok());
     }
 
-    let integral = integral_str.parse::<u32>().map_err(|_| {
-        ValidationError::new("Decimal amount not formatted correctly, integral is not a valid u32")
-    })?;
-    let fractional = fractional_str.parse::<u32>().map_err(|_| {
-        ValidationError::new(
-            "Decimal amount not formatted correctly, fractional is not a valid u32",
-        )
-    })?;
+    fn qr_data_json(reference_type: &str, reference_number: &str) -> serde_json::Value {
+        serde_json::json!({
+            "creditor_iban": "CH4431999123000889012",
+            "creditor_name": "Tobias Rothlin",
+            "creditor_address": "Peterliwiese 33",
+            "creditor_zip_code": "8855",
+            "creditor_city": "Wangen SZ",
+            "creditor_country": "CH",
+            "debtor_name": "Hans Muster",
+            "debtor_address": "Sonnenstrasse 31",
+            "debtor_zip_code": "2000",
+            "debtor_city": "Schöningen",
+            "debtor_country": "CH",
+            "amount": "5000.00",
+            "currency": "CHF",
+            "reference_type": reference_type,
+            "reference_number": reference_number,
+            "additional_information": null
+        })
+    }
 
-    if integral == 0 && fractional == 0 {
-        return Err(ValidationError::new(
-            "Decimal amount not formatted correctly, amount must be at least 0.01",
-        ));
+    #[test]
+    fn test_json_format() {
+        let json = qr_data_json("QRR", "210000000003139471430009017");
+        let qr_data = serde_json::from_value::<QrData>(json.clone()).unwrap();
+        assert_eq!(qr_data.amount().cents(), 500000);
+        assert_eq!(qr_data.currency(), Currency::Chf);
+        assert_eq!(
+            qr_data.reference(),
+            &Reference::Qrr(String::from("210000000003139471430009017"))
+        );
+
+        assert_eq!(serde_json::to_value(&qr_data).unwrap(), json);
+
+        // a QR-IBAN requires a QR reference
+        assert!(serde_json::from_value::<QrData>(qr_data_json("NON", "")).is_err());
+        assert!(
+            serde_json::from_value::<QrData>(qr_data_json("SCOR", "RF18539007547034")).is_err()
+        );
+        assert!(serde_json::from_value::<QrData>(qr_data_json("QRR", "123")).is_err());
+
+        let mut invalid_amount = qr_data_json("QRR", "210000000003139471430009017");
+        invalid_amount["amount"] = serde_json::json!("5000");
+        assert!(serde_json::from_value::<QrData>(invalid_amount).is_err());
     }
 
-    if integral > 999999999 {
-        return Err(ValidationError::new(
-            "Decimal amount not formatted correctly, amount may not exceed 999999999.99",
-        ));
+    #[test]
+    fn test_builder() {
+        let builder = || {
+            QrData::builder()
+                .creditor_iban("CH9300762011623852957".parse().unwrap())
+                .creditor("Pia Rutschmann", "Marktgasse 28", "9400", "Rorschach", "CH")
+                .debtor("Simon Muster", "Musterstrasse 1", "8000", "Zürich", "CH")
+                .amount(Amount::from_cents(19995).unwrap())
+                .currency(Currency::Eur)
+        };
+
+        let qr_data = builder()
+            .reference(Reference::scor("RF18539007547034").unwrap())
+            .additional_information("Rechnung 2021-07")
+            .build()
+            .unwrap();
+        let payload = build_qr_payload(&qr_data);
+        let lines = payload.split('\n').collect::<Vec<&str>>();
+        assert_eq!(lines[3], "CH9300762011623852957");
+        assert_eq!(&lines[18..20], &["199.95", "EUR"]);
+        assert_eq!(
+            &lines[27..30],
+            &["SCOR", "RF18539007547034", "Rechnung 2021-07"]
+        );
+
+        assert_eq!(builder().build().unwrap().reference(), &Reference::Non);
+        assert!(builder()
+            .reference(Reference::qrr("210000000003139471430009017").unwrap())
+            .build()
+            .is_err());
+        assert!(builder().additional_information("🙂").build().is_err());
+        assert!(QrData::builder().build().is_err());
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_generate_html_slip() {
+        let qr_data = qr_data(
+            "Hans Muster",
+            "Musterstrasse 1",
+            "8000",
+            "Zürich",
+            "NON",
+            None,
+            None,
+        );
+        let html = generate_html_slip(&[qr_data], &[String::from("<svg/>")]).unwrap();
 
-fn validate_currency(currency: &str) -> Result<(), ValidationError> {
-    if !(currency == "CHF" || currency == "EUR") {
-        return Err(ValidationError::new("Currency must be either CHF or EUR"));
+        // the IBAN is printed in the paper format on the receipt and the payment part
+        assert_eq!(html.matches("CH93 0076 2011 6238 5295 7").count(), 2);
+        assert!(!html.contains("CH9300762011623852957"));
     }
 
-    Ok(())
+    /// Renders a slip, run in a separate process by [`test_render_outside_of_repository`] so that
+    /// the templates are loaded while the working directory is outside of the repository.
+    #[test]
+    #[ignore]
+    fn render_slip_in_working_directory() {
+        let qr_data = qr_data(
+            "Hans Muster",
+            "Musterstrasse 1",
+            "8000",
+            "Zürich",
+            "NON",
+            None,
+            None,
+        );
+        let html = generate_html_slip(&[qr_data], &[String::from("<svg/>")]).unwrap();
+
+        assert!(html.contains("Hans Muster"));
+        assert_eq!(TEMPLATE_VERSION.len(), 64);
+    }
+
+    #[test]
+    fn test_render_outside_of_repository() {
+        let dir = std::env::temp_dir().join(format!("qr_slip_cwd_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&[
+                "templating::tests::render_slip_in_working_directory",
+                "--exact",
+                "--ignored",
+            ])
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
 }
@@ -0,0 +1,574 @@
+//! Throttles login attempts per client IP address and per user name. After a number of free failed
+//! attempts, further attempts are blocked for an exponentially growing duration, until the lockout
+//! is reached, which blocks attempts for the lockout duration. The state is stored in Postgres so
+//! that it is shared by all instances of the service.
+
+use chrono::{offset::Utc, DateTime, Duration};
+use diesel::{expression_methods::BoolExpressionMethods, Connection};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use warp::{Rejection, Reply};
+
+use crate::{
+    acquire_db_connection,
+    diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl},
+    error::Error,
+    model::{LoginThrottle, NewLoginThrottle, Principal},
+    schema::login_throttle,
+    DbConnection,
+};
+
+/// Scope of the throttles of client IP addresses.
+pub const IP_SCOPE: &str = "ip";
+/// Scope of the throttles of user names.
+pub const USER_NAME_SCOPE: &str = "user_name";
+/// Scope of the throttles of password reset requests per client IP address.
+pub const RESET_IP_SCOPE: &str = "reset_ip";
+/// Scope of the throttles of password reset requests per user name.
+pub const RESET_USER_NAME_SCOPE: &str = "reset_user_name";
+
+lazy_static! {
+    pub static ref LOGIN_THROTTLE_CONFIG: ThrottleConfig = ThrottleConfig::from_env();
+}
+
+/// Configures after how many failed login attempts further attempts are blocked and for how long.
+#[derive(Clone, Debug)]
+pub struct ThrottleConfig {
+    /// The number of failed attempts that do not block further attempts.
+    pub free_attempts: i32,
+    /// The number of failed attempts after which attempts are blocked for the lockout duration.
+    pub lockout_attempts: i32,
+    /// The duration of the lockout, failed attempts are forgotten after the same duration without
+    /// failed attempts.
+    pub lockout_duration: Duration,
+}
+
+impl ThrottleConfig {
+    /// Creates the config from the LOGIN_FREE_ATTEMPTS (defaults to 3), LOGIN_LOCKOUT_ATTEMPTS
+    /// (defaults to 10) and LOGIN_LOCKOUT_MINUTES (defaults to 15) environment variables.
+    pub fn from_env() -> Self {
+        let free_attempts = std::env::var("LOGIN_FREE_ATTEMPTS").map_or(3, |val| {
+            val.parse::<i32>()
+                .expect("LOGIN_FREE_ATTEMPTS is not a valid i32")
+        });
+        let lockout_attempts = std::env::var("LOGIN_LOCKOUT_ATTEMPTS").map_or(10, |val| {
+            val.parse::<i32>()
+                .expect("LOGIN_LOCKOUT_ATTEMPTS is not a valid i32")
+        });
+        let lockout_minutes = std::env::var("LOGIN_LOCKOUT_MINUTES").map_or(15, |val| {
+            val.parse::<i64>()
+                .expect("LOGIN_LOCKOUT_MINUTES is not a valid i64")
+        });
+        assert!(
+            free_attempts >= 0 && lockout_attempts > free_attempts,
+            "LOGIN_LOCKOUT_ATTEMPTS must be greater than LOGIN_FREE_ATTEMPTS"
+        );
+        assert!(
+            lockout_minutes > 0,
+            "LOGIN_LOCKOUT_MINUTES must be positive"
+        );
+
+        Self {
+            free_attempts,
+            lockout_attempts,
+            lockout_duration: Duration::minutes(lockout_minutes),
+        }
+    }
+
+    /// Returns how long attempts are blocked after the given number of consecutive failed attempts:
+    /// not at all for the free attempts, then for one second, doubling with each failed attempt but
+    /// never longer than the lockout, and for the lockout duration once the lockout is reached.
+    pub fn block_duration(&self, failed_attempts: i32) -> Option<Duration> {
+        if failed_attempts >= self.lockout_attempts {
+            Some(self.lockout_duration)
+        } else if failed_attempts > self.free_attempts {
+            let exponent = (failed_attempts - self.free_attempts - 1).min(30);
+            Some(std::cmp::min(
+                Duration::seconds(1i64 << exponent),
+                self.lockout_duration,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of consecutive attempts and the time until which further attempts are
+    /// blocked after counting an attempt at the provided time, or None if the throttle is currently
+    /// blocked. Attempts are forgotten after the lockout duration without attempts.
+    pub fn next_attempt(
+        &self,
+        throttle: &LoginThrottle,
+        current_utc: DateTime<Utc>,
+    ) -> Option<(i32, Option<DateTime<Utc>>)> {
+        if throttle
+            .blocked_until
+            .map_or(false, |blocked_until| blocked_until > current_utc)
+        {
+            return None;
+        }
+
+        let failed_attempts = if throttle.last_failure < current_utc - self.lockout_duration {
+            1
+        } else {
+            throttle.failed_attempts + 1
+        };
+        let blocked_until = self
+            .block_duration(failed_attempts)
+            .map(|duration| current_utc + duration);
+
+        Some((failed_attempts, blocked_until))
+    }
+}
+
+/// The throttle state of an IP address or user name returned by the /admin/login-throttles endpoint.
+#[derive(Serialize)]
+pub struct LoginThrottleStatus {
+    pub scope: String,
+    pub key: String,
+    pub failed_attempts: i32,
+    pub last_failure: String,
+    pub blocked_until: Option<String>,
+    /// Whether login attempts are currently blocked.
+    pub blocked: bool,
+    /// Whether the lockout has been reached, as opposed to a backoff after fewer failed attempts.
+    pub locked_out: bool,
+}
+
+/// A login attempt of a client IP address and user name that has been counted as failed before
+/// checking the credentials, so that concurrent attempts cannot bypass the throttle. The attempt
+/// stays counted unless it is undone by [`LoginAttempt::succeeded`] or [`LoginAttempt::release`].
+#[must_use]
+pub struct LoginAttempt {
+    reservations: Vec<Reservation>,
+}
+
+/// An attempt counted for the throttle of a key along with the attempts before and after counting
+/// it, so that it can be undone.
+struct Reservation {
+    pk: i32,
+    scope: &'static str,
+    key: String,
+    previous: AttemptCount,
+    reserved: AttemptCount,
+}
+
+/// The attempts counted by a throttle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AttemptCount {
+    failed_attempts: i32,
+    last_failure: DateTime<Utc>,
+    blocked_until: Option<DateTime<Utc>>,
+}
+
+impl From<&LoginThrottle> for AttemptCount {
+    fn from(throttle: &LoginThrottle) -> Self {
+        Self {
+            failed_attempts: throttle.failed_attempts,
+            last_failure: throttle.last_failure,
+            blocked_until: throttle.blocked_until,
+        }
+    }
+}
+
+impl Reservation {
+    /// Returns the attempts of the throttle after undoing this attempt given its current attempts,
+    /// which are restored to the attempts before this attempt if no other attempt has been counted
+    /// since, or else only reduced by one while keeping the block caused by the other attempts.
+    fn undo(&self, current: AttemptCount) -> AttemptCount {
+        if current == self.reserved {
+            self.previous
+        } else {
+            AttemptCount {
+                failed_attempts: (current.failed_attempts - 1).max(0),
+                ..current
+            }
+        }
+    }
+}
+
+/// Counts a login attempt of the client IP address and the user name as failed before the
+/// credentials are checked, see [`LoginAttempt`]. Returns a TooManyLoginAttemptsError with the
+/// seconds until attempts are allowed again, without counting the attempt, if either the client IP
+/// address or the user name is currently blocked.
+pub fn reserve_login_attempt(
+    ip_address: Option<&str>,
+    user_name: &str,
+    connection: &DbConnection,
+) -> Result<LoginAttempt, Error> {
+    let mut attempt = LoginAttempt {
+        reservations: Vec::new(),
+    };
+
+    for (scope, key) in throttle_keys(ip_address, user_name) {
+        match count_attempt(scope, key, connection)? {
+            Ok(reservation) => attempt.reservations.push(reservation),
+            Err(blocked_until) => {
+                attempt.release(connection)?;
+                let remaining_millis = (blocked_until - Utc::now()).num_milliseconds();
+                return Err(Error::TooManyLoginAttemptsError(
+                    ((remaining_millis + 999) / 1000).max(1),
+                ));
+            }
+        }
+    }
+
+    Ok(attempt)
+}
+
+impl LoginAttempt {
+    /// Keeps the attempt counted as failed, reaching the lockout is logged as a security event.
+    pub fn failed(self) {
+        for reservation in self.reservations {
+            if reservation.reserved.failed_attempts == LOGIN_THROTTLE_CONFIG.lockout_attempts {
+                log_lockout(reservation.scope, &reservation.key);
+            }
+        }
+    }
+
+    /// Forgets the failed attempts of the user name after a successful login and undoes the attempt
+    /// of the IP address. The failed attempts of the IP address are kept, so that logging in to an
+    /// account of their own does not allow clients to continue guessing the passwords of other
+    /// accounts.
+    pub fn succeeded(self, connection: &DbConnection) -> Result<(), Error> {
+        for reservation in self.reservations {
+            if reservation.scope == USER_NAME_SCOPE {
+                diesel::delete(login_throttle::table.filter(login_throttle::pk.eq(reservation.pk)))
+                    .execute(connection)
+                    .map_err(|_| Error::QueryError)?;
+            } else {
+                undo_attempt(&reservation, connection)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Undoes the attempt without resetting the failed attempts, e.g. when the password is correct
+    /// but the login has to be completed using the second factor.
+    pub fn release(self, connection: &DbConnection) -> Result<(), Error> {
+        for reservation in &self.reservations {
+            undo_attempt(reservation, connection)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn undo_attempt(reservation: &Reservation, connection: &DbConnection) -> Result<(), Error> {
+    connection.transaction::<_, Error, _>(|| {
+        // the throttle may have been deleted by a successful login in the meantime
+        let throttle = login_throttle::table
+            .filter(login_throttle::pk.eq(reservation.pk))
+            .for_update()
+            .first::<LoginThrottle>(connection)
+            .optional()
+            .map_err(|_| Error::QueryError)?;
+        let throttle = match throttle {
+            Some(throttle) => throttle,
+            None => return Ok(()),
+        };
+
+        let attempts = reservation.undo(AttemptCount::from(&throttle));
+        diesel::update(login_throttle::table)
+            .filter(login_throttle::pk.eq(throttle.pk))
+            .set((
+                login_throttle::failed_attempts.eq(attempts.failed_attempts),
+                login_throttle::last_failure.eq(attempts.last_failure),
+                login_throttle::blocked_until.eq(attempts.blocked_until),
+            ))
+            .execute(connection)
+            .map_err(|_| Error::QueryError)?;
+
+        Ok(())
+    })
+}
+
+/// Counts a password reset request for the client IP address and the user name, which are throttled
+/// like failed logins but separately from them, so that requesting password resets cannot lock out
+/// the login of a principal. Returns false without counting the request if either is blocked.
+pub fn record_password_reset_request(
+    ip_address: Option<&str>,
+    user_name: &str,
+    connection: &DbConnection,
+) -> Result<bool, Error> {
+    let mut keys = vec![(RESET_USER_NAME_SCOPE, user_name)];
+    if let Some(ip_address) = ip_address {
+        keys.push((RESET_IP_SCOPE, ip_address));
+    }
+
+    for (scope, key) in keys {
+        match count_attempt(scope, key, connection)? {
+            Ok(reservation) => {
+                if reservation.reserved.failed_attempts == LOGIN_THROTTLE_CONFIG.lockout_attempts {
+                    log_lockout(scope, key);
+                }
+            }
+            Err(_) => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Counts an attempt for the key of the scope while holding the lock on its throttle, or returns
+/// the time until which the key is blocked without counting the attempt.
+fn count_attempt(
+    scope: &'static str,
+    key: &str,
+    connection: &DbConnection,
+) -> Result<Result<Reservation, DateTime<Utc>>, Error> {
+    connection.transaction::<_, Error, _>(|| {
+        let current_utc = Utc::now();
+
+        // create the row if missing so that it can be locked by concurrent requests
+        diesel::insert_into(login_throttle::table)
+            .values(&NewLoginThrottle {
+                scope,
+                key,
+                failed_attempts: 0,
+                last_failure: current_utc,
+            })
+            .on_conflict((login_throttle::scope, login_throttle::key))
+            .do_nothing()
+            .execute(connection)
+            .map_err(|_| Error::QueryError)?;
+
+        let throttle = login_throttle::table
+            .filter(
+                login_throttle::scope
+                    .eq(scope)
+                    .and(login_throttle::key.eq(key)),
+            )
+            .for_update()
+            .first::<LoginThrottle>(connection)
+            .map_err(|_| Error::QueryError)?;
+
+        let (failed_attempts, blocked_until) =
+            match LOGIN_THROTTLE_CONFIG.next_attempt(&throttle, current_utc) {
+                Some(next_attempt) => next_attempt,
+                None => return Ok(Err(throttle.blocked_until.unwrap_or(current_utc))),
+            };
+
+        diesel::update(login_throttle::table)
+            .filter(login_throttle::pk.eq(throttle.pk))
+            .set((
+                login_throttle::failed_attempts.eq(failed_attempts),
+                login_throttle::last_failure.eq(current_utc),
+                login_throttle::blocked_until.eq(blocked_until),
+            ))
+            .execute(connection)
+            .map_err(|_| Error::QueryError)?;
+
+        Ok(Ok(Reservation {
+            pk: throttle.pk,
+            scope,
+            key: String::from(key),
+            previous: AttemptCount::from(&throttle),
+            reserved: AttemptCount {
+                failed_attempts,
+                last_failure: current_utc,
+                blocked_until,
+            },
+        }))
+    })
+}
+
+fn log_lockout(scope: &str, key: &str) {
+    let config = &*LOGIN_THROTTLE_CONFIG;
+    log::warn!(
+        target: "qr_slip::security",
+        "Locked out {} '{}' for {} minutes after {} attempts",
+        scope,
+        key,
+        config.lockout_duration.num_minutes(),
+        config.lockout_attempts
+    );
+}
+
+/// Deletes the throttles whose failed attempts have been forgotten, returning the number of deleted
+/// throttles.
+pub fn purge_login_throttles(connection: &DbConnection) -> Result<usize, Error> {
+    let forgotten_before = Utc::now() - LOGIN_THROTTLE_CONFIG.lockout_duration;
+
+    diesel::delete(login_throttle::table.filter(login_throttle::last_failure.lt(forgotten_before)))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)
+}
+
+/// Returns the throttle state of all IP addresses and user names with failed login attempts that
+/// have not been forgotten yet, most failed attempts first. Requires admin permissions.
+pub async fn login_throttles_handler(_admin: Principal) -> Result<impl Reply, Rejection> {
+    let connection = acquire_db_connection()?;
+    let config = &*LOGIN_THROTTLE_CONFIG;
+    let current_utc = Utc::now();
+
+    let throttles = login_throttle::table
+        .filter(login_throttle::last_failure.ge(current_utc - config.lockout_duration))
+        .order((
+            login_throttle::failed_attempts.desc(),
+            login_throttle::last_failure.desc(),
+        ))
+        .load::<LoginThrottle>(&connection)
+        .map_err(|_| warp::reject::custom(Error::QueryError))?;
+
+    let statuses = throttles
+        .into_iter()
+        .map(|throttle| {
+            let blocked = throttle
+                .blocked_until
+                .map_or(false, |blocked_until| blocked_until > current_utc);
+            LoginThrottleStatus {
+                blocked,
+                locked_out: blocked && throttle.failed_attempts >= config.lockout_attempts,
+                scope: throttle.scope,
+                key: throttle.key,
+                failed_attempts: throttle.failed_attempts,
+                last_failure: throttle.last_failure.to_rfc3339(),
+                blocked_until: throttle
+                    .blocked_until
+                    .map(|blocked_until| blocked_until.to_rfc3339()),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&statuses))
+}
+
+fn throttle_keys<'a>(
+    ip_address: Option<&'a str>,
+    user_name: &'a str,
+) -> Vec<(&'static str, &'a str)> {
+    let mut keys = vec![(USER_NAME_SCOPE, user_name)];
+    if let Some(ip_address) = ip_address {
+        keys.push((IP_SCOPE, ip_address));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_duration() {
+        let config = ThrottleConfig {
+            free_attempts: 3,
+            lockout_attempts: 10,
+            lockout_duration: Duration::minutes(15),
+        };
+
+        assert_eq!(config.block_duration(1), None);
+        assert_eq!(config.block_duration(3), None);
+        assert_eq!(config.block_duration(4), Some(Duration::seconds(1)));
+        assert_eq!(config.block_duration(5), Some(Duration::seconds(2)));
+        assert_eq!(config.block_duration(9), Some(Duration::seconds(32)));
+        assert_eq!(config.block_duration(10), Some(Duration::minutes(15)));
+        assert_eq!(config.block_duration(42), Some(Duration::minutes(15)));
+
+        let config = ThrottleConfig {
+            free_attempts: 0,
+            lockout_attempts: 100,
+            lockout_duration: Duration::minutes(1),
+        };
+        assert_eq!(config.block_duration(7), Some(Duration::seconds(60)));
+        assert_eq!(config.block_duration(99), Some(Duration::seconds(60)));
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        let config = ThrottleConfig {
+            free_attempts: 3,
+            lockout_attempts: 10,
+            lockout_duration: Duration::minutes(15),
+        };
+        let current_utc = Utc::now();
+        let throttle = |failed_attempts, last_failure, blocked_until| LoginThrottle {
+            pk: 1,
+            scope: String::from(USER_NAME_SCOPE),
+            key: String::from("pia"),
+            failed_attempts,
+            last_failure,
+            blocked_until,
+        };
+
+        // rows are created with 0 attempts before counting the first attempt
+        assert_eq!(
+            config.next_attempt(&throttle(0, current_utc, None), current_utc),
+            Some((1, None))
+        );
+        let last_failure = current_utc - Duration::seconds(10);
+        assert_eq!(
+            config.next_attempt(&throttle(3, last_failure, None), current_utc),
+            Some((4, Some(current_utc + Duration::seconds(1))))
+        );
+        assert_eq!(
+            config.next_attempt(
+                &throttle(9, last_failure, Some(current_utc - Duration::seconds(1))),
+                current_utc
+            ),
+            Some((10, Some(current_utc + Duration::minutes(15))))
+        );
+
+        // blocked attempts are not counted
+        assert_eq!(
+            config.next_attempt(
+                &throttle(4, last_failure, Some(current_utc + Duration::seconds(1))),
+                current_utc
+            ),
+            None
+        );
+
+        // attempts are forgotten after the lockout duration
+        let last_failure = current_utc - Duration::minutes(16);
+        assert_eq!(
+            config.next_attempt(
+                &throttle(10, last_failure, Some(last_failure + Duration::minutes(15))),
+                current_utc
+            ),
+            Some((1, None))
+        );
+    }
+
+    #[test]
+    fn test_undo_attempt() {
+        let current_utc = Utc::now();
+        let previous = AttemptCount {
+            failed_attempts: 4,
+            last_failure: current_utc - Duration::seconds(10),
+            blocked_until: Some(current_utc - Duration::seconds(9)),
+        };
+        let reservation = Reservation {
+            pk: 1,
+            scope: IP_SCOPE,
+            key: String::from("192.0.2.1"),
+            previous,
+            reserved: AttemptCount {
+                failed_attempts: 5,
+                last_failure: current_utc,
+                blocked_until: Some(current_utc + Duration::seconds(2)),
+            },
+        };
+
+        // the attempts before the reservation are restored if no other attempt has been counted
+        assert_eq!(reservation.undo(reservation.reserved), previous);
+
+        // otherwise only this attempt is subtracted and the block of the later attempt is kept
+        let concurrent = AttemptCount {
+            failed_attempts: 6,
+            last_failure: current_utc + Duration::milliseconds(20),
+            blocked_until: Some(current_utc + Duration::seconds(4)),
+        };
+        assert_eq!(
+            reservation.undo(concurrent),
+            AttemptCount {
+                failed_attempts: 5,
+                ..concurrent
+            }
+        );
+        let forgotten = AttemptCount {
+            failed_attempts: 0,
+            ..concurrent
+        };
+        assert_eq!(reservation.undo(forgotten), forgotten);
+    }
+}
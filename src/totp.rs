@@ -0,0 +1,651 @@
+//! Optional two-factor authentication using time-based one-time passwords as defined by
+//! [RFC 6238](https://tools.ietf.org/html/rfc6238), as generated by common authenticator apps.
+//!
+//! Principals enrol by scanning the otpauth URI returned by /enroll-totp and enable the second
+//! factor by confirming a code with /confirm-totp, which returns one-time recovery codes. Once
+//! enabled, /login only returns a short-lived token, which has to be provided to /login-totp with a
+//! code or a recovery code to complete the login.
+
+use chrono::{offset::Utc, DateTime, Duration};
+use diesel::{expression_methods::BoolExpressionMethods, Connection};
+use lazy_static::lazy_static;
+use qrcode::{render::svg, EcLevel, QrCode};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+use crate::{
+    acquire_db_connection,
+    auth::{verify_password, ClientInfo},
+    cache::hash_bytes,
+    diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl},
+    error::Error,
+    model::{NewTotpChallenge, NewTotpRecoveryCode, Principal, TotpChallenge, TotpRecoveryCode},
+    schema::{principal, totp_challenge, totp_recovery_code},
+    throttle, DbConnection,
+};
+
+/// Number of digits of a code.
+const TOTP_DIGITS: u32 = 6;
+/// Seconds after which the code changes.
+const TOTP_STEP_SECS: i64 = 30;
+/// Number of steps before and after the current step whose codes are accepted as well, to allow for
+/// clocks that are slightly off and codes entered just before they change.
+const TOTP_ALLOWED_DRIFT_STEPS: i64 = 1;
+/// Length of the secret in bytes, which matches the output of HMAC-SHA1 as recommended by RFC 4226.
+const TOTP_SECRET_LENGTH: usize = 20;
+/// Number of recovery codes generated when enabling two-factor authentication.
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Length of a recovery code in bytes, encoded as 16 base32 characters.
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// Number of invalid codes after which a challenge is invalidated, requiring to log in again.
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+/// Minutes after which a challenge issued by /login expires.
+pub const TOTP_CHALLENGE_EXPIRY_MINUTES: i64 = 5;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+lazy_static! {
+    /// The issuer shown by authenticator apps next to the user name, set by the TOTP_ISSUER
+    /// environment variable, defaults to `qr_slip`.
+    pub static ref TOTP_ISSUER: String =
+        std::env::var("TOTP_ISSUER").unwrap_or_else(|_| String::from("qr_slip"));
+}
+
+/// Struct received by the /confirm-totp endpoint.
+#[derive(Deserialize)]
+pub struct ConfirmTotpRequest {
+    pub code: String,
+}
+
+/// Struct received by the /disable-totp endpoint, if two-factor authentication is enabled either a
+/// code generated by the authenticator or a recovery code must be provided along with the password.
+#[derive(Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+/// Struct returned by the /enroll-totp endpoint.
+#[derive(Serialize)]
+pub struct TotpEnrolment {
+    /// The base32 encoded secret, for authenticator apps that cannot scan the QR code.
+    pub secret: String,
+    pub uri: String,
+    /// The svg graphic of the QR code encoding the `uri`.
+    pub qr_code: String,
+}
+
+/// Struct returned by the /confirm-totp endpoint.
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Handler for the /enroll-totp endpoint that generates a new secret for the logged in principal and
+/// returns a [`TotpEnrolment`]. Two-factor authentication is only enabled once a code generated with
+/// the secret is confirmed using /confirm-totp, enrolling again before replaces the secret. Returns
+/// a TotpAlreadyEnabledError if two-factor authentication is already enabled.
+pub async fn enroll_totp_handler(principal: Principal) -> Result<impl Reply, Rejection> {
+    if principal.totp_enabled {
+        return Err(warp::reject::custom(Error::TotpAlreadyEnabledError));
+    }
+
+    let secret = random_bytes(TOTP_SECRET_LENGTH)?;
+    let encoded_secret = base32_encode(&secret);
+    let uri = otpauth_uri(&TOTP_ISSUER, &principal.user_name, &encoded_secret);
+    let qr_code = QrCode::with_error_correction_level(uri.as_bytes(), EcLevel::M)
+        .map_err(|e| warp::reject::custom(Error::QrCodeError(e.to_string())))?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    let connection = acquire_db_connection()?;
+    diesel::update(principal::table)
+        .filter(
+            principal::pk
+                .eq(principal.pk)
+                .and(principal::totp_enabled.eq(false)),
+        )
+        .set((
+            principal::totp_secret.eq(&secret),
+            principal::totp_last_used_step.eq(None::<i64>),
+        ))
+        .execute(&connection)
+        .map_err(|_| warp::reject::custom(Error::QueryError))?;
+
+    Ok(warp::reply::json(&TotpEnrolment {
+        secret: encoded_secret,
+        uri,
+        qr_code,
+    }))
+}
+
+/// Handler for the /confirm-totp endpoint that enables two-factor authentication for the logged in
+/// principal if the provided code matches the secret returned by /enroll-totp. Returns the
+/// [`RecoveryCodes`], which are only stored hashed and cannot be retrieved again, an
+/// InvalidTotpCodeError if the code does not match or a TotpNotEnrolledError if the principal did not
+/// enrol.
+pub async fn confirm_totp_handler(
+    principal: Principal,
+    request: ConfirmTotpRequest,
+) -> Result<impl Reply, Rejection> {
+    if principal.totp_enabled {
+        return Err(warp::reject::custom(Error::TotpAlreadyEnabledError));
+    }
+    let secret = principal
+        .totp_secret
+        .as_ref()
+        .ok_or(Error::TotpNotEnrolledError)?;
+    let step = verify_code(secret, &request.code, Utc::now().timestamp(), None)
+        .ok_or(Error::InvalidTotpCodeError)?;
+
+    let recovery_codes = generate_recovery_codes()?;
+    let connection = acquire_db_connection()?;
+    connection.transaction::<_, Error, _>(|| {
+        diesel::update(principal::table)
+            .filter(principal::pk.eq(principal.pk))
+            .set((
+                principal::totp_enabled.eq(true),
+                principal::totp_last_used_step.eq(step),
+            ))
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        diesel::delete(
+            totp_recovery_code::table.filter(totp_recovery_code::fk_principal.eq(principal.pk)),
+        )
+        .execute(&connection)
+        .map_err(|_| Error::QueryError)?;
+
+        let new_recovery_codes = recovery_codes
+            .iter()
+            .map(|recovery_code| NewTotpRecoveryCode {
+                code_hash: recovery_code_hash(recovery_code),
+                fk_principal: principal.pk,
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(totp_recovery_code::table)
+            .values(&new_recovery_codes)
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)
+    })?;
+
+    Ok(warp::reply::json(&RecoveryCodes { recovery_codes }))
+}
+
+/// Handler for the /disable-totp endpoint that disables two-factor authentication for the logged in
+/// principal and deletes its secret and recovery codes. Requires the password and, if two-factor
+/// authentication is enabled, a valid TOTP code or recovery code, which count as login attempts of
+/// the principal and the client IP address, see [`crate::throttle`]. Returns a
+/// InvalidCredentialsError if the password is invalid and a InvalidTotpCodeError if the code is
+/// invalid.
+pub async fn disable_totp_handler(
+    principal: Principal,
+    request: DisableTotpRequest,
+    client: ClientInfo,
+) -> Result<impl Reply, Rejection> {
+    let connection = acquire_db_connection()?;
+    let attempt = throttle::reserve_login_attempt(
+        client.ip_address.as_deref(),
+        &principal.user_name,
+        &connection,
+    )?;
+    let DisableTotpRequest {
+        password,
+        code,
+        recovery_code,
+    } = request;
+    if !verify_password(password, principal.password.clone()).await? {
+        attempt.failed();
+        return Err(warp::reject::custom(Error::InvalidCredentialsError));
+    }
+
+    let current_utc = Utc::now();
+    let verified = connection.transaction::<_, Error, _>(|| {
+        // lock the principal so that concurrent requests cannot use the same code
+        let principal = principal::table
+            .filter(principal::pk.eq(principal.pk))
+            .for_update()
+            .first::<Principal>(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        // a pending enrolment is discarded with the password alone
+        let verified = !principal.totp_enabled
+            || match (code.as_deref(), recovery_code.as_deref()) {
+                (Some(code), _) => use_totp_code(&principal, code, current_utc, &connection)?,
+                (None, Some(recovery_code)) => {
+                    use_recovery_code(&principal, recovery_code, current_utc, &connection)?
+                }
+                (None, None) => false,
+            };
+        if !verified {
+            return Ok(false);
+        }
+
+        diesel::update(principal::table)
+            .filter(principal::pk.eq(principal.pk))
+            .set((
+                principal::totp_secret.eq(None::<Vec<u8>>),
+                principal::totp_enabled.eq(false),
+                principal::totp_last_used_step.eq(None::<i64>),
+            ))
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        diesel::delete(totp_challenge::table.filter(totp_challenge::fk_principal.eq(principal.pk)))
+            .execute(&connection)
+            .map_err(|_| Error::QueryError)?;
+
+        diesel::delete(
+            totp_recovery_code::table.filter(totp_recovery_code::fk_principal.eq(principal.pk)),
+        )
+        .execute(&connection)
+        .map_err(|_| Error::QueryError)?;
+
+        Ok(true)
+    })?;
+
+    if !verified {
+        attempt.failed();
+        return Err(warp::reject::custom(Error::InvalidTotpCodeError));
+    }
+    attempt.succeeded(&connection)?;
+
+    Ok(warp::reply())
+}
+
+/// Issues a challenge for the principal whose password has been checked by /login, returning the
+/// token that has to be provided to /login-totp along with a code within
+/// TOTP_CHALLENGE_EXPIRY_MINUTES minutes.
+pub fn create_totp_challenge(
+    fk_principal: i32,
+    connection: &DbConnection,
+) -> Result<String, Error> {
+    let token = Uuid::new_v4().to_simple().to_string();
+    let new_challenge = NewTotpChallenge {
+        token_hash: hash_bytes(token.as_bytes()),
+        expiry: Utc::now() + Duration::minutes(TOTP_CHALLENGE_EXPIRY_MINUTES),
+        fk_principal,
+    };
+
+    diesel::insert_into(totp_challenge::table)
+        .values(&new_challenge)
+        .execute(connection)
+        .map_err(|_| Error::QueryError)?;
+
+    Ok(token)
+}
+
+/// Completes the challenge of the provided token if either the TOTP code or the recovery code is
+/// valid, returning the principal to log in. Invalid codes count as failed login attempts of the
+/// principal and the client IP address, see [`crate::throttle`], and invalidate the challenge after
+/// MAX_CHALLENGE_ATTEMPTS attempts. Returns a InvalidTotpTokenError if the token does not exist or
+/// is expired and a InvalidTotpCodeError if the code is invalid.
+pub fn complete_totp_challenge(
+    token: &str,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+    ip_address: Option<&str>,
+    connection: &DbConnection,
+) -> Result<Principal, Error> {
+    let token_hash = hash_bytes(token.as_bytes());
+    let current_utc = Utc::now();
+
+    // lock the challenge and principal so that concurrent requests cannot use the same code
+    let (principal, verified, attempt) = connection.transaction::<_, Error, _>(|| {
+        let challenge = totp_challenge::table
+            .filter(totp_challenge::token_hash.eq(&token_hash))
+            .for_update()
+            .first::<TotpChallenge>(connection)
+            .optional()
+            .map_err(|_| Error::QueryError)?
+            .ok_or(Error::InvalidTotpTokenError)?;
+        if challenge.expiry < current_utc {
+            return Err(Error::InvalidTotpTokenError);
+        }
+
+        let principal = principal::table
+            .filter(principal::pk.eq(challenge.fk_principal))
+            .for_update()
+            .first::<Principal>(connection)
+            .map_err(|_| Error::QueryError)?;
+        if !principal.totp_enabled {
+            return Err(Error::InvalidTotpTokenError);
+        }
+        // counted within the transaction so that it is rolled back if the challenge cannot be used
+        let attempt =
+            throttle::reserve_login_attempt(ip_address, &principal.user_name, connection)?;
+
+        let verified = match (code, recovery_code) {
+            (Some(code), _) => use_totp_code(&principal, code, current_utc, connection)?,
+            (None, Some(recovery_code)) => {
+                use_recovery_code(&principal, recovery_code, current_utc, connection)?
+            }
+            (None, None) => false,
+        };
+
+        if verified || challenge.failed_attempts + 1 >= MAX_CHALLENGE_ATTEMPTS {
+            diesel::delete(totp_challenge::table.filter(totp_challenge::pk.eq(challenge.pk)))
+                .execute(connection)
+                .map_err(|_| Error::QueryError)?;
+        } else {
+            diesel::update(totp_challenge::table)
+                .filter(totp_challenge::pk.eq(challenge.pk))
+                .set(totp_challenge::failed_attempts.eq(challenge.failed_attempts + 1))
+                .execute(connection)
+                .map_err(|_| Error::QueryError)?;
+        }
+
+        Ok((principal, verified, attempt))
+    })?;
+
+    if verified {
+        attempt.succeeded(connection)?;
+        Ok(principal)
+    } else {
+        attempt.failed();
+        Err(Error::InvalidTotpCodeError)
+    }
+}
+
+/// Deletes expired challenges, returning the number of deleted challenges.
+pub fn purge_totp_challenges(connection: &DbConnection) -> Result<usize, Error> {
+    diesel::delete(totp_challenge::table.filter(totp_challenge::expiry.lt(Utc::now())))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)
+}
+
+fn use_totp_code(
+    principal: &Principal,
+    code: &str,
+    current_utc: DateTime<Utc>,
+    connection: &DbConnection,
+) -> Result<bool, Error> {
+    let secret = match principal.totp_secret {
+        Some(ref secret) => secret,
+        None => return Ok(false),
+    };
+    let step = match verify_code(
+        secret,
+        code,
+        current_utc.timestamp(),
+        principal.totp_last_used_step,
+    ) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    diesel::update(principal::table)
+        .filter(principal::pk.eq(principal.pk))
+        .set(principal::totp_last_used_step.eq(step))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)?;
+
+    Ok(true)
+}
+
+fn use_recovery_code(
+    principal: &Principal,
+    recovery_code: &str,
+    current_utc: DateTime<Utc>,
+    connection: &DbConnection,
+) -> Result<bool, Error> {
+    let recovery_code = totp_recovery_code::table
+        .filter(
+            totp_recovery_code::fk_principal
+                .eq(principal.pk)
+                .and(totp_recovery_code::code_hash.eq(recovery_code_hash(recovery_code)))
+                .and(totp_recovery_code::used_at.is_null()),
+        )
+        .for_update()
+        .first::<TotpRecoveryCode>(connection)
+        .optional()
+        .map_err(|_| Error::QueryError)?;
+    let recovery_code = match recovery_code {
+        Some(recovery_code) => recovery_code,
+        None => return Ok(false),
+    };
+
+    diesel::update(totp_recovery_code::table)
+        .filter(totp_recovery_code::pk.eq(recovery_code.pk))
+        .set(totp_recovery_code::used_at.eq(current_utc))
+        .execute(connection)
+        .map_err(|_| Error::QueryError)?;
+
+    log::warn!(
+        target: "qr_slip::security",
+        "Principal {} used a TOTP recovery code",
+        principal.pk
+    );
+    Ok(true)
+}
+
+/// Returns the step of the code if it is valid for the current step or one of the steps within the
+/// allowed drift that is later than the last used step. Whitespace in the code is ignored.
+fn verify_code(
+    secret: &[u8],
+    code: &str,
+    unix_time: i64,
+    last_used_step: Option<i64>,
+) -> Option<i64> {
+    let code = code
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    let current_step = unix_time.div_euclid(TOTP_STEP_SECS);
+
+    (current_step - TOTP_ALLOWED_DRIFT_STEPS..=current_step + TOTP_ALLOWED_DRIFT_STEPS)
+        .filter(|step| last_used_step.map_or(true, |last_used_step| *step > last_used_step))
+        .find(|step| {
+            let expected_code = totp_code(secret, *step, TOTP_DIGITS);
+            verify_slices_are_equal(expected_code.as_bytes(), code.as_bytes()).is_ok()
+        })
+}
+
+/// Returns the code for the time step, the HOTP value (RFC 4226) of the step as zero-padded decimal.
+fn totp_code(secret: &[u8], step: i64, digits: u32) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let digest = tag.as_ref();
+
+    // dynamic truncation, the last 4 bits select the offset of the 31 bit value
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset],
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]) & 0x7fff_ffff;
+
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+/// Builds the otpauth URI understood by authenticator apps, see
+/// https://github.com/google/google-authenticator/wiki/Key-Uri-Format.
+fn otpauth_uri(issuer: &str, account: &str, encoded_secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = encoded_secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP_SECS
+    )
+}
+
+/// Generates recovery codes of 16 base32 characters in groups of 4 separated by dashes.
+fn generate_recovery_codes() -> Result<Vec<String>, Error> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let encoded = base32_encode(&random_bytes(RECOVERY_CODE_LENGTH)?);
+            Ok(encoded
+                .as_bytes()
+                .chunks(4)
+                .map(|group| String::from_utf8_lossy(group).into_owned())
+                .collect::<Vec<_>>()
+                .join("-"))
+        })
+        .collect()
+}
+
+/// Hashes the recovery code, ignoring dashes, whitespace and case.
+fn recovery_code_hash(recovery_code: &str) -> String {
+    let normalized = recovery_code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    hash_bytes(normalized.as_bytes())
+}
+
+fn random_bytes(length: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0; length];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::EncryptionError)?;
+    Ok(bytes)
+}
+
+/// Encodes the bytes using the base32 alphabet of RFC 4648 without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Percent-encodes all characters but the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_totp_code() {
+        // test vectors of RFC 6238 for SHA1
+        let vectors = [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ];
+        for (unix_time, code) in vectors.iter() {
+            assert_eq!(totp_code(RFC_SECRET, unix_time / TOTP_STEP_SECS, 8), *code);
+        }
+        assert_eq!(totp_code(RFC_SECRET, 1, 6), "287082");
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let unix_time = 1_111_111_109;
+        let step = unix_time / TOTP_STEP_SECS;
+        let code = totp_code(RFC_SECRET, step, TOTP_DIGITS);
+        assert_eq!(code, "081804");
+
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081 804", unix_time, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time + 30, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time - 30, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time + 60, None),
+            None
+        );
+        assert_eq!(verify_code(RFC_SECRET, "81804", unix_time, None), None);
+        assert_eq!(verify_code(RFC_SECRET, "081805", unix_time, None), None);
+        // codes cannot be used again
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time, Some(step)),
+            None
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", unix_time, Some(step - 1)),
+            Some(step)
+        );
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        assert_eq!(
+            base32_encode(RFC_SECRET),
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        );
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(
+            otpauth_uri("QR Slip", "robin.friedli", "GEZDGNBVGY3TQOJQ"),
+            "otpauth://totp/QR%20Slip:robin.friedli?secret=GEZDGNBVGY3TQOJQ&issuer=QR%20Slip&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let recovery_codes = generate_recovery_codes().unwrap();
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+        for recovery_code in &recovery_codes {
+            assert_eq!(recovery_code.len(), 19);
+            assert_eq!(recovery_code.matches('-').count(), 3);
+        }
+
+        let recovery_code = &recovery_codes[0];
+        assert_eq!(
+            recovery_code_hash(&recovery_code.replace('-', " ").to_lowercase()),
+            recovery_code_hash(recovery_code)
+        );
+        assert_ne!(
+            recovery_code_hash(recovery_code),
+            recovery_code_hash(&recovery_codes[1])
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use warp::{hyper::body::Bytes, Rejection, Reply};
+
+use crate::error::Error::InvalidRequestInputError;
+use crate::i18n::Language;
+use crate::input::{self, ColumnMapping, SheetFormat};
+use crate::model::Principal;
+use crate::plugin::QrGenerator;
+use crate::templating::{self, SlipFormat};
+use crate::validation::{self, FieldError, ItemValidation};
+
+/// Maximum size of files uploaded to the /upload-slips endpoint.
+pub const UPLOAD_SIZE_LIMIT: u64 = 16 * 1024 * 1024;
+
+const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Struct containing the query parameters of the /upload-slips endpoint.
+#[derive(Deserialize)]
+pub struct UploadSlipsQuery {
+    /// The format of the uploaded file, determined by the Content-Type header if not set and else
+    /// defaults to [`SheetFormat::Csv`].
+    pub file_format: Option<SheetFormat>,
+    /// The column mapping as comma separated list of `field:column` pairs, see [`ColumnMapping`].
+    pub mapping: Option<String>,
+    /// The format of the response, defaults to [`SlipFormat::Pdf`].
+    pub format: Option<SlipFormat>,
+    /// Whether characters outside of the permitted character set should be transliterated instead
+    /// of failing validation, see
+    /// [`QrData::transliterate`](crate::templating::QrData::transliterate). Defaults to false.
+    pub transliterate: Option<bool>,
+    /// The name of the QR generator plugin used to generate the qr codes, see [`QrGenerator::select`].
+    pub generator: Option<String>,
+}
+
+/// Struct returned by the /upload-slips endpoint if any row is invalid.
+#[derive(Serialize)]
+pub struct UploadReport {
+    /// The number of data rows in the uploaded file, excluding empty rows.
+    pub rows: usize,
+    pub invalid_rows: Vec<InvalidRow>,
+}
+
+/// An invalid field of the given row, the field is reported by its name in the JSON format rather
+/// than the name of its column.
+#[derive(Serialize)]
+pub struct InvalidRow {
+    /// The number of the row as displayed by spreadsheet applications, i.e. the header is row 1.
+    pub row: usize,
+    #[serde(flatten)]
+    pub error: FieldError,
+}
+
+/// Handler for the /upload-slips endpoint which reads a [`QrData`](crate::templating::QrData) object
+/// from each row of the uploaded CSV or XLSX file and creates the slips like the /generate-slip
+/// endpoint.
+///
+/// Each row is validated separately, see [`validate_item`](validation::validate_item). If any row is
+/// invalid, no slips are generated and the response contains an [`UploadReport`] listing all invalid
+/// fields of all rows instead, with the messages in the language of the request.
+pub async fn upload_slips_handler(
+    query: UploadSlipsQuery,
+    content_type: Option<String>,
+    principal: Option<Principal>,
+    language: Language,
+    body: Bytes,
+) -> Result<impl Reply, Rejection> {
+    let mapping = match query.mapping.as_deref() {
+        Some(mapping) => mapping.parse::<ColumnMapping>()?,
+        None => ColumnMapping::default(),
+    };
+    let file_format = query
+        .file_format
+        .unwrap_or_else(|| sheet_format_for_content_type(content_type.as_deref()));
+
+    let generator =
+        QrGenerator::select_for_principal(query.generator.as_deref(), principal.as_ref())?;
+
+    let rows = input::read_sheet(&body, file_format, &mapping)?;
+    if rows.is_empty() {
+        return Err(warp::reject::custom(InvalidRequestInputError(
+            String::from("The file does not contain any rows"),
+        )));
+    }
+
+    let row_count = rows.len();
+    let transliterate = query.transliterate.unwrap_or(false);
+    let mut qr_data_vec = Vec::with_capacity(row_count);
+    let mut qr_svg_vec = Vec::with_capacity(row_count);
+    let mut invalid_rows = Vec::new();
+    for sheet_row in rows {
+        match validation::validate_item(&sheet_row.object, transliterate, &generator)? {
+            ItemValidation::Valid(qr_data, qr_svg) => {
+                qr_data_vec.push(*qr_data);
+                qr_svg_vec.push(qr_svg);
+            }
+            ItemValidation::Invalid(errors) => {
+                invalid_rows.extend(errors.into_iter().map(|mut error| {
+                    error.localize(language);
+                    InvalidRow {
+                        row: sheet_row.row,
+                        error,
+                    }
+                }))
+            }
+        }
+    }
+
+    if !invalid_rows.is_empty() {
+        let report = UploadReport {
+            rows: row_count,
+            invalid_rows,
+        };
+        return Ok(validation::invalid_response(&report)?.into_response());
+    }
+
+    let response = templating::slip_response(
+        query.format.unwrap_or(SlipFormat::Pdf),
+        &qr_data_vec,
+        &qr_svg_vec,
+    )
+    .await?;
+
+    Ok(response)
+}
+
+fn sheet_format_for_content_type(content_type: Option<&str>) -> SheetFormat {
+    match content_type {
+        Some(content_type) if content_type.starts_with(XLSX_CONTENT_TYPE) => SheetFormat::Xlsx,
+        _ => SheetFormat::Csv,
+    }
+}
@@ -0,0 +1,521 @@
+//! Validates [`QrData`] objects in their JSON format, reporting every invalid field of every object
+//! of a batch instead of failing on the first error.
+
+use std::fmt::{self, Display};
+
+#[cfg(feature = "server")]
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use validator::{Validate, ValidationError, ValidationErrors};
+#[cfg(feature = "server")]
+use warp::{
+    http::{header, Response, StatusCode},
+    Rejection, Reply,
+};
+
+#[cfg(feature = "server")]
+use crate::error::Error::SerialisationError;
+use crate::error::{
+    Error,
+    Error::{InvalidRequestInputError, QrCodeError},
+};
+use crate::i18n::{self, Language};
+use crate::input::{OPTIONAL_QR_DATA_FIELDS, QR_DATA_FIELDS};
+#[cfg(feature = "server")]
+use crate::model::Principal;
+use crate::plugin::QrGenerator;
+use crate::templating::{self, QrData, QrDataJson};
+
+/// An invalid field of a [`QrData`] object.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    /// The field in the JSON format, `None` if the error concerns the entire object.
+    pub field: Option<String>,
+    /// Identifies the kind of error, e.g. `missing`, `invalid_iban` or `length`, see the README for
+    /// all codes.
+    pub code: String,
+    /// The English message, or the message in the language of the request, see
+    /// [`FieldError::localize`].
+    pub message: String,
+    /// The parameters of the message, e.g. `min` and `max` for `length`, so that clients can build
+    /// their own message for the code.
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub params: Map<String, Value>,
+}
+
+impl FieldError {
+    pub(crate) fn new(field: &str, code: &str, error: Error) -> Self {
+        FieldError {
+            field: Some(field.to_string()),
+            code: code.to_string(),
+            message: error_message(error),
+            params: Map::new(),
+        }
+    }
+
+    fn object(code: &str, message: String) -> Self {
+        FieldError {
+            field: None,
+            code: code.to_string(),
+            message,
+            params: Map::new(),
+        }
+    }
+
+    /// Translates the message into the given language, keeping the English message if there is no
+    /// translation for the code.
+    pub fn localize(&mut self, language: Language) {
+        if let Some(message) = i18n::field_message(&self.code, &self.params, language) {
+            self.message = message;
+        }
+    }
+
+    /// Converts the error into the [`Error`] returned when deserializing the object fails.
+    pub(crate) fn to_error(&self) -> Error {
+        InvalidRequestInputError(self.message.clone())
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}", field, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// An invalid field of the [`QrData`] object at the given index of a batch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ValidationIssue {
+    pub index: usize,
+    #[serde(flatten)]
+    pub error: FieldError,
+}
+
+/// Struct returned by the /validate-slips endpoint, and by the /generate-slip endpoint if any object
+/// is invalid.
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    /// Whether all [`QrData`] objects are valid.
+    pub valid: bool,
+    /// The number of [`QrData`] objects that were checked.
+    pub checked: usize,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// Translates the messages of all issues into the given language, see [`FieldError::localize`].
+    pub fn localize(&mut self, language: Language) {
+        for issue in self.issues.iter_mut() {
+            issue.error.localize(language);
+        }
+    }
+}
+
+/// The result of [`validate_batch`].
+pub struct BatchValidation {
+    pub report: ValidationReport,
+    /// The verified [`QrData`] objects, only complete if the report is valid.
+    pub qr_data_vec: Vec<QrData>,
+    /// The qr code svgs of the verified [`QrData`] objects.
+    pub qr_svg_vec: Vec<String>,
+}
+
+/// The result of [`validate_item`].
+pub enum ItemValidation {
+    /// The verified [`QrData`] object and its qr code svg.
+    Valid(Box<QrData>, String),
+    /// All invalid fields of the object, ordered by field.
+    Invalid(Vec<FieldError>),
+}
+
+/// Validates each item of the batch with [`validate_item`], collecting the errors of all invalid
+/// items into a [`ValidationReport`].
+pub fn validate_batch(
+    items: &[Value],
+    transliterate: bool,
+    generator: &QrGenerator,
+) -> Result<BatchValidation, Error> {
+    let mut qr_data_vec = Vec::with_capacity(items.len());
+    let mut qr_svg_vec = Vec::with_capacity(items.len());
+    let mut issues = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        match validate_item(item, transliterate, generator)? {
+            ItemValidation::Valid(qr_data, qr_svg) => {
+                qr_data_vec.push(*qr_data);
+                qr_svg_vec.push(qr_svg);
+            }
+            ItemValidation::Invalid(errors) => issues.extend(
+                errors
+                    .into_iter()
+                    .map(|error| ValidationIssue { index, error }),
+            ),
+        }
+    }
+
+    Ok(BatchValidation {
+        report: ValidationReport {
+            valid: issues.is_empty(),
+            checked: items.len(),
+            issues,
+        },
+        qr_data_vec,
+        qr_svg_vec,
+    })
+}
+
+/// Validates the JSON representation of a [`QrData`] object and generates its qr code.
+///
+/// The item is checked in stages, each stage only running if the previous one found no errors:
+/// the presence and type of all fields, the typed fields (IBAN, amount, currency and reference),
+/// the text fields, after transliterating them if requested, and finally the qr code generation.
+/// All errors of a stage are reported.
+///
+/// Only fails if the qr code cannot be generated for reasons other than the data, e.g. if the
+/// python generator is not available.
+pub fn validate_item(
+    item: &Value,
+    transliterate: bool,
+    generator: &QrGenerator,
+) -> Result<ItemValidation, Error> {
+    let errors = check_fields(item);
+    if !errors.is_empty() {
+        return Ok(ItemValidation::Invalid(errors));
+    }
+
+    let json = match serde_json::from_value::<QrDataJson>(item.clone()) {
+        Ok(json) => json,
+        Err(e) => {
+            let error = FieldError::object("invalid_type", e.to_string());
+            return Ok(ItemValidation::Invalid(vec![error]));
+        }
+    };
+    let mut qr_data = match QrData::from_json(json) {
+        Ok(qr_data) => qr_data,
+        Err(errors) => return Ok(ItemValidation::Invalid(errors)),
+    };
+
+    if transliterate {
+        qr_data.transliterate();
+    }
+    if let Err(e) = qr_data.validate() {
+        return Ok(ItemValidation::Invalid(field_errors(&e)));
+    }
+
+    match templating::generate_qr_svg(&qr_data, generator) {
+        Ok(qr_svg) => Ok(ItemValidation::Valid(Box::new(qr_data), qr_svg)),
+        Err(QrCodeError(message)) => Ok(ItemValidation::Invalid(vec![FieldError::object(
+            "qr_code", message,
+        )])),
+        Err(InvalidRequestInputError(message)) => {
+            let mut error = FieldError::object("generator", message.clone());
+            error
+                .params
+                .insert(String::from("detail"), Value::String(message));
+            Ok(ItemValidation::Invalid(vec![error]))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks that the item is an object containing all required fields as strings and the optional
+/// fields as strings or null.
+fn check_fields(item: &Value) -> Vec<FieldError> {
+    let object = match item.as_object() {
+        Some(object) => object,
+        None => {
+            return vec![FieldError::object(
+                "invalid_type",
+                String::from("Expected a JSON object"),
+            )]
+        }
+    };
+
+    QR_DATA_FIELDS
+        .iter()
+        .filter_map(|field| match object.get(*field) {
+            Some(Value::String(_)) => None,
+            None | Some(Value::Null) if OPTIONAL_QR_DATA_FIELDS.contains(field) => None,
+            None | Some(Value::Null) => Some(FieldError::new(
+                field,
+                "missing",
+                InvalidRequestInputError(format!("Missing required field {}", field)),
+            )),
+            Some(_) => Some(FieldError::new(
+                field,
+                "invalid_type",
+                InvalidRequestInputError(String::from("Expected a string")),
+            )),
+        })
+        .collect()
+}
+
+/// Converts the errors returned by [`Validate::validate`] for a [`QrData`] object, ordered by field.
+/// Errors of the schema validation are attributed to the field given by their `field` parameter.
+pub(crate) fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    ordered_field_errors(errors, &QR_DATA_FIELDS)
+}
+
+/// Converts the errors returned by [`Validate::validate`] for any struct like [`field_errors`],
+/// ordered by the position of the field in `fields`.
+pub(crate) fn ordered_field_errors(errors: &ValidationErrors, fields: &[&str]) -> Vec<FieldError> {
+    let mut field_errors = errors
+        .field_errors()
+        .into_iter()
+        .flat_map(|(field, errors)| {
+            errors.iter().map(move |error| {
+                let field = match field {
+                    "__all__" => error
+                        .params
+                        .get("field")
+                        .and_then(Value::as_str)
+                        .map(String::from),
+                    field => Some(field.to_string()),
+                };
+                let params = error
+                    .params
+                    .iter()
+                    .filter(|(name, _)| *name != "value" && *name != "field")
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect();
+                let mut field_error = FieldError {
+                    field,
+                    code: error.code.to_string(),
+                    message: String::new(),
+                    params,
+                };
+                field_error.message = validation_message(error, &field_error.params);
+                field_error
+            })
+        })
+        .collect::<Vec<FieldError>>();
+
+    field_errors.sort_by_key(|error| {
+        error
+            .field
+            .as_deref()
+            .and_then(|field| fields.iter().position(|f| *f == field))
+            .unwrap_or(fields.len())
+    });
+    field_errors
+}
+
+/// Returns the message of the error, built from the parameters for the built-in validations which
+/// do not set one.
+fn validation_message(error: &ValidationError, params: &Map<String, Value>) -> String {
+    match &error.message {
+        Some(message) => message.to_string(),
+        None => i18n::field_message(&error.code, params, Language::En)
+            .unwrap_or_else(|| format!("Validation '{}' failed", error.code)),
+    }
+}
+
+fn error_message(error: Error) -> String {
+    match error {
+        InvalidRequestInputError(message) | QrCodeError(message) => message,
+        e => e.to_string(),
+    }
+}
+
+/// Struct containing the query parameters of the /validate-slips endpoint.
+#[cfg(feature = "server")]
+#[derive(Deserialize)]
+pub struct ValidateSlipsQuery {
+    /// Whether characters outside of the permitted character set should be transliterated before
+    /// validating, see [`QrData::transliterate`]. Defaults to false.
+    pub transliterate: Option<bool>,
+    /// The name of the QR generator plugin used to generate the qr codes, see [`QrGenerator::select`].
+    pub generator: Option<String>,
+}
+
+/// Handler for the /validate-slips endpoint which validates the provided [`QrData`] objects like the
+/// /generate-slip endpoint without generating the slips, returning a [`ValidationReport`] with the
+/// messages in the language of the request.
+#[cfg(feature = "server")]
+pub async fn validate_slips_handler(
+    query: ValidateSlipsQuery,
+    principal: Option<Principal>,
+    language: Language,
+    items: Vec<Value>,
+) -> Result<impl Reply, Rejection> {
+    let generator =
+        QrGenerator::select_for_principal(query.generator.as_deref(), principal.as_ref())?;
+    let mut validation = validate_batch(&items, query.transliterate.unwrap_or(false), &generator)?;
+    validation.report.localize(language);
+
+    Ok(warp::reply::json(&validation.report))
+}
+
+/// Builds a response with status 400 returning the serialized report of invalid objects.
+#[cfg(feature = "server")]
+pub fn invalid_response<T: Serialize>(report: &T) -> Result<Response<Vec<u8>>, Error> {
+    let body = serde_json::to_vec(report).map_err(|_| SerialisationError)?;
+
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .map_err(|_| SerialisationError)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn valid_item() -> Value {
+        json!({
+            "creditor_iban": "CH4431999123000889012",
+            "creditor_name": "Robert Schneider AG",
+            "creditor_address": "Rue du Lac 1268",
+            "creditor_zip_code": "2501",
+            "creditor_city": "Biel",
+            "creditor_country": "CH",
+            "debtor_name": "Pia Rutschmann",
+            "debtor_address": "Grosse Marktgasse 28",
+            "debtor_zip_code": "9400",
+            "debtor_city": "Rorschach",
+            "debtor_country": "CH",
+            "amount": "1949.75",
+            "currency": "CHF",
+            "reference_type": "QRR",
+            "reference_number": "210000000003139471430009017"
+        })
+    }
+
+    fn issue(index: usize, field: &str, code: &str) -> (usize, Option<String>, String) {
+        (index, Some(field.to_string()), code.to_string())
+    }
+
+    fn validate(items: &[Value], transliterate: bool) -> ValidationReport {
+        validate_batch(items, transliterate, &QrGenerator::default())
+            .unwrap()
+            .report
+    }
+
+    fn issue_keys(report: &ValidationReport) -> Vec<(usize, Option<String>, String)> {
+        report
+            .issues
+            .iter()
+            .map(|i| (i.index, i.error.field.clone(), i.error.code.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_batch() {
+        let mut missing = valid_item();
+        missing.as_object_mut().unwrap().remove("debtor_name");
+        missing["amount"] = json!(1949.75);
+        let mut typed = valid_item();
+        typed["creditor_iban"] = json!("CH4431999123000889013");
+        typed["amount"] = json!("5");
+        typed["currency"] = json!("USD");
+        let mut text = valid_item();
+        text["debtor_name"] = json!("");
+        text["debtor_country"] = json!("CHE");
+        text["additional_information"] = json!("Zahlung für 😀");
+        // the combined length is only checked if all fields are valid
+        let mut schema = valid_item();
+        schema["creditor_city"] = json!("Biel".repeat(20));
+
+        let items = vec![
+            valid_item(),
+            missing,
+            typed,
+            text,
+            schema,
+            json!("no object"),
+        ];
+        let report = validate(&items, false);
+        assert!(!report.valid);
+        assert_eq!(report.checked, 6);
+        assert_eq!(
+            issue_keys(&report),
+            vec![
+                issue(1, "debtor_name", "missing"),
+                issue(1, "amount", "invalid_type"),
+                issue(2, "creditor_iban", "invalid_iban"),
+                issue(2, "amount", "invalid_amount"),
+                issue(2, "currency", "invalid_currency"),
+                issue(3, "debtor_name", "length"),
+                issue(3, "debtor_country", "length"),
+                issue(3, "additional_information", "character_set"),
+                issue(4, "creditor_city", "zip_code_city_length"),
+                (5, None, String::from("invalid_type")),
+            ]
+        );
+        assert!(report.issues[3]
+            .error
+            .message
+            .starts_with("Decimal amount '5'"));
+        assert_eq!(
+            report.issues[5].error.message,
+            "Length must be between 1 and 70 characters"
+        );
+        assert_eq!(
+            report.issues[6].error.message,
+            "Length must be exactly 2 characters"
+        );
+    }
+
+    #[test]
+    fn test_validate_reference() {
+        let mut mismatch = valid_item();
+        mismatch["reference_type"] = json!("NON");
+        mismatch["reference_number"] = Value::Null;
+        let mut unknown = valid_item();
+        unknown["reference_type"] = json!("ISR");
+        let mut scor = valid_item();
+        scor["reference_type"] = json!("SCOR");
+
+        let report = validate(&[mismatch, unknown, scor], false);
+        assert_eq!(
+            issue_keys(&report),
+            vec![
+                issue(0, "reference_type", "reference_type_mismatch"),
+                issue(1, "reference_type", "invalid_reference"),
+                issue(2, "reference_number", "invalid_reference"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_transliterate() {
+        let mut item = valid_item();
+        item["debtor_name"] = json!("Zoë Łukasiewicz");
+
+        assert!(!validate(&[item.clone()], false).valid);
+
+        let validation = validate_batch(&[item], true, &QrGenerator::default()).unwrap();
+        assert!(validation.report.valid);
+        assert!(validation.report.issues.is_empty());
+        assert_eq!(validation.qr_data_vec[0].debtor_name(), "Zoë Lukasiewicz");
+        assert_eq!(validation.qr_svg_vec.len(), 1);
+    }
+
+    #[test]
+    fn test_verify_error() {
+        let mut item = valid_item();
+        item["debtor_name"] = json!("");
+        let mut qr_data = serde_json::from_value::<QrData>(item).unwrap();
+
+        let error = qr_data.verify().unwrap_err();
+        assert_eq!(error.code(), "invalid_fields");
+        assert_eq!(
+            error.details(),
+            &[FieldError {
+                field: Some(String::from("debtor_name")),
+                code: String::from("length"),
+                message: String::from("Length must be between 1 and 70 characters"),
+                params: json!({"min": 1, "max": 70}).as_object().unwrap().clone(),
+            }]
+        );
+        assert_eq!(
+            error.to_string(),
+            "The request input could not be validated: 'debtor_name: Length must be between 1 and 70 characters'"
+        );
+    }
+}